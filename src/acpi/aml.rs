@@ -0,0 +1,78 @@
+// just enough AML to find the sleep type values of \_S5
+// there is no interpreter, the DSDT bytes are searched for the name `_S5_`
+// followed by a package. firmware may compute the package in a method,
+// then this finds nothing and the caller needs a fallback
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_PREFIX: u8 = b'\\';
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+
+/// Returns the SLP_TYPa and SLP_TYPb values of the `\_S5` package in `aml`.
+pub fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let mut start = 0;
+    while let Some(position) = find(&aml[start..], b"_S5_") {
+        let name = start + position;
+        start = name + 1;
+        if let Some(values) = parse_s5(aml, name) {
+            return Some(values);
+        }
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// `name` is the offset of `_S5_`, which must be the name of a NameOp
+fn parse_s5(aml: &[u8], name: usize) -> Option<(u8, u8)> {
+    let is_definition = match (name.checked_sub(1).map(|i| aml[i]),
+                               name.checked_sub(2).map(|i| aml[i])) {
+        (Some(NAME_OP), _) => true,
+        (Some(ROOT_PREFIX), Some(NAME_OP)) => true,
+        _ => false,
+    };
+    if !is_definition || aml.get(name + 4) != Some(&PACKAGE_OP) {
+        return None;
+    }
+
+    // PkgLength: bits 6 and 7 of the lead byte say how many bytes follow
+    let mut offset = name + 5;
+    let lead = match aml.get(offset) {
+        Some(&lead) => lead,
+        None => return None,
+    };
+    offset += 1 + (lead >> 6) as usize;
+    // NumElements
+    offset += 1;
+
+    let (a, offset) = match integer(aml, offset) {
+        Some(value) => value,
+        None => return None,
+    };
+    let (b, _) = match integer(aml, offset) {
+        Some(value) => value,
+        None => return None,
+    };
+    Some((a, b))
+}
+
+// decodes an integer constant, returns its low byte and the next offset
+fn integer(aml: &[u8], offset: usize) -> Option<(u8, usize)> {
+    let length = match aml.get(offset) {
+        Some(&ZERO_OP) => return Some((0, offset + 1)),
+        Some(&ONE_OP) => return Some((1, offset + 1)),
+        Some(&BYTE_PREFIX) => 1,
+        Some(&WORD_PREFIX) => 2,
+        Some(&DWORD_PREFIX) => 4,
+        _ => return None,
+    };
+    // little endian, the sleep types are small
+    aml.get(offset + 1).map(|&byte| (byte, offset + 1 + length))
+}
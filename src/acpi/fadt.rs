@@ -0,0 +1,144 @@
+// fixed ACPI description table (signature "FACP")
+// offsets are from the start of the table, header included
+
+use core::ptr;
+use super::{find_table, SdtHeader};
+
+const DSDT_OFFSET: usize = 40;
+const SMI_COMMAND_OFFSET: usize = 48;
+const ACPI_ENABLE_OFFSET: usize = 52;
+const PM1A_CONTROL_OFFSET: usize = 64;
+const PM1B_CONTROL_OFFSET: usize = 68;
+const CENTURY_OFFSET: usize = 108;
+const FLAGS_OFFSET: usize = 112;
+const RESET_REGISTER_OFFSET: usize = 116;
+const RESET_VALUE_OFFSET: usize = 128;
+const X_DSDT_OFFSET: usize = 140;
+
+const FLAG_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// The address spaces of a generic address structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+/// A register described by a generic address structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub address: u64,
+}
+
+fn generic_address(bytes: &[u8]) -> GenericAddress {
+    GenericAddress {
+        space: match bytes[0] {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        },
+        bit_width: bytes[1],
+        address: unsafe { ptr::read_unaligned(bytes[4..12].as_ptr() as *const u64) },
+    }
+}
+
+// the FADT bytes after the header, None without a FADT
+fn data() -> Option<&'static [u8]> {
+    find_table(b"FACP").map(|table| table.data())
+}
+
+// offsets above are from the start of the table
+fn field(data: &[u8], offset: usize, length: usize) -> Option<&[u8]> {
+    let start = offset - ::core::mem::size_of::<SdtHeader>();
+    data.get(start..start + length)
+}
+
+/// Returns the reset register and the value to write to it, if the FADT
+/// (revision 2 and later) has one.
+pub fn reset_register() -> Option<(GenericAddress, u8)> {
+    let data = match data() {
+        Some(data) => data,
+        None => return None,
+    };
+    let flags = match field(data, FLAGS_OFFSET, 4) {
+        Some(flags) => unsafe { ptr::read_unaligned(flags.as_ptr() as *const u32) },
+        None => return None,
+    };
+    if flags & FLAG_RESET_REGISTER_SUPPORTED == 0 {
+        return None;
+    }
+    match (field(data, RESET_REGISTER_OFFSET, 12), field(data, RESET_VALUE_OFFSET, 1)) {
+        (Some(register), Some(value)) => Some((generic_address(register), value[0])),
+        _ => None,
+    }
+}
+
+/// Returns the CMOS register holding the century, if the FADT names one.
+pub fn century_register() -> Option<u8> {
+    let table = match find_table(b"FACP") {
+        Some(table) => table,
+        None => return None,
+    };
+    let offset = CENTURY_OFFSET - ::core::mem::size_of::<super::SdtHeader>();
+    match table.data().get(offset) {
+        Some(&register) if register != 0 => Some(register),
+        _ => None,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    field(data, offset, 4).map(|bytes| unsafe { ptr::read_unaligned(bytes.as_ptr() as *const u32) })
+}
+
+/// Returns the physical address of the DSDT. The 64 bit field of ACPI 2.0
+/// wins over the old one.
+pub fn dsdt_address() -> Option<usize> {
+    let data = match data() {
+        Some(data) => data,
+        None => return None,
+    };
+    let x_dsdt = field(data, X_DSDT_OFFSET, 8)
+        .map(|bytes| unsafe { ptr::read_unaligned(bytes.as_ptr() as *const u64) })
+        .unwrap_or(0);
+    if x_dsdt != 0 {
+        return Some(x_dsdt as usize);
+    }
+    match read_u32(data, DSDT_OFFSET) {
+        Some(dsdt) if dsdt != 0 => Some(dsdt as usize),
+        _ => None,
+    }
+}
+
+/// Returns the I/O ports of the PM1a and (if there is one) PM1b control
+/// blocks.
+pub fn pm1_control_ports() -> Option<(u16, Option<u16>)> {
+    let data = match data() {
+        Some(data) => data,
+        None => return None,
+    };
+    match (read_u32(data, PM1A_CONTROL_OFFSET), read_u32(data, PM1B_CONTROL_OFFSET)) {
+        (Some(a), b) if a != 0 => {
+            Some((a as u16, b.and_then(|b| if b != 0 { Some(b as u16) } else { None })))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the SMI command port and the value that switches the firmware
+/// into ACPI mode, None if the machine is always in ACPI mode.
+pub fn acpi_enable_command() -> Option<(u16, u8)> {
+    let data = match data() {
+        Some(data) => data,
+        None => return None,
+    };
+    let port = read_u32(data, SMI_COMMAND_OFFSET).unwrap_or(0);
+    match field(data, ACPI_ENABLE_OFFSET, 1) {
+        Some(value) if port != 0 && value[0] != 0 => Some((port as u16, value[0])),
+        _ => None,
+    }
+}
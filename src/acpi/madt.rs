@@ -0,0 +1,268 @@
+// multiple APIC description table (signature "APIC")
+// lists the local APICs, the I/O APICs, how the ISA IRQs are wired to the
+// global system interrupts (GSIs) of the I/O APICs and where the NMIs come
+// in. `init` copies everything into a `Madt` in fixed size tables, so the
+// topology stays available without walking the table again
+
+use core::ptr;
+use spin::Once;
+use super::find_table;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_NMI_SOURCE: u8 = 3;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+pub const MAX_LOCAL_APICS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_NMIS: usize = 16;
+
+/// The processor ID of a local APIC NMI entry that applies to all.
+pub const ALL_PROCESSORS: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// The processor can be used.
+    pub enabled: bool,
+    /// A disabled processor that can be brought online at runtime.
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not connected to the GSI with the same number, or not
+/// with the ISA default of edge triggered, active high.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// A GSI that is connected to NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSource {
+    pub flags: u16,
+    pub gsi: u32,
+}
+
+/// A local APIC LINT input that is connected to NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// `ALL_PROCESSORS` or the processor ID of one local APIC.
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic(LocalApic),
+    IoApic(IoApic),
+    InterruptOverride(InterruptOverride),
+    NmiSource(NmiSource),
+    LocalApicNmi(LocalApicNmi),
+    Other(u8),
+}
+
+/// The parsed MADT. Entries beyond the table sizes are dropped (`init`
+/// says so).
+pub struct Madt {
+    /// The physical address of the local APICs.
+    pub local_apic_address: u32,
+    local_apics: [LocalApic; MAX_LOCAL_APICS],
+    local_apic_count: usize,
+    io_apics: [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [InterruptOverride; MAX_OVERRIDES],
+    override_count: usize,
+    nmi_sources: [NmiSource; MAX_NMIS],
+    nmi_source_count: usize,
+    local_apic_nmis: [LocalApicNmi; MAX_NMIS],
+    local_apic_nmi_count: usize,
+}
+
+// appends to one of the tables, returns false if it is full
+macro_rules! push {
+    ($table:expr, $count:expr, $entry:expr) => {
+        if $count < $table.len() {
+            $table[$count] = $entry;
+            $count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Madt {
+    pub fn local_apics(&self) -> &[LocalApic] {
+        &self.local_apics[..self.local_apic_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn interrupt_overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    pub fn nmi_sources(&self) -> &[NmiSource] {
+        &self.nmi_sources[..self.nmi_source_count]
+    }
+
+    pub fn local_apic_nmis(&self) -> &[LocalApicNmi] {
+        &self.local_apic_nmis[..self.local_apic_nmi_count]
+    }
+
+    /// Returns the number of processors that can be used, enabled or
+    /// online capable.
+    pub fn usable_cpus(&self) -> usize {
+        self.local_apics().iter().filter(|cpu| cpu.enabled || cpu.online_capable).count()
+    }
+}
+
+static MADT: Once<Madt> = Once::new();
+
+/// Parses the MADT, if there is one. Called by `acpi::init` once the tables
+/// are mapped.
+pub fn init() {
+    let table = match find_table(b"APIC") {
+        Some(table) => table,
+        None => return,
+    };
+    let mut madt = Madt {
+        local_apic_address: read_u32(table.data(), 0),
+        local_apics: [LocalApic { processor_id: 0, apic_id: 0, enabled: false,
+                                  online_capable: false }; MAX_LOCAL_APICS],
+        local_apic_count: 0,
+        io_apics: [IoApic { id: 0, address: 0, gsi_base: 0 }; MAX_IO_APICS],
+        io_apic_count: 0,
+        overrides: [InterruptOverride { bus: 0, irq: 0, gsi: 0, flags: 0 }; MAX_OVERRIDES],
+        override_count: 0,
+        nmi_sources: [NmiSource { flags: 0, gsi: 0 }; MAX_NMIS],
+        nmi_source_count: 0,
+        local_apic_nmis: [LocalApicNmi { processor_id: 0, flags: 0, lint: 0 }; MAX_NMIS],
+        local_apic_nmi_count: 0,
+    };
+    let mut dropped = 0;
+    for entry in entries() {
+        let stored = match entry {
+            MadtEntry::LocalApic(entry) => {
+                push!(madt.local_apics, madt.local_apic_count, entry)
+            }
+            MadtEntry::IoApic(entry) => push!(madt.io_apics, madt.io_apic_count, entry),
+            MadtEntry::InterruptOverride(entry) => {
+                push!(madt.overrides, madt.override_count, entry)
+            }
+            MadtEntry::NmiSource(entry) => {
+                push!(madt.nmi_sources, madt.nmi_source_count, entry)
+            }
+            MadtEntry::LocalApicNmi(entry) => {
+                push!(madt.local_apic_nmis, madt.local_apic_nmi_count, entry)
+            }
+            MadtEntry::Other(_) => true,
+        };
+        if !stored {
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        println!("acpi: {} MADT entries didn't fit", dropped);
+    }
+    MADT.call_once(|| madt);
+}
+
+/// Returns the parsed MADT, None if there is none.
+pub fn parsed() -> Option<&'static Madt> {
+    MADT.try()
+}
+
+/// Iterates over the raw entries of the MADT, empty if there is none.
+pub fn entries() -> MadtEntries {
+    // the entries come after the local APIC address and the flags
+    let data = find_table(b"APIC").map(|table| &table.data()[8..]).unwrap_or(&[]);
+    MadtEntries { data: data }
+}
+
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        // every entry starts with its type and length
+        if self.data.len() < 2 {
+            return None;
+        }
+        let entry_type = self.data[0];
+        let length = self.data[1] as usize;
+        if length < 2 || length > self.data.len() {
+            return None; // malformed table
+        }
+        let entry = &self.data[..length];
+        self.data = &self.data[length..];
+
+        let parsed = match entry_type {
+            ENTRY_LOCAL_APIC if length >= 8 => {
+                let flags = read_u32(entry, 4);
+                MadtEntry::LocalApic(LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: flags & LOCAL_APIC_ENABLED != 0,
+                    online_capable: flags & LOCAL_APIC_ONLINE_CAPABLE != 0,
+                })
+            }
+            ENTRY_IO_APIC if length >= 12 => MadtEntry::IoApic(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE if length >= 10 => {
+                MadtEntry::InterruptOverride(InterruptOverride {
+                    bus: entry[2],
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                })
+            }
+            ENTRY_NMI_SOURCE if length >= 8 => MadtEntry::NmiSource(NmiSource {
+                flags: read_u16(entry, 2),
+                gsi: read_u32(entry, 4),
+            }),
+            ENTRY_LOCAL_APIC_NMI if length >= 6 => MadtEntry::LocalApicNmi(LocalApicNmi {
+                processor_id: entry[2],
+                flags: read_u16(entry, 3),
+                lint: entry[5],
+            }),
+            other => MadtEntry::Other(other),
+        };
+        Some(parsed)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    assert!(offset + 2 <= data.len());
+    unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const u16) }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    assert!(offset + 4 <= data.len());
+    unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const u32) }
+}
@@ -0,0 +1,232 @@
+// ACPI table discovery
+// the root system description pointer (RSDP) lives on a 16 byte boundary in
+// the BIOS area and points to the RSDT (32 bit entries) or, since ACPI 2.0,
+// the XSDT (64 bit entries), which list all other tables. every table is
+// identity mapped during `init`, so lookups afterwards need no page tables
+
+use core::{mem, ptr, slice};
+use spin::Once;
+use memory::{MemoryController, PhysicalAddress};
+
+pub mod fadt;
+pub mod madt;
+pub mod aml;
+
+pub use self::madt::Madt;
+
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+// the part of the RSDP that exists since ACPI 1.0
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+}
+
+// ACPI 2.0 extension
+#[repr(C, packed)]
+struct Rsdp2 {
+    rsdp: Rsdp,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every system description table starts with.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn address(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    // the bytes following the header
+    pub fn data(&self) -> &[u8] {
+        let start = self.address() + mem::size_of::<SdtHeader>();
+        let length = self.length as usize - mem::size_of::<SdtHeader>();
+        unsafe { slice::from_raw_parts(start as *const u8, length) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidChecksum([u8; 4]),
+}
+
+// the RSDT or XSDT
+struct RootTable {
+    header: &'static SdtHeader,
+    entry_size: usize,
+}
+
+static ROOT: Once<RootTable> = Once::new();
+// not listed in the root table, the FADT points to it
+static DSDT: Once<&'static SdtHeader> = Once::new();
+
+/// Finds the RSDP and maps the root table, every table it lists and the
+/// DSDT.
+pub fn init(memory_controller: &mut MemoryController) -> Result<(), AcpiError> {
+    assert_has_not_been_called!("acpi::init must be called only once");
+
+    memory_controller.map_mmio(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START);
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+
+    let (root_address, entry_size) = if rsdp.rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdp.rsdt_address as usize, 4)
+    };
+    let header = unsafe { map_table(memory_controller, root_address) }?;
+    let root = ROOT.call_once(|| RootTable {
+        header: header,
+        entry_size: entry_size,
+    });
+
+    for address in root.entries() {
+        match unsafe { map_table(memory_controller, address) } {
+            Ok(table) => println!("acpi: {} at {:#x}", signature_str(&table.signature),
+                                  address),
+            Err(error) => println!("acpi: skipping table at {:#x}: {:?}", address, error),
+        }
+    }
+    if let Some(address) = fadt::dsdt_address() {
+        match unsafe { map_table(memory_controller, address) } {
+            Ok(table) => {
+                println!("acpi: DSDT at {:#x}", address);
+                DSDT.call_once(|| table);
+            }
+            Err(error) => println!("acpi: skipping the DSDT at {:#x}: {:?}", address, error),
+        }
+    }
+    madt::init();
+    Ok(())
+}
+
+// searches the BIOS area, the EBDA is not checked since it would mean
+// mapping page 0
+fn find_rsdp() -> Option<&'static Rsdp2> {
+    let mut address = BIOS_AREA_START;
+    while address < BIOS_AREA_END {
+        let rsdp = unsafe { &*(address as *const Rsdp) };
+        if &rsdp.signature == RSDP_SIGNATURE
+            && checksum(address, mem::size_of::<Rsdp>())
+        {
+            let rsdp = unsafe { &*(address as *const Rsdp2) };
+            // the extended part is only valid from revision 2 on
+            if rsdp.rsdp.revision < 2
+                || checksum(address, rsdp.length as usize)
+            {
+                return Some(rsdp);
+            }
+        }
+        address += 16;
+    }
+    None
+}
+
+// maps the header first, since the length of the table is only known then
+unsafe fn map_table(memory_controller: &mut MemoryController, address: PhysicalAddress)
+                    -> Result<&'static SdtHeader, AcpiError>
+{
+    memory_controller.map_mmio(address, mem::size_of::<SdtHeader>());
+    let header = &*(address as *const SdtHeader);
+    memory_controller.map_mmio(address, header.length as usize);
+    if !checksum(address, header.length as usize) {
+        return Err(AcpiError::InvalidChecksum(header.signature));
+    }
+    Ok(header)
+}
+
+// all bytes of a valid structure sum up to 0
+fn checksum(address: usize, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+impl RootTable {
+    fn entries(&self) -> RootEntries {
+        let data = self.header.data();
+        RootEntries {
+            start: data.as_ptr() as usize,
+            count: data.len() / self.entry_size,
+            entry_size: self.entry_size,
+            index: 0,
+        }
+    }
+}
+
+struct RootEntries {
+    start: usize,
+    count: usize,
+    entry_size: usize,
+    index: usize,
+}
+
+impl Iterator for RootEntries {
+    type Item = PhysicalAddress;
+
+    fn next(&mut self) -> Option<PhysicalAddress> {
+        if self.index >= self.count {
+            return None;
+        }
+        let entry = self.start + self.index * self.entry_size;
+        self.index += 1;
+        // the entries are not necessarily aligned
+        let address = unsafe {
+            if self.entry_size == 8 {
+                ptr::read_unaligned(entry as *const u64) as usize
+            } else {
+                ptr::read_unaligned(entry as *const u32) as usize
+            }
+        };
+        Some(address)
+    }
+}
+
+/// Returns the first table with the given signature, e.g. `b"APIC"` for the
+/// MADT. Only tables with a valid checksum are returned.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let root = match ROOT.try() {
+        Some(root) => root,
+        None => return None,
+    };
+    root.entries()
+        .map(|address| unsafe { &*(address as *const SdtHeader) })
+        .filter(|table| &table.signature == signature)
+        .find(|table| checksum(table.address(), table.length as usize))
+}
+
+/// Returns the parsed MADT: processors, I/O APICs, interrupt overrides and
+/// NMI wiring. None without a MADT or before `init`.
+pub fn madt() -> Option<&'static Madt> {
+    madt::parsed()
+}
+
+/// Returns the differentiated system description table, which holds the
+/// AML code of the machine.
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    DSDT.try().cloned()
+}
+
+fn signature_str(signature: &[u8; 4]) -> &str {
+    ::core::str::from_utf8(signature).unwrap_or("????")
+}
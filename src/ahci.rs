@@ -0,0 +1,451 @@
+// AHCI SATA controllers (PCI class 01:06), how QEMU's q35 machine attaches
+// its disks
+// the HBA registers are in BAR 5 (the ABAR). every implemented port with a
+// SATA disk gets one DMA allocation holding its command list, the area the
+// HBA stores received FISes in, a single command table and a bounce buffer.
+// commands are built as a register FIS in the table of slot 0, with one PRD
+// entry for the bounce buffer, which is physically contiguous. there is one
+// command per port at a time and it is polled: the issue bit is watched
+// until the HBA clears it. the port interrupts stay disabled. writes end
+// with a FLUSH CACHE EXT, like on the IDE channels. a transfer copies its
+// drive out of `DRIVES` and holds the port's blocking `Mutex` while it
+// polls, so interrupts stay on
+
+use alloc::boxed::Box;
+use core::{fmt, ptr, str};
+use sync::{IrqMutex, Mutex};
+use memory::{MemoryController, DmaMemory, PAGE_SIZE};
+use pci::{self, Bar};
+use block::{self, BlockDevice, BlockError};
+use ata;
+
+// HBA registers, byte offsets into the ABAR
+const HBA_CAPABILITIES: usize = 0x00;
+const HBA_GLOBAL_CONTROL: usize = 0x04;
+const HBA_PORTS_IMPLEMENTED: usize = 0x0c;
+const HBA_VERSION: usize = 0x10;
+
+const CAPABILITIES_64BIT: u32 = 1 << 31;
+const GLOBAL_CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+// port registers, offsets from the port's base
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_COMMAND_LIST_UPPER: usize = 0x04;
+const PORT_FIS: usize = 0x08;
+const PORT_FIS_UPPER: usize = 0x0c;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+const PORT_COMMAND_START: u32 = 1 << 0;
+const PORT_COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const PORT_COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const PORT_COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+// the task file register mirrors the ATA status and error registers
+const TASK_FILE_ERROR: u32 = 1 << 0;
+const TASK_FILE_DATA_REQUEST: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+
+// device detection and interface power management of the SATA status
+const SATA_STATUS_DETECTION_MASK: u32 = 0xf;
+const SATA_STATUS_DEVICE_PRESENT: u32 = 0x3;
+const SATA_STATUS_POWER_SHIFT: u32 = 8;
+const SATA_STATUS_POWER_MASK: u32 = 0xf;
+const SATA_STATUS_POWER_ACTIVE: u32 = 0x1;
+
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+const SIGNATURE_ATAPI: u32 = 0xeb14_0101;
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY_DEVICE: u8 = 0xec;
+
+// host to device register FIS
+const FIS_TYPE_REGISTER_H2D: u8 = 0x27;
+const FIS_LENGTH: usize = 20;
+// the FIS carries a command, not a control register update
+const FIS_COMMAND: u8 = 1 << 7;
+const FIS_DEVICE_LBA: u8 = 1 << 6;
+
+// command header: FIS length in dwords, direction and PRD table length
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDT_LENGTH_SHIFT: u32 = 16;
+
+// layout of a port's DMA memory. the command list must be 1 KiB aligned,
+// the receive area 256 bytes and the command table 128 bytes
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+const COMMAND_TABLE_PRDT: usize = 0x80;
+const COMMAND_TABLE_SIZE: usize = COMMAND_TABLE_PRDT + 16;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const BOUNCE_SIZE: usize = BOUNCE_PAGES * PAGE_SIZE;
+
+// in register polls, like the IDE channels
+const TIMEOUT: usize = 10_000_000;
+
+const MAX_DRIVES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// The port stayed busy, or a command didn't finish.
+    Timeout,
+    /// The drive aborted the command, with the contents of its error
+    /// register.
+    CommandAborted(u8),
+    /// There is no DMA memory the HBA can address.
+    OutOfMemory,
+    /// The drive has no 48 bit commands.
+    UnsupportedDrive,
+    /// The sectors lie beyond the end of the drive.
+    LbaOutOfRange,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    base: usize,
+}
+
+impl Registers {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register) as *mut u32, value) };
+    }
+
+    // waits until all of `bits` are clear in `register`
+    fn wait_clear(&self, register: usize, bits: u32) -> Result<(), AhciError> {
+        for _ in 0..TIMEOUT {
+            if self.read(register) & bits == 0 {
+                return Ok(());
+            }
+        }
+        Err(AhciError::Timeout)
+    }
+}
+
+/// A SATA disk on one of the ports.
+#[derive(Clone, Copy)]
+struct Drive {
+    port: usize,
+    registers: Registers,
+    // never freed, so every copy of the drive can use it
+    memory: &'static DmaMemory,
+    model: [u8; ata::MODEL_LENGTH],
+    model_length: usize,
+    sectors: u64,
+    sector_size: usize,
+}
+
+impl Drive {
+    fn model(&self) -> &str {
+        str::from_utf8(&self.model[..self.model_length]).unwrap_or("?")
+    }
+
+    // runs one command on the first `length` bytes of the bounce buffer
+    fn command(&self, command: u8, lba: u64, count: u16, length: usize, writing: bool)
+               -> Result<(), AhciError> {
+        let table_address = self.memory.physical_address() + COMMAND_TABLE_OFFSET;
+        let bounce_address = self.memory.physical_address() + BOUNCE_OFFSET;
+
+        let mut fis = [0; FIS_LENGTH];
+        fis[0] = FIS_TYPE_REGISTER_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[2] = command;
+        fis[4] = lba as u8;
+        fis[5] = (lba >> 8) as u8;
+        fis[6] = (lba >> 16) as u8;
+        fis[7] = FIS_DEVICE_LBA;
+        fis[8] = (lba >> 24) as u8;
+        fis[9] = (lba >> 32) as u8;
+        fis[10] = (lba >> 40) as u8;
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+
+        let prdt_length = if length > 0 { 1 } else { 0 };
+        let mut flags = (FIS_LENGTH / 4) as u32 | prdt_length << HEADER_PRDT_LENGTH_SHIFT;
+        if writing {
+            flags |= HEADER_WRITE;
+        }
+        unsafe {
+            let table = self.memory.pointer(COMMAND_TABLE_OFFSET);
+            ptr::write_bytes(table, 0, COMMAND_TABLE_SIZE);
+            ptr::copy_nonoverlapping(fis.as_ptr(), table, FIS_LENGTH);
+            if length > 0 {
+                let prd = table.offset(COMMAND_TABLE_PRDT as isize) as *mut u32;
+                ptr::write_volatile(prd, bounce_address as u32);
+                ptr::write_volatile(prd.offset(1), (bounce_address as u64 >> 32) as u32);
+                // the byte count is stored minus one
+                ptr::write_volatile(prd.offset(3), (length - 1) as u32);
+            }
+
+            let header = self.memory.pointer(COMMAND_LIST_OFFSET) as *mut u32;
+            ptr::write_volatile(header, flags);
+            ptr::write_volatile(header.offset(1), 0);
+            ptr::write_volatile(header.offset(2), table_address as u32);
+            ptr::write_volatile(header.offset(3), (table_address as u64 >> 32) as u32);
+        }
+
+        let registers = self.registers;
+        registers.wait_clear(PORT_TASK_FILE, TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST)?;
+        registers.write(PORT_INTERRUPT_STATUS, !0);
+        registers.write(PORT_COMMAND_ISSUE, 1);
+        for _ in 0..TIMEOUT {
+            let failed = registers.read(PORT_INTERRUPT_STATUS) & INTERRUPT_TASK_FILE_ERROR != 0;
+            if failed || registers.read(PORT_COMMAND_ISSUE) & 1 == 0 {
+                let task_file = registers.read(PORT_TASK_FILE);
+                if failed || task_file & TASK_FILE_ERROR != 0 {
+                    return Err(AhciError::CommandAborted((task_file >> 8) as u8));
+                }
+                return Ok(());
+            }
+        }
+        Err(AhciError::Timeout)
+    }
+
+    fn check_range(&self, lba: u64, count: usize) -> Result<(), AhciError> {
+        if lba + count as u64 > self.sectors {
+            Err(AhciError::LbaOutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buffer.len() / self.sector_size)?;
+        let sectors_per_command = BOUNCE_SIZE / self.sector_size;
+        for (index, chunk) in buffer.chunks_mut(sectors_per_command * self.sector_size)
+            .enumerate() {
+            let start = lba + (index * sectors_per_command) as u64;
+            let count = (chunk.len() / self.sector_size) as u16;
+            self.command(COMMAND_READ_DMA_EXT, start, count, chunk.len(), false)?;
+            unsafe {
+                ptr::copy_nonoverlapping(self.memory.pointer(BOUNCE_OFFSET), chunk.as_mut_ptr(),
+                                         chunk.len());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buffer.len() / self.sector_size)?;
+        let sectors_per_command = BOUNCE_SIZE / self.sector_size;
+        for (index, chunk) in buffer.chunks(sectors_per_command * self.sector_size).enumerate() {
+            let start = lba + (index * sectors_per_command) as u64;
+            let count = (chunk.len() / self.sector_size) as u16;
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), self.memory.pointer(BOUNCE_OFFSET),
+                                         chunk.len());
+            }
+            self.command(COMMAND_WRITE_DMA_EXT, start, count, chunk.len(), true)?;
+        }
+        self.command(COMMAND_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "port {}: {} ({} MiB, {} byte sectors)", self.port, self.model(),
+               self.sectors * self.sector_size as u64 >> 20, self.sector_size)
+    }
+}
+
+static DRIVES: IrqMutex<[Option<Drive>; MAX_DRIVES]> =
+    IrqMutex::new([None, None, None, None, None, None, None, None]);
+// held through a transfer on the drive with the same index
+static TRANSFERS: [Mutex<()>; MAX_DRIVES] = [
+    Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()),
+    Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()),
+];
+
+/// Sets up the first AHCI controller and identifies the SATA disks on its
+/// ports. Returns false if there is no controller.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("ahci::init must be called only once");
+
+    let controller = match pci::find(0x01, 0x06) {
+        Some(controller) => controller,
+        None => return false,
+    };
+    let abar = match controller.bar(5) {
+        Some(Bar::Io { .. }) | None => {
+            println!("ahci: {} has no ABAR", controller);
+            return false;
+        }
+        Some(bar) => bar,
+    };
+    controller.enable_memory_space();
+    controller.enable_bus_mastering();
+    let hba = Registers {
+        base: memory_controller.map_mmio(abar.base() as usize, abar.size() as usize),
+    };
+    hba.write(HBA_GLOBAL_CONTROL, hba.read(HBA_GLOBAL_CONTROL) | GLOBAL_CONTROL_AHCI_ENABLE);
+    let addresses_64bit = hba.read(HBA_CAPABILITIES) & CAPABILITIES_64BIT != 0;
+    let implemented = hba.read(HBA_PORTS_IMPLEMENTED);
+    let version = hba.read(HBA_VERSION);
+    println!("ahci: {}, AHCI {}.{}, ports {:#x}", controller, version >> 16,
+             (version >> 8) & 0xff, implemented);
+
+    let mut drives = DRIVES.lock();
+    let mut count = 0;
+    for port in 0..32 {
+        if implemented & 1 << port == 0 {
+            continue;
+        }
+        let registers = Registers { base: hba.base + PORT_BASE + port * PORT_SIZE };
+        let status = registers.read(PORT_SATA_STATUS);
+        if status & SATA_STATUS_DETECTION_MASK != SATA_STATUS_DEVICE_PRESENT
+            || (status >> SATA_STATUS_POWER_SHIFT) & SATA_STATUS_POWER_MASK
+                != SATA_STATUS_POWER_ACTIVE {
+            continue;
+        }
+        match registers.read(PORT_SIGNATURE) {
+            SIGNATURE_ATA => {}
+            SIGNATURE_ATAPI => {
+                println!("ahci: port {}: ATAPI device, skipped", port);
+                continue;
+            }
+            signature => {
+                println!("ahci: port {}: unknown signature {:#x}, skipped", port, signature);
+                continue;
+            }
+        }
+        if count == MAX_DRIVES {
+            println!("ahci: more than {} disks, ignoring port {}", MAX_DRIVES, port);
+            break;
+        }
+        match init_port(port, registers, addresses_64bit, memory_controller) {
+            Ok(drive) => {
+                println!("ahci: {}", drive);
+                drives[count] = Some(drive);
+                block::register(&DISKS[count]);
+                count += 1;
+            }
+            Err(error) => println!("ahci: port {}: {:?}", port, error),
+        }
+    }
+    true
+}
+
+// gives the port its memory, starts it and sends IDENTIFY DEVICE
+fn init_port(port: usize, registers: Registers, addresses_64bit: bool,
+             memory_controller: &mut MemoryController) -> Result<Drive, AhciError> {
+    // the addresses may only change while the port is stopped
+    registers.write(PORT_COMMAND, registers.read(PORT_COMMAND) & !PORT_COMMAND_START);
+    registers.wait_clear(PORT_COMMAND, PORT_COMMAND_LIST_RUNNING)?;
+    registers.write(PORT_COMMAND, registers.read(PORT_COMMAND) & !PORT_COMMAND_FIS_RECEIVE);
+    registers.wait_clear(PORT_COMMAND, PORT_COMMAND_FIS_RUNNING)?;
+
+    let memory: &'static DmaMemory = match memory_controller.alloc_dma(1 + BOUNCE_PAGES) {
+        Some(memory) => unsafe { &*Box::into_raw(Box::new(memory)) },
+        None => return Err(AhciError::OutOfMemory),
+    };
+    let physical_address = memory.physical_address() as u64;
+    if !addresses_64bit && physical_address + memory.size() as u64 > 1 << 32 {
+        return Err(AhciError::OutOfMemory);
+    }
+    let command_list = physical_address + COMMAND_LIST_OFFSET as u64;
+    let fis = physical_address + FIS_OFFSET as u64;
+    registers.write(PORT_COMMAND_LIST, command_list as u32);
+    registers.write(PORT_COMMAND_LIST_UPPER, (command_list >> 32) as u32);
+    registers.write(PORT_FIS, fis as u32);
+    registers.write(PORT_FIS_UPPER, (fis >> 32) as u32);
+    // both are cleared by writing ones
+    registers.write(PORT_SATA_ERROR, !0);
+    registers.write(PORT_INTERRUPT_STATUS, !0);
+    registers.write(PORT_INTERRUPT_ENABLE, 0);
+
+    registers.wait_clear(PORT_TASK_FILE, TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST)?;
+    registers.write(PORT_COMMAND, registers.read(PORT_COMMAND) | PORT_COMMAND_FIS_RECEIVE);
+    registers.write(PORT_COMMAND, registers.read(PORT_COMMAND) | PORT_COMMAND_START);
+
+    let mut drive = Drive {
+        port: port,
+        registers: registers,
+        memory: memory,
+        model: [0; ata::MODEL_LENGTH],
+        model_length: 0,
+        sectors: 0,
+        sector_size: ata::SECTOR_SIZE,
+    };
+    drive.command(COMMAND_IDENTIFY_DEVICE, 0, 0, 512, false)?;
+    let mut data = [0u16; 256];
+    unsafe {
+        ptr::copy_nonoverlapping(drive.memory.pointer(BOUNCE_OFFSET) as *const u16,
+                                 data.as_mut_ptr(), data.len());
+    }
+    drive.model_length = ata::identify_model(&data, &mut drive.model);
+    drive.sector_size = ata::identify_sector_size(&data);
+    // every SATA disk has the 48 bit commands, the driver uses nothing else
+    drive.sectors = match ata::identify_lba48_sectors(&data) {
+        Some(sectors) => sectors,
+        None => return Err(AhciError::UnsupportedDrive),
+    };
+    Ok(drive)
+}
+
+/// A disk of the `DRIVES` table as a `block::BlockDevice`.
+pub struct AhciDisk {
+    index: usize,
+    name: &'static str,
+}
+
+static DISKS: [AhciDisk; MAX_DRIVES] = [
+    AhciDisk { index: 0, name: "ahci0" },
+    AhciDisk { index: 1, name: "ahci1" },
+    AhciDisk { index: 2, name: "ahci2" },
+    AhciDisk { index: 3, name: "ahci3" },
+    AhciDisk { index: 4, name: "ahci4" },
+    AhciDisk { index: 5, name: "ahci5" },
+    AhciDisk { index: 6, name: "ahci6" },
+    AhciDisk { index: 7, name: "ahci7" },
+];
+
+impl AhciDisk {
+    // a copy, the IrqMutex is only held while it is taken
+    fn drive(&self) -> Drive {
+        DRIVES.lock()[self.index].expect("ahci: registered disk vanished")
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.drive().sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.drive().sectors
+    }
+
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let drive = self.drive();
+        block::check_length(buffer.len(), drive.sector_size)?;
+        let _transfer = TRANSFERS[self.index].lock();
+        drive.read(lba, buffer).map_err(BlockError::from)
+    }
+
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let drive = self.drive();
+        block::check_length(buffer.len(), drive.sector_size)?;
+        let _transfer = TRANSFERS[self.index].lock();
+        drive.write(lba, buffer).map_err(BlockError::from)
+    }
+}
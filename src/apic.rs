@@ -0,0 +1,300 @@
+// local APIC
+// every CPU has one. it receives the interrupts routed to the CPU, has its
+// own timer, and sends and receives inter-processor interrupts (IPIs).
+// until the I/O APIC routes the legacy IRQs, LINT0 is set to ExtINT
+// ("virtual wire mode"), so the 8259 keeps delivering through the local APIC
+
+use core::ptr;
+use x86_64::registers::msr::{rdmsr, wrmsr};
+use memory::MemoryController;
+use sync::InitCell;
+use cmdline;
+use cpu;
+use interrupts::{self, IrqHandler};
+use time::{self, TickSource};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// register offsets into the MMIO page
+const REG_ID: usize = 0x20;
+const REG_VERSION: usize = 0x30;
+const REG_TASK_PRIORITY: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ERROR_STATUS: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// interrupt command register bits
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// the timer counts down at the bus frequency divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const CALIBRATION_MS: u32 = 10;
+
+// the lowest 4 bits of the spurious vector are hardwired to 1 on old CPUs
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+pub const TIMER_VECTOR: u8 = 0xf0;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf1;
+
+// LVT entry bits
+pub const LVT_MASKED: u32 = 1 << 16;
+pub const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+pub const LVT_DELIVERY_EXTINT: u32 = 0b111 << 8;
+
+// virtual address of the register page, only set if the APIC is in use
+static BASE: InitCell<usize> = InitCell::new();
+
+/// The local vector table entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lvt {
+    Timer,
+    Thermal,
+    PerformanceCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    fn register(self) -> usize {
+        match self {
+            Lvt::Timer => 0x320,
+            Lvt::Thermal => 0x330,
+            Lvt::PerformanceCounter => 0x340,
+            Lvt::Lint0 => 0x350,
+            Lvt::Lint1 => 0x360,
+            Lvt::Error => 0x370,
+        }
+    }
+}
+
+/// Returns whether the CPU has a local APIC (CPUID.1:EDX bit 9).
+pub fn is_supported() -> bool {
+    cpu::cpuid(1).edx & (1 << 9) != 0
+}
+
+/// Returns whether `init` switched to the local APIC.
+pub fn is_enabled() -> bool {
+    BASE.get().is_some()
+}
+
+/// Enables the local APIC, unless the CPU has none or `nolapic` is on the
+/// command line. Returns whether the APIC is in use. The PICs must already
+/// be remapped by `interrupts::init`.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    if cmdline::has("nolapic") {
+        println!("apic: disabled on the command line, using the PIC");
+        return false;
+    }
+    if !is_supported() {
+        println!("apic: no local APIC, using the PIC");
+        return false;
+    }
+
+    // the firmware may have moved the registers from 0xfee00000
+    let msr = unsafe { rdmsr(IA32_APIC_BASE) };
+    let physical = (msr & APIC_BASE_ADDRESS_MASK) as usize;
+    if msr & APIC_BASE_ENABLE == 0 {
+        unsafe { wrmsr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE) };
+    }
+    let base = memory_controller.map_mmio(physical, 4096);
+    BASE.init_or_panic("local APIC", base);
+
+    unsafe {
+        // the error LVT must be set before the status is valid, keep it
+        // masked and clear stale errors (the register needs a write first)
+        write_lvt(Lvt::Error, LVT_MASKED);
+        write(REG_ERROR_STATUS, 0);
+        write(REG_ERROR_STATUS, 0);
+
+        write_lvt(Lvt::Timer, LVT_MASKED);
+        write_lvt(Lvt::Thermal, LVT_MASKED);
+        write_lvt(Lvt::PerformanceCounter, LVT_MASKED);
+        // legacy IRQs come in on LINT0 and NMIs on LINT1
+        write_lvt(Lvt::Lint0, LVT_DELIVERY_EXTINT);
+        write_lvt(Lvt::Lint1, LVT_DELIVERY_NMI);
+
+        // accept all priorities and software enable the APIC
+        write(REG_TASK_PRIORITY, 0);
+        write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    println!("apic: local APIC {} (version {:#x}) at {:#x}",
+             id(), version() & 0xff, physical);
+    time::register_tick_source(&APIC_TIMER);
+    true
+}
+
+/// Enables the local APIC of an application processor, with every LVT entry
+/// masked: the legacy IRQs, NMIs and the tick all go to the BSP, the AP only
+/// gets IPIs. `init` must have run on the BSP, all APICs share the register
+/// address.
+pub fn init_ap() {
+    let msr = unsafe { rdmsr(IA32_APIC_BASE) };
+    if msr & APIC_BASE_ENABLE == 0 {
+        unsafe { wrmsr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE) };
+    }
+    unsafe {
+        write_lvt(Lvt::Error, LVT_MASKED);
+        write(REG_ERROR_STATUS, 0);
+        write(REG_ERROR_STATUS, 0);
+        for &entry in &[Lvt::Timer, Lvt::Thermal, Lvt::PerformanceCounter, Lvt::Lint0,
+                        Lvt::Lint1] {
+            write_lvt(entry, LVT_MASKED);
+        }
+        write(REG_TASK_PRIORITY, 0);
+        write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+}
+
+/// Masks LINT0 once the I/O APIC delivers the legacy IRQs, so nothing the
+/// (masked) PICs still raise gets through.
+pub fn disable_virtual_wire() {
+    unsafe { write_lvt(Lvt::Lint0, LVT_MASKED | LVT_DELIVERY_EXTINT) };
+}
+
+fn base() -> usize {
+    *BASE.get_or_panic("local APIC")
+}
+
+/// Reads the register at the given offset of the register page.
+pub unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile((base() + register) as *const u32)
+}
+
+pub unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile((base() + register) as *mut u32, value)
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+/// Returns the APIC ID of the executing CPU.
+pub fn id() -> u8 {
+    (unsafe { read(REG_ID) } >> 24) as u8
+}
+
+pub fn version() -> u32 {
+    unsafe { read(REG_VERSION) }
+}
+
+pub fn read_lvt(entry: Lvt) -> u32 {
+    unsafe { read(entry.register()) }
+}
+
+pub unsafe fn write_lvt(entry: Lvt, value: u32) {
+    write(entry.register(), value)
+}
+
+/// Sends an NMI to the local APIC with the given ID, which may be our own.
+/// The self shorthand can't be used for this, it only works for fixed
+/// interrupts.
+pub fn send_nmi(destination: u8) {
+    send_ipi(destination, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a fixed interrupt with the given vector to the local APIC with the
+/// given ID.
+pub fn send_fixed(destination: u8, vector: u8) {
+    send_ipi(destination, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends INIT to the local APIC with the given ID, asserted and then
+/// deasserted as the old APICs need it. The CPU waits for a startup IPI.
+pub fn send_init(destination: u8) {
+    send_ipi(destination, ICR_DELIVERY_INIT | ICR_TRIGGER_LEVEL | ICR_LEVEL_ASSERT);
+    send_ipi(destination, ICR_DELIVERY_INIT | ICR_TRIGGER_LEVEL);
+}
+
+/// Sends a startup IPI, the CPU starts in real mode at `page` * 4 KiB.
+pub fn send_startup(destination: u8, page: u8) {
+    send_ipi(destination, ICR_DELIVERY_STARTUP | page as u32);
+}
+
+// writing the low half of the ICR sends the IPI, so the destination goes
+// first. waits until the APIC has accepted it
+fn send_ipi(destination: u8, command: u32) {
+    unsafe {
+        write(REG_ICR_HIGH, (destination as u32) << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+    }
+}
+
+/// Measures how many timer ticks (at divide by 16) pass per millisecond by
+/// letting the timer count down in one-shot mode while PIT channel 2 waits
+/// a fixed interval. Leaves the timer stopped.
+pub fn calibrate_timer() -> u32 {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write_lvt(Lvt::Timer, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_INITIAL_COUNT, u32::max_value());
+        ::pit::busy_wait_ms(CALIBRATION_MS);
+        let remaining = read(REG_TIMER_CURRENT_COUNT);
+        write(REG_TIMER_INITIAL_COUNT, 0);
+        (u32::max_value() - remaining) / CALIBRATION_MS
+    }
+}
+
+/// Starts the timer in periodic mode, raising `TIMER_VECTOR` every `count`
+/// ticks.
+pub fn start_periodic_timer(count: u32) {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write_lvt(Lvt::Timer, TIMER_PERIODIC | TIMER_VECTOR as u32);
+        write(REG_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+pub fn stop_timer() {
+    unsafe {
+        write_lvt(Lvt::Timer, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+/// The timer as a `time::TickSource`. It is calibrated against the PIT
+/// every time it starts.
+pub struct ApicTimer;
+
+static APIC_TIMER: ApicTimer = ApicTimer;
+
+impl TickSource for ApicTimer {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn start(&self, hz: u32, handler: IrqHandler) -> Result<u32, ()> {
+        let ticks_per_ms = calibrate_timer();
+        println!("apic: timer runs at {} ticks/ms ({} kHz bus clock)",
+                 ticks_per_ms, ticks_per_ms * 16);
+        interrupts::set_apic_timer_handler(handler);
+        start_periodic_timer(ticks_per_ms * 1000 / hz);
+        Ok(hz)
+    }
+
+    fn stop(&self, _handler: IrqHandler) {
+        stop_timer();
+    }
+}
@@ -0,0 +1,87 @@
+;;; startup code of the application processors (see smp.rs)
+;;; an AP starts in real mode at the page the startup IPI names. `smp::init`
+;;; copies everything from `ap_trampoline_start` to `ap_trampoline_end` to
+;;; TRAMPOLINE and fills in `ap_trampoline_data` before every start. the
+;;; code goes from real mode straight to long mode on the kernel's page
+;;; tables, which identity map the trampoline page, and calls the entry
+;;; with a pointer to the data. every address is computed from TRAMPOLINE,
+;;; this copy here is never run
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_data
+
+;;; must match `smp::TRAMPOLINE`
+TRAMPOLINE equ 0x8000
+
+%define relocated(label) (TRAMPOLINE + (label - ap_trampoline_start))
+
+section .rodata
+bits 16
+
+ap_trampoline_start:
+	cli
+	cld
+	xor ax, ax
+	mov ds, ax
+
+	o32 lgdt [relocated(gdt.pointer)]
+
+	;;  PAE on, the kernel's P4 table (below 4 GiB) into cr3
+	mov eax, cr4
+	or eax, 1 << 5
+	mov cr4, eax
+	mov eax, [relocated(ap_trampoline_data.cr3)]
+	mov cr3, eax
+
+	;;  long mode and no-execute in EFER, like the BSP has them
+	mov ecx, 0xC0000080
+	rdmsr
+	or eax, (1 << 8) | (1 << 11)
+	wrmsr
+
+	;;  protected mode, write protect and paging at once
+	mov eax, cr0
+	or eax, (1 << 31) | (1 << 16) | 1
+	mov cr0, eax
+
+	jmp dword gdt.code:relocated(long_mode)
+
+bits 64
+long_mode:
+	mov ax, 0
+	mov ss, ax
+	mov ds, ax
+	mov es, ax
+	mov fs, ax
+	mov gs, ax
+
+	mov rsp, [relocated(ap_trampoline_data.stack_top)]
+	mov rdi, relocated(ap_trampoline_data)
+	call [relocated(ap_trampoline_data.entry)]
+	;;  the entry never returns
+.hang:
+	cli
+	hlt
+	jmp .hang
+
+	;; the same GDT as in boot.asm, the entry loads the real one
+align 8
+gdt:
+	dq 0
+.code: equ $ - gdt
+	dq (1<<43) | (1<<44) | (1<<47) | (1<<53)
+.pointer:
+	dw $ - gdt - 1
+	dd relocated(gdt)
+
+	;; must match `smp::TrampolineData`
+align 8
+ap_trampoline_data:
+.cr3:		dq 0
+.stack_top:	dq 0
+.entry:		dq 0
+.gdt:		dq 0
+.percpu:	dq 0
+
+ap_trampoline_end:
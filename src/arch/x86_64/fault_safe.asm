@@ -0,0 +1,24 @@
+;;; memory accesses that may fault
+;;; the instructions between the `_start` and `_end` labels are registered as
+;;; recoverable (see interrupts/oops.rs): a fault there resumes at the
+;;; `_fixup` label, which returns 0 instead of 1
+
+global fault_safe_read_byte
+global fault_safe_read_start
+global fault_safe_read_end
+global fault_safe_read_fixup
+
+section .text
+bits 64
+
+;;; u64 fault_safe_read_byte(const u8 *address, u8 *out)
+fault_safe_read_byte:
+fault_safe_read_start:
+	mov al, [rdi]
+fault_safe_read_end:
+	mov [rsi], al
+	mov eax, 1
+	ret
+fault_safe_read_fixup:
+	xor eax, eax
+	ret
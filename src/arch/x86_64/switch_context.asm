@@ -0,0 +1,33 @@
+;;; context switch between kernel threads (see task.rs)
+;;; saves the callee-saved registers, RSP and RFLAGS of the running thread
+;;; into `old` and loads those of `new`. the caller saved everything else
+;;; before the call, so returning on the new stack resumes the new thread
+;;; where it called this (or at its entry, see `task::spawn_raw`)
+
+global switch_context
+
+section .text
+bits 64
+
+;;; void switch_context(Context *old, const Context *new)
+switch_context:
+	mov [rdi + 0x00], rsp
+	mov [rdi + 0x08], rbx
+	mov [rdi + 0x10], rbp
+	mov [rdi + 0x18], r12
+	mov [rdi + 0x20], r13
+	mov [rdi + 0x28], r14
+	mov [rdi + 0x30], r15
+	pushfq
+	pop qword [rdi + 0x38]
+
+	mov rsp, [rsi + 0x00]
+	mov rbx, [rsi + 0x08]
+	mov rbp, [rsi + 0x10]
+	mov r12, [rsi + 0x18]
+	mov r13, [rsi + 0x20]
+	mov r14, [rsi + 0x28]
+	mov r15, [rsi + 0x30]
+	push qword [rsi + 0x38]
+	popfq
+	ret
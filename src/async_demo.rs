@@ -0,0 +1,46 @@
+// two executor tasks on one thread, for the `async_demo` command line flag
+// one echoes the typed characters from a `keyboard::ScancodeStream`, the
+// other blinks a star in the top right corner of the screen twice a second.
+// while it runs the keyboard belongs to the stream, the hotkeys are off
+
+use memory::MemoryController;
+use task::{self, executor};
+use task::executor::{Future, Poll, Waker};
+use vga_buffer::{self, BUFFER_WIDTH};
+use keyboard;
+use time::{self, Sleep};
+
+const BLINK_MS: u64 = 500;
+
+/// Spawns the thread that runs both tasks.
+pub fn start(memory_controller: &mut MemoryController) {
+    let thread = task::spawn(memory_controller, || {
+        executor::spawn(keyboard::print_keypresses()).expect("async demo: could not spawn a task");
+        executor::spawn(Blink { on: false, sleep: time::sleep_async(0) })
+            .expect("async demo: could not spawn a task");
+        executor::run();
+    });
+    if let Err(error) = thread {
+        println!("async demo: no thread: {:?}", error);
+    }
+}
+
+struct Blink {
+    on: bool,
+    sleep: Sleep,
+}
+
+impl Future for Blink {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        loop {
+            if let Poll::Pending = self.sleep.poll(waker) {
+                return Poll::Pending;
+            }
+            self.on = !self.on;
+            vga_buffer::swap_char(0, BUFFER_WIDTH - 1, if self.on { b'*' } else { b' ' });
+            self.sleep = time::sleep_async(BLINK_MS);
+        }
+    }
+}
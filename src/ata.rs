@@ -0,0 +1,993 @@
+// ATA hard disks on the two legacy IDE channels
+// `init` sends IDENTIFY DEVICE to the four possible drives and records the
+// ATA ones (ATAPI and SATA signatures are skipped) in a fixed table, the
+// read and write paths then consult the recorded `Drive`.
+// if the PCI IDE controller can do bus master DMA, transfers of drives that
+// support it go through a bounce buffer in DMA memory: the PRD table points
+// the engine at the buffer, the DMA command is issued and the caller waits
+// for a `DmaDone` future, woken by the channel's IRQ (14 or 15), under a
+// `time::timeout`. otherwise the
+// transfer is polled PIO: the command is written to the task file ports,
+// then the status register is watched until the drive has a sector ready
+// and the data words are moved one by one, with the drive's interrupt
+// masked (nIEN). writes end with a CACHE FLUSH, so the data is on the
+// medium when `write_sectors` returns
+
+use core::{fmt, ptr, str};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::{inb, inw, outb, outw, outl};
+use spin::Once;
+use sync::{IrqMutex, Mutex};
+use memory::{MemoryController, DmaMemory, PAGE_SIZE};
+use interrupts::{self, InterruptContext, IrqHandler};
+use pci;
+use block::{self, BlockDevice, BlockError};
+use task::executor::{self, Future, Poll, Waker};
+use time;
+
+pub const SECTOR_SIZE: usize = 512;
+/// Bytes of the model string in IDENTIFY DEVICE data.
+pub const MODEL_LENGTH: usize = IDENTIFY_MODEL_WORDS * 2;
+
+const PRIMARY_BASE: u16 = 0x1f0;
+const PRIMARY_CONTROL: u16 = 0x3f6;
+const SECONDARY_BASE: u16 = 0x170;
+const SECONDARY_CONTROL: u16 = 0x376;
+
+const PRIMARY_IRQ: u8 = 14;
+const SECONDARY_IRQ: u8 = 15;
+
+// task file registers, offsets from the base port
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const ERROR_NO_MEDIA: u8 = 1 << 1;
+// only has this meaning for commands that write
+const ERROR_WRITE_PROTECTED: u8 = 1 << 6;
+
+// written to the control port (reads of it return the alternate status)
+const CONTROL_INTERRUPT_DISABLE: u8 = 1 << 1;
+
+// bits 5 and 7 are obsolete but must be set, bit 6 selects LBA addressing
+const DRIVE_HEAD_LBA: u8 = 0xe0;
+const DRIVE_HEAD_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY_DEVICE: u8 = 0xec;
+const COMMAND_READ_DMA: u8 = 0xc8;
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA: u8 = 0xca;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+
+// words of the IDENTIFY DEVICE data
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MODEL_WORDS: usize = 20;
+const IDENTIFY_CAPABILITIES: usize = 49;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_COMMAND_SETS: usize = 83;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+const IDENTIFY_SECTOR_SIZE_INFO: usize = 106;
+const IDENTIFY_LOGICAL_SECTOR_SIZE: usize = 117;
+
+const CAPABILITIES_DMA: u16 = 1 << 8;
+const COMMAND_SETS_LBA48: u16 = 1 << 10;
+// word 106 is only valid if bit 14 is set and bit 15 clear
+const SECTOR_SIZE_INFO_VALID_MASK: u16 = 0b11 << 14;
+const SECTOR_SIZE_INFO_VALID: u16 = 0b01 << 14;
+const SECTOR_SIZE_INFO_LONG_SECTORS: u16 = 1 << 12;
+
+// in status polls. a real disk spinning up can take seconds, which is still
+// far below this on any machine we run on
+const TIMEOUT: usize = 10_000_000;
+
+const MAX_LBA28: u64 = 1 << 28;
+
+// a PIO command transfers at most this many sectors. LBA48 commands could
+// do 65536, but the chunking is shared
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+const MAX_DRIVES: usize = 4;
+
+// bus master registers (BAR 4 of the IDE controller), the secondary
+// channel's are 8 ports above the primary's
+const BUS_MASTER_SECONDARY_OFFSET: u16 = 8;
+const BUS_MASTER_COMMAND: u16 = 0;
+const BUS_MASTER_STATUS: u16 = 2;
+const BUS_MASTER_PRDT: u16 = 4;
+
+const BUS_MASTER_START: u8 = 1 << 0;
+// the direction is seen from the bus master: set means it writes memory
+const BUS_MASTER_WRITE_MEMORY: u8 = 1 << 3;
+const BUS_MASTER_STATUS_ERROR: u8 = 1 << 1;
+const BUS_MASTER_STATUS_INTERRUPT: u8 = 1 << 2;
+
+// prog if bits of the IDE controller
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+const PROG_IF_BUS_MASTER: u8 = 1 << 7;
+
+// a PRD entry is the physical address, the byte count and the flags. every
+// entry covers one page of the bounce buffer, so none crosses a 64 KiB
+// boundary
+const PRD_SIZE: usize = 8;
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+
+// layout of a channel's DMA memory: the PRD table, then the bounce buffer
+const PRDT_OFFSET: usize = 0;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const BOUNCE_SIZE: usize = BOUNCE_PAGES * PAGE_SIZE;
+
+// for a DMA command to signal its end
+const DMA_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelId {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Master,
+    Slave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// The drive stayed busy or never requested the data transfer.
+    Timeout,
+    /// The drive reported a device fault.
+    DeviceFault,
+    /// The drive aborted the command, with the contents of the error
+    /// register.
+    CommandAborted(u8),
+    /// The drive has removable media and none is inserted.
+    NoMedia,
+    /// The medium is write protected.
+    WriteProtected,
+    /// There is no ATA drive at this position.
+    UnsupportedDrive,
+    /// The sectors lie beyond the end of the drive, or beyond what 28 bit
+    /// LBA can address on a drive without LBA48.
+    LbaOutOfRange,
+    /// The bus master engine reported an error.
+    DmaError,
+}
+
+/// An ATA drive found by `init`, with what IDENTIFY DEVICE told about it.
+#[derive(Clone, Copy)]
+pub struct Drive {
+    pub channel: ChannelId,
+    pub position: Position,
+    model: [u8; MODEL_LENGTH],
+    model_length: usize,
+    /// Sectors addressable with 28 bit commands.
+    pub lba28_sectors: u32,
+    /// Sectors addressable with 48 bit commands, if the drive has them.
+    pub lba48_sectors: Option<u64>,
+    /// In bytes, usually `SECTOR_SIZE`.
+    pub sector_size: usize,
+    /// The drive supports the DMA commands.
+    pub dma: bool,
+}
+
+impl Drive {
+    pub fn model(&self) -> &str {
+        str::from_utf8(&self.model[..self.model_length]).unwrap_or("?")
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.lba48_sectors.unwrap_or(self.lba28_sectors as u64)
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.sectors() * self.sector_size as u64
+    }
+
+    fn channel(&self) -> &'static IrqMutex<Channel> {
+        channel(self.channel)
+    }
+
+    // whether transfers go through the bus master
+    fn uses_dma(&self) -> bool {
+        self.dma && self.channel().lock().bus_master.is_some()
+    }
+
+    // whether the sectors `lba..lba + count` need the 48 bit commands
+    fn needs_lba48(&self, lba: u64, count: usize) -> Result<bool, AtaError> {
+        let end = lba + count as u64;
+        if end > self.sectors() {
+            Err(AtaError::LbaOutOfRange)
+        } else {
+            Ok(end > MAX_LBA28)
+        }
+    }
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {:?}: {} ({} MiB, {} byte sectors{})",
+               self.channel, self.position, self.model(), self.size_bytes() >> 20,
+               self.sector_size, if self.lba48_sectors.is_some() { ", LBA48" } else { "" })
+    }
+}
+
+static PRIMARY: IrqMutex<Channel> = IrqMutex::new(Channel {
+    base: PRIMARY_BASE,
+    control: PRIMARY_CONTROL,
+    bus_master: None,
+});
+static SECONDARY: IrqMutex<Channel> = IrqMutex::new(Channel {
+    base: SECONDARY_BASE,
+    control: SECONDARY_CONTROL,
+    bus_master: None,
+});
+
+// held for a whole transfer. a DMA transfer sleeps without the channel
+// lock, so this one doesn't disable interrupts, and blocks the threads
+// that wait for it
+static PRIMARY_TRANSFER: Mutex<()> = Mutex::new(());
+static SECONDARY_TRANSFER: Mutex<()> = Mutex::new(());
+
+// set by the IRQ handlers, which must not take the channel locks
+static BUS_MASTER_BASE: Once<u16> = Once::new();
+static DMA_DONE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+// the `DmaDone` futures waiting for them
+static DMA_WAKERS: [IrqMutex<Option<Waker>>; 2] = [IrqMutex::new(None), IrqMutex::new(None)];
+
+static DRIVES: IrqMutex<[Option<Drive>; MAX_DRIVES]> = IrqMutex::new([None; MAX_DRIVES]);
+
+fn channel(id: ChannelId) -> &'static IrqMutex<Channel> {
+    match id {
+        ChannelId::Primary => &PRIMARY,
+        ChannelId::Secondary => &SECONDARY,
+    }
+}
+
+fn transfer_lock(id: ChannelId) -> &'static Mutex<()> {
+    match id {
+        ChannelId::Primary => &PRIMARY_TRANSFER,
+        ChannelId::Secondary => &SECONDARY_TRANSFER,
+    }
+}
+
+fn channel_index(id: ChannelId) -> usize {
+    match id {
+        ChannelId::Primary => 0,
+        ChannelId::Secondary => 1,
+    }
+}
+
+fn bus_master_port(id: ChannelId, register: u16) -> Option<u16> {
+    let offset = match id {
+        ChannelId::Primary => 0,
+        ChannelId::Secondary => BUS_MASTER_SECONDARY_OFFSET,
+    };
+    BUS_MASTER_BASE.try().map(|&base| base + offset + register)
+}
+
+struct Channel {
+    base: u16,
+    control: u16,
+    bus_master: Option<BusMaster>,
+}
+
+// the bus master registers of one channel and its PRD table and bounce
+// buffer
+struct BusMaster {
+    base: u16,
+    memory: DmaMemory,
+}
+
+impl BusMaster {
+    // fills the PRD table for the first `length` bytes of the bounce buffer
+    // and clears the status
+    unsafe fn prepare(&self, length: usize, writing: bool) {
+        let pages = (length + PAGE_SIZE - 1) / PAGE_SIZE;
+        for page in 0..pages {
+            let entry = self.memory.pointer(PRDT_OFFSET + page * PRD_SIZE);
+            let address = self.memory.physical_address() + BOUNCE_OFFSET + page * PAGE_SIZE;
+            let count = if page == pages - 1 { length - page * PAGE_SIZE } else { PAGE_SIZE };
+            let flags = if page == pages - 1 { PRD_END_OF_TABLE } else { 0 };
+            ptr::write_volatile(entry as *mut u32, address as u32);
+            ptr::write_volatile(entry.offset(4) as *mut u16, count as u16);
+            ptr::write_volatile(entry.offset(6) as *mut u16, flags);
+        }
+        outl(self.base + BUS_MASTER_PRDT,
+             (self.memory.physical_address() + PRDT_OFFSET) as u32);
+        outb(self.base + BUS_MASTER_COMMAND, direction(writing));
+        // the error and interrupt bits are cleared by writing ones
+        outb(self.base + BUS_MASTER_STATUS,
+             BUS_MASTER_STATUS_ERROR | BUS_MASTER_STATUS_INTERRUPT);
+    }
+
+    unsafe fn start(&self, writing: bool) {
+        outb(self.base + BUS_MASTER_COMMAND, direction(writing) | BUS_MASTER_START);
+    }
+
+    // stops the engine and returns its status
+    unsafe fn stop(&self) -> u8 {
+        let command = inb(self.base + BUS_MASTER_COMMAND);
+        outb(self.base + BUS_MASTER_COMMAND, command & !BUS_MASTER_START);
+        let status = inb(self.base + BUS_MASTER_STATUS);
+        outb(self.base + BUS_MASTER_STATUS,
+             BUS_MASTER_STATUS_ERROR | BUS_MASTER_STATUS_INTERRUPT);
+        status
+    }
+
+    fn bounce_buffer(&self) -> *mut u8 {
+        self.memory.pointer(BOUNCE_OFFSET)
+    }
+}
+
+fn direction(writing: bool) -> u8 {
+    if writing { 0 } else { BUS_MASTER_WRITE_MEMORY }
+}
+
+impl Channel {
+    unsafe fn status(&self) -> u8 {
+        inb(self.base + STATUS)
+    }
+
+    // the status is only valid 400ns after selecting a drive or issuing a
+    // command. every read of the alternate status takes about 100ns
+    unsafe fn delay_400ns(&self) {
+        for _ in 0..4 {
+            inb(self.control);
+        }
+    }
+
+    unsafe fn wait_not_busy(&self) -> Result<u8, AtaError> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    // turns the fault and error bits of a status into an error
+    unsafe fn check_status(&self, status: u8, writing: bool) -> Result<(), AtaError> {
+        if status & STATUS_DEVICE_FAULT != 0 {
+            return Err(AtaError::DeviceFault);
+        }
+        if status & STATUS_ERROR != 0 {
+            let error = inb(self.base + ERROR);
+            return Err(if error & ERROR_NO_MEDIA != 0 {
+                AtaError::NoMedia
+            } else if writing && error & ERROR_WRITE_PROTECTED != 0 {
+                AtaError::WriteProtected
+            } else {
+                AtaError::CommandAborted(error)
+            });
+        }
+        Ok(())
+    }
+
+    // waits until the drive is ready to transfer a sector
+    unsafe fn wait_data_request(&self, writing: bool) -> Result<(), AtaError> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BUSY != 0 {
+                continue;
+            }
+            self.check_status(status, writing)?;
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    // waits for the end of a command that transfers no (more) data
+    unsafe fn wait_completion(&self, writing: bool) -> Result<(), AtaError> {
+        let status = self.wait_not_busy()?;
+        self.check_status(status, writing)
+    }
+
+    // `lba_bits` are the LBA bits 24 to 27 of 28 bit commands. `interrupt`
+    // lets the drive raise its IRQ for the next command
+    unsafe fn select(&self, position: Position, lba_bits: u8, interrupt: bool)
+                     -> Result<(), AtaError> {
+        outb(self.control, if interrupt { 0 } else { CONTROL_INTERRUPT_DISABLE });
+        let mut drive_head = DRIVE_HEAD_LBA | (lba_bits & 0x0f);
+        if position == Position::Slave {
+            drive_head |= DRIVE_HEAD_SLAVE;
+        }
+        outb(self.base + DRIVE_HEAD, drive_head);
+        self.delay_400ns();
+        // a floating bus reads as 0xff, an empty position as 0
+        match self.status() {
+            0 | 0xff => Err(AtaError::UnsupportedDrive),
+            _ => self.wait_not_busy().map(|_| ()),
+        }
+    }
+
+    // sends a command for `count` sectors (0 means 256) starting at `lba`
+    unsafe fn command_lba28(&self, position: Position, lba: u32, count: u8, command: u8,
+                            interrupt: bool) -> Result<(), AtaError> {
+        self.select(position, (lba >> 24) as u8, interrupt)?;
+        outb(self.base + SECTOR_COUNT, count);
+        outb(self.base + LBA_LOW, lba as u8);
+        outb(self.base + LBA_MID, (lba >> 8) as u8);
+        outb(self.base + LBA_HIGH, (lba >> 16) as u8);
+        outb(self.base + COMMAND, command);
+        self.delay_400ns();
+        Ok(())
+    }
+
+    // the registers are two deep for 48 bit commands, the high order bytes
+    // are written first
+    unsafe fn command_lba48(&self, position: Position, lba: u64, count: u16, command: u8,
+                            interrupt: bool) -> Result<(), AtaError> {
+        self.select(position, 0, interrupt)?;
+        outb(self.base + SECTOR_COUNT, (count >> 8) as u8);
+        outb(self.base + LBA_LOW, (lba >> 24) as u8);
+        outb(self.base + LBA_MID, (lba >> 32) as u8);
+        outb(self.base + LBA_HIGH, (lba >> 40) as u8);
+        outb(self.base + SECTOR_COUNT, count as u8);
+        outb(self.base + LBA_LOW, lba as u8);
+        outb(self.base + LBA_MID, (lba >> 8) as u8);
+        outb(self.base + LBA_HIGH, (lba >> 16) as u8);
+        outb(self.base + COMMAND, command);
+        self.delay_400ns();
+        Ok(())
+    }
+
+    unsafe fn command(&self, position: Position, lba: u64, count: usize, lba48: bool,
+                      command_lba28: u8, command_lba48: u8, interrupt: bool)
+                      -> Result<(), AtaError> {
+        if lba48 {
+            self.command_lba48(position, lba, count as u16, command_lba48, interrupt)
+        } else {
+            self.command_lba28(position, lba as u32, count as u8, command_lba28, interrupt)
+        }
+    }
+
+    unsafe fn read_sector_data(&self, buffer: &mut [u8]) {
+        for word in buffer.chunks_mut(2) {
+            let value = inw(self.base + DATA);
+            word[0] = value as u8;
+            word[1] = (value >> 8) as u8;
+        }
+    }
+
+    unsafe fn write_sector_data(&self, buffer: &[u8]) {
+        for word in buffer.chunks(2) {
+            outw(self.base + DATA, word[0] as u16 | (word[1] as u16) << 8);
+        }
+    }
+
+    unsafe fn cache_flush(&self, position: Position, lba48: bool) -> Result<(), AtaError> {
+        self.select(position, 0, false)?;
+        let command = if lba48 { COMMAND_CACHE_FLUSH_EXT } else { COMMAND_CACHE_FLUSH };
+        outb(self.base + COMMAND, command);
+        self.delay_400ns();
+        self.wait_completion(true)
+    }
+
+    // sends IDENTIFY DEVICE. `UnsupportedDrive` if there is no drive or it
+    // isn't an ATA disk
+    unsafe fn identify(&self, position: Position) -> Result<[u16; 256], AtaError> {
+        self.select(position, 0, false)?;
+        outb(self.base + SECTOR_COUNT, 0);
+        outb(self.base + LBA_LOW, 0);
+        outb(self.base + LBA_MID, 0);
+        outb(self.base + LBA_HIGH, 0);
+        outb(self.base + COMMAND, COMMAND_IDENTIFY_DEVICE);
+        self.delay_400ns();
+        if self.status() == 0 {
+            return Err(AtaError::UnsupportedDrive);
+        }
+        self.wait_not_busy()?;
+        // ATAPI (0x14 0xeb) and SATA (0x3c 0xc3) devices abort the command
+        // and leave their signature here, ATA disks leave zeros. checking
+        // before waiting for the data avoids waiting on devices that never
+        // send any
+        if inb(self.base + LBA_MID) != 0 || inb(self.base + LBA_HIGH) != 0 {
+            return Err(AtaError::UnsupportedDrive);
+        }
+        self.wait_data_request(false)?;
+        let mut data = [0; 256];
+        for word in data.iter_mut() {
+            *word = inw(self.base + DATA);
+        }
+        Ok(data)
+    }
+}
+
+fn parse_identify(channel: ChannelId, position: Position, data: &[u16; 256]) -> Drive {
+    let mut model = [0; MODEL_LENGTH];
+    let model_length = identify_model(data, &mut model);
+    let lba28_sectors = data[IDENTIFY_LBA28_SECTORS] as u32
+        | (data[IDENTIFY_LBA28_SECTORS + 1] as u32) << 16;
+    let dma = data[IDENTIFY_CAPABILITIES] & CAPABILITIES_DMA != 0;
+
+    Drive {
+        channel: channel,
+        position: position,
+        model: model,
+        model_length: model_length,
+        lba28_sectors: lba28_sectors,
+        lba48_sectors: identify_lba48_sectors(data),
+        sector_size: identify_sector_size(data),
+        dma: dma,
+    }
+}
+
+// the parts of IDENTIFY DEVICE that don't depend on the transport, AHCI
+// drives answer with the same data
+
+/// Copies the model out of IDENTIFY DEVICE data, returns its length.
+pub fn identify_model(data: &[u16; 256], model: &mut [u8; MODEL_LENGTH]) -> usize {
+    // the model is space padded ASCII with the bytes of every word swapped
+    for (index, word) in data[IDENTIFY_MODEL..IDENTIFY_MODEL + IDENTIFY_MODEL_WORDS]
+        .iter()
+        .enumerate() {
+        model[index * 2] = (word >> 8) as u8;
+        model[index * 2 + 1] = *word as u8;
+    }
+    let mut model_length = model.len();
+    while model_length > 0 && (model[model_length - 1] == b' ' || model[model_length - 1] == 0) {
+        model_length -= 1;
+    }
+    model_length
+}
+
+/// Returns the sectors addressable with 48 bit commands, None if the drive
+/// has none.
+pub fn identify_lba48_sectors(data: &[u16; 256]) -> Option<u64> {
+    if data[IDENTIFY_COMMAND_SETS] & COMMAND_SETS_LBA48 != 0 {
+        let mut sectors = 0;
+        for index in (0..4).rev() {
+            sectors = sectors << 16 | data[IDENTIFY_LBA48_SECTORS + index] as u64;
+        }
+        Some(sectors)
+    } else {
+        None
+    }
+}
+
+/// Returns the logical sector size in bytes.
+pub fn identify_sector_size(data: &[u16; 256]) -> usize {
+    let info = data[IDENTIFY_SECTOR_SIZE_INFO];
+    if info & SECTOR_SIZE_INFO_VALID_MASK == SECTOR_SIZE_INFO_VALID
+        && info & SECTOR_SIZE_INFO_LONG_SECTORS != 0 {
+        let words = data[IDENTIFY_LOGICAL_SECTOR_SIZE] as usize
+            | (data[IDENTIFY_LOGICAL_SECTOR_SIZE + 1] as usize) << 16;
+        words * 2
+    } else {
+        SECTOR_SIZE
+    }
+}
+
+/// Sets up bus master DMA if the IDE controller has it, then identifies the
+/// drives on both channels and prints them.
+pub fn init(memory_controller: &mut MemoryController) {
+    assert_has_not_been_called!("ata::init must be called only once");
+
+    init_bus_master(memory_controller);
+    let mut drives = DRIVES.lock();
+    let mut count = 0;
+    for &id in [ChannelId::Primary, ChannelId::Secondary].iter() {
+        for &position in [Position::Master, Position::Slave].iter() {
+            let result = unsafe { channel(id).lock().identify(position) };
+            match result {
+                Ok(data) => {
+                    let drive = parse_identify(id, position, &data);
+                    println!("ata: {}", drive);
+                    drives[count] = Some(drive);
+                    block::register(&DISKS[count]);
+                    count += 1;
+                }
+                Err(AtaError::UnsupportedDrive) => {}
+                Err(error) => println!("ata: {:?} {:?}: identify failed: {:?}",
+                                       id, position, error),
+            }
+        }
+    }
+}
+
+// finds the bus master registers and gives every channel in compatibility
+// mode (the legacy ports and IRQs this driver uses) a PRD table and a
+// bounce buffer
+fn init_bus_master(memory_controller: &mut MemoryController) {
+    let controller = match pci::find(0x01, 0x01) {
+        Some(controller) => controller,
+        None => return,
+    };
+    let base = match controller.bar(4) {
+        Some(pci::Bar::Io { base, .. }) if controller.prog_if & PROG_IF_BUS_MASTER != 0 => base,
+        _ => {
+            println!("ata: {} can't do bus master DMA, using PIO", controller);
+            return;
+        }
+    };
+    controller.enable_io_space();
+    controller.enable_bus_mastering();
+    BUS_MASTER_BASE.call_once(|| base);
+
+    let channels = [
+        (ChannelId::Primary, PROG_IF_PRIMARY_NATIVE, PRIMARY_IRQ,
+         ata_primary_interrupt as IrqHandler),
+        (ChannelId::Secondary, PROG_IF_SECONDARY_NATIVE, SECONDARY_IRQ,
+         ata_secondary_interrupt as IrqHandler),
+    ];
+    for &(id, native, irq, handler) in channels.iter() {
+        if controller.prog_if & native != 0 {
+            println!("ata: {:?} channel is in native mode, using PIO", id);
+            continue;
+        }
+        // the PRD entries hold 32 bit addresses
+        let memory = match memory_controller.alloc_dma(1 + BOUNCE_PAGES) {
+            Some(memory) if memory.physical_address() + memory.size() <= 1 << 32 => memory,
+            _ => {
+                println!("ata: no DMA memory below 4 GiB for the {:?} channel, using PIO", id);
+                continue;
+            }
+        };
+        if let Err(error) = interrupts::register_irq(irq, handler) {
+            println!("ata: can't use IRQ {}: {:?}, using PIO", irq, error);
+            continue;
+        }
+        channel(id).lock().bus_master = Some(BusMaster {
+            base: bus_master_port(id, 0).unwrap(),
+            memory: memory,
+        });
+    }
+    println!("ata: bus master DMA at {:#x}", base);
+}
+
+fn ata_primary_interrupt(_context: &mut InterruptContext) {
+    dma_interrupt(ChannelId::Primary);
+}
+
+fn ata_secondary_interrupt(_context: &mut InterruptContext) {
+    dma_interrupt(ChannelId::Secondary);
+}
+
+fn dma_interrupt(id: ChannelId) {
+    let status_port = match bus_master_port(id, BUS_MASTER_STATUS) {
+        Some(port) => port,
+        None => return,
+    };
+    unsafe {
+        // not ours, PIO commands run with the interrupt masked
+        if inb(status_port) & BUS_MASTER_STATUS_INTERRUPT == 0 {
+            return;
+        }
+        // reading the status register deasserts the drive's INTRQ
+        channel_status_unlocked(id);
+        outb(status_port, BUS_MASTER_STATUS_INTERRUPT);
+    }
+    DMA_DONE[channel_index(id)].store(true, Ordering::SeqCst);
+    if let Some(waker) = DMA_WAKERS[channel_index(id)].lock().take() {
+        waker.wake();
+    }
+}
+
+unsafe fn channel_status_unlocked(id: ChannelId) -> u8 {
+    match id {
+        ChannelId::Primary => inb(PRIMARY_BASE + STATUS),
+        ChannelId::Secondary => inb(SECONDARY_BASE + STATUS),
+    }
+}
+
+pub struct Drives {
+    index: usize,
+}
+
+impl Iterator for Drives {
+    type Item = Drive;
+
+    fn next(&mut self) -> Option<Drive> {
+        let drives = DRIVES.lock();
+        while self.index < MAX_DRIVES {
+            let drive = drives[self.index];
+            self.index += 1;
+            if drive.is_some() {
+                return drive;
+            }
+        }
+        None
+    }
+}
+
+/// Returns the ATA drives found by `init`.
+pub fn drives() -> Drives {
+    Drives { index: 0 }
+}
+
+/// Returns the drive at the given position, if `init` found one there.
+pub fn find(channel: ChannelId, position: Position) -> Option<Drive> {
+    drives().find(|drive| drive.channel == channel && drive.position == position)
+}
+
+/// Reads `count` sectors starting at `lba` into `buffer`, which must be
+/// exactly `count * drive.sector_size` bytes long.
+pub fn read_sectors(drive: &Drive, lba: u64, count: usize, buffer: &mut [u8])
+                    -> Result<(), AtaError> {
+    assert_eq!(buffer.len(), count * drive.sector_size,
+               "ata: buffer size doesn't match the count");
+    let lba48 = drive.needs_lba48(lba, count)?;
+
+    let _transfer = transfer_lock(drive.channel).lock();
+    if drive.uses_dma() {
+        return read_sectors_dma(drive, lba, lba48, buffer);
+    }
+    let channel = drive.channel().lock();
+    let chunk_size = MAX_SECTORS_PER_COMMAND * drive.sector_size;
+    for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+        let start = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+        let sectors = chunk.len() / drive.sector_size;
+        unsafe {
+            channel.command(drive.position, start, sectors, lba48,
+                            COMMAND_READ_SECTORS, COMMAND_READ_SECTORS_EXT, false)?;
+            for sector in chunk.chunks_mut(drive.sector_size) {
+                channel.wait_data_request(false)?;
+                channel.read_sector_data(sector);
+                channel.delay_400ns();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `count` sectors from `buffer`, which must be exactly
+/// `count * drive.sector_size` bytes long, starting at `lba`, and flushes
+/// the drive's write cache.
+pub fn write_sectors(drive: &Drive, lba: u64, count: usize, buffer: &[u8])
+                     -> Result<(), AtaError> {
+    assert_eq!(buffer.len(), count * drive.sector_size,
+               "ata: buffer size doesn't match the count");
+    let lba48 = drive.needs_lba48(lba, count)?;
+
+    let _transfer = transfer_lock(drive.channel).lock();
+    if drive.uses_dma() {
+        write_sectors_dma(drive, lba, lba48, buffer)?;
+        let channel = drive.channel().lock();
+        return unsafe { channel.cache_flush(drive.position, drive.lba48_sectors.is_some()) };
+    }
+    let channel = drive.channel().lock();
+    let chunk_size = MAX_SECTORS_PER_COMMAND * drive.sector_size;
+    for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+        let start = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+        let sectors = chunk.len() / drive.sector_size;
+        unsafe {
+            channel.command(drive.position, start, sectors, lba48,
+                            COMMAND_WRITE_SECTORS, COMMAND_WRITE_SECTORS_EXT, false)?;
+            for sector in chunk.chunks(drive.sector_size) {
+                channel.wait_data_request(true)?;
+                channel.write_sector_data(sector);
+                channel.delay_400ns();
+            }
+            channel.wait_completion(true)?;
+        }
+    }
+    unsafe { channel.cache_flush(drive.position, drive.lba48_sectors.is_some()) }
+}
+
+// the DMA paths move as many whole sectors as fit into the bounce buffer
+// per command. the caller holds the transfer lock
+fn read_sectors_dma(drive: &Drive, lba: u64, lba48: bool, buffer: &mut [u8])
+                    -> Result<(), AtaError> {
+    let sectors_per_command = BOUNCE_SIZE / drive.sector_size;
+    let chunk_size = sectors_per_command * drive.sector_size;
+    for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+        let start = lba + (index * sectors_per_command) as u64;
+        dma_command(drive, start, chunk.len(), lba48, false)?;
+        let channel = drive.channel().lock();
+        let bus_master = channel.bus_master.as_ref().expect("ata: bus master vanished");
+        unsafe {
+            ptr::copy_nonoverlapping(bus_master.bounce_buffer(), chunk.as_mut_ptr(), chunk.len());
+        }
+    }
+    Ok(())
+}
+
+fn write_sectors_dma(drive: &Drive, lba: u64, lba48: bool, buffer: &[u8])
+                     -> Result<(), AtaError> {
+    let sectors_per_command = BOUNCE_SIZE / drive.sector_size;
+    let chunk_size = sectors_per_command * drive.sector_size;
+    for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+        let start = lba + (index * sectors_per_command) as u64;
+        {
+            let channel = drive.channel().lock();
+            let bus_master = channel.bus_master.as_ref().expect("ata: bus master vanished");
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), bus_master.bounce_buffer(), chunk.len());
+            }
+        }
+        dma_command(drive, start, chunk.len(), lba48, true)?;
+    }
+    Ok(())
+}
+
+// runs one DMA command on the first `length` bytes of the bounce buffer and
+// sleeps until it is done
+fn dma_command(drive: &Drive, lba: u64, length: usize, lba48: bool, writing: bool)
+               -> Result<(), AtaError> {
+    let (command_lba28, command_lba48) = if writing {
+        (COMMAND_WRITE_DMA, COMMAND_WRITE_DMA_EXT)
+    } else {
+        (COMMAND_READ_DMA, COMMAND_READ_DMA_EXT)
+    };
+    {
+        let channel = drive.channel().lock();
+        let bus_master = channel.bus_master.as_ref().expect("ata: bus master vanished");
+        unsafe {
+            bus_master.prepare(length, writing);
+            DMA_DONE[channel_index(drive.channel)].store(false, Ordering::SeqCst);
+            channel.command(drive.position, lba, length / drive.sector_size, lba48,
+                            command_lba28, command_lba48, true)?;
+            bus_master.start(writing);
+        }
+    }
+
+    let completed = wait_dma(drive.channel);
+
+    let channel = drive.channel().lock();
+    let bus_master = channel.bus_master.as_ref().expect("ata: bus master vanished");
+    unsafe {
+        let bus_master_status = bus_master.stop();
+        completed?;
+        let status = channel.wait_not_busy()?;
+        channel.check_status(status, writing)?;
+        if bus_master_status & BUS_MASTER_STATUS_ERROR != 0 {
+            return Err(AtaError::DmaError);
+        }
+    }
+    Ok(())
+}
+
+fn wait_dma(id: ChannelId) -> Result<(), AtaError> {
+    // before interrupts are enabled nothing would wake the future, the
+    // interrupt bit of the bus master status is polled instead
+    if !::interrupts::interrupts_enabled() {
+        for _ in 0..TIMEOUT {
+            if dma_done(id) {
+                return Ok(());
+            }
+        }
+        return Err(AtaError::Timeout);
+    }
+    executor::block_on(time::timeout(DMA_TIMEOUT_MS, DmaDone { id: id }))
+        .map_err(|_| AtaError::Timeout)
+}
+
+fn dma_done(id: ChannelId) -> bool {
+    let status_port = bus_master_port(id, BUS_MASTER_STATUS).expect("ata: no bus master");
+    DMA_DONE[channel_index(id)].load(Ordering::SeqCst)
+        || unsafe { inb(status_port) } & BUS_MASTER_STATUS_INTERRUPT != 0
+}
+
+// ready once the IRQ reported the end of the running DMA command
+struct DmaDone {
+    id: ChannelId,
+}
+
+impl Future for DmaDone {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        if dma_done(self.id) {
+            return Poll::Ready(());
+        }
+        *DMA_WAKERS[channel_index(self.id)].lock() = Some(*waker);
+        // the IRQ may have come before the waker was set
+        if dma_done(self.id) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// A drive of the `drives()` table as a `block::BlockDevice`.
+pub struct AtaDisk {
+    index: usize,
+    name: &'static str,
+}
+
+static DISKS: [AtaDisk; MAX_DRIVES] = [
+    AtaDisk { index: 0, name: "ata0" },
+    AtaDisk { index: 1, name: "ata1" },
+    AtaDisk { index: 2, name: "ata2" },
+    AtaDisk { index: 3, name: "ata3" },
+];
+
+impl AtaDisk {
+    fn drive(&self) -> Drive {
+        DRIVES.lock()[self.index].expect("ata: registered drive vanished")
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.drive().sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.drive().sectors()
+    }
+
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let drive = self.drive();
+        let count = block::check_length(buffer.len(), drive.sector_size)?;
+        read_sectors(&drive, lba, count, buffer).map_err(BlockError::from)
+    }
+
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let drive = self.drive();
+        let count = block::check_length(buffer.len(), drive.sector_size)?;
+        write_sectors(&drive, lba, count, buffer).map_err(BlockError::from)
+    }
+}
+
+/// Writes a pattern to sectors 1 and 2 of the primary master and reads it
+/// back. Destroys their contents, so it only runs on a scratch disk: the
+/// QEMU disk of a `test-mode` build (`make test` attaches a scratch image),
+/// or any disk with `ata.scratch` on the command line. GRUB keeps its core
+/// image right after the MBR.
+#[cfg(debug_assertions)]
+pub fn test_write_read() {
+    const SECTORS: usize = 2;
+
+    let drive = match find(ChannelId::Primary, Position::Master) {
+        Some(drive) => drive,
+        None => {
+            serial_println!("ata: no primary master, skipping the write test");
+            return;
+        }
+    };
+    let scratch = (cfg!(feature = "test-mode") && drive.model() == "QEMU HARDDISK") ||
+                  ::cmdline::has("ata.scratch");
+    if !scratch {
+        serial_println!("ata: the primary master isn't a scratch disk, skipping the write test");
+        return;
+    }
+    assert_eq!(drive.sector_size, SECTOR_SIZE, "ata: the test needs 512 byte sectors");
+
+    let mut pattern = [0; SECTORS * SECTOR_SIZE];
+    for (index, byte) in pattern.iter_mut().enumerate() {
+        *byte = (index * 7 + index / SECTOR_SIZE) as u8;
+    }
+
+    // sector by sector, while the read below transfers both in one command
+    for sector in 0..SECTORS {
+        let data = &pattern[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE];
+        if let Err(error) = write_sectors(&drive, 1 + sector as u64, 1, data) {
+            panic!("ata: writing sector {} failed: {:?}", 1 + sector, error);
+        }
+    }
+
+    let mut read_back = [0; SECTORS * SECTOR_SIZE];
+    read_sectors(&drive, 1, SECTORS, &mut read_back)
+        .expect("ata: reading the test sectors back failed");
+    for index in 0..pattern.len() {
+        assert_eq!(read_back[index], pattern[index], "ata: byte {} differs", index);
+    }
+    serial_println!("ata: write test passed");
+}
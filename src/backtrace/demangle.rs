@@ -0,0 +1,123 @@
+// a minimal demangler for rustc's legacy symbol names
+// `_ZN` then the path as length prefixed segments and `E`, the last segment
+// a hash like `h0123456789abcdef`. the segments escape the characters that
+// aren't allowed in symbols as `$LT$` and the like and `::` as `..`.
+// everything else is printed as it is
+
+use core::fmt;
+
+/// Shows the demangled form of the symbol name, or the name itself if it
+/// isn't a Rust name.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut path = match path(self.0) {
+            Some(path) => path,
+            None => return f.write_str(self.0),
+        };
+        let mut first = true;
+        while let Some((segment, rest)) = next_segment(path) {
+            path = rest;
+            if rest.is_empty() && is_hash(segment) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+// the segments between `_ZN` and `E`, if they all parse
+fn path(name: &str) -> Option<&str> {
+    if !name.starts_with("_ZN") || !name.ends_with('E') || name.len() < 4 {
+        return None;
+    }
+    let path = &name[3..name.len() - 1];
+    let mut rest = path;
+    while !rest.is_empty() {
+        match next_segment(rest) {
+            Some((_, next)) => rest = next,
+            None => return None,
+        }
+    }
+    Some(path)
+}
+
+// splits off the first length prefixed segment
+fn next_segment(path: &str) -> Option<(&str, &str)> {
+    let digits = path.bytes().take_while(|&byte| byte >= b'0' && byte <= b'9').count();
+    if digits == 0 {
+        return None;
+    }
+    let length: usize = match path[..digits].parse() {
+        Ok(length) => length,
+        Err(_) => return None,
+    };
+    let rest = &path[digits..];
+    if length > rest.len() || !rest.is_char_boundary(length) {
+        return None;
+    }
+    Some((&rest[..length], &rest[length..]))
+}
+
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17 && segment.starts_with('h')
+        && segment[1..].bytes().all(|byte| match byte {
+            b'0'...b'9' | b'a'...b'f' => true,
+            _ => false,
+        })
+}
+
+fn write_segment(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    // a leading `$` is escaped with an underscore
+    let mut rest = if segment.starts_with("_$") { &segment[1..] } else { segment };
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+            continue;
+        }
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                if let Some(replacement) = escape(&rest[1..end + 1]) {
+                    f.write_str(replacement)?;
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+        let character = rest.chars().next().unwrap();
+        write!(f, "{}", character)?;
+        rest = &rest[character.len_utf8()..];
+    }
+    Ok(())
+}
+
+fn escape(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "SP" => "@",
+        "BP" => "*",
+        "RF" => "&",
+        "LT" => "<",
+        "GT" => ">",
+        "LP" => "(",
+        "RP" => ")",
+        "C" => ",",
+        "u20" => " ",
+        "u22" => "\"",
+        "u27" => "'",
+        "u2b" => "+",
+        "u3b" => ";",
+        "u5b" => "[",
+        "u5d" => "]",
+        "u7b" => "{",
+        "u7d" => "}",
+        "u7e" => "~",
+        _ => return None,
+    })
+}
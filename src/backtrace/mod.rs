@@ -0,0 +1,141 @@
+// stack backtraces
+// the kernel is built with frame pointers (`eliminate-frame-pointer` in the
+// target), so every frame starts with the caller's RBP and the return
+// address above it. the chain is followed as long as it stays on a kernel
+// stack, every frame is read with `memory::read_checked`, so a corrupted
+// chain ends the backtrace instead of faulting again. on the stack of a
+// thread from `task` it also has to stay on that stack. the addresses are
+// shown with the function they are in if the symbol table was found (see
+// `symbols`)
+
+mod demangle;
+mod symbols;
+
+pub use self::demangle::Demangle;
+pub use self::symbols::{init, is_symbol_section, symbolize};
+
+use core::fmt::{self, Write};
+use core::mem;
+use memory;
+use task;
+
+const MAX_DEPTH: usize = 32;
+
+/// Prints the return addresses of the callers of the current function.
+#[inline(never)]
+pub fn print<W: Write>(out: &mut W) {
+    let frame_pointer: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(frame_pointer) ::: "intel", "volatile") };
+    print_from(out, frame_pointer);
+}
+
+/// Prints the return addresses of the chain that starts at the frame
+/// `frame_pointer` points to, like the RBP of an interrupted context.
+pub fn print_from<W: Write>(out: &mut W, frame_pointer: usize) {
+    let owner = task::stack_owner(frame_pointer);
+    let _ = match owner {
+        Some((id, _)) => writeln!(out, "    backtrace (thread {}):", id.0),
+        None => writeln!(out, "    backtrace:"),
+    };
+    let mut frame = frame_pointer;
+    for depth in 0..MAX_DEPTH {
+        if frame == 0 {
+            return; // the outermost frame
+        }
+        if let Some((id, stack)) = owner {
+            if !stack.contains(frame) {
+                let _ = writeln!(out, "    (frame pointer {:#x} left the stack of thread {})",
+                                 frame, id.0);
+                return;
+            }
+        }
+        let (next, return_address) = match read_frame(frame) {
+            Ok(words) => words,
+            Err(FrameError::OffStack) => {
+                let _ = writeln!(out, "    (frame pointer {:#x} is off the stack)", frame);
+                return;
+            }
+            Err(FrameError::Unmapped) => {
+                let _ = writeln!(out, "    (frame {:#x} is unmapped)", frame);
+                return;
+            }
+        };
+        if return_address == 0 {
+            return;
+        }
+        let _ = write!(out, "    {:2}: ", depth);
+        print_address(out, return_address);
+        // the callers' frames are further up the stack
+        if next <= frame {
+            if next != 0 {
+                let _ = writeln!(out, "    (frame pointer {:#x} goes down the stack)", next);
+            }
+            return;
+        }
+        frame = next;
+    }
+    let _ = writeln!(out, "    ...");
+}
+
+/// Stores the return addresses of the callers of the current function in
+/// `addresses`, the nearest first, and returns how many it found. Stops
+/// silently where `print` would say why.
+#[inline(never)]
+pub fn callers(addresses: &mut [usize]) -> usize {
+    let mut frame: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(frame) ::: "intel", "volatile") };
+    let mut count = 0;
+    while count < addresses.len() && frame != 0 {
+        let (next, return_address) = match read_frame(frame) {
+            Ok(words) => words,
+            Err(_) => break,
+        };
+        if return_address == 0 {
+            break;
+        }
+        addresses[count] = return_address;
+        count += 1;
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    count
+}
+
+/// Prints `address` and the function it is in, if the symbol table has it,
+/// and ends the line.
+pub fn print_address<W: Write>(out: &mut W, address: usize) {
+    let _ = writeln!(out, "{}", Address(address));
+}
+
+/// Shows a code address with the function it is in, if the symbol table
+/// has it.
+pub struct Address(pub usize);
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match symbolize(self.0) {
+            Some((name, offset)) => write!(f, "{:016x} {}+{:#x}", self.0, Demangle(name), offset),
+            None => write!(f, "{:016x}", self.0),
+        }
+    }
+}
+
+enum FrameError {
+    OffStack,
+    Unmapped,
+}
+
+// the saved RBP and the return address of the frame at `frame`
+fn read_frame(frame: usize) -> Result<(usize, usize), FrameError> {
+    if frame % 8 != 0 || !memory::is_kernel_stack(frame) || !memory::is_kernel_stack(frame + 15) {
+        return Err(FrameError::OffStack);
+    }
+    let mut bytes = [0u8; 16];
+    if memory::read_checked(frame, &mut bytes) != bytes.len() {
+        return Err(FrameError::Unmapped);
+    }
+    let words: [usize; 2] = unsafe { mem::transmute(bytes) };
+    Ok((words[0], words[1]))
+}
@@ -0,0 +1,125 @@
+// the kernel's ELF symbol table
+// GRUB loads every section of the kernel, also the ones that aren't
+// allocated, and the elf-sections tag says where. `memory::init` keeps the
+// frames of the symbol and string tables and maps them, `init` finds them
+// there. there is no heap for a sorted copy, so `symbolize` scans the
+// table, which is fast enough for the few lines of a backtrace
+
+use core::{mem, slice, str};
+use spin::Once;
+use multiboot2::{BootInformation, ElfSection};
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const STT_FUNC: u8 = 2;
+
+// an ELF64 section header, `multiboot2::ElfSection` doesn't show the type
+// and the link
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: u64,
+}
+
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+struct Table {
+    symbols: &'static [Symbol],
+    names: &'static [u8],
+}
+
+static TABLE: Once<Table> = Once::new();
+
+fn header<'a>(section: &'a ElfSection) -> &'a SectionHeader {
+    unsafe { &*(section as *const ElfSection as *const SectionHeader) }
+}
+
+/// Returns whether `section` is a symbol or string table, which
+/// `memory::init` has to keep although they aren't allocated.
+pub fn is_symbol_section(section: &ElfSection) -> bool {
+    let section_type = header(section).section_type;
+    section.addr != 0 && (section_type == SHT_SYMTAB || section_type == SHT_STRTAB)
+}
+
+/// Finds the symbol table. Must run after `memory::init` mapped it.
+pub fn init(boot_info: &BootInformation) {
+    let tag = match boot_info.elf_sections_tag() {
+        Some(tag) => tag,
+        None => return,
+    };
+    let symbols = match tag.sections().map(header).find(|h| h.section_type == SHT_SYMTAB) {
+        Some(symbols) if symbols.address != 0
+            && symbols.entry_size as usize == mem::size_of::<Symbol>() => symbols,
+        _ => {
+            println!("backtrace: no symbol table, addresses only");
+            return;
+        }
+    };
+    // the link is a section index. `sections` skips the null section at
+    // index 0, so the first one it returns is at index 1
+    let first = tag.sections().next().unwrap() as *const ElfSection as usize;
+    let header_size = mem::size_of::<SectionHeader>();
+    let names = unsafe {
+        &*((first - header_size + symbols.link as usize * header_size) as *const SectionHeader)
+    };
+    if names.section_type != SHT_STRTAB || names.address == 0 {
+        println!("backtrace: no symbol names, addresses only");
+        return;
+    }
+    let table = TABLE.call_once(|| unsafe {
+        Table {
+            symbols: slice::from_raw_parts(symbols.address as *const Symbol,
+                                           symbols.size as usize / mem::size_of::<Symbol>()),
+            names: slice::from_raw_parts(names.address as *const u8, names.size as usize),
+        }
+    });
+    println!("backtrace: {} symbols", table.symbols.len());
+}
+
+/// Returns the name of the function `address` is in and the offset into
+/// it, None without a symbol table or below the first function.
+pub fn symbolize(address: usize) -> Option<(&'static str, usize)> {
+    let table = match TABLE.try() {
+        Some(table) => table,
+        None => return None,
+    };
+    let mut nearest: Option<&Symbol> = None;
+    for symbol in table.symbols {
+        if symbol.info & 0xf != STT_FUNC || symbol.value as usize > address {
+            continue;
+        }
+        if nearest.map_or(true, |nearest| symbol.value > nearest.value) {
+            nearest = Some(symbol);
+        }
+    }
+    nearest.and_then(|symbol| {
+        name(table, symbol.name).map(|name| (name, address - symbol.value as usize))
+    })
+}
+
+// the NUL terminated string at `offset` of the string table
+fn name(table: &Table, offset: u32) -> Option<&'static str> {
+    let names: &'static [u8] = table.names;
+    let start = offset as usize;
+    if start >= names.len() {
+        return None;
+    }
+    let length = names[start..].iter().position(|&byte| byte == 0).unwrap_or(names.len() - start);
+    str::from_utf8(&names[start..start + length]).ok()
+}
@@ -0,0 +1,112 @@
+// block devices
+// every disk driver implements `BlockDevice` and registers the disks it
+// found, so code that reads sectors (like a file system) doesn't care
+// whether they are on an IDE channel, an AHCI port or a virtio device.
+// the table is filled during boot and never shrinks. it is an `RwLock`,
+// readers only block while a driver registers a disk
+
+use core::fmt;
+use sync::RwLock;
+use ata::AtaError;
+use ahci::AhciError;
+use virtio_blk::VirtioBlkError;
+
+const MAX_DEVICES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The length of the buffer is no multiple of the sector size.
+    PartialSector,
+    Ata(AtaError),
+    Ahci(AhciError),
+    VirtioBlk(VirtioBlkError),
+}
+
+impl From<AtaError> for BlockError {
+    fn from(error: AtaError) -> BlockError {
+        BlockError::Ata(error)
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(error: AhciError) -> BlockError {
+        BlockError::Ahci(error)
+    }
+}
+
+impl From<VirtioBlkError> for BlockError {
+    fn from(error: VirtioBlkError) -> BlockError {
+        BlockError::VirtioBlk(error)
+    }
+}
+
+/// A disk.
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    /// In bytes.
+    fn sector_size(&self) -> usize;
+
+    fn sectors(&self) -> u64;
+
+    /// Reads `buffer.len() / sector_size()` sectors starting at `lba`.
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer` to the sectors starting at `lba`. The data is on the
+    /// medium when this returns.
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+impl fmt::Display for BlockDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} MiB, {} byte sectors)", self.name(),
+               self.sectors() * self.sector_size() as u64 >> 20, self.sector_size())
+    }
+}
+
+static DEVICES: RwLock<[Option<&'static BlockDevice>; MAX_DEVICES]> =
+    RwLock::new([None; MAX_DEVICES]);
+
+/// Adds a disk to the table. Called by the drivers' `init`.
+pub fn register(device: &'static BlockDevice) {
+    let mut devices = DEVICES.write();
+    match devices.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(device),
+        None => println!("block: table full, dropping {}", device.name()),
+    }
+}
+
+pub struct Devices {
+    index: usize,
+}
+
+impl Iterator for Devices {
+    type Item = &'static BlockDevice;
+
+    fn next(&mut self) -> Option<&'static BlockDevice> {
+        let devices = DEVICES.read();
+        while self.index < MAX_DEVICES {
+            let device = devices[self.index];
+            self.index += 1;
+            if device.is_some() {
+                return device;
+            }
+        }
+        None
+    }
+}
+
+/// Returns the registered disks in the order they were found.
+pub fn devices() -> Devices {
+    Devices { index: 0 }
+}
+
+/// For the drivers: returns the number of sectors in a buffer of
+/// `buffer_length` bytes, an error if it ends with a partial one.
+pub fn check_length(buffer_length: usize, sector_size: usize) -> Result<usize, BlockError> {
+    if buffer_length % sector_size == 0 {
+        Ok(buffer_length / sector_size)
+    } else {
+        Err(BlockError::PartialSector)
+    }
+}
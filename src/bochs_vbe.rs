@@ -0,0 +1,179 @@
+// Bochs/QEMU display interface (the "dispi" of `-vga std`)
+// an index and a data port give access to registers for resolution, depth
+// and enable bits, so modes can be set at any time without calling the
+// video BIOS. the linear framebuffer is BAR 0 of the PCI display
+// controller (1234:1111). the mode ends up as a `video::FramebufferInfo`
+
+use x86_64::instructions::port::{inw, outw};
+use memory::MemoryController;
+use pci;
+use video::{self, ColorField, FramebufferInfo};
+
+const INDEX_PORT: u16 = 0x1ce;
+const DATA_PORT: u16 = 0x1cf;
+
+const REGISTER_ID: u16 = 0x0;
+const REGISTER_X_RESOLUTION: u16 = 0x1;
+const REGISTER_Y_RESOLUTION: u16 = 0x2;
+const REGISTER_BPP: u16 = 0x3;
+const REGISTER_ENABLE: u16 = 0x4;
+const REGISTER_VIRTUAL_WIDTH: u16 = 0x6;
+const REGISTER_X_OFFSET: u16 = 0x8;
+const REGISTER_Y_OFFSET: u16 = 0x9;
+const REGISTER_VIDEO_MEMORY_64K: u16 = 0xa;
+
+// the versions of the interface, 0xb0c5 is the newest
+const ID_FIRST: u16 = 0xb0c0;
+const ID_LAST: u16 = 0xb0c5;
+// the video memory register exists from this version on
+const ID_VIDEO_MEMORY: u16 = 0xb0c2;
+
+const ENABLED: u16 = 0x01;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+
+const VENDOR_ID: u16 = 0x1234;
+const DEVICE_ID: u16 = 0x1111;
+
+const MAX_WIDTH: u32 = 2560;
+const MAX_HEIGHT: u32 = 1600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VbeError {
+    /// The ID register doesn't answer with a known version.
+    NotPresent,
+    /// There is no display controller with a memory BAR 0.
+    NoFramebuffer,
+    /// Depth or resolution is not supported.
+    UnsupportedMode,
+    /// The mode doesn't fit into the video memory.
+    OutOfVideoMemory,
+}
+
+fn read_register(register: u16) -> u16 {
+    unsafe {
+        outw(INDEX_PORT, register);
+        inw(DATA_PORT)
+    }
+}
+
+fn write_register(register: u16, value: u16) {
+    unsafe {
+        outw(INDEX_PORT, register);
+        outw(DATA_PORT, value);
+    }
+}
+
+/// Returns the interface version, None if there is no interface.
+pub fn detect() -> Option<u16> {
+    match read_register(REGISTER_ID) {
+        id @ ID_FIRST...ID_LAST => Some(id),
+        _ => None,
+    }
+}
+
+/// Sets a linear framebuffer mode, maps the framebuffer and registers it
+/// with `video`. `bits_per_pixel` is 15, 16, 24 or 32.
+pub fn set_mode(width: u32, height: u32, bits_per_pixel: u8,
+                memory_controller: &mut MemoryController)
+                -> Result<FramebufferInfo, VbeError> {
+    let version = match detect() {
+        Some(version) => version,
+        None => return Err(VbeError::NotPresent),
+    };
+    let (red, green, blue) = match color_fields(bits_per_pixel) {
+        Some(fields) => fields,
+        None => return Err(VbeError::UnsupportedMode),
+    };
+    if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+        return Err(VbeError::UnsupportedMode);
+    }
+    let pitch = width as usize * ((bits_per_pixel as usize + 7) / 8);
+    let size = pitch * height as usize;
+
+    let display = pci::devices()
+        .find(|device| device.vendor_id == VENDOR_ID && device.device_id == DEVICE_ID)
+        .or_else(|| pci::find(0x03, 0x00));
+    let display = match display {
+        Some(display) => display,
+        None => return Err(VbeError::NoFramebuffer),
+    };
+    let framebuffer = match display.bar(0) {
+        Some(pci::Bar::Io { .. }) | None => return Err(VbeError::NoFramebuffer),
+        Some(bar) => bar,
+    };
+    // older versions don't say, the BAR size is a good guess then
+    let video_memory = if version >= ID_VIDEO_MEMORY {
+        read_register(REGISTER_VIDEO_MEMORY_64K) as u64 * 0x10000
+    } else {
+        framebuffer.size()
+    };
+    if size as u64 > video_memory {
+        return Err(VbeError::OutOfVideoMemory);
+    }
+    display.enable_memory_space();
+
+    // the registers may only be changed while the interface is disabled
+    write_register(REGISTER_ENABLE, 0);
+    write_register(REGISTER_X_RESOLUTION, width as u16);
+    write_register(REGISTER_Y_RESOLUTION, height as u16);
+    write_register(REGISTER_BPP, bits_per_pixel as u16);
+    write_register(REGISTER_VIRTUAL_WIDTH, width as u16);
+    write_register(REGISTER_X_OFFSET, 0);
+    write_register(REGISTER_Y_OFFSET, 0);
+    write_register(REGISTER_ENABLE, ENABLED | LINEAR_FRAMEBUFFER);
+
+    // the device rounds or refuses what it can't do
+    if read_register(REGISTER_X_RESOLUTION) != width as u16
+        || read_register(REGISTER_Y_RESOLUTION) != height as u16
+        || read_register(REGISTER_BPP) != bits_per_pixel as u16 {
+        write_register(REGISTER_ENABLE, 0);
+        return Err(VbeError::UnsupportedMode);
+    }
+
+    let physical_address = framebuffer.base() as usize;
+    let info = FramebufferInfo {
+        physical_address: physical_address,
+        address: memory_controller.map_write_combining(physical_address, size),
+        width: width as usize,
+        height: height as usize,
+        pitch: pitch,
+        bits_per_pixel: bits_per_pixel,
+        red: red,
+        green: green,
+        blue: blue,
+    };
+    println!("bochs-vbe: interface {:#x}, {}", version, info);
+    video::set_framebuffer(info);
+    Ok(info)
+}
+
+/// Turns the interface off again, which brings back VGA (text) mode.
+pub fn disable() {
+    if detect().is_some() {
+        write_register(REGISTER_ENABLE, 0);
+    }
+}
+
+fn color_fields(bits_per_pixel: u8) -> Option<(ColorField, ColorField, ColorField)> {
+    let field = |position, size| ColorField { position: position, size: size };
+    match bits_per_pixel {
+        15 => Some((field(10, 5), field(5, 5), field(0, 5))),
+        16 => Some((field(11, 5), field(5, 6), field(0, 5))),
+        24 | 32 => Some((field(16, 8), field(8, 8), field(0, 8))),
+        _ => None,
+    }
+}
+
+/// Parses a `vbe=` argument like `1024x768x32`.
+pub fn parse_mode(argument: &str) -> Option<(u32, u32, u8)> {
+    let mut parts = argument.split('x');
+    let width = parts.next().and_then(|part| part.parse().ok());
+    let height = parts.next().and_then(|part| part.parse().ok());
+    let bits_per_pixel = parts.next().map_or(Some(32), |part| part.parse().ok());
+    match (width, height, bits_per_pixel, parts.next()) {
+        (Some(width), Some(height), Some(bits_per_pixel), None) => {
+            Some((width, height, bits_per_pixel))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,64 @@
+// kernel command line from the multiboot2 information structure
+// arguments are separated by spaces and are either flags (`nolapic`) or
+// key value pairs (`keyboard=sv`)
+
+use core::{slice, str};
+use spin::Once;
+
+const COMMAND_LINE_TAG: u32 = 1;
+
+static COMMAND_LINE: Once<&'static str> = Once::new();
+
+/// Finds the command line tag in the multiboot information structure at the
+/// given address. Without a tag (or with invalid UTF-8) the command line is
+/// empty.
+pub fn init(multiboot_information_address: usize) {
+    COMMAND_LINE.call_once(|| {
+        unsafe { find_command_line(multiboot_information_address) }.unwrap_or("")
+    });
+}
+
+unsafe fn find_command_line(multiboot_information_address: usize) -> Option<&'static str> {
+    let total_size = *(multiboot_information_address as *const u32) as usize;
+    let end = multiboot_information_address + total_size;
+
+    // tags start after the 8 byte header and are 8 byte aligned
+    let mut tag = multiboot_information_address + 8;
+    while tag + 8 <= end {
+        let tag_type = *(tag as *const u32);
+        let tag_size = *((tag + 4) as *const u32) as usize;
+        if tag_type == 0 || tag_size < 8 {
+            break; // end tag
+        }
+        if tag_type == COMMAND_LINE_TAG {
+            // NUL terminated string after type and size
+            let start = tag + 8;
+            let bytes = slice::from_raw_parts(start as *const u8, tag_size - 8);
+            let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            return str::from_utf8(&bytes[..length]).ok();
+        }
+        tag += (tag_size + 7) & !7;
+    }
+    None
+}
+
+/// Returns the whole command line.
+pub fn command_line() -> &'static str {
+    COMMAND_LINE.try().map(|s| *s).unwrap_or("")
+}
+
+/// Returns the value of the last `key=value` argument with the given key.
+pub fn get(key: &str) -> Option<&'static str> {
+    command_line().split(' ').filter_map(|argument| {
+        let mut parts = argument.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(value)) if k == key => Some(value),
+            _ => None,
+        }
+    }).last()
+}
+
+/// Returns whether the given flag argument is present.
+pub fn has(flag: &str) -> bool {
+    command_line().split(' ').any(|argument| argument == flag)
+}
@@ -0,0 +1,129 @@
+// CMOS memory (NVRAM) next to the real-time clock
+// a register is selected by writing its number to the index port and then
+// read or written at the data port. bit 7 of the index port is the NMI
+// mask, so every write of the index carries the mask we want instead of
+// silently masking NMIs for good. an IrqMutex keeps index/data pairs from
+// interleaving
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::{inb, outb};
+use sync::{IrqMutex, IrqMutexGuard};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const NMI_DISABLE: u8 = 1 << 7;
+
+// the RTC
+pub const SECONDS: u8 = 0x00;
+pub const SECONDS_ALARM: u8 = 0x01;
+pub const MINUTES: u8 = 0x02;
+pub const MINUTES_ALARM: u8 = 0x03;
+pub const HOURS: u8 = 0x04;
+pub const HOURS_ALARM: u8 = 0x05;
+pub const WEEKDAY: u8 = 0x06;
+pub const DAY: u8 = 0x07;
+pub const MONTH: u8 = 0x08;
+pub const YEAR: u8 = 0x09;
+pub const STATUS_A: u8 = 0x0a;
+pub const STATUS_B: u8 = 0x0b;
+pub const STATUS_C: u8 = 0x0c;
+pub const STATUS_D: u8 = 0x0d;
+// the rest is NVRAM
+pub const DIAGNOSTIC_STATUS: u8 = 0x0e;
+/// What the BIOS does after the next reset, see `SHUTDOWN_*`.
+pub const SHUTDOWN_STATUS: u8 = 0x0f;
+pub const FLOPPY_TYPES: u8 = 0x10;
+pub const HARD_DISK_TYPES: u8 = 0x12;
+pub const EQUIPMENT: u8 = 0x14;
+pub const BASE_MEMORY_LOW: u8 = 0x15;
+pub const BASE_MEMORY_HIGH: u8 = 0x16;
+pub const EXTENDED_MEMORY_LOW: u8 = 0x17;
+pub const EXTENDED_MEMORY_HIGH: u8 = 0x18;
+pub const CHECKSUM_HIGH: u8 = 0x2e;
+pub const CHECKSUM_LOW: u8 = 0x2f;
+/// The usual century register. The FADT says where it really is.
+pub const CENTURY: u8 = 0x32;
+
+/// Shutdown status for a warm reset: the BIOS jumps to the vector at
+/// 0x40:0x67 without sending an EOI. Used to start application processors.
+pub const SHUTDOWN_JUMP_WITHOUT_EOI: u8 = 0x0a;
+
+// the standard checksum covers these registers
+const CHECKSUM_FIRST: u8 = 0x10;
+const CHECKSUM_LAST: u8 = 0x2d;
+
+static CMOS: IrqMutex<Cmos> = IrqMutex::new(Cmos { _private: () });
+static NMI_MASKED: AtomicBool = AtomicBool::new(false);
+
+/// Access to the CMOS registers, from `lock`.
+pub struct Cmos {
+    _private: (),
+}
+
+impl Cmos {
+    fn select(&self, register: u8) {
+        let nmi = if NMI_MASKED.load(Ordering::Relaxed) { NMI_DISABLE } else { 0 };
+        unsafe { outb(INDEX_PORT, nmi | (register & !NMI_DISABLE)) };
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.select(register);
+        unsafe { inb(DATA_PORT) }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.select(register);
+        unsafe { outb(DATA_PORT, value) };
+    }
+
+    fn computed_checksum(&self) -> u16 {
+        (CHECKSUM_FIRST..CHECKSUM_LAST + 1)
+            .fold(0u16, |sum, register| sum.wrapping_add(self.read(register) as u16))
+    }
+
+    fn stored_checksum(&self) -> u16 {
+        (self.read(CHECKSUM_HIGH) as u16) << 8 | self.read(CHECKSUM_LOW) as u16
+    }
+}
+
+/// Locks the CMOS for a sequence of accesses that must not be interleaved
+/// with others (like reading all time registers).
+pub fn lock() -> IrqMutexGuard<'static, Cmos> {
+    CMOS.lock()
+}
+
+/// Reads a single register.
+pub fn read(register: u8) -> u8 {
+    lock().read(register)
+}
+
+/// Writes a single register. Writing the checksummed range without
+/// `update_checksum` makes the BIOS complain on the next boot.
+pub fn write(register: u8, value: u8) {
+    lock().write(register, value)
+}
+
+/// Returns whether the standard checksum over registers 0x10 to 0x2d
+/// matches the one stored in 0x2e and 0x2f.
+pub fn checksum_valid() -> bool {
+    let cmos = lock();
+    cmos.computed_checksum() == cmos.stored_checksum()
+}
+
+/// Recomputes and stores the standard checksum.
+pub fn update_checksum() {
+    let mut cmos = lock();
+    let checksum = cmos.computed_checksum();
+    cmos.write(CHECKSUM_HIGH, (checksum >> 8) as u8);
+    cmos.write(CHECKSUM_LOW, checksum as u8);
+}
+
+/// Masks or unmasks NMIs through bit 7 of the index port. Every later
+/// access keeps this setting.
+pub fn set_nmi_masked(masked: bool) {
+    let cmos = lock();
+    NMI_MASKED.store(masked, Ordering::Relaxed);
+    // reselect a harmless register to latch the bit
+    cmos.select(STATUS_D);
+}
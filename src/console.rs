@@ -0,0 +1,175 @@
+// console output backends
+// every place text can go to implements `ConsoleSink`. the fatal paths
+// (`emergency::Writer`) write to all enabled sinks with `force_write_str`,
+// which never waits for a lock the interrupted code may hold.
+// `console=vga|serial|both` on the command line picks where `print!` goes,
+// `both` if there is none. `klog` keeps a copy of everything
+
+use core::fmt;
+use spin::Once;
+use vga_buffer;
+use fbcon;
+use serial;
+use debugcon;
+use klog::{self, KlogSink};
+use cmdline;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Vga,
+    Serial,
+    Both,
+}
+
+impl Mode {
+    pub fn has_vga(self) -> bool {
+        self != Mode::Serial
+    }
+
+    pub fn has_serial(self) -> bool {
+        self != Mode::Vga
+    }
+}
+
+static MODE: Once<Mode> = Once::new();
+
+/// Picks the primary console from the command line. Called right after
+/// `cmdline::init`, before anything is printed.
+pub fn init() {
+    let mode = match cmdline::get("console") {
+        Some("vga") => Mode::Vga,
+        Some("serial") => Mode::Serial,
+        Some("both") | None => Mode::Both,
+        Some(other) => {
+            serial_println!("console: unknown console={}, using both", other);
+            Mode::Both
+        }
+    };
+    MODE.call_once(|| mode);
+}
+
+/// Returns the console mode, `Both` until `init` ran.
+pub fn mode() -> Mode {
+    MODE.try().cloned().unwrap_or(Mode::Both)
+}
+
+/// Used by `print!`: writes to the screen and/or COM1, depending on the
+/// mode. COM1 is skipped if there is no UART, the writes would time out.
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = KlogWriter.write_fmt(args);
+    let mode = mode();
+    if mode.has_vga() {
+        screen_print(args);
+    }
+    if mode.has_serial() && serial::is_present() {
+        serial::print(args);
+    }
+}
+
+/// `print!` as a `fmt::Write`, for code that takes a writer.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+struct KlogWriter;
+
+impl fmt::Write for KlogWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        klog::write_str(s);
+        Ok(())
+    }
+}
+
+// the screen is the framebuffer console once there is one, the VGA text
+// buffer is not visible then
+fn screen_print(args: fmt::Arguments) {
+    if fbcon::is_enabled() {
+        fbcon::print(args);
+    } else {
+        vga_buffer::print(args);
+    }
+}
+
+/// Clears the screen. On a serial only console this sends the ANSI clear
+/// sequence instead.
+pub fn clear_screen() {
+    let mode = mode();
+    if mode.has_vga() {
+        if fbcon::is_enabled() {
+            fbcon::clear_screen();
+        } else {
+            vga_buffer::clear_screen();
+        }
+    }
+    if mode == Mode::Serial {
+        serial_print!("\x1b[2J\x1b[H");
+    }
+}
+
+pub trait ConsoleSink: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend exists and is switched on.
+    fn is_enabled(&self) -> bool;
+
+    fn write_str(&self, s: &str);
+
+    /// Like `write_str`, but breaks or bypasses any lock. Only for fatal
+    /// paths, where the lock holder never runs again.
+    fn force_write_str(&self, s: &str);
+}
+
+pub struct VgaSink;
+
+impl ConsoleSink for VgaSink {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn is_enabled(&self) -> bool {
+        mode().has_vga()
+    }
+
+    fn write_str(&self, s: &str) {
+        vga_buffer::print(format_args!("{}", s));
+    }
+
+    fn force_write_str(&self, s: &str) {
+        vga_buffer::force_write_str(s);
+    }
+}
+
+pub struct SerialSink;
+
+impl ConsoleSink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn is_enabled(&self) -> bool {
+        serial::is_present()
+    }
+
+    fn write_str(&self, s: &str) {
+        serial_print!("{}", s);
+    }
+
+    fn force_write_str(&self, s: &str) {
+        use core::fmt::Write;
+        let _ = serial::RawWriter.write_str(s);
+    }
+}
+
+static SINKS: [&'static ConsoleSink; 5] = [&VgaSink, &fbcon::FbconSink, &SerialSink,
+                                           &debugcon::DebugconSink, &KlogSink];
+
+/// Returns all sinks, enabled or not.
+pub fn sinks() -> &'static [&'static ConsoleSink] {
+    &SINKS
+}
@@ -0,0 +1,72 @@
+// small wrappers around privileged instructions
+
+/// Halts until the next interrupt arrives.
+pub fn halt() {
+    unsafe { asm!("hlt" :::: "volatile") };
+}
+
+/// Enables interrupts and halts until the next one. Since sti only takes
+/// effect after the following instruction, no interrupt can arrive between
+/// the two, so a wakeup checked for with interrupts off can't be missed.
+pub fn enable_interrupts_and_halt() {
+    unsafe { asm!("sti; hlt" :::: "volatile") };
+}
+
+/// Idles forever, waking up for every interrupt. Only makes sense after the
+/// IDT is set up, or with interrupts intentionally left disabled.
+pub fn halt_loop() -> ! {
+    loop {
+        halt();
+    }
+}
+
+/// Disables interrupts and halts for good. Used when the current context is
+/// broken (panics, fatal exceptions) and no handler should run again. NMIs
+/// can still wake the CPU, hence the loop.
+pub fn halt_forever() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile") };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes cpuid for the given leaf (and subleaf 0).
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    cpuid_subleaf(leaf, 0)
+}
+
+pub fn cpuid_subleaf(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile");
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile") };
+    (high as u64) << 32 | low as u64
+}
+
+/// Calls `function` with RSP switched to `stack_top` and switches back
+/// afterwards. The stack must be mapped (or growable) and 16 byte aligned.
+pub unsafe fn call_on_stack(stack_top: usize, function: extern "C" fn()) {
+    // the old RSP is pushed twice, once as padding, so the call is made with
+    // the 16 byte alignment the ABI expects
+    asm!("mov rax, rsp; mov rsp, $0; push rax; push rax; call $1; pop rax; pop rsp"
+         :: "r"(stack_top), "r"(function)
+         : "rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "memory", "cc"
+         : "intel", "volatile");
+}
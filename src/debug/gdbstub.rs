@@ -0,0 +1,568 @@
+// GDB remote serial protocol stub on COM2
+// with `gdb` on the command line, int3 breakpoints, finished single steps
+// and panics stop in here and wait for a debugger on the second serial port:
+//     qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server,nowait
+//     gdb build/kernel.bin -ex 'target remote :1234'
+// the stub runs inside the exception handler with interrupts off and polls
+// the port, so it works whatever state the rest of the kernel is in.
+// memory accesses are checked against the page tables, a bad address gets
+// an error reply instead of a page fault
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtualAddress;
+use x86_64::structures::idt::ExceptionStackFrame;
+use serial::{SerialPort, COM2};
+use sync::IrqMutex;
+use interrupts::{self, SavedRegisters};
+use memory;
+use cmdline;
+use gdt;
+use super::watchpoint::{read_dr6, write_dr6};
+
+// the largest packet we accept, advertised in qSupported
+const PACKET_SIZE: usize = 512;
+// signal numbers for the stop replies
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+const TRAP_FLAG: u64 = 1 << 8;
+// DR6 bit for a finished single step
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+const INT3: u8 = 0xcc;
+const MAX_BREAKPOINTS: usize = 16;
+
+// the `g` packet: 16 general purpose registers and rip (8 bytes each), then
+// eflags, cs, ss, ds, es, fs and gs (4 bytes each)
+const GPR_COUNT: usize = 16;
+const REGISTER_BYTES: usize = (GPR_COUNT + 1) * 8 + 7 * 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// set while a session runs, so a fault inside the stub doesn't re-enter it
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// address and original byte of every inserted software breakpoint
+static BREAKPOINTS: IrqMutex<[Option<(usize, u8)>; MAX_BREAKPOINTS]> =
+    IrqMutex::new([None; MAX_BREAKPOINTS]);
+
+/// Sets up COM2 for the stub if `gdb` is on the command line. The first
+/// stop is the int3 in `rust_main`.
+pub fn init() {
+    if !cmdline::has("gdb") {
+        return;
+    }
+    if !SerialPort::new(COM2).init() {
+        println!("gdbstub: no UART at COM2, not enabled");
+        return;
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    println!("gdbstub: waiting for gdb on COM2 at the next breakpoint");
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Called by the #BP handler. Returns false if the stub isn't enabled.
+pub fn handle_breakpoint(stack_frame: &mut ExceptionStackFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    // int3 traps behind itself. for our own breakpoints gdb wants to see
+    // the address of the breakpoint, where the original instruction runs
+    let address = stack_frame.instruction_pointer.0 - 1;
+    if BREAKPOINTS.lock().iter().any(|slot| slot.map(|(a, _)| a) == Some(address)) {
+        stack_frame.instruction_pointer = VirtualAddress(address);
+    }
+    enter(stack_frame, SIGTRAP, true);
+    true
+}
+
+/// Called by the #DB handler. Returns false if the exception isn't a
+/// single step requested by the debugger.
+pub fn handle_single_step(stack_frame: &mut ExceptionStackFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let dr6 = unsafe { read_dr6() };
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return false;
+    }
+    unsafe { write_dr6(dr6 & !DR6_SINGLE_STEP) };
+    enter(stack_frame, SIGTRAP, true);
+    true
+}
+
+/// Called by the panic handler. Lets the debugger inspect the machine, but
+/// continuing isn't possible. Returns when the debugger detaches.
+pub fn panic_session() {
+    if !is_enabled() {
+        return;
+    }
+    let (instruction_pointer, stack_pointer): (usize, usize);
+    unsafe {
+        asm!("lea $0, [rip]; mov $1, rsp"
+             : "=r"(instruction_pointer), "=r"(stack_pointer) ::: "intel", "volatile");
+    }
+    let mut stack_frame = ExceptionStackFrame {
+        instruction_pointer: VirtualAddress(instruction_pointer),
+        code_segment: gdt::KERNEL_CODE_SELECTOR.0 as u64,
+        cpu_flags: 0,
+        stack_pointer: VirtualAddress(stack_pointer),
+        stack_segment: gdt::KERNEL_DATA_SELECTOR.0 as u64,
+    };
+    enter(&mut stack_frame, SIGABRT, false);
+}
+
+fn enter(stack_frame: &mut ExceptionStackFrame, signal: u8, resumable: bool) {
+    if ACTIVE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // the registers captured by the entry point belong to the stopped code
+    // only if we can resume it, a panic has its own
+    let captured = if resumable { unsafe { interrupts::captured_registers_mut() } } else { None };
+    let mut session = Session {
+        port: SerialPort::new(COM2),
+        registers: captured.as_ref().map(|registers| **registers),
+        stack_frame: stack_frame,
+        signal: signal,
+        resumable: resumable,
+    };
+    session.run();
+    if let (Some(captured), Some(registers)) = (captured, session.registers) {
+        *captured = registers;
+    }
+    ACTIVE.store(false, Ordering::SeqCst);
+}
+
+struct Session<'a> {
+    port: SerialPort,
+    // None if the general purpose registers are unknown
+    registers: Option<SavedRegisters>,
+    stack_frame: &'a mut ExceptionStackFrame,
+    signal: u8,
+    resumable: bool,
+}
+
+enum Action {
+    Reply,
+    Resume,
+}
+
+impl<'a> Session<'a> {
+    // serves requests until the debugger continues, steps or detaches
+    fn run(&mut self) {
+        let mut packet = [0u8; PACKET_SIZE];
+        let mut reply = Reply::new();
+
+        reply.stop(self.signal);
+        self.send(&reply);
+        loop {
+            let length = self.receive(&mut packet);
+            reply.clear();
+            match self.handle(&packet[..length], &mut reply) {
+                Action::Reply => self.send(&reply),
+                Action::Resume => return,
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8], reply: &mut Reply) -> Action {
+        let (command, arguments) = match packet.split_first() {
+            Some((&command, arguments)) => (command, arguments),
+            None => return Action::Reply,
+        };
+        match command {
+            b'?' => reply.stop(self.signal),
+            b'g' => self.read_registers(reply),
+            b'G' => self.write_registers(arguments, reply),
+            b'm' => read_memory(arguments, reply),
+            b'M' => write_memory(arguments, reply),
+            b'c' | b's' => {
+                if !self.resumable {
+                    // after a panic there is nothing to continue
+                    reply.stop(self.signal);
+                    return Action::Reply;
+                }
+                if let Some(address) = parse_hex(arguments) {
+                    self.stack_frame.instruction_pointer = VirtualAddress(address as usize);
+                }
+                if command == b's' {
+                    self.stack_frame.cpu_flags |= TRAP_FLAG;
+                } else {
+                    self.stack_frame.cpu_flags &= !TRAP_FLAG;
+                }
+                return Action::Resume;
+            }
+            b'Z' | b'z' => breakpoint(command == b'Z', arguments, reply),
+            b'D' => {
+                reply.push_str("OK");
+                self.send(reply);
+                self.stack_frame.cpu_flags &= !TRAP_FLAG;
+                return Action::Resume;
+            }
+            // kill has no reply, the closest thing we can do is to go on
+            b'k' => {
+                self.stack_frame.cpu_flags &= !TRAP_FLAG;
+                return Action::Resume;
+            }
+            b'H' => reply.push_str("OK"),
+            b'q' if arguments.starts_with(b"Supported") => {
+                reply.push_str("PacketSize=200")
+            }
+            b'q' if arguments.starts_with(b"Attached") => reply.push_str("1"),
+            // an empty reply means "not supported"
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn read_registers(&self, reply: &mut Reply) {
+        match self.registers {
+            Some(ref registers) => {
+                let mut values = gdb_order(registers);
+                values[RSP_INDEX] = self.stack_frame.stack_pointer.0 as u64;
+                for &value in values.iter() {
+                    reply.push_le(value, 8);
+                }
+            }
+            None => {
+                // unknown registers are sent as xx, except rsp
+                for index in 0..GPR_COUNT {
+                    if index == RSP_INDEX {
+                        reply.push_le(self.stack_frame.stack_pointer.0 as u64, 8);
+                    } else {
+                        reply.push_str("xxxxxxxxxxxxxxxx");
+                    }
+                }
+            }
+        }
+        let frame = &self.stack_frame;
+        reply.push_le(frame.instruction_pointer.0 as u64, 8);
+        reply.push_le(frame.cpu_flags, 4);
+        reply.push_le(frame.code_segment, 4);
+        reply.push_le(frame.stack_segment, 4);
+        // ds, es, fs and gs are unused in long mode
+        for _ in 0..4 {
+            reply.push_le(0, 4);
+        }
+    }
+
+    // changes the general purpose registers, rsp, rip and rflags. the
+    // segment registers stay as they are
+    fn write_registers(&mut self, arguments: &[u8], reply: &mut Reply) {
+        let mut bytes = [0u8; REGISTER_BYTES];
+        let length = match decode_hex(arguments, &mut bytes) {
+            Some(length) if length >= (GPR_COUNT + 1) * 8 + 4 => length,
+            _ => return reply.error(ERROR_INVALID),
+        };
+        let bytes = &bytes[..length];
+        let mut values = [0u64; GPR_COUNT];
+        for (index, value) in values.iter_mut().enumerate() {
+            *value = read_le(&bytes[index * 8..], 8);
+        }
+
+        if let Some(ref mut registers) = self.registers {
+            set_from_gdb_order(registers, &values);
+        }
+        self.stack_frame.stack_pointer = VirtualAddress(values[RSP_INDEX] as usize);
+        self.stack_frame.instruction_pointer =
+            VirtualAddress(read_le(&bytes[GPR_COUNT * 8..], 8) as usize);
+        self.stack_frame.cpu_flags = read_le(&bytes[(GPR_COUNT + 1) * 8..], 4);
+        reply.push_str("OK");
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.try_read_byte() {
+                return byte;
+            }
+        }
+    }
+
+    // waits for a packet with a valid checksum and acknowledges it. returns
+    // the length of the packet data
+    fn receive(&mut self, packet: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            // skips acks and the ctrl-c byte, we are stopped already
+            while self.read_byte() != b'$' {}
+
+            let mut length = 0;
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                let byte = self.read_byte();
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if length < packet.len() {
+                    packet[length] = byte;
+                    length += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+            let high = hex_value(self.read_byte());
+            let low = hex_value(self.read_byte());
+            let valid = match (high, low) {
+                (Some(high), Some(low)) => high << 4 | low == checksum,
+                _ => false,
+            };
+            if valid && !overflow {
+                self.port.write_byte(b'+');
+                return length;
+            }
+            self.port.write_byte(b'-');
+        }
+    }
+
+    // sends until the debugger acknowledges the packet
+    fn send(&mut self, reply: &Reply) {
+        loop {
+            self.port.write_byte(b'$');
+            let mut checksum = 0u8;
+            for &byte in reply.data() {
+                self.port.write_byte(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            self.port.write_byte(b'#');
+            self.port.write_byte(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.port.write_byte(HEX_DIGITS[(checksum & 0xf) as usize]);
+            loop {
+                match self.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// gdb error numbers are errno values
+const ERROR_INVALID: u8 = 0x16;  // EINVAL
+const ERROR_FAULT: u8 = 0x0e;    // EFAULT
+const ERROR_NO_SPACE: u8 = 0x1c; // ENOSPC
+
+const RSP_INDEX: usize = 7;
+
+// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15. rsp isn't saved by the
+// entry points, it is taken from the stack frame
+fn gdb_order(r: &SavedRegisters) -> [u64; GPR_COUNT] {
+    [r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rbp, 0,
+     r.r8, r.r9, r.r10, r.r11, r.r12, r.r13, r.r14, r.r15]
+}
+
+fn set_from_gdb_order(r: &mut SavedRegisters, values: &[u64; GPR_COUNT]) {
+    r.rax = values[0];
+    r.rbx = values[1];
+    r.rcx = values[2];
+    r.rdx = values[3];
+    r.rsi = values[4];
+    r.rdi = values[5];
+    r.rbp = values[6];
+    r.r8 = values[8];
+    r.r9 = values[9];
+    r.r10 = values[10];
+    r.r11 = values[11];
+    r.r12 = values[12];
+    r.r13 = values[13];
+    r.r14 = values[14];
+    r.r15 = values[15];
+}
+
+// `m addr,length`, replies with as much as is mapped
+fn read_memory(arguments: &[u8], reply: &mut Reply) {
+    let (address, length) = match parse_address_length(arguments) {
+        Some(parsed) => parsed,
+        None => return reply.error(ERROR_INVALID),
+    };
+    // two hex digits per byte
+    let mut bytes = [0u8; PACKET_SIZE / 2];
+    let length = ::core::cmp::min(length, bytes.len());
+    let count = memory::read_checked(address, &mut bytes[..length]);
+    if count == 0 && length != 0 {
+        return reply.error(ERROR_FAULT);
+    }
+    for &byte in bytes[..count].iter() {
+        reply.push_hex(byte);
+    }
+}
+
+// `M addr,length:data`, only writes to pages mapped writable
+fn write_memory(arguments: &[u8], reply: &mut Reply) {
+    let colon = match arguments.iter().position(|&byte| byte == b':') {
+        Some(colon) => colon,
+        None => return reply.error(ERROR_INVALID),
+    };
+    let (address, length) = match parse_address_length(&arguments[..colon]) {
+        Some(parsed) => parsed,
+        None => return reply.error(ERROR_INVALID),
+    };
+    let mut bytes = [0u8; PACKET_SIZE / 2];
+    match decode_hex(&arguments[colon + 1..], &mut bytes) {
+        Some(decoded) if decoded == length => {}
+        _ => return reply.error(ERROR_INVALID),
+    }
+    if memory::write_checked(address, &bytes[..length]) == length {
+        reply.push_str("OK");
+    } else {
+        reply.error(ERROR_FAULT);
+    }
+}
+
+// `Z0,addr,kind` and `z0,addr,kind`, only software breakpoints are supported
+fn breakpoint(insert: bool, arguments: &[u8], reply: &mut Reply) {
+    if !arguments.starts_with(b"0,") {
+        return; // empty reply, gdb falls back to writing int3 itself
+    }
+    let address = match arguments[2..].split(|&byte| byte == b',').next().and_then(parse_hex) {
+        Some(address) => address as usize,
+        None => return reply.error(ERROR_INVALID),
+    };
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let existing = breakpoints.iter().position(|slot| slot.map(|(a, _)| a) == Some(address));
+    match (insert, existing) {
+        (true, Some(_)) => reply.push_str("OK"),
+        (true, None) => {
+            let mut original = [0u8];
+            if memory::read_checked(address, &mut original) != 1 {
+                return reply.error(ERROR_FAULT);
+            }
+            let slot = match breakpoints.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => slot,
+                None => return reply.error(ERROR_NO_SPACE),
+            };
+            *slot = Some((address, original[0]));
+            unsafe { patch_code(address, INT3) };
+            reply.push_str("OK");
+        }
+        (false, Some(index)) => {
+            if let Some((address, original)) = breakpoints[index].take() {
+                unsafe { patch_code(address, original) };
+            }
+            reply.push_str("OK");
+        }
+        (false, None) => reply.error(ERROR_INVALID),
+    }
+}
+
+// the kernel code is mapped read only, so CR0.WP is cleared for the write.
+// interrupts are off in the stub, nothing else runs meanwhile
+unsafe fn patch_code(address: usize, byte: u8) {
+    use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
+
+    let saved = cr0();
+    cr0_write(saved & !Cr0::WRITE_PROTECT);
+    ptr::write_volatile(address as *mut u8, byte);
+    cr0_write(saved);
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+struct Reply {
+    buffer: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply { buffer: [0; PACKET_SIZE], length: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    fn push(&mut self, byte: u8) {
+        // the requests are sized so the replies fit, drop the rest if not
+        if self.length < self.buffer.len() {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+    }
+
+    // registers go over the wire in target byte order
+    fn push_le(&mut self, value: u64, bytes: usize) {
+        for i in 0..bytes {
+            self.push_hex((value >> (8 * i)) as u8);
+        }
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.push(b'S');
+        self.push_hex(signal);
+    }
+
+    fn error(&mut self, number: u8) {
+        self.push(b'E');
+        self.push_hex(number);
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'...b'9' => Some(digit - b'0'),
+        b'a'...b'f' => Some(digit - b'a' + 10),
+        b'A'...b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+// a big endian hex number like the addresses and lengths in the requests
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for &digit in digits {
+        match hex_value(digit) {
+            Some(digit) => value = value << 4 | digit as u64,
+            None => return None,
+        }
+    }
+    Some(value)
+}
+
+fn parse_address_length(arguments: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = arguments.splitn(2, |&byte| byte == b',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(address), Some(length)) => Some((address as usize, length as usize)),
+        _ => None,
+    }
+}
+
+// hex pairs into bytes, returns the number of bytes or None for bad input
+fn decode_hex(digits: &[u8], bytes: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > bytes.len() {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => return None,
+        }
+    }
+    Some(digits.len() / 2)
+}
+
+// little endian value of `count` bytes
+fn read_le(bytes: &[u8], count: usize) -> u64 {
+    bytes[..count].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
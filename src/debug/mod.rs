@@ -0,0 +1,10 @@
+// kernel debugging helpers
+
+pub use self::watchpoint::{set_watchpoint, handle_debug_exception, WatchpointHandle,
+                           WatchpointKind, WatchpointError};
+
+pub mod gdbstub;
+mod watchpoint;
+
+#[cfg(debug_assertions)]
+pub use self::watchpoint::test_watchpoint;
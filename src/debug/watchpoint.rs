@@ -0,0 +1,199 @@
+// hardware watchpoints
+// DR0-DR3 hold up to four addresses, DR7 enables them and sets the access
+// kind and length of each, DR6 tells the #DB handler which one fired.
+// watchpoints fire after the access, so the handler can just resume
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::VirtualAddress;
+use x86_64::structures::idt::ExceptionStackFrame;
+use emergency::NonBlockingWriter;
+use interrupts;
+
+const WATCHPOINT_COUNT: usize = 4;
+
+// DR6 bits 0-3 say which address matched
+const DR6_HIT_MASK: u64 = 0b1111;
+
+/// What kind of access triggers a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    // all four debug registers are taken
+    NoFreeRegister,
+    // only 1, 2, 4 and 8 bytes can be watched
+    InvalidLength,
+    // the address must be aligned to the length
+    Misaligned,
+}
+
+// bit n is set when DRn is in use
+static USED: AtomicUsize = AtomicUsize::new(0);
+// number of watchpoint hits reported so far
+static HITS: AtomicUsize = AtomicUsize::new(0);
+// the watched addresses, for the report
+static WATCHED: [AtomicUsize; WATCHPOINT_COUNT] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// Frees its debug register when dropped.
+#[derive(Debug)]
+pub struct WatchpointHandle {
+    index: usize,
+}
+
+impl WatchpointHandle {
+    pub fn address(&self) -> VirtualAddress {
+        VirtualAddress(WATCHED[self.index].load(Ordering::SeqCst))
+    }
+}
+
+impl Drop for WatchpointHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let dr7 = read_dr7();
+            write_dr7(dr7 & !dr7_mask(self.index));
+        }
+        WATCHED[self.index].store(0, Ordering::SeqCst);
+        USED.fetch_and(!(1 << self.index), Ordering::SeqCst);
+    }
+}
+
+/// Watches the `length` bytes at `address` with one of the four debug
+/// registers. The watchpoint stays active until the handle is dropped.
+pub fn set_watchpoint(address: VirtualAddress, length: usize, kind: WatchpointKind)
+                      -> Result<WatchpointHandle, WatchpointError>
+{
+    let length_bits = match length {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        _ => return Err(WatchpointError::InvalidLength),
+    };
+    if address.0 % length != 0 {
+        return Err(WatchpointError::Misaligned);
+    }
+    let kind_bits = match kind {
+        WatchpointKind::Write => 0b01,
+        WatchpointKind::ReadWrite => 0b11,
+    };
+
+    // claim a free register
+    let index = loop {
+        let used = USED.load(Ordering::SeqCst);
+        let index = match (0..WATCHPOINT_COUNT).find(|&i| used & (1 << i) == 0) {
+            Some(index) => index,
+            None => return Err(WatchpointError::NoFreeRegister),
+        };
+        if USED.compare_and_swap(used, used | 1 << index, Ordering::SeqCst) == used {
+            break index;
+        }
+    };
+
+    WATCHED[index].store(address.0, Ordering::SeqCst);
+    unsafe {
+        write_address(index, address.0 as u64);
+        let dr7 = read_dr7() & !dr7_mask(index);
+        let enable = 1 << (2 * index);  // local enable
+        write_dr7(dr7 | enable | dr7_field(index, length_bits << 2 | kind_bits));
+    }
+    Ok(WatchpointHandle { index: index })
+}
+
+// positions the 4 bit length/kind field of DRn (kind in the low 2 bits)
+fn dr7_field(index: usize, field: u64) -> u64 {
+    field << (16 + 4 * index)
+}
+
+// all DR7 bits belonging to DRn: its field and its local/global enable
+fn dr7_mask(index: usize) -> u64 {
+    dr7_field(index, 0b1111) | 0b11 << (2 * index)
+}
+
+/// Called by the #DB handler. Reports and clears a watchpoint hit and
+/// returns true, or returns false if no watchpoint fired.
+pub fn handle_debug_exception(stack_frame: &ExceptionStackFrame) -> bool {
+    let dr6 = unsafe { read_dr6() };
+    if dr6 & DR6_HIT_MASK == 0 {
+        return false;
+    }
+
+    let mut out = NonBlockingWriter;
+    HITS.fetch_add(1, Ordering::SeqCst);
+    for index in (0..WATCHPOINT_COUNT).filter(|&i| dr6 & (1 << i) != 0) {
+        let _ = writeln!(out, "\nWATCHPOINT {}: {:#x} accessed at rip {:#x}",
+                         index, WATCHED[index].load(Ordering::SeqCst),
+                         stack_frame.instruction_pointer.0);
+    }
+    if let Some(registers) = interrupts::captured_registers() {
+        interrupts::print_registers(&mut out, &registers);
+    }
+
+    // DR6 is sticky, clear it for the next hit
+    unsafe { write_dr6(dr6 & !DR6_HIT_MASK) };
+    true
+}
+
+pub unsafe fn read_dr6() -> u64 {
+    let value: u64;
+    asm!("mov $0, dr6" : "=r"(value) ::: "intel", "volatile");
+    value
+}
+
+pub unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, $0" :: "r"(value) :: "intel", "volatile");
+}
+
+unsafe fn read_dr7() -> u64 {
+    let value: u64;
+    asm!("mov $0, dr7" : "=r"(value) ::: "intel", "volatile");
+    value
+}
+
+unsafe fn write_dr7(value: u64) {
+    asm!("mov dr7, $0" :: "r"(value) :: "intel", "volatile");
+}
+
+unsafe fn write_address(index: usize, address: u64) {
+    match index {
+        0 => asm!("mov dr0, $0" :: "r"(address) :: "intel", "volatile"),
+        1 => asm!("mov dr1, $0" :: "r"(address) :: "intel", "volatile"),
+        2 => asm!("mov dr2, $0" :: "r"(address) :: "intel", "volatile"),
+        3 => asm!("mov dr3, $0" :: "r"(address) :: "intel", "volatile"),
+        _ => unreachable!(),
+    }
+}
+
+/// Watches a static, writes to it and checks that the hit was reported, and
+/// that a fifth watchpoint is refused while four are set.
+#[cfg(debug_assertions)]
+pub fn test_watchpoint() {
+    use core::ptr;
+
+    static mut WATCHED_VALUE: [u64; WATCHPOINT_COUNT] = [0; WATCHPOINT_COUNT];
+
+    let address = |i: usize| VirtualAddress(unsafe { &WATCHED_VALUE[i] } as *const u64 as usize);
+    let handles = [
+        set_watchpoint(address(0), 8, WatchpointKind::Write).unwrap(),
+        set_watchpoint(address(1), 8, WatchpointKind::Write).unwrap(),
+        set_watchpoint(address(2), 8, WatchpointKind::Write).unwrap(),
+        set_watchpoint(address(3), 8, WatchpointKind::ReadWrite).unwrap(),
+    ];
+    assert_eq!(set_watchpoint(address(0), 8, WatchpointKind::Write).unwrap_err(),
+               WatchpointError::NoFreeRegister);
+
+    let hits = HITS.load(Ordering::SeqCst);
+    unsafe { ptr::write_volatile(&mut WATCHED_VALUE[2], 42) };
+    assert_eq!(HITS.load(Ordering::SeqCst), hits + 1, "watchpoint did not fire");
+
+    drop(handles);
+    unsafe { ptr::write_volatile(&mut WATCHED_VALUE[2], 43) };
+    assert_eq!(HITS.load(Ordering::SeqCst), hits + 1, "watchpoint not removed");
+    println!("watchpoint test passed");
+}
@@ -0,0 +1,63 @@
+// the "debugcon" port 0xe9 of QEMU and Bochs
+// every byte written to the port shows up on the host (QEMU needs
+// `-debugcon stdio` or `-debugcon file:debug.log`). there is nothing to
+// initialize, so `Writer` works from the first instruction of `rust_main`,
+// before the command line is parsed and before the serial port is set up
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::{inb, outb};
+use console::ConsoleSink;
+use cmdline;
+
+pub const DEBUGCON_PORT: u16 = 0xe9;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the debugcon sink if `debugcon` is on the command line, the
+/// `debugcon` feature is set, or the port reads back as 0xe9, which is what
+/// Bochs does. QEMU's port reads as 0xff, so it has to be enabled explicitly.
+pub fn init() {
+    let bochs = unsafe { inb(DEBUGCON_PORT) } == DEBUGCON_PORT as u8;
+    let enabled = bochs || cfg!(feature = "debugcon") || cmdline::has("debugcon");
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Writes to the port unconditionally, enabled or not. Without a listener
+/// the bytes just go nowhere.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            unsafe { outb(DEBUGCON_PORT, byte) };
+        }
+        Ok(())
+    }
+}
+
+pub struct DebugconSink;
+
+impl ConsoleSink for DebugconSink {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn is_enabled(&self) -> bool {
+        is_enabled()
+    }
+
+    // one outb per byte and no state, so this needs no lock
+    fn write_str(&self, s: &str) {
+        use core::fmt::Write;
+        let _ = Writer.write_str(s);
+    }
+
+    fn force_write_str(&self, s: &str) {
+        self.write_str(s);
+    }
+}
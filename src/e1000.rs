@@ -0,0 +1,419 @@
+// Intel 8254x (e1000) network cards, QEMU's default NIC
+// the card reads and writes packets through two rings of descriptors in
+// DMA memory. every receive descriptor points to a 2 KiB buffer the card
+// fills, the interrupt handler passes the received frames to a callback and
+// gives the buffers back by moving the tail. frames to send are copied into
+// the buffer of the next transmit descriptor, `send` waits until the card
+// reports it done
+
+use core::{mem, ptr, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use sync::IrqMutex;
+use memory::{MemoryController, DmaMemory, PAGE_SIZE};
+use interrupts::{self, InterruptContext};
+use net::{MacAddress, NetError, NetworkDevice, ReceiveCallback};
+use pci::{self, Bar};
+
+const VENDOR_INTEL: u16 = 0x8086;
+// 82540EM (QEMU's e1000) and 82545EM (VMware)
+const DEVICE_IDS: [u16; 2] = [0x100e, 0x100f];
+
+// registers, byte offsets into BAR 0
+const REG_CTRL: usize = 0x0000;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_AUTO_SPEED: u32 = 1 << 5;
+const CTRL_SET_LINK_UP: u32 = 1 << 6;
+const CTRL_RESET: u32 = 1 << 26;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const INTERRUPT_LINK_STATUS: u32 = 1 << 2;
+const INTERRUPT_RX_MIN_THRESHOLD: u32 = 1 << 4;
+const INTERRUPT_RX_OVERRUN: u32 = 1 << 6;
+const INTERRUPT_RX_TIMER: u32 = 1 << 7;
+const INTERRUPTS_RX: u32 = INTERRUPT_RX_MIN_THRESHOLD | INTERRUPT_RX_OVERRUN | INTERRUPT_RX_TIMER;
+
+const RCTL_ENABLE: u32 = 1 << 1;
+const RCTL_BROADCAST_ACCEPT: u32 = 1 << 15;
+// buffer size 2048 is the default (bits 16 and 17 clear)
+const RCTL_STRIP_CRC: u32 = 1 << 26;
+
+const TCTL_ENABLE: u32 = 1 << 1;
+const TCTL_PAD_SHORT_PACKETS: u32 = 1 << 3;
+const TCTL_COLLISION_THRESHOLD: u32 = 0x10 << 4;
+const TCTL_COLLISION_DISTANCE: u32 = 0x40 << 12;
+// the recommended inter packet gap for copper
+const TIPG_DEFAULT: u32 = 0x0060_200a;
+// the receive address register is only used if this is set
+const RAH_ADDRESS_VALID: u32 = 1 << 31;
+
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const DESCRIPTOR_END_OF_PACKET: u8 = 1 << 1;
+const TX_COMMAND_END_OF_PACKET: u8 = 1 << 0;
+const TX_COMMAND_INSERT_FCS: u8 = 1 << 1;
+const TX_COMMAND_REPORT_STATUS: u8 = 1 << 3;
+
+const RX_DESCRIPTORS: usize = 32;
+const TX_DESCRIPTORS: usize = 8;
+const BUFFER_SIZE: usize = 2048;
+// without the frame check sequence, the card appends it
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+// in register polls
+const TIMEOUT: usize = 1_000_000;
+
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    base: usize,
+}
+
+impl Registers {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register) as *mut u32, value) };
+    }
+
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(REG_EERD, (word as u32) << 8 | EERD_START);
+        for _ in 0..TIMEOUT {
+            let value = self.read(REG_EERD);
+            if value & EERD_DONE != 0 {
+                return Some((value >> 16) as u16);
+            }
+        }
+        None
+    }
+}
+
+// descriptors and buffers of one direction
+struct Ring {
+    descriptors: DmaMemory,
+    buffers: DmaMemory,
+    // the next descriptor the driver looks at
+    next: usize,
+}
+
+impl Ring {
+    fn new(memory_controller: &mut MemoryController, count: usize) -> Option<Ring> {
+        let descriptors = memory_controller.alloc_dma(1);
+        let buffers = memory_controller.alloc_dma(count * BUFFER_SIZE / PAGE_SIZE);
+        match (descriptors, buffers) {
+            (Some(descriptors), Some(buffers)) => Some(Ring {
+                descriptors: descriptors,
+                buffers: buffers,
+                next: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    fn buffer_address(&self, index: usize) -> u64 {
+        (self.buffers.physical_address() + index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        self.buffers.pointer(index * BUFFER_SIZE)
+    }
+
+    fn rx_descriptor(&self, index: usize) -> *mut RxDescriptor {
+        assert!(index < RX_DESCRIPTORS);
+        self.descriptors.pointer(index * mem::size_of::<RxDescriptor>()) as *mut RxDescriptor
+    }
+
+    fn tx_descriptor(&self, index: usize) -> *mut TxDescriptor {
+        assert!(index < TX_DESCRIPTORS);
+        self.descriptors.pointer(index * mem::size_of::<TxDescriptor>()) as *mut TxDescriptor
+    }
+}
+
+static REGISTERS: Once<Registers> = Once::new();
+static MAC_ADDRESS: Once<MacAddress> = Once::new();
+// RX is only touched by the interrupt handler after init, TX by `send`.
+// separate locks, so the receive callback can send a reply
+static RX: IrqMutex<Option<Ring>> = IrqMutex::new(None);
+static TX: IrqMutex<Option<Ring>> = IrqMutex::new(None);
+// stored as usize like the IRQ handlers, 0 means none
+static RECEIVE_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Resets the first e1000 card, sets up the rings and enables receiving
+/// and sending. Returns false if there is no card or it couldn't be set up.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("e1000::init must be called only once");
+
+    let device = match pci::devices().find(|device| {
+        device.vendor_id == VENDOR_INTEL && DEVICE_IDS.contains(&device.device_id)
+    }) {
+        Some(device) => device,
+        None => return false,
+    };
+    let (physical, size) = match device.bar(0) {
+        Some(Bar::Memory32 { base, size, .. }) => (base as usize, size as usize),
+        Some(Bar::Memory64 { base, size, .. }) => (base as usize, size as usize),
+        _ => {
+            println!("e1000: {}: BAR 0 isn't a memory BAR", device);
+            return false;
+        }
+    };
+    device.set_command_bits(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+    let registers = Registers { base: memory_controller.map_mmio(physical, size) };
+
+    // the reset bit clears itself when the reset is done
+    registers.write(REG_IMC, !0);
+    registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_RESET);
+    if !(0..TIMEOUT).any(|_| registers.read(REG_CTRL) & CTRL_RESET == 0) {
+        println!("e1000: reset timed out");
+        return false;
+    }
+    registers.write(REG_IMC, !0);
+    registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_SET_LINK_UP | CTRL_AUTO_SPEED);
+
+    let mac_address = match read_mac_address(&registers) {
+        Some(address) => address,
+        None => {
+            println!("e1000: could not read the MAC address");
+            return false;
+        }
+    };
+    // the card only accepts unicast frames for the receive address, which
+    // EEPROM loading set already on most cards, write it to make sure
+    let low = mac_address.0;
+    registers.write(REG_RAL, low[0] as u32 | (low[1] as u32) << 8 | (low[2] as u32) << 16
+                    | (low[3] as u32) << 24);
+    registers.write(REG_RAH, low[4] as u32 | (low[5] as u32) << 8 | RAH_ADDRESS_VALID);
+    for index in 0..128 {
+        registers.write(REG_MTA + index * 4, 0);
+    }
+
+    let (rx, tx) = match (Ring::new(memory_controller, RX_DESCRIPTORS),
+                          Ring::new(memory_controller, TX_DESCRIPTORS)) {
+        (Some(rx), Some(tx)) => (rx, tx),
+        _ => {
+            println!("e1000: out of memory");
+            return false;
+        }
+    };
+    for index in 0..RX_DESCRIPTORS {
+        unsafe { (*rx.rx_descriptor(index)).address = rx.buffer_address(index) };
+    }
+    // transmit descriptors count as done until they are used
+    for index in 0..TX_DESCRIPTORS {
+        unsafe {
+            (*tx.tx_descriptor(index)).address = tx.buffer_address(index);
+            (*tx.tx_descriptor(index)).status = DESCRIPTOR_DONE;
+        }
+    }
+
+    let rx_base = rx.descriptors.physical_address() as u64;
+    registers.write(REG_RDBAL, rx_base as u32);
+    registers.write(REG_RDBAH, (rx_base >> 32) as u32);
+    registers.write(REG_RDLEN, (RX_DESCRIPTORS * mem::size_of::<RxDescriptor>()) as u32);
+    registers.write(REG_RDH, 0);
+    // all descriptors but one belong to the card, head == tail means none
+    registers.write(REG_RDT, RX_DESCRIPTORS as u32 - 1);
+    registers.write(REG_RCTL, RCTL_ENABLE | RCTL_BROADCAST_ACCEPT | RCTL_STRIP_CRC);
+
+    let tx_base = tx.descriptors.physical_address() as u64;
+    registers.write(REG_TDBAL, tx_base as u32);
+    registers.write(REG_TDBAH, (tx_base >> 32) as u32);
+    registers.write(REG_TDLEN, (TX_DESCRIPTORS * mem::size_of::<TxDescriptor>()) as u32);
+    registers.write(REG_TDH, 0);
+    registers.write(REG_TDT, 0);
+    registers.write(REG_TIPG, TIPG_DEFAULT);
+    registers.write(REG_TCTL, TCTL_ENABLE | TCTL_PAD_SHORT_PACKETS | TCTL_COLLISION_THRESHOLD
+                    | TCTL_COLLISION_DISTANCE);
+
+    *RX.lock() = Some(rx);
+    *TX.lock() = Some(tx);
+    REGISTERS.call_once(|| registers);
+    MAC_ADDRESS.call_once(|| mac_address);
+
+    if let Err(error) = interrupts::register_irq(device.interrupt_line, e1000_interrupt) {
+        println!("e1000: can't use IRQ {}: {:?}", device.interrupt_line, error);
+        return false;
+    }
+    // reading ICR clears what is pending from before
+    registers.read(REG_ICR);
+    registers.write(REG_IMS, INTERRUPTS_RX | INTERRUPT_LINK_STATUS);
+
+    println!("e1000: registers at {:#x}, MAC {}, IRQ {}", physical, mac_address,
+             device.interrupt_line);
+    true
+}
+
+// the receive address register holds the address if the EEPROM was loaded
+// into it, otherwise it's in the first three EEPROM words
+fn read_mac_address(registers: &Registers) -> Option<MacAddress> {
+    let high = registers.read(REG_RAH);
+    if high & RAH_ADDRESS_VALID != 0 {
+        let low = registers.read(REG_RAL);
+        return Some(MacAddress([low as u8, (low >> 8) as u8, (low >> 16) as u8,
+                                (low >> 24) as u8, high as u8, (high >> 8) as u8]));
+    }
+    let mut address = [0; 6];
+    for word in 0..3 {
+        let value = match registers.read_eeprom(word as u8) {
+            Some(value) => value,
+            None => return None,
+        };
+        address[word * 2] = value as u8;
+        address[word * 2 + 1] = (value >> 8) as u8;
+    }
+    Some(MacAddress(address))
+}
+
+/// Returns the MAC address of the card, None without one.
+pub fn mac_address() -> Option<MacAddress> {
+    MAC_ADDRESS.try().cloned()
+}
+
+/// Sets the function received frames are passed to. It runs in interrupt
+/// context, without the frame check sequence.
+pub fn set_receive_callback(callback: ReceiveCallback) {
+    RECEIVE_CALLBACK.store(callback as usize, Ordering::SeqCst);
+}
+
+fn e1000_interrupt(_context: &mut InterruptContext) {
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return,
+    };
+    // reading clears the causes. zero means the (shared) line wasn't ours
+    let causes = registers.read(REG_ICR);
+    if causes & INTERRUPTS_RX != 0 {
+        receive(registers);
+    }
+}
+
+fn receive(registers: &Registers) {
+    let mut rx = RX.lock();
+    let rx = match rx.as_mut() {
+        Some(rx) => rx,
+        None => return,
+    };
+    let callback = RECEIVE_CALLBACK.load(Ordering::Acquire);
+    loop {
+        let index = rx.next;
+        let descriptor = rx.rx_descriptor(index);
+        let (status, length) = unsafe {
+            (ptr::read_volatile(&(*descriptor).status), (*descriptor).length as usize)
+        };
+        if status & DESCRIPTOR_DONE == 0 {
+            break;
+        }
+        // frames longer than a buffer are spread over several descriptors,
+        // with 2 KiB buffers that can't happen for standard frames. drop
+        // anything that isn't complete in one
+        if status & DESCRIPTOR_END_OF_PACKET != 0 && callback != 0 {
+            let callback: ReceiveCallback = unsafe { mem::transmute(callback) };
+            callback(unsafe { slice::from_raw_parts(rx.buffer(index), length) });
+        }
+        unsafe { ptr::write_volatile(&mut (*descriptor).status, 0) };
+        rx.next = (index + 1) % RX_DESCRIPTORS;
+        // the processed descriptor goes back to the card
+        registers.write(REG_RDT, index as u32);
+    }
+}
+
+/// Sends `frame`, which starts with the ethernet header and doesn't
+/// include the frame check sequence. Waits until the card has sent it.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(NetError::FrameTooLong);
+    }
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return Err(NetError::NoDevice),
+    };
+    let mut tx = TX.lock();
+    let tx = tx.as_mut().expect("e1000 registers without TX ring");
+
+    let index = tx.next;
+    let descriptor = tx.tx_descriptor(index);
+    // the previous user of the descriptor must be done
+    let done = || unsafe { ptr::read_volatile(&(*descriptor).status) } & DESCRIPTOR_DONE != 0;
+    if !(0..TIMEOUT).any(|_| done()) {
+        return Err(NetError::Timeout);
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(frame.as_ptr(), tx.buffer(index), frame.len());
+        (*descriptor).length = frame.len() as u16;
+        (*descriptor).command = TX_COMMAND_END_OF_PACKET | TX_COMMAND_INSERT_FCS
+            | TX_COMMAND_REPORT_STATUS;
+        ptr::write_volatile(&mut (*descriptor).status, 0);
+    }
+    tx.next = (index + 1) % TX_DESCRIPTORS;
+    registers.write(REG_TDT, tx.next as u32);
+
+    if (0..TIMEOUT).any(|_| done()) {
+        Ok(())
+    } else {
+        Err(NetError::Timeout)
+    }
+}
+
+/// The card as a `net::NetworkDevice`.
+pub struct E1000;
+
+impl NetworkDevice for E1000 {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        mac_address().expect("e1000 not initialized")
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        send(frame)
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        set_receive_callback(callback)
+    }
+}
@@ -0,0 +1,33 @@
+// output for fatal paths
+// `Writer` writes to every enabled console sink, bypassing or breaking
+// their locks (the serial port is written without a lock, the WRITER lock
+// of the screen is broken), so it works even if the fault hit in the middle
+// of a println!
+
+use core::fmt::{self, Write};
+use serial::RawWriter;
+use vga_buffer;
+use console;
+
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for sink in console::sinks().iter().filter(|sink| sink.is_enabled()) {
+            sink.force_write_str(s);
+        }
+        Ok(())
+    }
+}
+
+// for handlers that resume the interrupted code: the screen is skipped when
+// its lock is held, since breaking it would corrupt the interrupted println!
+pub struct NonBlockingWriter;
+
+impl fmt::Write for NonBlockingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let _ = RawWriter.write_str(s);
+        vga_buffer::try_write_str(s);
+        Ok(())
+    }
+}
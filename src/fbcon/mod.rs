@@ -0,0 +1,351 @@
+// text console on a linear framebuffer
+// draws the console text with the 8x16 PSF font in `font.psf` (ASCII
+// only, everything else is shown as a box) once `video` has a pixel
+// framebuffer. a newline on the last line scrolls by moving the whole text
+// area up one line with a single memmove and clearing the freed line, the
+// glyphs aren't drawn again. the cursor is the cell at the write position
+// with its pixels inverted, it is taken away before and put back after
+// every write, like the mouse pointer (see `pointer`)
+
+mod pointer;
+
+use core::{fmt, ptr};
+use sync::IrqMutex;
+use vga_buffer::Color;
+use video::{self, FramebufferInfo};
+use console::ConsoleSink;
+use self::pointer::Pointer;
+
+static FONT: &'static [u8] = include_bytes!("font.psf");
+
+// PSF 1 header: magic, mode and the bytes per glyph (one per row)
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 1 << 0;
+
+const GLYPH_WIDTH: usize = 8;
+const GLYPH_HEIGHT: usize = 16;
+// the glyph drawn for characters the font doesn't have
+const MISSING_GLYPH: usize = 0;
+
+struct Console {
+    framebuffer: FramebufferInfo,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+    cursor_shown: bool,
+    // the colors of the mouse pointer
+    white: u32,
+    black: u32,
+    // None until the mouse moves
+    pointer: Option<Pointer>,
+}
+
+static CONSOLE: IrqMutex<Option<Console>> = IrqMutex::new(None);
+
+/// Takes over the current `video` framebuffer, if there is one, and clears
+/// it. Returns whether `print!` now draws on it.
+pub fn init() -> bool {
+    let framebuffer = match video::framebuffer() {
+        Some(framebuffer) => framebuffer,
+        None => return false,
+    };
+    if FONT.len() < PSF1_HEADER_SIZE || FONT[..2] != PSF1_MAGIC
+        || FONT[3] as usize != GLYPH_HEIGHT {
+        println!("fbcon: font.psf is no 8x16 PSF 1 font");
+        return false;
+    }
+    match framebuffer.bits_per_pixel {
+        15 | 16 | 24 | 32 => {}
+        other => {
+            println!("fbcon: {} bits per pixel are not supported", other);
+            return false;
+        }
+    }
+
+    let mut console = Console {
+        framebuffer: framebuffer,
+        columns: framebuffer.width / GLYPH_WIDTH,
+        rows: framebuffer.height / GLYPH_HEIGHT,
+        column: 0,
+        row: 0,
+        foreground: 0,
+        background: 0,
+        cursor_shown: false,
+        white: pixel(&framebuffer, Color::White),
+        black: pixel(&framebuffer, Color::Black),
+        pointer: None,
+    };
+    console.set_color(Color::LightGreen, Color::Black);
+    console.clear();
+    console.show_cursor();
+    let (columns, rows) = (console.columns, console.rows);
+    *CONSOLE.lock() = Some(console);
+    println!("fbcon: {}x{} characters on {}", columns, rows, framebuffer);
+    true
+}
+
+/// Returns whether the console draws on a framebuffer.
+pub fn is_enabled() -> bool {
+    CONSOLE.lock().is_some()
+}
+
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(ref mut console) = *CONSOLE.lock() {
+        let _ = console.write_fmt(args);
+    }
+}
+
+pub fn clear_screen() {
+    if let Some(ref mut console) = *CONSOLE.lock() {
+        console.hide_pointer();
+        console.clear();
+        console.show_cursor();
+        console.show_pointer();
+    }
+}
+
+/// Moves the mouse pointer by `dx`, `dy` pixels (positive `dy` is down).
+/// The first call puts it in the middle of the screen.
+pub fn move_pointer(dx: i32, dy: i32) {
+    if let Some(ref mut console) = *CONSOLE.lock() {
+        let (width, height) = (console.framebuffer.width, console.framebuffer.height);
+        console.hide_pointer();
+        console.pointer.get_or_insert(Pointer::new(width / 2, height / 2))
+            .move_by(dx, dy, width, height);
+        console.show_pointer();
+    }
+}
+
+/// The console as a `console::ConsoleSink`.
+pub struct FbconSink;
+
+impl ConsoleSink for FbconSink {
+    fn name(&self) -> &'static str {
+        "fbcon"
+    }
+
+    fn is_enabled(&self) -> bool {
+        is_enabled()
+    }
+
+    fn write_str(&self, s: &str) {
+        print(format_args!("{}", s));
+    }
+
+    fn force_write_str(&self, s: &str) {
+        use core::fmt::Write;
+        if CONSOLE.try_lock().is_none() {
+            unsafe { CONSOLE.force_unlock() };
+        }
+        if let Some(ref mut console) = *CONSOLE.lock() {
+            let _ = console.write_str(s);
+        }
+    }
+}
+
+impl Console {
+    fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = pixel(&self.framebuffer, foreground);
+        self.background = pixel(&self.framebuffer, background);
+    }
+
+    fn clear(&mut self) {
+        let background = self.background;
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        self.fill(0, 0, width, height, background);
+        self.column = 0;
+        self.row = 0;
+        self.cursor_shown = false;
+    }
+
+    fn write_char(&mut self, character: char) {
+        match character {
+            '\n' => self.new_line(),
+            '\u{8}' => {
+                if self.column > 0 {
+                    self.column -= 1;
+                    let (column, row) = (self.column, self.row);
+                    self.draw_glyph(column, row, ' ');
+                }
+            }
+            character => {
+                if self.column >= self.columns {
+                    self.new_line();
+                }
+                let (column, row) = (self.column, self.row);
+                self.draw_glyph(column, row, character);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        // one copy of the text area. the source and destination overlap,
+        // `ptr::copy` is a memmove
+        let line_bytes = GLYPH_HEIGHT * self.framebuffer.pitch;
+        let base = self.framebuffer.address as *mut u8;
+        unsafe {
+            ptr::copy(base.offset(line_bytes as isize), base, (self.rows - 1) * line_bytes);
+        }
+        let (background, width) = (self.background, self.columns * GLYPH_WIDTH);
+        let last_line = (self.rows - 1) * GLYPH_HEIGHT;
+        self.fill(0, last_line, width, GLYPH_HEIGHT, background);
+    }
+
+    fn draw_glyph(&mut self, column: usize, row: usize, character: char) {
+        let glyph = glyph(character);
+        let (x, y) = (column * GLYPH_WIDTH, row * GLYPH_HEIGHT);
+        for (line, &bits) in glyph.iter().enumerate() {
+            for bit in 0..GLYPH_WIDTH {
+                let value = if bits & (0x80 >> bit) != 0 { self.foreground } else { self.background };
+                self.put_pixel(x + bit, y + line, value);
+            }
+        }
+    }
+
+    // inverts the cell at the write position. after the last column of a
+    // line it stays on that column, the wrap only happens with the next
+    // character
+    fn toggle_cursor(&mut self) {
+        let column = if self.column < self.columns { self.column } else { self.columns - 1 };
+        let (x, y) = (column * GLYPH_WIDTH, self.row * GLYPH_HEIGHT);
+        for line in 0..GLYPH_HEIGHT {
+            for offset in 0..GLYPH_WIDTH {
+                let value = self.get_pixel(x + offset, y + line);
+                self.put_pixel(x + offset, y + line, !value);
+            }
+        }
+        self.cursor_shown = !self.cursor_shown;
+    }
+
+    fn show_pointer(&mut self) {
+        if let Some(mut pointer) = self.pointer.take() {
+            pointer.show(self);
+            self.pointer = Some(pointer);
+        }
+    }
+
+    fn hide_pointer(&mut self) {
+        if let Some(mut pointer) = self.pointer.take() {
+            pointer.hide(self);
+            self.pointer = Some(pointer);
+        }
+    }
+
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.framebuffer.width && y < self.framebuffer.height
+    }
+
+    fn show_cursor(&mut self) {
+        if !self.cursor_shown {
+            self.toggle_cursor();
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.cursor_shown {
+            self.toggle_cursor();
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, value: u32) {
+        for line in y..y + height {
+            for column in x..x + width {
+                self.put_pixel(column, line, value);
+            }
+        }
+    }
+
+    fn pixel_pointer(&self, x: usize, y: usize) -> *mut u8 {
+        let bytes_per_pixel = (self.framebuffer.bits_per_pixel as usize + 7) / 8;
+        (self.framebuffer.address + y * self.framebuffer.pitch + x * bytes_per_pixel) as *mut u8
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        let pointer = self.pixel_pointer(x, y);
+        unsafe {
+            match self.framebuffer.bits_per_pixel {
+                32 => ptr::write_volatile(pointer as *mut u32, value),
+                24 => {
+                    ptr::write_volatile(pointer, value as u8);
+                    ptr::write_volatile(pointer.offset(1), (value >> 8) as u8);
+                    ptr::write_volatile(pointer.offset(2), (value >> 16) as u8);
+                }
+                _ => ptr::write_volatile(pointer as *mut u16, value as u16),
+            }
+        }
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> u32 {
+        let pointer = self.pixel_pointer(x, y);
+        unsafe {
+            match self.framebuffer.bits_per_pixel {
+                32 => ptr::read_volatile(pointer as *const u32),
+                24 => {
+                    ptr::read_volatile(pointer) as u32
+                        | (ptr::read_volatile(pointer.offset(1)) as u32) << 8
+                        | (ptr::read_volatile(pointer.offset(2)) as u32) << 16
+                }
+                _ => ptr::read_volatile(pointer as *const u16) as u32,
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // the pointer goes first and comes back last, so the text cursor
+        // and the scrolling never see it
+        self.hide_pointer();
+        self.hide_cursor();
+        for character in s.chars() {
+            self.write_char(character);
+        }
+        self.show_cursor();
+        self.show_pointer();
+        Ok(())
+    }
+}
+
+fn glyph(character: char) -> &'static [u8] {
+    let glyphs = if FONT[2] & PSF1_MODE_512 != 0 { 512 } else { 256 };
+    let index = match character as usize {
+        index @ 0x20...0x7e if index < glyphs => index,
+        _ => MISSING_GLYPH,
+    };
+    let start = PSF1_HEADER_SIZE + index * GLYPH_HEIGHT;
+    &FONT[start..start + GLYPH_HEIGHT]
+}
+
+// the colors of the VGA text mode palette
+fn pixel(framebuffer: &FramebufferInfo, color: Color) -> u32 {
+    let (red, green, blue) = match color {
+        Color::Black => (0x00, 0x00, 0x00),
+        Color::Blue => (0x00, 0x00, 0xaa),
+        Color::Green => (0x00, 0xaa, 0x00),
+        Color::Cyan => (0x00, 0xaa, 0xaa),
+        Color::Red => (0xaa, 0x00, 0x00),
+        Color::Magenta => (0xaa, 0x00, 0xaa),
+        Color::Brown => (0xaa, 0x55, 0x00),
+        Color::LightGray => (0xaa, 0xaa, 0xaa),
+        Color::DarkGray => (0x55, 0x55, 0x55),
+        Color::LightBlue => (0x55, 0x55, 0xff),
+        Color::LightGreen => (0x55, 0xff, 0x55),
+        Color::LightCyan => (0x55, 0xff, 0xff),
+        Color::LightRed => (0xff, 0x55, 0x55),
+        Color::Pink => (0xff, 0x55, 0xff),
+        Color::Yellow => (0xff, 0xff, 0x55),
+        Color::White => (0xff, 0xff, 0xff),
+    };
+    framebuffer.pixel(red, green, blue)
+}
@@ -0,0 +1,100 @@
+// the mouse pointer on the framebuffer
+// an arrow composited over the console. the pixels beneath it are saved
+// before it is drawn and put back before it moves. the console takes it
+// away around every write and scroll and draws it again afterwards (under
+// the same lock), so what is saved is never older than the text
+
+use super::Console;
+
+const WIDTH: usize = 11;
+const HEIGHT: usize = 17;
+
+// `#` is the outline, `.` the inside, spaces are transparent
+const ARROW: [&'static str; HEIGHT] = [
+    "#          ",
+    "##         ",
+    "#.#        ",
+    "#..#       ",
+    "#...#      ",
+    "#....#     ",
+    "#.....#    ",
+    "#......#   ",
+    "#.......#  ",
+    "#........# ",
+    "#.....#####",
+    "#..#..#    ",
+    "#.# #..#   ",
+    "##  #..#   ",
+    "#    #..#  ",
+    "     #..#  ",
+    "      ##   ",
+];
+
+pub struct Pointer {
+    // the tip of the arrow
+    x: usize,
+    y: usize,
+    saved: [u32; WIDTH * HEIGHT],
+    shown: bool,
+}
+
+impl Pointer {
+    pub fn new(x: usize, y: usize) -> Pointer {
+        Pointer { x: x, y: y, saved: [0; WIDTH * HEIGHT], shown: false }
+    }
+
+    /// Moves the tip by `dx`, `dy` pixels, clamped to the screen. Only
+    /// while it is hidden.
+    pub fn move_by(&mut self, dx: i32, dy: i32, width: usize, height: usize) {
+        debug_assert!(!self.shown, "fbcon: moving a pointer that is drawn");
+        self.x = clamp(self.x as i32 + dx, width);
+        self.y = clamp(self.y as i32 + dy, height);
+    }
+
+    pub fn show(&mut self, console: &mut Console) {
+        if self.shown {
+            return;
+        }
+        let (white, black) = (console.white, console.black);
+        for (row, line) in ARROW.iter().enumerate() {
+            for (column, shape) in line.bytes().enumerate() {
+                let (x, y) = (self.x + column, self.y + row);
+                if !console.contains(x, y) {
+                    continue;
+                }
+                self.saved[row * WIDTH + column] = console.get_pixel(x, y);
+                match shape {
+                    b'#' => console.put_pixel(x, y, black),
+                    b'.' => console.put_pixel(x, y, white),
+                    _ => {}
+                }
+            }
+        }
+        self.shown = true;
+    }
+
+    pub fn hide(&mut self, console: &mut Console) {
+        if !self.shown {
+            return;
+        }
+        for row in 0..HEIGHT {
+            for column in 0..WIDTH {
+                let (x, y) = (self.x + column, self.y + row);
+                if console.contains(x, y) {
+                    console.put_pixel(x, y, self.saved[row * WIDTH + column]);
+                }
+            }
+        }
+        self.shown = false;
+    }
+}
+
+fn clamp(value: i32, limit: usize) -> usize {
+    if value < 0 {
+        0
+    } else if value as usize >= limit {
+        limit - 1
+    } else {
+        value as usize
+    }
+}
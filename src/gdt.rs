@@ -0,0 +1,224 @@
+// global descriptor table and task state segment
+// replaces the minimal GDT from boot.asm, which only has a code segment and
+// no place for the TSS we need for the interrupt stack table (IST). the
+// application processors get a copy each, with a TSS of their own
+
+use alloc::boxed::Box;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::VirtualAddress;
+use spin::Once;
+use memory::MemoryController;
+
+// the layout is fixed, so the selectors can be constants
+// user data comes before user code because that is the order sysret expects
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector(1 << 3);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector(2 << 3);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector(3 << 3 | 3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector(4 << 3 | 3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector(5 << 3);
+
+// indexes into the interrupt stack table of the TSS, the IDT entries (and
+// the manuals) count from 1, so NMI and machine check use IST2 and IST3
+pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+pub const NMI_IST_INDEX: usize = 1;
+pub const MACHINE_CHECK_IST_INDEX: usize = 2;
+pub const PAGE_FAULT_IST_INDEX: usize = 3;
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<Gdt> = Once::new();
+
+/// Builds the TSS and GDT, loads the GDT and reloads all segment registers.
+/// Must run before `interrupts::init()`, since the double fault IDT entry
+/// refers to a stack in the TSS loaded here.
+pub fn init(memory_controller: &mut MemoryController) {
+    assert_has_not_been_called!("gdt::init must be called only once");
+
+    let tss = TSS.call_once(|| new_tss(memory_controller));
+    let gdt = GDT.call_once(|| new_gdt(tss));
+    unsafe { load(gdt) };
+}
+
+/// Builds a GDT with a TSS and interrupt stacks of its own for an
+/// application processor, which loads it with `init_ap`. Every CPU needs its
+/// own TSS, loading one marks it busy. They are never freed.
+pub fn new_for_ap(memory_controller: &mut MemoryController) -> &'static Gdt {
+    let tss = unsafe { &*Box::into_raw(Box::new(new_tss(memory_controller))) };
+    unsafe { &*Box::into_raw(Box::new(new_gdt(tss))) }
+}
+
+/// Loads a GDT from `new_for_ap` on the running application processor.
+pub unsafe fn init_ap(gdt: &'static Gdt) {
+    load(gdt);
+}
+
+fn new_tss(memory_controller: &mut MemoryController) -> TaskStateSegment {
+    let double_fault_stack = memory_controller.alloc_stack(1)
+        .expect("could not allocate double fault stack");
+    // NMI and machine check can arrive at any point, even with a bad RSP
+    // (in the middle of a stack switch or an overflow). `memory::init` sets
+    // up the stack allocator, so these come from it like any other stack
+    let nmi_stack = memory_controller.alloc_stack(1)
+        .expect("could not allocate NMI stack");
+    let machine_check_stack = memory_controller.alloc_stack(1)
+        .expect("could not allocate machine check stack");
+    // a fault on a stack guard page can't push its frame onto that stack.
+    // the page fault handler prints a lot, so it gets 2 pages
+    let page_fault_stack = memory_controller.alloc_stack(2)
+        .expect("could not allocate page fault stack");
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(
+        double_fault_stack.top());
+    tss.interrupt_stack_table[NMI_IST_INDEX] = VirtualAddress(
+        nmi_stack.top());
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX] = VirtualAddress(
+        machine_check_stack.top());
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(
+        page_fault_stack.top());
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> Gdt {
+    let mut gdt = Gdt::new();
+    let selectors = [
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        gdt.add_entry(Descriptor::tss_segment(tss)),
+    ];
+    let expected = [KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR,
+                    USER_DATA_SELECTOR, USER_CODE_SELECTOR, TSS_SELECTOR];
+    for (selector, expected) in selectors.iter().zip(expected.iter()) {
+        assert!(selector.0 == expected.0, "unexpected GDT layout");
+    }
+    gdt
+}
+
+unsafe fn load(gdt: &'static Gdt) {
+    use x86_64::instructions::segmentation::{set_cs, load_ss, load_ds, load_es};
+    use x86_64::instructions::tables::load_tss;
+
+    gdt.load();
+    // reload code segment register (via a far return) and the data
+    // segments, then load the TSS with ltr
+    set_cs(KERNEL_CODE_SELECTOR);
+    load_ss(KERNEL_DATA_SELECTOR);
+    load_ds(KERNEL_DATA_SELECTOR);
+    load_es(KERNEL_DATA_SELECTOR);
+    load_tss(TSS_SELECTOR);
+}
+
+pub struct Gdt {
+    table: [u64; 8],
+    next_free: usize,
+}
+
+impl Gdt {
+    pub fn new() -> Gdt {
+        Gdt {
+            table: [0; 8],  // entry 0 is always the null descriptor
+            next_free: 1,
+        }
+    }
+
+    pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let (index, privilege_level) = match entry {
+            Descriptor::UserSegment(value) => {
+                // the requested privilege level is the DPL of the segment
+                let dpl = (value >> 45) & 0b11;
+                (self.push(value), dpl as u16)
+            }
+            // system segments take up two entries
+            Descriptor::SystemSegment(value_low, value_high) => {
+                let index = self.push(value_low);
+                self.push(value_high);
+                (index, 0)
+            }
+        };
+        SegmentSelector((index as u16) << 3 | privilege_level)
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        if self.next_free < self.table.len() {
+            let index = self.next_free;
+            self.table[index] = value;
+            self.next_free += 1;
+            index
+        } else {
+            panic!("GDT full");
+        }
+    }
+
+    pub fn load(&'static self) {
+        use x86_64::instructions::tables::{DescriptorTablePointer, lgdt};
+        use core::mem::size_of;
+
+        let ptr = DescriptorTablePointer {
+            base: self.table.as_ptr() as u64,
+            limit: (self.table.len() * size_of::<u64>() - 1) as u16,
+        };
+
+        unsafe { lgdt(&ptr) };
+    }
+}
+
+pub enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    pub fn kernel_code_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE | RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | WRITABLE | RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        use core::mem::size_of;
+
+        let ptr = tss as *const _ as u64;
+
+        // base address is split over bits 16..40 and 56..64,
+        // the limit lives in bits 0..16
+        let mut low = PRESENT.bits();
+        low |= (ptr & 0xff_ffff) << 16;
+        low |= ((ptr >> 24) & 0xff) << 56;
+        low |= (size_of::<TaskStateSegment>() - 1) as u64;
+        // type: available 64-bit TSS
+        low |= 0b1001 << 40;
+
+        // the upper 32 bits of the base go into the second entry
+        let high = ptr >> 32;
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+bitflags! {
+    struct DescriptorFlags: u64 {
+        const WRITABLE          = 1 << 41;
+        const CONFORMING        = 1 << 42;
+        const EXECUTABLE        = 1 << 43;
+        const USER_SEGMENT      = 1 << 44;
+        const RING_3            = 3 << 45;
+        const PRESENT           = 1 << 47;
+        const LONG_MODE         = 1 << 53;
+    }
+}
@@ -0,0 +1,226 @@
+// high precision event timer
+// a free running main counter with a fixed period (at least 10 MHz) and a
+// few comparators that raise interrupts. timer 0 can replace the PIT on
+// IRQ 0 in "legacy replacement" mode, that's how the tick uses it
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use memory::MemoryController;
+use interrupts::{self, IrqHandler};
+use acpi;
+use time::{self, ClockSource, TickSource};
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+const REG_TIMER0_CONFIG: usize = 0x100;
+const REG_TIMER0_COMPARATOR: usize = 0x108;
+
+const CAP_COUNTER_64_BIT: u64 = 1 << 13;
+const CAP_LEGACY_REPLACEMENT: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// lets the comparator be written directly in periodic mode: the first
+// write sets the first deadline, the next one the period
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+struct Hpet {
+    base: usize,
+    // counter period in femtoseconds
+    period_fs: u64,
+    counter_64_bit: bool,
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+// the last value returned by `counter`, only needed to extend a 32 bit
+// counter to 64 bit. at 14.3 MHz (QEMU) it wraps every 5 minutes, so
+// `counter` must run at least that often, which the tick makes sure of
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl Hpet {
+    unsafe fn read(&self, register: usize) -> u64 {
+        ptr::read_volatile((self.base + register) as *const u64)
+    }
+
+    unsafe fn write(&self, register: usize, value: u64) {
+        ptr::write_volatile((self.base + register) as *mut u64, value)
+    }
+}
+
+/// Finds the HPET through its ACPI table, maps the registers and starts the
+/// main counter. Returns false if there is none.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("hpet::init must be called only once");
+
+    // after the event timer block ID comes the base address as a generic
+    // address structure, with the 64 bit address at offset 4 of it
+    let physical = match acpi::find_table(b"HPET") {
+        Some(table) if table.data().len() >= 16 => unsafe {
+            ptr::read_unaligned(table.data()[8..].as_ptr() as *const u64) as usize
+        },
+        _ => {
+            println!("hpet: no HPET table");
+            return false;
+        }
+    };
+
+    let base = memory_controller.map_mmio(physical, 1024);
+    let capabilities = unsafe { ptr::read_volatile((base + REG_CAPABILITIES) as *const u64) };
+    let hpet = HPET.call_once(|| Hpet {
+        base: base,
+        period_fs: capabilities >> 32,
+        counter_64_bit: capabilities & CAP_COUNTER_64_BIT != 0,
+    });
+    unsafe {
+        let config = hpet.read(REG_CONFIG);
+        hpet.write(REG_CONFIG, config | CONFIG_ENABLE);
+    }
+
+    println!("hpet: at {:#x}, {} kHz, {} bit counter", physical,
+             frequency() / 1000, if hpet.counter_64_bit { 64 } else { 32 });
+    time::register_clock_source(&HPET_COUNTER);
+    time::register_tick_source(&HPET_TICK);
+    true
+}
+
+/// Returns whether `init` found an HPET.
+pub fn is_enabled() -> bool {
+    HPET.try().is_some()
+}
+
+/// Returns the counter frequency in Hz.
+pub fn frequency() -> u64 {
+    let hpet = HPET.try().expect("HPET not initialized");
+    1_000_000_000_000_000 / hpet.period_fs
+}
+
+/// Returns the value of the main counter, extended to 64 bit if the counter
+/// is only 32 bit wide.
+pub fn counter() -> u64 {
+    let hpet = HPET.try().expect("HPET not initialized");
+    if hpet.counter_64_bit {
+        return unsafe { hpet.read(REG_MAIN_COUNTER) };
+    }
+
+    loop {
+        let last = LAST_COUNTER.load(Ordering::SeqCst);
+        let low = unsafe { hpet.read(REG_MAIN_COUNTER) } & 0xffff_ffff;
+        let mut value = (last & !0xffff_ffff) | low;
+        if value < last {
+            // the low half wrapped since the last read
+            value += 1 << 32;
+        }
+        // retry if someone else extended the counter in the meantime
+        if LAST_COUNTER.compare_and_swap(last, value, Ordering::SeqCst) == last {
+            return value;
+        }
+    }
+}
+
+/// Returns the nanoseconds since the main counter was started.
+pub fn now_ns() -> u64 {
+    let hpet = HPET.try().expect("HPET not initialized");
+    // split up to not overflow: the period is up to 10^8 fs
+    let counter = counter();
+    let per_second = 1_000_000_000_000_000 / hpet.period_fs;
+    let seconds = counter / per_second;
+    let rest = counter % per_second;
+    seconds * 1_000_000_000 + rest * hpet.period_fs / FEMTOSECONDS_PER_NANOSECOND
+}
+
+/// Programs timer 0 for `hz` periodic interrupts in legacy replacement
+/// mode, which takes IRQ 0 from the PIT (and IRQ 8 from the RTC), and
+/// registers `handler` for them. Returns the real frequency.
+pub fn start_periodic(hz: u32, handler: IrqHandler) -> Result<u32, ()> {
+    let hpet = HPET.try().expect("HPET not initialized");
+    unsafe {
+        let capabilities = hpet.read(REG_CAPABILITIES);
+        let timer_config = hpet.read(REG_TIMER0_CONFIG);
+        if capabilities & CAP_LEGACY_REPLACEMENT == 0
+            || timer_config & TIMER_PERIODIC_CAPABLE == 0
+        {
+            return Err(());
+        }
+        interrupts::register_irq(0, handler).map_err(|_| ())?;
+
+        let period = frequency() / hz as u64;
+        // stop the counter, so the first deadline can't be in the past
+        let config = hpet.read(REG_CONFIG);
+        hpet.write(REG_CONFIG, config & !CONFIG_ENABLE);
+        hpet.write(REG_TIMER0_CONFIG, timer_config | TIMER_INTERRUPT_ENABLE
+                                      | TIMER_PERIODIC | TIMER_VALUE_SET);
+        let now = hpet.read(REG_MAIN_COUNTER);
+        hpet.write(REG_TIMER0_COMPARATOR, now + period);
+        hpet.write(REG_TIMER0_COMPARATOR, period);
+        hpet.write(REG_CONFIG, config | CONFIG_ENABLE | CONFIG_LEGACY_REPLACEMENT);
+        Ok((frequency() / period) as u32)
+    }
+}
+
+/// Stops timer 0 and gives IRQ 0 back to the PIT. The main counter keeps
+/// running.
+pub fn stop_periodic(handler: IrqHandler) {
+    let hpet = HPET.try().expect("HPET not initialized");
+    unsafe {
+        let timer_config = hpet.read(REG_TIMER0_CONFIG);
+        hpet.write(REG_TIMER0_CONFIG, timer_config & !TIMER_INTERRUPT_ENABLE);
+        let config = hpet.read(REG_CONFIG);
+        hpet.write(REG_CONFIG, config & !CONFIG_LEGACY_REPLACEMENT);
+    }
+    let _ = interrupts::unregister_irq(0, handler);
+}
+
+/// The main counter as a `time::ClockSource`.
+pub struct HpetCounter;
+
+/// Timer 0 as a `time::TickSource`.
+pub struct HpetTick;
+
+static HPET_COUNTER: HpetCounter = HpetCounter;
+static HPET_TICK: HpetTick = HpetTick;
+
+impl ClockSource for HpetCounter {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        frequency()
+    }
+
+    fn read(&self) -> u64 {
+        counter()
+    }
+
+    // slower to read than an invariant TSC, but never changes its rate
+    fn rating(&self) -> u32 {
+        250
+    }
+}
+
+impl TickSource for HpetTick {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn start(&self, hz: u32, handler: IrqHandler) -> Result<u32, ()> {
+        start_periodic(hz, handler)
+    }
+
+    fn stop(&self, handler: IrqHandler) {
+        stop_periodic(handler);
+    }
+}
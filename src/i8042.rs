@@ -0,0 +1,284 @@
+// 8042 PS/2 controller
+// the firmware (or QEMU) usually leaves the controller in a usable state,
+// but nothing guarantees it. `init` does the full sequence: both ports off,
+// stale bytes flushed, controller and port tests, a known command byte and
+// a reset of the keyboard. translation is turned off, so the keyboard is
+// switched to scancode set 1 itself, which is what the decoder expects.
+// keyboards that refuse get translation back instead
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::{inb, outb};
+
+pub const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// the byte in the output buffer is from port 2
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT2: u8 = 0xa7;
+const ENABLE_PORT2: u8 = 0xa8;
+const TEST_PORT2: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_PORT1: u8 = 0xab;
+const DISABLE_PORT1: u8 = 0xad;
+const ENABLE_PORT1: u8 = 0xae;
+const WRITE_PORT2: u8 = 0xd4;
+// pulses output port bit 0, the CPU reset line
+const PULSE_RESET: u8 = 0xfe;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// bits of the controller configuration byte
+const CONFIG_PORT1_INTERRUPT: u8 = 1 << 0;
+const CONFIG_PORT2_INTERRUPT: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// device commands and responses
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const KEYBOARD_SCANCODE_SET: u8 = 0xf0;
+pub const KEYBOARD_SET_LEDS: u8 = 0xed;
+pub const KEYBOARD_SET_TYPEMATIC: u8 = 0xf3;
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+const RESET_PASSED: u8 = 0xaa;
+
+/// The usual timeout for `read_data`, in status port reads of roughly a
+/// microsecond each.
+pub const RESPONSE_TIMEOUT: usize = 100_000;
+// a device reset may take up to half a second
+const RESET_TIMEOUT: usize = 1_000_000;
+// bytes thrown away at most while flushing
+const FLUSH_LIMIT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I8042Error {
+    /// The controller didn't accept a byte, its input buffer stayed full.
+    InputTimeout,
+    /// Nothing arrived while waiting for the named response.
+    NoResponse(&'static str),
+    /// The controller self-test returned this instead of 0x55.
+    SelfTestFailed(u8),
+    /// The port test of port 1 or 2 returned this error code.
+    PortTestFailed(u8, u8),
+    /// A device answered the named command with this byte.
+    UnexpectedResponse(&'static str, u8),
+}
+
+static PORT1_OK: AtomicBool = AtomicBool::new(false);
+static PORT2_OK: AtomicBool = AtomicBool::new(false);
+
+/// Initializes the controller and the keyboard. Ports that fail their test
+/// stay disabled, `port1_ok` and `port2_ok` tell. Must run before the
+/// keyboard and mouse drivers and with interrupts disabled.
+pub fn init() -> Result<(), I8042Error> {
+    assert_has_not_been_called!("i8042::init must be called only once");
+
+    unsafe {
+        command(DISABLE_PORT1)?;
+        command(DISABLE_PORT2)?;
+        flush();
+
+        // no interrupts and no translation while testing. port 2 is
+        // disabled, so a clear clock bit means there is no port 2
+        let config = command_with_response(READ_CONFIG, "configuration byte")?;
+        let mut dual_channel = config & CONFIG_PORT2_CLOCK_DISABLED != 0;
+        let config = config & !(CONFIG_PORT1_INTERRUPT | CONFIG_PORT2_INTERRUPT | CONFIG_TRANSLATION);
+        write_config(config)?;
+
+        match command_with_response(SELF_TEST, "self-test result")? {
+            SELF_TEST_PASSED => {}
+            result => return Err(I8042Error::SelfTestFailed(result)),
+        }
+        // some controllers reset themselves during the self-test
+        write_config(config)?;
+
+        if dual_channel {
+            command(ENABLE_PORT2)?;
+            let config = command_with_response(READ_CONFIG, "configuration byte")?;
+            dual_channel = config & CONFIG_PORT2_CLOCK_DISABLED == 0;
+            command(DISABLE_PORT2)?;
+        }
+
+        let mut port1 = test_port(1, TEST_PORT1)?;
+        let port2 = dual_channel && test_port(2, TEST_PORT2)?;
+
+        // the replies of the keyboard commands are polled, so the interrupts
+        // are only enabled afterwards
+        let mut config = config;
+        if port1 {
+            command(ENABLE_PORT1)?;
+            match init_keyboard() {
+                Ok(true) => config |= CONFIG_PORT1_INTERRUPT,
+                Ok(false) => {
+                    println!("i8042: the keyboard refused scancode set 1, translating");
+                    config |= CONFIG_PORT1_INTERRUPT | CONFIG_TRANSLATION;
+                }
+                Err(error) => {
+                    // the mouse may still work
+                    println!("i8042: keyboard: {:?}", error);
+                    command(DISABLE_PORT1)?;
+                    port1 = false;
+                }
+            }
+        }
+        if port2 {
+            command(ENABLE_PORT2)?;
+            config |= CONFIG_PORT2_INTERRUPT;
+        }
+        write_config(config)?;
+
+        PORT1_OK.store(port1, Ordering::SeqCst);
+        PORT2_OK.store(port2, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Returns whether port 1 (the keyboard) passed its tests and is enabled.
+pub fn port1_ok() -> bool {
+    PORT1_OK.load(Ordering::SeqCst)
+}
+
+/// Returns whether port 2 (the mouse) exists, passed its tests and is
+/// enabled.
+pub fn port2_ok() -> bool {
+    PORT2_OK.load(Ordering::SeqCst)
+}
+
+/// Returns a waiting keyboard byte without waiting, for code that runs with
+/// interrupts off. A waiting mouse byte is thrown away, it would block the
+/// keyboard.
+pub fn poll_keyboard_byte() -> Option<u8> {
+    unsafe {
+        let status = inb(STATUS_PORT);
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let byte = inb(DATA_PORT);
+        if status & STATUS_AUX_DATA != 0 { None } else { Some(byte) }
+    }
+}
+
+/// Asks the controller to pulse the CPU reset line. Returns if the
+/// controller didn't take the command or nothing happened.
+pub fn pulse_reset() {
+    unsafe {
+        let _ = command(PULSE_RESET);
+    }
+}
+
+// resets the keyboard, selects scancode set 1 and enables scanning. returns
+// false if the keyboard doesn't support set 1
+unsafe fn init_keyboard() -> Result<bool, I8042Error> {
+    write_data(DEVICE_RESET)?;
+    expect(ACK, "keyboard reset", RESPONSE_TIMEOUT)?;
+    expect(RESET_PASSED, "keyboard reset", RESET_TIMEOUT)?;
+
+    keyboard_command(DEVICE_DISABLE_SCANNING, "disable scanning")?;
+    write_data(KEYBOARD_SCANCODE_SET)?;
+    let set1 = match read_data("scancode set ack", RESPONSE_TIMEOUT)? {
+        ACK => {
+            write_data(1)?;
+            match read_data("scancode set ack", RESPONSE_TIMEOUT)? {
+                ACK => true,
+                RESEND => false,
+                other => return Err(I8042Error::UnexpectedResponse("scancode set", other)),
+            }
+        }
+        RESEND => false,
+        other => return Err(I8042Error::UnexpectedResponse("scancode set", other)),
+    };
+    keyboard_command(DEVICE_ENABLE_SCANNING, "enable scanning")?;
+    Ok(set1)
+}
+
+unsafe fn keyboard_command(value: u8, name: &'static str) -> Result<(), I8042Error> {
+    write_data(value)?;
+    expect(ACK, name, RESPONSE_TIMEOUT)
+}
+
+unsafe fn test_port(number: u8, test_command: u8) -> Result<bool, I8042Error> {
+    match command_with_response(test_command, "port test result")? {
+        PORT_TEST_PASSED => Ok(true),
+        result => {
+            println!("i8042: {:?}", I8042Error::PortTestFailed(number, result));
+            Ok(false)
+        }
+    }
+}
+
+unsafe fn expect(expected: u8, name: &'static str, timeout: usize) -> Result<(), I8042Error> {
+    match read_data(name, timeout)? {
+        value if value == expected => Ok(()),
+        other => Err(I8042Error::UnexpectedResponse(name, other)),
+    }
+}
+
+// throws away whatever the devices sent before we took over
+unsafe fn flush() {
+    for _ in 0..FLUSH_LIMIT {
+        if inb(STATUS_PORT) & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        inb(DATA_PORT);
+    }
+}
+
+unsafe fn write_config(config: u8) -> Result<(), I8042Error> {
+    command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+unsafe fn wait_input_empty() -> Result<(), I8042Error> {
+    for _ in 0..RESPONSE_TIMEOUT {
+        if inb(STATUS_PORT) & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(I8042Error::InputTimeout)
+}
+
+unsafe fn command(command: u8) -> Result<(), I8042Error> {
+    wait_input_empty()?;
+    outb(COMMAND_PORT, command);
+    Ok(())
+}
+
+unsafe fn command_with_response(command_byte: u8, name: &'static str) -> Result<u8, I8042Error> {
+    command(command_byte)?;
+    read_data(name, RESPONSE_TIMEOUT)
+}
+
+/// Waits for a byte from the controller or a device. `name` describes the
+/// awaited byte for the error.
+pub unsafe fn read_data(name: &'static str, timeout: usize) -> Result<u8, I8042Error> {
+    for _ in 0..timeout {
+        if inb(STATUS_PORT) & STATUS_OUTPUT_FULL != 0 {
+            return Ok(inb(DATA_PORT));
+        }
+    }
+    Err(I8042Error::NoResponse(name))
+}
+
+/// Sends a byte to the keyboard (or the controller after a command that
+/// takes an argument).
+pub unsafe fn write_data(value: u8) -> Result<(), I8042Error> {
+    wait_input_empty()?;
+    outb(DATA_PORT, value);
+    Ok(())
+}
+
+/// Sends a byte to the device on port 2.
+pub unsafe fn write_port2(value: u8) -> Result<(), I8042Error> {
+    command(WRITE_PORT2)?;
+    write_data(value)
+}
@@ -0,0 +1,158 @@
+// character input from the keyboard and the serial port
+// code that reads the user (like a shell) goes through this module and
+// doesn't care whether they type on the PS/2 keyboard or over `-serial stdio`
+
+use keyboard;
+use serial;
+use console::{self, Mode};
+use cpu;
+
+/// Something characters can be typed on.
+pub trait InputSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns the next queued character, if there is one. Must not block.
+    fn read_char(&self) -> Option<char>;
+}
+
+pub struct Keyboard;
+
+impl InputSource for Keyboard {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn read_char(&self) -> Option<char> {
+        keyboard::read_char()
+    }
+}
+
+pub struct Serial;
+
+impl InputSource for Serial {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn read_char(&self) -> Option<char> {
+        // terminals send the bytes of their encoding, treat them as latin-1
+        serial::read_byte().map(|byte| byte as char)
+    }
+}
+
+static SOURCES: [&'static InputSource; 2] = [&Keyboard, &Serial];
+// with `console=serial` COM1 is asked first
+static SERIAL_FIRST_SOURCES: [&'static InputSource; 2] = [&Serial, &Keyboard];
+
+fn sources() -> &'static [&'static InputSource] {
+    match console::mode() {
+        Mode::Serial => &SERIAL_FIRST_SOURCES,
+        _ => &SOURCES,
+    }
+}
+
+/// Returns the next character from any source, or `None` if nothing is
+/// queued.
+pub fn read_char() -> Option<char> {
+    sources().iter().filter_map(|source| source.read_char()).next()
+}
+
+/// Waits (with `hlt`) until a character arrives on any source. Interrupts
+/// must be enabled.
+pub fn next_char() -> char {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        // check with interrupts off, so a character can't slip in between
+        // the check and the hlt (`sti; hlt` can't miss the wakeup). both
+        // sources can wake us, so it halts instead of using their wait queues
+        unsafe { interrupts::disable() };
+        if let Some(character) = read_char() {
+            unsafe { interrupts::enable() };
+            return character;
+        }
+        cpu::enable_interrupts_and_halt();
+    }
+}
+
+/// Reads a line typed on any source into `buffer` as UTF-8, echoing it to
+/// the console. Returns its length in bytes without the line end.
+pub fn read_line(buffer: &mut [u8]) -> usize {
+    let mut editor = LineEditor::new(buffer);
+    loop {
+        let character = next_char();
+        let done = editor.feed(character, &mut |s: &str| print!("{}", s));
+        if done {
+            return editor.len();
+        }
+    }
+}
+
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7f';
+
+/// Line editing shared by the `read_line` functions: collects characters
+/// into a buffer, handles backspace and echoes through a callback.
+pub struct LineEditor<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    // a `\n` right after a `\r` belongs to the same line end
+    after_carriage_return: bool,
+}
+
+impl<'a> LineEditor<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> LineEditor<'a> {
+        LineEditor {
+            buffer: buffer,
+            length: 0,
+            after_carriage_return: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn line(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    /// Processes one typed character. Returns true when it ended the line.
+    /// Characters that don't fit into the buffer are ignored, and so are the
+    /// other control characters.
+    pub fn feed(&mut self, character: char, echo: &mut FnMut(&str)) -> bool {
+        let after_carriage_return = self.after_carriage_return;
+        self.after_carriage_return = character == '\r';
+        match character {
+            '\n' if after_carriage_return => false,
+            '\r' | '\n' => {
+                echo("\n");
+                true
+            }
+            BACKSPACE | DELETE => {
+                if self.length > 0 {
+                    // remove a whole UTF-8 sequence, its continuation
+                    // bytes are 0b10xxxxxx
+                    self.length -= 1;
+                    while self.length > 0 && self.buffer[self.length] & 0xc0 == 0x80 {
+                        self.length -= 1;
+                    }
+                    echo("\x08 \x08");
+                }
+                false
+            }
+            c if c.is_control() => false,
+            c => {
+                let mut bytes = [0; 4];
+                let encoded = c.encode_utf8(&mut bytes);
+                let end = self.length + encoded.len();
+                if end <= self.buffer.len() {
+                    self.buffer[self.length..end].copy_from_slice(encoded.as_bytes());
+                    self.length = end;
+                    echo(encoded);
+                }
+                false
+            }
+        }
+    }
+}
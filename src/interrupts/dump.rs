@@ -0,0 +1,315 @@
+// machine state dumps for fatal exceptions
+// the x86-interrupt calling convention hides the register values of the
+// interrupted code, so the fatal exceptions get naked entry points that
+// push all general purpose registers before calling the Rust handler.
+// `dump_state` then prints them together with the stack frame.
+// `dump_regs!` and `dump_control_regs!` print the same at any line of the
+// kernel, for debugging without gdb
+
+use core::fmt::Write;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::idt::ExceptionStackFrame;
+use emergency;
+use memory;
+use backtrace;
+
+/// The general purpose registers in the order the entry points push them
+/// (rax first, so it ends up at the highest address).
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+// what the stack looks like when an entry point calls into Rust. vectors
+// without an error code push a 0 so the layout is always the same
+#[repr(C)]
+pub struct ExceptionEntry {
+    pub registers: SavedRegisters,
+    pub error_code: u64,
+    pub stack_frame: ExceptionStackFrame,
+}
+
+pub type CapturedHandler = fn(&mut ExceptionStackFrame, u64);
+
+// registers of the exception being handled right now, 0 if none
+static CAPTURED: AtomicUsize = AtomicUsize::new(0);
+
+macro_rules! push_registers {
+    () => {
+        asm!("push rax; push rbx; push rcx; push rdx; push rsi; push rdi; push rbp;
+              push r8; push r9; push r10; push r11; push r12; push r13; push r14; push r15"
+             :::: "intel", "volatile");
+    };
+}
+
+macro_rules! pop_registers {
+    () => {
+        asm!("pop r15; pop r14; pop r13; pop r12; pop r11; pop r10; pop r9; pop r8;
+              pop rbp; pop rdi; pop rsi; pop rdx; pop rcx; pop rbx; pop rax"
+             :::: "intel", "volatile");
+    };
+}
+
+// calls into Rust with a pointer to the `ExceptionEntry`. together with the
+// frame and the error code the 15 registers leave the stack 8 bytes off
+// the 16 byte alignment the ABI wants, hence the extra 8
+macro_rules! call_with_entry {
+    ($call:ident) => {
+        asm!("mov rdi, rsp; sub rsp, 8; call $0; add rsp, 8"
+             :: "i"($call as extern "C" fn(&mut $crate::interrupts::dump::ExceptionEntry))
+             : "rdi" : "intel", "volatile");
+    };
+}
+
+/// Generates the naked entry point `$entry`, which saves the registers and
+/// calls `$handler: CapturedHandler`. Add `error_code` for vectors where the
+/// CPU pushes one. Install it with `entry_point`.
+macro_rules! capturing_entry {
+    ($entry:ident, $handler:expr, error_code) => {
+        #[naked]
+        pub extern "C" fn $entry() -> ! {
+            extern "C" fn call(entry: &mut $crate::interrupts::dump::ExceptionEntry) {
+                $crate::interrupts::dump::call_captured(entry, $handler);
+            }
+            unsafe {
+                push_registers!();
+                call_with_entry!(call);
+                pop_registers!();
+                // drop the error code
+                asm!("add rsp, 8; iretq" :::: "intel", "volatile");
+                ::core::intrinsics::unreachable();
+            }
+        }
+    };
+    ($entry:ident, $handler:expr) => {
+        #[naked]
+        pub extern "C" fn $entry() -> ! {
+            extern "C" fn call(entry: &mut $crate::interrupts::dump::ExceptionEntry) {
+                $crate::interrupts::dump::call_captured(entry, $handler);
+            }
+            unsafe {
+                asm!("push 0" :::: "intel", "volatile");
+                push_registers!();
+                call_with_entry!(call);
+                pop_registers!();
+                asm!("add rsp, 8; iretq" :::: "intel", "volatile");
+                ::core::intrinsics::unreachable();
+            }
+        }
+    };
+}
+
+/// Converts an entry point generated by `capturing_entry!` into the handler
+/// type of an IDT entry.
+pub fn entry_point<F: Copy>(entry: extern "C" fn() -> !) -> F {
+    assert!(mem::size_of::<F>() == mem::size_of::<usize>());
+    unsafe { mem::transmute_copy(&entry) }
+}
+
+pub fn call_captured(entry: &mut ExceptionEntry, handler: CapturedHandler) {
+    let registers = &entry.registers as *const SavedRegisters as usize;
+    let previous = CAPTURED.swap(registers, Ordering::SeqCst);
+    handler(&mut entry.stack_frame, entry.error_code);
+    CAPTURED.store(previous, Ordering::SeqCst);
+}
+
+/// Returns the registers saved by the entry point of the exception that is
+/// being handled, if it has one.
+pub fn captured_registers() -> Option<SavedRegisters> {
+    match CAPTURED.load(Ordering::SeqCst) {
+        0 => None,
+        address => Some(unsafe { *(address as *const SavedRegisters) }),
+    }
+}
+
+/// Like `captured_registers`, but changes to the returned registers are
+/// restored into the interrupted code when the handler returns. The
+/// reference must not be used after that.
+pub unsafe fn captured_registers_mut() -> Option<&'static mut SavedRegisters> {
+    match CAPTURED.load(Ordering::SeqCst) {
+        0 => None,
+        address => Some(&mut *(address as *mut SavedRegisters)),
+    }
+}
+
+/// What `dump_regs!` captures: the flags and the registers in the order it
+/// pushes them (flags last, so they end up at the lowest address), then the
+/// stack pointer before the pushes and an address inside the capture.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RegisterSnapshot {
+    pub rflags: u64,
+    pub registers: SavedRegisters,
+    pub rsp: u64,
+    pub rip: u64,
+}
+
+pub const SNAPSHOT_WORDS: usize = 18;
+
+// written by the asm of `dump_regs!` with interrupts off, `take_snapshot`
+// copies it out before they go back on
+pub static mut SNAPSHOT: [u64; SNAPSHOT_WORDS] = [0; SNAPSHOT_WORDS];
+
+/// Prints the registers at the line where it is used, in the format of the
+/// exception dump. Goes through `println!`, so the dump also lands in klog.
+/// Everything is pushed before anything is touched, so the values are the
+/// ones of the surrounding code.
+macro_rules! dump_regs {
+    () => {{
+        unsafe {
+            asm!("push rax; push rbx; push rcx; push rdx; push rsi; push rdi; push rbp
+                  push r8; push r9; push r10; push r11; push r12; push r13; push r14; push r15
+                  pushfq
+                  cli
+                  mov rdi, offset $0
+                  lea rax, [rsp + 16 * 8]
+                  mov [rdi + 16 * 8], rax
+                  lea rax, [rip]
+                  mov [rdi + 17 * 8], rax
+                  mov rsi, rsp
+                  mov rcx, 16
+                  cld
+                  rep movsq
+                  add rsp, 8
+                  pop r15; pop r14; pop r13; pop r12; pop r11; pop r10; pop r9; pop r8
+                  pop rbp; pop rdi; pop rsi; pop rdx; pop rcx; pop rbx; pop rax"
+                 :: "i"(&$crate::interrupts::SNAPSHOT)
+                 : "cc", "memory" : "intel", "volatile");
+        }
+        let snapshot = unsafe { $crate::interrupts::take_snapshot() };
+        $crate::interrupts::print_snapshot(&snapshot, file!(), line!());
+    }};
+}
+
+/// Prints CR0, CR2, CR3, CR4 and EFER through `println!`.
+macro_rules! dump_control_regs {
+    () => {{
+        println!("control registers at {}:{}:", file!(), line!());
+        $crate::interrupts::print_control_registers(&mut $crate::console::Writer);
+    }};
+}
+
+/// For `dump_regs!`: copies the snapshot its asm wrote and turns interrupts
+/// back on if they were on before.
+pub unsafe fn take_snapshot() -> RegisterSnapshot {
+    const INTERRUPT_FLAG: u64 = 1 << 9;
+    let snapshot: RegisterSnapshot = mem::transmute(SNAPSHOT);
+    if snapshot.rflags & INTERRUPT_FLAG != 0 {
+        asm!("sti" :::: "volatile");
+    }
+    snapshot
+}
+
+pub fn print_snapshot(snapshot: &RegisterSnapshot, file: &str, line: u32) {
+    use console::Writer;
+    println!("registers at {}:{}:", file, line);
+    print_registers(&mut Writer, &snapshot.registers);
+    println!("    rip~{:016x}    rsp={:016x} rflags={:016x}",
+             snapshot.rip, snapshot.rsp, snapshot.rflags);
+}
+
+pub fn print_registers<W: Write>(out: &mut W, registers: &SavedRegisters) {
+    let r = registers;
+    let rows = [
+        [("rax", r.rax), ("rbx", r.rbx), ("rcx", r.rcx)],
+        [("rdx", r.rdx), ("rsi", r.rsi), ("rdi", r.rdi)],
+        [("rbp", r.rbp), ("r8 ", r.r8), ("r9 ", r.r9)],
+        [("r10", r.r10), ("r11", r.r11), ("r12", r.r12)],
+        [("r13", r.r13), ("r14", r.r14), ("r15", r.r15)],
+    ];
+    for row in rows.iter() {
+        for &(name, value) in row.iter() {
+            let _ = write!(out, "    {}={:016x}", name, value);
+        }
+        let _ = writeln!(out, "");
+    }
+}
+
+pub fn print_control_registers<W: Write>(out: &mut W) {
+    use x86_64::registers::msr::{IA32_EFER, rdmsr};
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    let efer;
+    unsafe {
+        asm!("mov $0, cr0" : "=r"(cr0) ::: "intel", "volatile");
+        asm!("mov $0, cr2" : "=r"(cr2) ::: "intel", "volatile");
+        asm!("mov $0, cr3" : "=r"(cr3) ::: "intel", "volatile");
+        asm!("mov $0, cr4" : "=r"(cr4) ::: "intel", "volatile");
+        efer = rdmsr(IA32_EFER);
+    }
+    let _ = writeln!(out, "    cr0={:016x}    cr2={:016x}", cr0, cr2);
+    let _ = writeln!(out, "    cr3={:016x}    cr4={:016x}", cr3, cr4);
+    let _ = writeln!(out, "   efer={:016x}", efer);
+}
+
+/// Prints the registers saved by the entry point, the stack frame, the
+/// control registers, the stack around RSP and the backtrace of the
+/// interrupted code. Uses the lock-free emergency
+/// output and only reads mapped stack memory.
+pub fn dump_state(stack_frame: &ExceptionStackFrame, error_code: Option<u64>) {
+    let mut out = emergency::Writer;
+
+    if let Some(error_code) = error_code {
+        let _ = writeln!(out, "    error code={:#x}", error_code);
+    }
+    match captured_registers() {
+        Some(registers) => print_registers(&mut out, &registers),
+        None => { let _ = writeln!(out, "    (general purpose registers not saved)"); }
+    }
+    let _ = writeln!(out, "    rip={:016x}    rsp={:016x} rflags={:016x}",
+                     stack_frame.instruction_pointer.0, stack_frame.stack_pointer.0,
+                     stack_frame.cpu_flags);
+    let _ = writeln!(out, "     cs={:016x}     ss={:016x}",
+                     stack_frame.code_segment, stack_frame.stack_segment);
+    print_control_registers(&mut out);
+    print_stack(&mut out, stack_frame.stack_pointer.0);
+    if let Some(registers) = captured_registers() {
+        backtrace::print_from(&mut out, registers.rbp as usize);
+    }
+}
+
+// 16 quadwords starting a bit below RSP, so recently popped values show up
+fn print_stack<W: Write>(out: &mut W, stack_pointer: usize) {
+    const QUADWORDS: usize = 16;
+    const BELOW: usize = 4;
+
+    let _ = writeln!(out, "    stack:");
+    let start = stack_pointer.wrapping_sub(BELOW * 8);
+    for i in 0..QUADWORDS {
+        let address = start.wrapping_add(i * 8);
+        let mut bytes = [0u8; 8];
+        let marker = if address == stack_pointer { " <- rsp" } else { "" };
+        if memory::read_checked(address, &mut bytes) == bytes.len() {
+            let value: u64 = unsafe { mem::transmute(bytes) };
+            let _ = writeln!(out, "    {:016x}: {:016x}{}", address, value, marker);
+        } else {
+            let _ = writeln!(out, "    {:016x}: <unmapped>{}", address, marker);
+        }
+    }
+}
+
+// the snapshot has to be of this stack, with the always set flags bit
+#[cfg(debug_assertions)]
+pub fn test_dump_regs() {
+    dump_regs!();
+    dump_control_regs!();
+    let snapshot: RegisterSnapshot = unsafe { mem::transmute(SNAPSHOT) };
+    assert!(snapshot.rflags & 1 << 1 != 0);
+    assert!(memory::is_kernel_stack(snapshot.rsp as usize));
+    println!("dump_regs: test passed");
+}
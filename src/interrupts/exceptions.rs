@@ -0,0 +1,590 @@
+// exception handlers
+// the special ones (breakpoint, double fault, #GP, #PF) are written out,
+// the last two resume at a fixup after faults in recoverable code (`oops`),
+// the others share a generic report generated by the macros below. the
+// ones that can be fatal are entered through `capturing_entry!`, so their
+// reports include the registers of the faulting code
+
+use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::VirtualAddress;
+use super::stats;
+use super::dump::dump_state;
+use super::oops::oops;
+use cpu;
+use memory;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicUsize;
+
+// generates the entry point for an exception without an error code
+macro_rules! exception_handler {
+    ($name:ident, $vector:expr, $description:expr) => {
+        capturing_entry!($name, {
+            fn handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+                generic_exception($vector, $description, stack_frame, None);
+            }
+            handler
+        });
+    };
+}
+
+// generates the entry point for an exception that pushes an error code
+macro_rules! exception_handler_with_error_code {
+    ($name:ident, $vector:expr, $description:expr) => {
+        capturing_entry!($name, {
+            fn handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+                generic_exception($vector, $description, stack_frame, Some(error_code));
+            }
+            handler
+        }, error_code);
+    };
+}
+
+exception_handler!(overflow_handler, 4, "OVERFLOW");
+exception_handler!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED");
+exception_handler!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE");
+exception_handler_with_error_code!(invalid_tss_handler, 10, "INVALID TSS");
+exception_handler_with_error_code!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT");
+exception_handler_with_error_code!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT");
+exception_handler!(x87_floating_point_handler, 16, "X87 FLOATING POINT");
+exception_handler_with_error_code!(alignment_check_handler, 17, "ALIGNMENT CHECK");
+exception_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT");
+exception_handler!(virtualization_handler, 20, "VIRTUALIZATION");
+exception_handler_with_error_code!(security_exception_handler, 30, "SECURITY EXCEPTION");
+
+// when set, a divide error skips the faulting instruction instead of panicking
+static RECOVER_DIV0: AtomicBool = AtomicBool::new(false);
+// set by the divide error handler whenever it recovered from a fault
+static ARITHMETIC_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// Debug switch: let the divide error handler skip the faulting `div`/`idiv`
+/// and continue, instead of reporting and panicking (the default).
+pub fn set_recover_div0(recover: bool) {
+    RECOVER_DIV0.store(recover, Ordering::SeqCst);
+}
+
+/// Returns whether a divide error was recovered from since the last call and
+/// clears the flag.
+pub fn take_arithmetic_fault() -> bool {
+    ARITHMETIC_FAULT.swap(false, Ordering::SeqCst)
+}
+
+capturing_entry!(debug_entry, debug_handler);
+
+// single steps of the GDB stub and hardware watchpoints resume, everything
+// else gets the generic report
+fn debug_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    use debug::{self, gdbstub};
+
+    stats::count(1);
+    if gdbstub::handle_single_step(stack_frame) {
+        return;
+    }
+    if debug::handle_debug_exception(stack_frame) {
+        return;
+    }
+    report_exception(1, "DEBUG", stack_frame, None);
+}
+
+capturing_entry!(divide_by_zero_entry, divide_by_zero_handler);
+
+fn divide_by_zero_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    stats::count(0);
+    if exception_expected(0) {
+        println!("\nEXCEPTION: DIVIDE ERROR (vector 0)\n{:#?}", stack_frame);
+        return;
+    }
+
+    if RECOVER_DIV0.load(Ordering::SeqCst) {
+        let instruction_pointer = stack_frame.instruction_pointer.0;
+        if let Some(length) = div_instruction_length(instruction_pointer) {
+            stack_frame.instruction_pointer = VirtualAddress(instruction_pointer + length);
+            ARITHMETIC_FAULT.store(true, Ordering::SeqCst);
+            return;
+        }
+        println!("\ncould not decode the faulting instruction, not recovering");
+    }
+
+    report_exception(0, "DIVIDE ERROR", stack_frame, None);
+}
+
+// decodes the length of the div/idiv instruction at `address`, which is
+// opcode F6 or F7 with /6 or /7 in the ModRM reg field. returns None for
+// anything else, so we never skip an instruction we don't understand
+fn div_instruction_length(address: usize) -> Option<usize> {
+    let mut bytes = [0u8; 15];  // maximum instruction length
+    let count = memory::read_checked(address, &mut bytes);
+    let bytes = &bytes[..count];
+
+    let mut i = 0;
+    // legacy prefixes (operand/address size, segment overrides, lock/rep)
+    while i < bytes.len() {
+        match bytes[i] {
+            0x66 | 0x67 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 |
+            0xf0 | 0xf2 | 0xf3 => i += 1,
+            _ => break,
+        }
+    }
+    // REX prefix
+    if i < bytes.len() && bytes[i] & 0xf0 == 0x40 {
+        i += 1;
+    }
+    if i + 1 >= bytes.len() || (bytes[i] != 0xf6 && bytes[i] != 0xf7) {
+        return None;
+    }
+    let modrm = bytes[i + 1];
+    i += 2;
+
+    let mode = modrm >> 6;
+    let reg = (modrm >> 3) & 0b111;
+    let rm = modrm & 0b111;
+    if reg != 6 && reg != 7 {
+        return None;  // not div/idiv (e.g. test, not, neg, mul)
+    }
+
+    if mode != 0b11 {
+        let mut base = rm;
+        if rm == 0b100 {
+            // SIB byte follows
+            if i >= bytes.len() {
+                return None;
+            }
+            base = bytes[i] & 0b111;
+            i += 1;
+        }
+        i += match mode {
+            0b00 if rm == 0b101 => 4,   // RIP relative
+            0b00 if base == 0b101 => 4, // SIB without base register
+            0b00 => 0,
+            0b01 => 1,
+            _ => 4,
+        };
+    }
+
+    if i <= bytes.len() { Some(i) } else { None }
+}
+
+fn generic_exception(vector: u8, description: &str, stack_frame: &ExceptionStackFrame,
+                     error_code: Option<u64>)
+{
+    stats::count(vector);
+    report_exception(vector, description, stack_frame, error_code);
+}
+
+// prints the generic report and panics, unless the exception was expected
+fn report_exception(vector: u8, description: &str, stack_frame: &ExceptionStackFrame,
+                    error_code: Option<u64>)
+{
+    println!("\nEXCEPTION: {} (vector {})", description, vector);
+    if exception_expected(vector) {
+        // raised on purpose by `trigger`, resume after the int instruction
+        if let Some(error_code) = error_code {
+            println!("    error code: {:#x}", error_code);
+        }
+        println!("{:#?}", stack_frame);
+        return;
+    }
+    dump_state(stack_frame, error_code);
+    panic!("unhandled exception: {}", description);
+}
+
+// vector the test code is about to raise, the handler returns instead of
+// panicking when it sees this one
+#[cfg(debug_assertions)]
+static EXPECTED_EXCEPTION: AtomicUsize = AtomicUsize::new(NO_EXCEPTION);
+#[cfg(debug_assertions)]
+static REPORTED_EXCEPTION: AtomicUsize = AtomicUsize::new(NO_EXCEPTION);
+#[cfg(debug_assertions)]
+const NO_EXCEPTION: usize = 256;
+
+#[cfg(debug_assertions)]
+fn exception_expected(vector: u8) -> bool {
+    let expected = EXPECTED_EXCEPTION.swap(NO_EXCEPTION, Ordering::SeqCst);
+    if expected == vector as usize {
+        REPORTED_EXCEPTION.store(vector as usize, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn exception_expected(_vector: u8) -> bool {
+    false
+}
+
+/// Raises the given exception vector with `int`. Debug builds only, used by
+/// the exception tests. Vectors that push an error code can't be raised this
+/// way: `int n` doesn't push one, so their handlers would misread the frame.
+#[cfg(debug_assertions)]
+pub fn trigger(vector: u8) {
+    unsafe {
+        match vector {
+            0 => asm!("int $$0" :::: "volatile"),
+            1 => asm!("int $$1" :::: "volatile"),
+            3 => asm!("int $$3" :::: "volatile"),
+            4 => asm!("int $$4" :::: "volatile"),
+            5 => asm!("int $$5" :::: "volatile"),
+            6 => asm!("int $$6" :::: "volatile"),
+            7 => asm!("int $$7" :::: "volatile"),
+            16 => asm!("int $$16" :::: "volatile"),
+            19 => asm!("int $$19" :::: "volatile"),
+            20 => asm!("int $$20" :::: "volatile"),
+            _ => panic!("vector {} can't be raised with int", vector),
+        }
+    }
+}
+
+/// Divides by a zero read from memory with `recover_div0` set and checks that
+/// the handler skipped the `div` and flagged the fault.
+#[cfg(debug_assertions)]
+pub fn test_divide_recovery() {
+    use core::ptr;
+
+    static ZERO: u64 = 0;
+
+    set_recover_div0(true);
+    take_arithmetic_fault();
+
+    let divisor = unsafe { ptr::read_volatile(&ZERO) };
+    let quotient: u64;
+    let remainder: u64;
+    // rust checks for zero before dividing, so use div directly
+    unsafe {
+        asm!("div $4"
+             : "={rax}"(quotient), "={rdx}"(remainder)
+             : "{rax}"(42u64), "{rdx}"(0u64), "r"(divisor)
+             :: "volatile");
+    }
+    let _ = (quotient, remainder);
+
+    set_recover_div0(false);
+    assert!(take_arithmetic_fault(), "divide error was not recovered");
+    println!("divide recovery test passed");
+}
+
+// runs with only a few hundred bytes of stack left, less than the NMI
+// report needs. without its IST stack the NMI would run into the guard page
+#[cfg(debug_assertions)]
+extern "C" fn send_nmi_on_exhausted_stack() {
+    use apic;
+
+    let before = stats::vector_count(2);
+    apic::send_nmi(apic::id());
+    for _ in 0..1_000_000 {
+        if stats::vector_count(2) != before {
+            return;
+        }
+    }
+}
+
+/// Sends an NMI to this CPU through the local APIC while RSP is 256 bytes
+/// above the guard page of a stack, and checks that the NMI was reported.
+/// Needs the local APIC.
+#[cfg(debug_assertions)]
+pub fn test_nmi_stack(memory_controller: &mut ::memory::MemoryController) {
+    use apic;
+
+    assert!(apic::is_enabled(), "the NMI test needs the local APIC");
+    let stack = memory_controller.alloc_stack(1)
+        .expect("could not allocate test stack");
+    let before = stats::vector_count(2);
+    unsafe { cpu::call_on_stack(stack.bottom() + 256, send_nmi_on_exhausted_stack) };
+    assert!(stats::vector_count(2) > before, "NMI was not reported");
+    println!("NMI stack test passed");
+}
+
+/// Raises every exception vector without an error code and checks that its
+/// handler reported it and returned.
+#[cfg(debug_assertions)]
+pub fn test_exceptions() {
+    for &vector in [0, 1, 3, 4, 5, 6, 7, 16, 19, 20].iter() {
+        EXPECTED_EXCEPTION.store(vector as usize, Ordering::SeqCst);
+        REPORTED_EXCEPTION.store(NO_EXCEPTION, Ordering::SeqCst);
+        trigger(vector);
+        // the breakpoint handler always returns, so it needs no expectation
+        EXPECTED_EXCEPTION.store(NO_EXCEPTION, Ordering::SeqCst);
+        assert!(vector == 3 || REPORTED_EXCEPTION.load(Ordering::SeqCst) == vector as usize,
+                "exception {} was not reported", vector);
+    }
+    println!("exception test passed");
+}
+
+capturing_entry!(breakpoint_entry, breakpoint_handler);
+
+// with the GDB stub enabled, int3 stops in the debugger instead
+fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    use debug::gdbstub;
+
+    stats::count(3);
+    if gdbstub::handle_breakpoint(stack_frame) {
+        return;
+    }
+    println!("\nEXCEPTION: BREAKPOINT at {:#x}\n{:#?}",
+             stack_frame.instruction_pointer.0, stack_frame);
+}
+
+capturing_entry!(general_protection_fault_entry, general_protection_fault_handler, error_code);
+
+fn general_protection_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    stats::count(13);
+    println!("\nEXCEPTION: GENERAL PROTECTION FAULT");
+    println!("    error code:          {:#x}", error_code);
+    if error_code != 0 {
+        // a non-zero error code is a segment selector index
+        let table = match (error_code >> 1) & 0b11 {
+            0 => "GDT",
+            1 | 3 => "IDT",
+            _ => "LDT",
+        };
+        let external = if error_code & 1 != 0 { " (external event)" } else { "" };
+        println!("    selector:            {} index {}{}",
+                 table, (error_code >> 3) & 0x1fff, external);
+    }
+    println!("    instruction pointer: {:#x}", stack_frame.instruction_pointer.0);
+    println!("    code segment:        {:#x}", stack_frame.code_segment);
+    println!("    cpu flags:           {:#x}", stack_frame.cpu_flags);
+    println!("    stack pointer:       {:#x}", stack_frame.stack_pointer.0);
+    let mut bytes = [0u8; 8];
+    let count = dump_instruction_bytes(stack_frame.instruction_pointer.0, &mut bytes);
+
+    // name the privileged instructions that commonly cause the fault
+    let name = match &bytes[..count] {
+        b if b.starts_with(&[0x0f, 0x30]) => Some("wrmsr (unsupported MSR or invalid value?)"),
+        b if b.starts_with(&[0x0f, 0x32]) => Some("rdmsr (unsupported MSR?)"),
+        b if b.starts_with(&[0x0f, 0x01]) => Some("lgdt/lidt family"),
+        b if b.starts_with(&[0x0f, 0x22]) => Some("mov to control register"),
+        b if b.starts_with(&[0x48, 0xcf]) || b.starts_with(&[0xcf]) => Some("iret"),
+        _ => None,
+    };
+    if let Some(name) = name {
+        println!("    instruction:         {}", name);
+    }
+    dump_state(stack_frame, Some(error_code));
+    if oops("general protection fault", stack_frame) {
+        return;
+    }
+    panic!("general protection fault at {:#x}", stack_frame.instruction_pointer.0);
+}
+
+// dumps the bytes at the faulting instruction into `bytes` and prints them,
+// reading only mapped memory. returns the number of bytes read, if RIP
+// points to unmapped memory we most likely jumped to garbage
+fn dump_instruction_bytes(instruction_pointer: usize, bytes: &mut [u8]) -> usize {
+    use memory;
+
+    let count = memory::read_checked(instruction_pointer, bytes);
+    if count == 0 {
+        println!("    instruction bytes:   <unmapped, jump to garbage?>");
+        return 0;
+    }
+
+    print!("    instruction bytes:  ");
+    for byte in &bytes[..count] {
+        print!(" {:02x}", byte);
+    }
+    println!("");
+    count
+}
+
+capturing_entry!(invalid_opcode_entry, invalid_opcode_handler);
+
+fn invalid_opcode_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    stats::count(6);
+    if exception_expected(6) {
+        println!("\nEXCEPTION: INVALID OPCODE (vector 6)\n{:#?}", stack_frame);
+        return;
+    }
+
+    let instruction_pointer = stack_frame.instruction_pointer.0;
+    println!("\nEXCEPTION: INVALID OPCODE");
+    println!("    instruction pointer: {:#x}", instruction_pointer);
+
+    let mut bytes = [0u8; 15];  // maximum instruction length
+    let count = dump_instruction_bytes(instruction_pointer, &mut bytes);
+    if bytes[..count].starts_with(&[0x0f, 0x0b]) {
+        println!("    explicit ud2 -- likely a reached unreachable!()");
+    }
+    dump_state(stack_frame, None);
+    panic!("invalid opcode at {:#x}", instruction_pointer);
+}
+
+// NMI and machine check may hit while the WRITER lock is held, so they
+// only use the lock-free serial output and never println!
+
+pub extern "x86-interrupt" fn nmi_handler(_stack_frame: &mut ExceptionStackFrame)
+{
+    stats::count(2);
+    use core::fmt::Write;
+    use serial::RawWriter;
+    use x86_64::instructions::port::inb;
+
+    // system control port B tells us where the NMI came from
+    let status = unsafe { inb(0x61) };
+    let mut out = RawWriter;
+    let _ = write!(out, "\nNMI: system control port B {:#x}", status);
+    if status & (1 << 7) != 0 {
+        let _ = write!(out, ", memory parity error");
+    }
+    if status & (1 << 6) != 0 {
+        let _ = write!(out, ", I/O channel check");
+    }
+    let _ = write!(out, "\n");
+}
+
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame)
+{
+    stats::count(18);
+    use core::fmt::Write;
+    use serial::RawWriter;
+    use x86_64::registers::msr::rdmsr;
+
+    const IA32_MCG_CAP: u32 = 0x179;
+    const IA32_MCG_STATUS: u32 = 0x17a;
+    const IA32_MC0_STATUS: u32 = 0x401;
+    const STATUS_VALID: u64 = 1 << 63;
+    const STATUS_MISC_VALID: u64 = 1 << 59;
+    const STATUS_ADDR_VALID: u64 = 1 << 58;
+
+    let mut out = RawWriter;
+    let _ = writeln!(out, "\nEXCEPTION: MACHINE CHECK at {:#x}",
+                     stack_frame.instruction_pointer.0);
+
+    unsafe {
+        let _ = writeln!(out, "    MCG_STATUS: {:#x}", rdmsr(IA32_MCG_STATUS));
+
+        // each bank has four MSRs: CTL, STATUS, ADDR, MISC
+        let banks = rdmsr(IA32_MCG_CAP) & 0xff;
+        for bank in 0..banks as u32 {
+            let status = rdmsr(IA32_MC0_STATUS + 4 * bank);
+            if status & STATUS_VALID == 0 {
+                continue;
+            }
+            let _ = write!(out, "    MC{}_STATUS: {:#x}", bank, status);
+            if status & STATUS_ADDR_VALID != 0 {
+                let _ = write!(out, " ADDR: {:#x}", rdmsr(IA32_MC0_STATUS + 4 * bank + 1));
+            }
+            if status & STATUS_MISC_VALID != 0 {
+                let _ = write!(out, " MISC: {:#x}", rdmsr(IA32_MC0_STATUS + 4 * bank + 2));
+            }
+            let _ = writeln!(out, "");
+        }
+    }
+
+    // the machine state is not trustworthy anymore
+    let _ = writeln!(out, "machine check, halting");
+    cpu::halt_forever()
+}
+
+// bits of the page fault error code
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_RESERVED_BIT: u64 = 1 << 3;
+const PF_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+capturing_entry!(page_fault_entry, page_fault_handler, error_code);
+
+fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    stats::count(14);
+    use x86_64::registers::control_regs;
+
+    // CR2 holds the address whose access caused the fault
+    let fault_address = control_regs::cr2().0;
+
+    // runs on its own IST stack, so faults on a stack guard page can be
+    // handled at all: the CPU can't push the frame onto the faulting stack
+    if resolve_page_fault(fault_address, error_code) {
+        // the fault was fixed up, retry the faulting instruction
+        return;
+    }
+
+    report_page_fault(stack_frame, fault_address, error_code);
+    if oops("page fault", stack_frame) {
+        return;
+    }
+    panic!("unresolved page fault at {:#x}", fault_address);
+}
+
+// place for handlers that can fix a fault and let the access be retried
+// (lazy mapping, copy on write). returns true if the fault was resolved
+fn resolve_page_fault(fault_address: usize, error_code: u64) -> bool {
+    use memory::{self, StackFault};
+    use task;
+
+    if error_code & (PF_PRESENT | PF_USER) != 0 {
+        return false;
+    }
+    // kernel stacks grow into the reserved pages below them
+    match memory::handle_stack_fault(fault_address) {
+        Some(StackFault::Grown) => true,
+        Some(StackFault::Overflow { top, max_size_in_pages }) => {
+            match task::stack_owner(fault_address) {
+                Some((id, _)) => println!("\nkernel stack overflow of thread {}: stack with \
+                                           top {:#x} reached its maximum of {} pages",
+                                          id.0, top, max_size_in_pages),
+                None => println!("\nkernel stack overflow: stack with top {:#x} reached its \
+                                  maximum of {} pages", top, max_size_in_pages),
+            }
+            false
+        }
+        Some(StackFault::Locked) => {
+            println!("\nkernel stack can't grow: the stack table or the frame allocator is \
+                      locked, maybe by the faulting code");
+            false
+        }
+        None => false,
+    }
+}
+
+fn report_page_fault(stack_frame: &ExceptionStackFrame, fault_address: usize,
+                     error_code: u64)
+{
+    use memory;
+
+    let access = if error_code & PF_INSTRUCTION_FETCH != 0 {
+        "instruction fetch"
+    } else if error_code & PF_WRITE != 0 {
+        "write"
+    } else {
+        "read"
+    };
+    let reason = if error_code & PF_PRESENT != 0 {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let mode = if error_code & PF_USER != 0 { "user" } else { "kernel" };
+
+    println!("\nEXCEPTION: PAGE FAULT");
+    println!("    accessed address:    {:#x}", fault_address);
+    println!("    cause:               {} ({} in {} mode)", reason, access, mode);
+    if error_code & PF_RESERVED_BIT != 0 {
+        println!("    reserved bit set in a page table entry");
+    }
+    println!("    error code:          {:#x}", error_code);
+    println!("    instruction pointer: {:#x}", stack_frame.instruction_pointer.0);
+    match memory::translate_with_flags(fault_address) {
+        Some((physical_address, flags)) => {
+            println!("    mapping:             {:#x} -> {:#x} {:?}",
+                     fault_address, physical_address, flags)
+        }
+        None => println!("    mapping:             not mapped"),
+    }
+    dump_state(stack_frame, Some(error_code));
+}
+
+// runs on its own stack, so a kernel stack overflow ends up here
+// instead of causing a triple fault
+capturing_entry!(double_fault_entry, double_fault_handler, error_code);
+
+fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    stats::count(8);
+    println!("\nEXCEPTION: DOUBLE FAULT");
+    dump_state(stack_frame, Some(error_code));
+    if let Some(stack) = memory::clobbered_canary() {
+        println!("    the canary of the stack {:#x}..{:#x} is overwritten, this was a stack overflow",
+                 stack.bottom(), stack.top());
+    }
+    cpu::halt_forever()
+}
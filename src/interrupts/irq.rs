@@ -0,0 +1,274 @@
+// hardware interrupts
+// every IRQ vector gets a generated stub that dispatches to the handlers
+// registered for it. the handler table is read lock free, since the stubs
+// can interrupt code that is (un)registering handlers
+//
+// handlers run in interrupt context: they must never block, so no heap
+// allocation and no lock the interrupted code might hold. the outermost
+// stub may switch threads on its way out (see `task`)
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::structures::idt::{Idt, ExceptionStackFrame};
+use sync::DebugMutex;
+use pic;
+use apic;
+use ioapic;
+use task;
+use tlb;
+use super::stats;
+
+pub const IRQ_COUNT: usize = 16;
+// handlers per IRQ, more than one means the line is shared
+const MAX_SHARED: usize = 4;
+
+pub type IrqHandler = fn(&mut InterruptContext);
+
+/// Passed to the registered handlers. For interrupts of the local APIC
+/// itself (like its timer), `irq` is the vector instead.
+pub struct InterruptContext<'a> {
+    pub irq: u8,
+    pub stack_frame: &'a mut ExceptionStackFrame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    AlreadyRegistered,
+    NotRegistered,
+    // all slots of the (shared) IRQ are taken
+    TableFull,
+}
+
+// function pointers stored as usize, 0 marks a free slot
+static HANDLERS: [[AtomicUsize; MAX_SHARED]; IRQ_COUNT] = [
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+];
+
+// serializes (un)registration and the mask updates, never taken by the stubs
+static REGISTRATION: DebugMutex<()> = DebugMutex::new(());
+
+// handler for the local APIC timer, stored like the IRQ handlers
+static APIC_TIMER_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+// set once the I/O APIC routes the legacy IRQs instead of the PICs
+static IOAPIC_MODE: AtomicBool = AtomicBool::new(false);
+
+// how many interrupt handlers are running right now (nested ones included)
+static NESTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers `handler` for the given IRQ. The first handler of an IRQ
+/// unmasks the line. Handlers must be interrupt safe: they can't block or
+/// take any lock that non-interrupt code takes with interrupts enabled.
+/// Must not be called from interrupt context.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    let _guard = REGISTRATION.lock();
+    let slots = &HANDLERS[irq as usize];
+    let value = handler as usize;
+
+    if slots.iter().any(|slot| slot.load(Ordering::SeqCst) == value) {
+        return Err(IrqError::AlreadyRegistered);
+    }
+    let was_unused = slots.iter().all(|slot| slot.load(Ordering::SeqCst) == 0);
+    match slots.iter().find(|slot| slot.load(Ordering::SeqCst) == 0) {
+        Some(slot) => slot.store(value, Ordering::SeqCst),
+        None => return Err(IrqError::TableFull),
+    }
+
+    if was_unused {
+        set_irq_masked(irq, false);
+    }
+    Ok(())
+}
+
+/// Removes a handler registered with `register_irq`. Removing the last
+/// handler of an IRQ masks the line again.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    let _guard = REGISTRATION.lock();
+    let slots = &HANDLERS[irq as usize];
+    let value = handler as usize;
+
+    match slots.iter().find(|slot| slot.load(Ordering::SeqCst) == value) {
+        Some(slot) => slot.store(0, Ordering::SeqCst),
+        None => return Err(IrqError::NotRegistered),
+    }
+
+    if slots.iter().all(|slot| slot.load(Ordering::SeqCst) == 0) {
+        set_irq_masked(irq, true);
+    }
+    Ok(())
+}
+
+/// Sets the handler for the local APIC timer interrupt. The timer itself is
+/// programmed through `apic`.
+pub fn set_apic_timer_handler(handler: IrqHandler) {
+    APIC_TIMER_HANDLER.store(handler as usize, Ordering::SeqCst);
+}
+
+/// Returns whether we are running inside a hardware interrupt handler.
+pub fn in_interrupt_context() -> bool {
+    NESTING.load(Ordering::Relaxed) != 0
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    if IOAPIC_MODE.load(Ordering::SeqCst) {
+        // keep the PIC vectors, so the stubs stay the same in both modes
+        ioapic::set_redirection(ioapic::gsi_for_irq(irq), pic::vector(irq),
+                                apic::id(), masked);
+    } else if masked {
+        pic::set_mask(irq);
+    } else {
+        pic::clear_mask(irq);
+    }
+}
+
+fn end_of_interrupt(irq: u8) {
+    if IOAPIC_MODE.load(Ordering::Relaxed) {
+        apic::eoi();
+    } else {
+        pic::notify_end_of_interrupt(irq);
+    }
+}
+
+/// Moves the legacy IRQs from the PICs to the I/O APIC: IRQs with handlers
+/// are routed to the local APIC of this CPU and the PICs are masked
+/// completely. The local APIC and the I/O APIC must be initialized.
+pub fn switch_to_ioapic() {
+    assert!(apic::is_enabled() && ioapic::is_enabled(),
+            "switching to the I/O APIC needs the local and the I/O APIC");
+    let _guard = REGISTRATION.lock();
+
+    pic::disable();
+    apic::disable_virtual_wire();
+    IOAPIC_MODE.store(true, Ordering::SeqCst);
+    for irq in 0..IRQ_COUNT as u8 {
+        let in_use = HANDLERS[irq as usize].iter()
+            .any(|slot| slot.load(Ordering::SeqCst) != 0);
+        if in_use {
+            set_irq_masked(irq, false);
+        }
+    }
+}
+
+// common part of all stubs
+fn dispatch(irq: u8, stack_frame: &mut ExceptionStackFrame) {
+    // IRQ 7 and 15 may be spurious, those must not reach the handlers.
+    // only the PICs produce those, the I/O APIC uses the APIC spurious vector
+    let legacy_pic = !IOAPIC_MODE.load(Ordering::Relaxed);
+    if legacy_pic && (irq == pic::MASTER_SPURIOUS_IRQ || irq == pic::SLAVE_SPURIOUS_IRQ) {
+        if pic::is_spurious(irq) {
+            return;
+        }
+    }
+
+    stats::count(pic::vector(irq));
+    NESTING.fetch_add(1, Ordering::Relaxed);
+    let mut context = InterruptContext {
+        irq: irq,
+        stack_frame: stack_frame,
+    };
+    for slot in HANDLERS[irq as usize].iter() {
+        let value = slot.load(Ordering::Acquire);
+        if value != 0 {
+            let handler: IrqHandler = unsafe { mem::transmute(value) };
+            handler(&mut context);
+        }
+    }
+    NESTING.fetch_sub(1, Ordering::Relaxed);
+
+    end_of_interrupt(irq);
+    // after the EOI, the next thread may run for a while
+    task::preempt_on_interrupt_return();
+}
+
+macro_rules! irq_stub {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            dispatch($irq, stack_frame);
+        }
+    };
+}
+
+irq_stub!(irq0_stub, 0);
+irq_stub!(irq1_stub, 1);
+irq_stub!(irq2_stub, 2);
+irq_stub!(irq3_stub, 3);
+irq_stub!(irq4_stub, 4);
+irq_stub!(irq5_stub, 5);
+irq_stub!(irq6_stub, 6);
+irq_stub!(irq7_stub, 7);
+irq_stub!(irq8_stub, 8);
+irq_stub!(irq9_stub, 9);
+irq_stub!(irq10_stub, 10);
+irq_stub!(irq11_stub, 11);
+irq_stub!(irq12_stub, 12);
+irq_stub!(irq13_stub, 13);
+irq_stub!(irq14_stub, 14);
+irq_stub!(irq15_stub, 15);
+
+// points the 16 IRQ vectors to the stubs
+pub fn install_stubs(idt: &mut Idt) {
+    let stubs: [extern "x86-interrupt" fn(&mut ExceptionStackFrame); IRQ_COUNT] = [
+        irq0_stub, irq1_stub, irq2_stub, irq3_stub,
+        irq4_stub, irq5_stub, irq6_stub, irq7_stub,
+        irq8_stub, irq9_stub, irq10_stub, irq11_stub,
+        irq12_stub, irq13_stub, irq14_stub, irq15_stub,
+    ];
+    for (irq, &stub) in stubs.iter().enumerate() {
+        idt[pic::vector(irq as u8) as usize].set_handler_fn(stub);
+    }
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_stub);
+    idt[apic::TIMER_VECTOR as usize].set_handler_fn(apic_timer_stub);
+    idt[apic::TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb_shootdown_stub);
+}
+
+extern "x86-interrupt" fn apic_timer_stub(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(apic::TIMER_VECTOR);
+    NESTING.fetch_add(1, Ordering::Relaxed);
+    let value = APIC_TIMER_HANDLER.load(Ordering::Acquire);
+    if value != 0 {
+        let handler: IrqHandler = unsafe { mem::transmute(value) };
+        handler(&mut InterruptContext {
+            irq: apic::TIMER_VECTOR,
+            stack_frame: stack_frame,
+        });
+    }
+    NESTING.fetch_sub(1, Ordering::Relaxed);
+    apic::eoi();
+    task::preempt_on_interrupt_return();
+}
+
+// sent by another CPU, see `tlb`. it runs on parked APs too, so it doesn't
+// go through the thread switch on the way out
+extern "x86-interrupt" fn tlb_shootdown_stub(_stack_frame: &mut ExceptionStackFrame) {
+    stats::count(apic::TLB_SHOOTDOWN_VECTOR);
+    tlb::shootdown_interrupt();
+    apic::eoi();
+}
+
+// the local APIC raises its spurious vector when an interrupt goes away
+// before it is delivered. it must not be acknowledged with an EOI
+extern "x86-interrupt" fn apic_spurious_stub(_stack_frame: &mut ExceptionStackFrame) {
+    stats::count(apic::SPURIOUS_VECTOR);
+}
@@ -0,0 +1,107 @@
+// interrupt descriptor table
+
+use x86_64::structures::idt::Idt;
+use spin::Once;
+use gdt;
+use pic;
+use self::exceptions::*;
+use self::dump::entry_point;
+pub use self::stats::{stats, vector_count, print_stats, InterruptStats, VectorName};
+pub use self::irq::{register_irq, unregister_irq, in_interrupt_context,
+                    switch_to_ioapic, set_apic_timer_handler, InterruptContext,
+                    IrqError, IrqHandler};
+
+#[macro_use]
+mod dump;
+mod exceptions;
+mod irq;
+mod oops;
+mod stats;
+mod unhandled;
+
+pub use self::exceptions::{set_recover_div0, take_arithmetic_fault};
+pub use self::oops::{oops_count, register_recoverable, read_byte as read_byte_fault_safe,
+                     RecoverableRegion, RegionError, MAX_OOPSES};
+pub use self::dump::{dump_state, captured_registers, captured_registers_mut, print_registers,
+                     print_control_registers, SavedRegisters, RegisterSnapshot, SNAPSHOT,
+                     take_snapshot, print_snapshot};
+#[cfg(debug_assertions)]
+pub use self::exceptions::{trigger, test_exceptions, test_divide_recovery, test_nmi_stack};
+#[cfg(debug_assertions)]
+pub use self::unhandled::test_unhandled_vector;
+#[cfg(debug_assertions)]
+pub use self::dump::test_dump_regs;
+#[cfg(debug_assertions)]
+pub use self::oops::test_oops;
+
+static IDT: Once<Idt> = Once::new();
+
+/// Creates and loads the IDT and remaps the PICs. `gdt::init` has to run
+/// first, because the double fault handler switches to a stack from the TSS.
+/// Interrupts have to be disabled, they are only enabled by `enable`.
+pub fn init() {
+    assert!(!interrupts_enabled(), "interrupts enabled before the IDT is loaded");
+
+    let idt = IDT.call_once(|| {
+        let mut idt = Idt::new();
+        // everything not set below panics naming its vector
+        unhandled::install_stubs(&mut idt);
+        idt.divide_by_zero.set_handler_fn(entry_point(divide_by_zero_entry));
+        idt.debug.set_handler_fn(entry_point(debug_entry));
+        idt.breakpoint.set_handler_fn(entry_point(breakpoint_entry));
+        idt.overflow.set_handler_fn(entry_point(overflow_handler));
+        idt.bound_range_exceeded.set_handler_fn(entry_point(bound_range_exceeded_handler));
+        idt.invalid_opcode.set_handler_fn(entry_point(invalid_opcode_entry));
+        idt.device_not_available.set_handler_fn(entry_point(device_not_available_handler));
+        idt.invalid_tss.set_handler_fn(entry_point(invalid_tss_handler));
+        idt.segment_not_present.set_handler_fn(entry_point(segment_not_present_handler));
+        idt.stack_segment_fault.set_handler_fn(entry_point(stack_segment_fault_handler));
+        idt.general_protection_fault.set_handler_fn(
+            entry_point(general_protection_fault_entry));
+        idt.x87_floating_point.set_handler_fn(entry_point(x87_floating_point_handler));
+        idt.alignment_check.set_handler_fn(entry_point(alignment_check_handler));
+        idt.simd_floating_point.set_handler_fn(entry_point(simd_floating_point_handler));
+        idt.virtualization.set_handler_fn(entry_point(virtualization_handler));
+        idt.security_exception.set_handler_fn(entry_point(security_exception_handler));
+        irq::install_stubs(&mut idt);
+        unsafe {
+            idt.double_fault.set_handler_fn(entry_point(double_fault_entry))
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX as u16);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX as u16);
+            idt.page_fault.set_handler_fn(entry_point(page_fault_entry))
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX as u16);
+        }
+        idt
+    });
+
+    idt.load();
+    oops::init();
+
+    // the PICs have to be out of the way of the exceptions before sti
+    pic::init();
+}
+
+/// Loads the IDT on an application processor. They all share the one from
+/// `init`.
+pub fn init_ap() {
+    IDT.try().expect("interrupts::init_ap before interrupts::init").load();
+}
+
+/// Sets RFLAGS.IF. Called exactly once, from `rust_main`, after the GDT/TSS,
+/// the IDT, the PICs and the timer and keyboard handlers are set up and the
+/// global frame allocator has taken over from the boot time one.
+pub fn enable() {
+    assert_has_not_been_called!("interrupts::enable must be called only once");
+    assert!(IDT.try().is_some(), "interrupts::enable called before interrupts::init");
+    unsafe { ::x86_64::instructions::interrupts::enable() };
+}
+
+/// Returns whether interrupts are enabled (RFLAGS.IF is set).
+pub fn interrupts_enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; pop $0" : "=r"(flags) ::: "volatile") };
+    flags & (1 << 9) != 0
+}
@@ -0,0 +1,131 @@
+// kernel oops: faults the kernel can survive
+// some code touches memory that may not be there on purpose, like
+// `memory::read_checked` behind the monitor's `md`. its faulting instructions
+// are registered as recoverable regions, each with a fixup address. the #PF
+// and #GP handlers print their full report as always and then call `oops`:
+// a fault inside a region resumes at the region's fixup, one anywhere else
+// still panics. every oops is counted, after `MAX_OOPSES` the kernel panics
+// anyway, something is going wrong over and over
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::VirtualAddress;
+use sync::IrqMutex;
+
+const MAX_REGIONS: usize = 16;
+pub const MAX_OOPSES: usize = 10;
+
+/// Code from `start` up to `end` (exclusive) whose faults resume at `fixup`.
+#[derive(Debug, Clone, Copy)]
+pub struct RecoverableRegion {
+    pub start: usize,
+    pub end: usize,
+    pub fixup: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    TableFull,
+    Empty,
+}
+
+static REGIONS: IrqMutex<[Option<RecoverableRegion>; MAX_REGIONS]> =
+    IrqMutex::new([None; MAX_REGIONS]);
+static OOPS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// in fault_safe.asm
+extern "C" {
+    fn fault_safe_read_byte(address: *const u8, out: *mut u8) -> u64;
+    static fault_safe_read_start: u8;
+    static fault_safe_read_end: u8;
+    static fault_safe_read_fixup: u8;
+}
+
+/// Registers the regions of `fault_safe.asm`. Called by `interrupts::init`.
+pub fn init() {
+    let region = unsafe {
+        RecoverableRegion {
+            start: &fault_safe_read_start as *const u8 as usize,
+            end: &fault_safe_read_end as *const u8 as usize,
+            fixup: &fault_safe_read_fixup as *const u8 as usize,
+        }
+    };
+    register_recoverable(region).expect("oops: could not register fault_safe_read_byte");
+}
+
+pub fn register_recoverable(region: RecoverableRegion) -> Result<(), RegionError> {
+    if region.start >= region.end {
+        return Err(RegionError::Empty);
+    }
+    let mut regions = REGIONS.lock();
+    match regions.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(region);
+            Ok(())
+        }
+        None => Err(RegionError::TableFull),
+    }
+}
+
+/// The number of faults the kernel recovered from since boot.
+pub fn oops_count() -> usize {
+    OOPS_COUNT.load(Ordering::SeqCst)
+}
+
+/// For exception handlers, after their report: if the fault happened in a
+/// recoverable region, points the stack frame at its fixup and returns
+/// true, the handler returns to it. Returns false if the handler has to
+/// panic. Panics itself after `MAX_OOPSES`.
+pub fn oops(context: &str, stack_frame: &mut ExceptionStackFrame) -> bool {
+    let address = stack_frame.instruction_pointer.0;
+    // a fault while the table is being changed is never recoverable
+    let fixup = match REGIONS.try_lock() {
+        Some(regions) => {
+            regions.iter()
+                .filter_map(|slot| slot.as_ref())
+                .find(|region| region.start <= address && address < region.end)
+                .map(|region| region.fixup)
+        }
+        None => None,
+    };
+    let fixup = match fixup {
+        Some(fixup) => fixup,
+        None => return false,
+    };
+    let count = OOPS_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
+    if count > MAX_OOPSES {
+        panic!("oops: {} at {:#x}, {} oopses are too many", context, address, count);
+    }
+    println!("oops #{}: {} at {:#x} is recoverable, resuming at {:#x}",
+             count, context, address, fixup);
+    stack_frame.instruction_pointer = VirtualAddress(fixup);
+    true
+}
+
+/// Reads the byte at `address`, returns None if that faults.
+pub fn read_byte(address: usize) -> Option<u8> {
+    let mut byte = 0;
+    match unsafe { fault_safe_read_byte(address as *const u8, &mut byte) } {
+        0 => None,
+        _ => Some(byte),
+    }
+}
+
+/// Reads an unmapped and a non-canonical address, the page fault and the
+/// general protection fault both have to be recovered from.
+#[cfg(debug_assertions)]
+pub fn test_oops() {
+    use memory;
+
+    const UNMAPPED: usize = 0;
+    const NON_CANONICAL: usize = 0x8000_0000_0000_0000;
+
+    assert!(memory::translate_with_flags(UNMAPPED).is_none(), "page 0 is mapped");
+    let before = oops_count();
+    assert_eq!(read_byte(UNMAPPED), None);
+    assert_eq!(read_byte(NON_CANONICAL), None);
+    assert_eq!(oops_count(), before + 2);
+    let mapped = 0x2au8;
+    assert_eq!(read_byte(&mapped as *const u8 as usize), Some(0x2a));
+    println!("oops test passed");
+}
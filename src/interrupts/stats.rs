@@ -0,0 +1,134 @@
+// per vector interrupt counters
+// incremented from interrupt context, so they are plain atomics. every CPU
+// counts its own, the readers add them up
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use percpu;
+use pic;
+
+// atomics aren't Copy, so the table has to be spelled out
+macro_rules! counter_row {
+    () => {
+        [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+         AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+         AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+         AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]
+    };
+}
+
+percpu! {
+    // indexed by [vector / 16][vector % 16]
+    static COUNTERS: [[AtomicU64; 16]; 16] = [
+        counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+        counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+        counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+        counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+    ];
+}
+
+// called on every interrupt or exception
+pub fn count(vector: u8) {
+    COUNTERS.get()[vector as usize / 16][vector as usize % 16].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how often the given vector fired on all CPUs. Unlike `stats`
+/// this reads a single counter per CPU, so it needs almost no stack.
+pub fn vector_count(vector: u8) -> u64 {
+    let mut count = 0;
+    for cpu in 0..percpu::cpus() {
+        if let Some(counters) = COUNTERS.of(cpu) {
+            count += counters[vector as usize / 16][vector as usize % 16].load(Ordering::Relaxed);
+        }
+    }
+    count
+}
+
+/// Snapshot of the interrupt counters.
+pub struct InterruptStats {
+    counts: [u64; 256],
+    pub spurious_master: u64,
+    pub spurious_slave: u64,
+}
+
+impl InterruptStats {
+    /// Returns how often the given vector fired.
+    pub fn count(&self, vector: u8) -> u64 {
+        self.counts[vector as usize]
+    }
+
+    /// Returns the number of exceptions (vectors 0-31) raised.
+    pub fn exceptions(&self) -> u64 {
+        self.counts[..32].iter().sum()
+    }
+}
+
+/// Returns a copy of all counters.
+pub fn stats() -> InterruptStats {
+    let mut counts = [0; 256];
+    for cpu in 0..percpu::cpus() {
+        let counters = match COUNTERS.of(cpu) {
+            Some(counters) => counters,
+            None => continue,
+        };
+        for (vector, count) in counts.iter_mut().enumerate() {
+            *count += counters[vector / 16][vector % 16].load(Ordering::Relaxed);
+        }
+    }
+    let (spurious_master, spurious_slave) = pic::spurious_counts();
+    InterruptStats {
+        counts: counts,
+        spurious_master: spurious_master,
+        spurious_slave: spurious_slave,
+    }
+}
+
+/// Prints every vector that fired at least once.
+pub fn print_stats() {
+    let stats = stats();
+    println!("vector  count       name");
+    for vector in 0..256 {
+        let count = stats.counts[vector];
+        if count != 0 {
+            println!("{:>6}  {:<10}  {}", vector, count, VectorName(vector as u8));
+        }
+    }
+    println!("exceptions: {}, spurious IRQ7: {}, spurious IRQ15: {}",
+             stats.exceptions(), stats.spurious_master, stats.spurious_slave);
+}
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "#DE divide error", "#DB debug", "NMI", "#BP breakpoint",
+    "#OF overflow", "#BR bound range", "#UD invalid opcode", "#NM device not available",
+    "#DF double fault", "coprocessor segment overrun", "#TS invalid TSS",
+    "#NP segment not present", "#SS stack fault", "#GP general protection",
+    "#PF page fault", "reserved", "#MF x87 floating point", "#AC alignment check",
+    "#MC machine check", "#XM SIMD floating point", "#VE virtualization",
+    "reserved", "reserved", "reserved", "reserved", "reserved", "reserved",
+    "reserved", "reserved", "reserved", "#SX security", "reserved",
+];
+
+const IRQ_NAMES: [&str; 16] = [
+    "timer", "keyboard", "cascade", "COM2", "COM1", "LPT2", "floppy", "LPT1",
+    "RTC", "free", "free", "free", "mouse", "FPU", "primary ATA", "secondary ATA",
+];
+
+/// Symbolic name of an IDT vector, e.g. `#PF page fault` or `IRQ0/timer`.
+pub struct VectorName(pub u8);
+
+impl ::core::fmt::Display for VectorName {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        let vector = self.0;
+        if vector < 32 {
+            write!(f, "{}", EXCEPTION_NAMES[vector as usize])
+        } else if vector >= pic::PIC1_OFFSET && vector < pic::PIC1_OFFSET + 16 {
+            let irq = vector - pic::PIC1_OFFSET;
+            write!(f, "IRQ{}/{}", irq, IRQ_NAMES[irq as usize])
+        } else if vector == ::apic::SPURIOUS_VECTOR {
+            write!(f, "APIC spurious")
+        } else if vector == ::apic::TIMER_VECTOR {
+            write!(f, "APIC timer")
+        } else {
+            write!(f, "software interrupt")
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// catch-all handlers
+// every one of the 256 IDT entries starts out pointing to a generated stub
+// that knows its own vector, so an interrupt nobody expected ends in a panic
+// naming the vector instead of a triple fault on a missing entry.
+// `interrupts::init` installs the real handlers over them afterwards
+//
+// the stubs don't take an error code. the vectors that push one all have
+// real handlers except the reserved #CP (21) and #VC (29), whose reports
+// would show a stack frame shifted by the error code
+
+use core::fmt::Write;
+use core::mem;
+use x86_64::structures::idt::{Idt, IdtEntry, HandlerFunc, ExceptionStackFrame};
+use emergency;
+use pic;
+use super::stats::{self, VectorName};
+use super::dump::dump_state;
+
+// generates the 16 stubs for the vectors `$base..$base+16` in module `$row`
+macro_rules! stub_row {
+    ($row:ident, $base:expr) => {
+        mod $row {
+            use x86_64::structures::idt::{HandlerFunc, ExceptionStackFrame};
+
+            macro_rules! stub {
+                ($name:ident, $offset:expr) => {
+                    extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+                        super::unhandled_interrupt($base + $offset, stack_frame);
+                    }
+                };
+            }
+
+            stub!(v0, 0); stub!(v1, 1); stub!(v2, 2); stub!(v3, 3);
+            stub!(v4, 4); stub!(v5, 5); stub!(v6, 6); stub!(v7, 7);
+            stub!(v8, 8); stub!(v9, 9); stub!(v10, 10); stub!(v11, 11);
+            stub!(v12, 12); stub!(v13, 13); stub!(v14, 14); stub!(v15, 15);
+
+            pub static STUBS: [HandlerFunc; 16] = [
+                v0, v1, v2, v3, v4, v5, v6, v7, v8, v9, v10, v11, v12, v13, v14, v15,
+            ];
+        }
+    };
+}
+
+stub_row!(row0, 0x00);
+stub_row!(row1, 0x10);
+stub_row!(row2, 0x20);
+stub_row!(row3, 0x30);
+stub_row!(row4, 0x40);
+stub_row!(row5, 0x50);
+stub_row!(row6, 0x60);
+stub_row!(row7, 0x70);
+stub_row!(row8, 0x80);
+stub_row!(row9, 0x90);
+stub_row!(row10, 0xa0);
+stub_row!(row11, 0xb0);
+stub_row!(row12, 0xc0);
+stub_row!(row13, 0xd0);
+stub_row!(row14, 0xe0);
+stub_row!(row15, 0xf0);
+
+static ROWS: [&[HandlerFunc; 16]; 16] = [
+    &row0::STUBS, &row1::STUBS, &row2::STUBS, &row3::STUBS,
+    &row4::STUBS, &row5::STUBS, &row6::STUBS, &row7::STUBS,
+    &row8::STUBS, &row9::STUBS, &row10::STUBS, &row11::STUBS,
+    &row12::STUBS, &row13::STUBS, &row14::STUBS, &row15::STUBS,
+];
+
+/// Points every entry of `idt` to the stub of its vector. `Idt` only hands
+/// out the entries that take a plain `HandlerFunc`, so the table is treated
+/// as the 256 entries it consists of in hardware.
+pub fn install_stubs(idt: &mut Idt) {
+    assert!(mem::size_of::<Idt>() == 256 * mem::size_of::<IdtEntry<HandlerFunc>>());
+    let entries = unsafe { &mut *(idt as *mut Idt as *mut [IdtEntry<HandlerFunc>; 256]) };
+    for (vector, entry) in entries.iter_mut().enumerate() {
+        entry.set_handler_fn(ROWS[vector / 16][vector % 16]);
+    }
+}
+
+/// Reports a vector that has no handler of its own and panics.
+fn unhandled_interrupt(vector: u8, stack_frame: &mut ExceptionStackFrame) -> ! {
+    stats::count(vector);
+
+    let mut out = emergency::Writer;
+    let _ = write!(out, "\nUNHANDLED INTERRUPT: vector {} ({:#x}), ", vector, vector);
+    if vector < 32 {
+        let _ = writeln!(out, "CPU exception {}", VectorName(vector));
+    } else if vector >= pic::PIC1_OFFSET && vector < pic::PIC1_OFFSET + 16 {
+        let _ = writeln!(out, "legacy IRQ{} ({})", vector - pic::PIC1_OFFSET,
+                         VectorName(vector));
+    } else {
+        let _ = writeln!(out, "unexpected software interrupt");
+    }
+    dump_state(stack_frame, None);
+    panic!("unhandled interrupt vector {} ({})", vector, VectorName(vector));
+}
+
+/// Raises a vector that has no handler. Must end in the unhandled interrupt
+/// panic.
+#[cfg(debug_assertions)]
+pub fn test_unhandled_vector() {
+    unsafe { asm!("int $$0x80" :::: "volatile") };
+    unreachable!("unhandled vector 0x80 returned");
+}
@@ -0,0 +1,128 @@
+// I/O APIC
+// routes device interrupts (global system interrupts, GSIs) to the local
+// APICs. every input has a 64 bit redirection entry with the vector, the
+// destination and the mask bit. the registers are accessed indirectly: the
+// register number goes to IOREGSEL, the value is read/written at IOWIN
+
+use core::ptr;
+use spin::Once;
+use sync::IrqMutex;
+use memory::MemoryController;
+use acpi;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_BASE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// polarity and trigger mode in the flags of a MADT interrupt override
+const OVERRIDE_POLARITY_MASK: u16 = 0b11;
+const OVERRIDE_ACTIVE_LOW: u16 = 0b11;
+const OVERRIDE_TRIGGER_MASK: u16 = 0b11 << 2;
+const OVERRIDE_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+// the select/window pair must not be used by two CPUs (or an interrupted
+// register access) at once
+static IO_APIC: Once<IrqMutex<IoApic>> = Once::new();
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    unsafe fn write_redirection(&self, index: u32, entry: u64) {
+        let register = REG_REDIRECTION_BASE + index * 2;
+        // mask the entry while the halves are written one by one, so it is
+        // never live with a half written destination
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Sets up the first I/O APIC of the MADT with every input masked. Returns
+/// false if there is none.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("ioapic::init must be called only once");
+
+    let info = match acpi::madt().and_then(|madt| madt.io_apics().first().cloned()) {
+        Some(info) => info,
+        None => {
+            println!("ioapic: none found in the MADT");
+            return false;
+        }
+    };
+    let base = memory_controller.map_mmio(info.address as usize, 4096);
+    let mut io_apic = IoApic {
+        base: base,
+        gsi_base: info.gsi_base,
+        entries: 0,
+    };
+    unsafe {
+        io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        for index in 0..io_apic.entries {
+            io_apic.write_redirection(index, REDIRECTION_MASKED);
+        }
+    }
+    println!("ioapic: id {} at {:#x}, GSIs {}-{}",
+             unsafe { io_apic.read(REG_ID) } >> 24, info.address,
+             io_apic.gsi_base, io_apic.gsi_base + io_apic.entries - 1);
+    IO_APIC.call_once(|| IrqMutex::new(io_apic));
+    true
+}
+
+/// Returns the GSI the given ISA IRQ is connected to.
+pub fn gsi_for_irq(irq: u8) -> u32 {
+    acpi::madt()
+        .and_then(|madt| madt.interrupt_overrides().iter().find(|o| o.irq == irq))
+        .map(|o| o.gsi)
+        .unwrap_or(irq as u32)
+}
+
+/// Routes `gsi` to `vector` on the local APIC with ID `dest_apic_id`.
+/// Polarity and trigger mode follow the MADT overrides, with the ISA
+/// default (edge triggered, active high) for everything else.
+pub fn set_redirection(gsi: u32, vector: u8, dest_apic_id: u8, masked: bool) {
+    let io_apic = IO_APIC.try().expect("I/O APIC not initialized").lock();
+    assert!(gsi >= io_apic.gsi_base && gsi < io_apic.gsi_base + io_apic.entries,
+            "GSI {} not handled by the I/O APIC", gsi);
+
+    let mut entry = vector as u64 | (dest_apic_id as u64) << 56;
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    let flags = acpi::madt()
+        .and_then(|madt| madt.interrupt_overrides().iter().find(|o| o.gsi == gsi))
+        .map(|o| o.flags)
+        .unwrap_or(0);
+    if flags & OVERRIDE_POLARITY_MASK == OVERRIDE_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags & OVERRIDE_TRIGGER_MASK == OVERRIDE_LEVEL_TRIGGERED {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    unsafe { io_apic.write_redirection(gsi - io_apic.gsi_base, entry) };
+}
+
+/// Returns whether `init` found an I/O APIC.
+pub fn is_enabled() -> bool {
+    IO_APIC.try().is_some()
+}
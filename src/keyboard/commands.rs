@@ -0,0 +1,233 @@
+// commands that set the keyboard's LEDs and typematic (key repeat) rate
+// both are a two byte exchange with the keyboard: the command, ACK, the
+// argument, ACK (or RESEND for either byte). the interrupt handler only
+// records the answer and schedules `step`, which writes the next byte from
+// the deferred work queue, so nothing waits for the keyboard in an
+// interrupt. one exchange runs at a time, a pending typematic rate goes
+// before a LED change
+
+use i8042::{self, I8042Error};
+use sync::IrqMutex;
+use time;
+use work;
+
+pub const SCROLL_LOCK: u8 = 1 << 0;
+pub const NUM_LOCK: u8 = 1 << 1;
+pub const CAPS_LOCK: u8 = 1 << 2;
+
+// a byte is sent again this often after a RESEND before we give up
+const MAX_RESENDS: u8 = 3;
+// an exchange without an answer for this long is abandoned
+const ACK_TIMEOUT_MS: u64 = 100;
+
+/// Characters per second while a key is held, the ones the hardware has
+/// (the exact rates are a bit off, 20 is really 20.7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatRate {
+    Cps30 = 0x00,
+    Cps24 = 0x02,
+    Cps20 = 0x04,
+    Cps15 = 0x08,
+    Cps12 = 0x0a,
+    Cps10 = 0x0c,
+    Cps8 = 0x0f,
+    Cps6 = 0x12,
+    Cps5 = 0x14,
+    Cps4 = 0x17,
+    Cps3 = 0x1a,
+    Cps2 = 0x1f,
+}
+
+impl RepeatRate {
+    /// Returns the fastest rate that is not faster than `cps`, the slowest
+    /// for anything below 2.
+    pub fn from_cps(cps: u32) -> RepeatRate {
+        use self::RepeatRate::*;
+        match cps {
+            0...2 => Cps2,
+            3 => Cps3,
+            4 => Cps4,
+            5 => Cps5,
+            6...7 => Cps6,
+            8...9 => Cps8,
+            10...11 => Cps10,
+            12...14 => Cps12,
+            15...19 => Cps15,
+            20...23 => Cps20,
+            24...29 => Cps24,
+            _ => Cps30,
+        }
+    }
+}
+
+/// How long a key is held before it starts repeating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatDelay {
+    Ms250 = 0,
+    Ms500 = 1,
+    Ms750 = 2,
+    Ms1000 = 3,
+}
+
+impl RepeatDelay {
+    pub fn from_ms(ms: u32) -> Option<RepeatDelay> {
+        match ms {
+            250 => Some(RepeatDelay::Ms250),
+            500 => Some(RepeatDelay::Ms500),
+            750 => Some(RepeatDelay::Ms750),
+            1000 => Some(RepeatDelay::Ms1000),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Idle,
+    SendCommand,
+    AwaitCommandAck,
+    SendArgument,
+    AwaitArgumentAck,
+}
+
+struct Commands {
+    step: Step,
+    // the running exchange
+    command: u8,
+    argument: u8,
+    resends: u8,
+    sent_at: u64,
+    // the LED mask the lock keys ask for and the one the keyboard shows
+    wanted_leds: u8,
+    shown_leds: u8,
+    // a typematic byte that waits to be sent
+    typematic: Option<u8>,
+    // a command the keyboard kept answering with RESEND, for `step` to
+    // report
+    rejected: Option<u8>,
+}
+
+static COMMANDS: IrqMutex<Commands> = IrqMutex::new(Commands {
+    step: Step::Idle,
+    command: 0,
+    argument: 0,
+    resends: 0,
+    sent_at: 0,
+    wanted_leds: 0,
+    // unknown after the reset, the first `set_leds` always sends
+    shown_leds: 0xff,
+    typematic: None,
+    rejected: None,
+});
+
+/// Asks for the LEDs in `mask` to be on. Returns at once, the keyboard is
+/// updated from the deferred work queue.
+pub fn set_leds(mask: u8) {
+    COMMANDS.lock().wanted_leds = mask;
+    work::schedule(step);
+}
+
+/// Asks for the given key repeat. Returns at once like `set_leds`.
+pub fn set_typematic(rate: RepeatRate, delay: RepeatDelay) {
+    COMMANDS.lock().typematic = Some((delay as u8) << 5 | rate as u8);
+    work::schedule(step);
+}
+
+/// Called by the keyboard interrupt with every byte. Returns true if it
+/// answered a command and is no scancode.
+pub fn response(byte: u8) -> bool {
+    let mut commands = COMMANDS.lock();
+    let next = match (commands.step, byte) {
+        (Step::AwaitCommandAck, i8042::ACK) => {
+            commands.resends = 0;
+            Step::SendArgument
+        }
+        (Step::AwaitArgumentAck, i8042::ACK) => {
+            if commands.command == i8042::KEYBOARD_SET_LEDS {
+                commands.shown_leds = commands.argument;
+            }
+            Step::Idle
+        }
+        (Step::AwaitCommandAck, i8042::RESEND) if commands.resends < MAX_RESENDS => {
+            commands.resends += 1;
+            Step::SendCommand
+        }
+        (Step::AwaitArgumentAck, i8042::RESEND) if commands.resends < MAX_RESENDS => {
+            commands.resends += 1;
+            Step::SendArgument
+        }
+        (Step::AwaitCommandAck, i8042::RESEND) | (Step::AwaitArgumentAck, i8042::RESEND) => {
+            // give up. the LEDs aren't tried again before the lock keys
+            // change
+            if commands.command == i8042::KEYBOARD_SET_LEDS {
+                commands.shown_leds = commands.wanted_leds;
+            }
+            commands.rejected = Some(commands.command);
+            Step::Idle
+        }
+        _ => return false,
+    };
+    commands.step = next;
+    work::schedule(step);
+    true
+}
+
+// writes the next byte of the exchange, or starts the next exchange
+fn step() {
+    let mut commands = COMMANDS.lock();
+    if let Some(command) = commands.rejected.take() {
+        println!("keyboard: command {:#x} rejected, continuing without it", command);
+    }
+    let waiting = commands.step == Step::AwaitCommandAck
+        || commands.step == Step::AwaitArgumentAck;
+    if waiting && time::uptime_ms() - commands.sent_at >= ACK_TIMEOUT_MS {
+        println!("keyboard: no answer to command {:#x}", commands.command);
+        if commands.command == i8042::KEYBOARD_SET_LEDS {
+            commands.shown_leds = 0xff;
+        }
+        commands.step = Step::Idle;
+    }
+    if commands.step == Step::Idle {
+        if let Some(typematic) = commands.typematic.take() {
+            start(&mut commands, i8042::KEYBOARD_SET_TYPEMATIC, typematic);
+        } else if commands.wanted_leds != commands.shown_leds {
+            let mask = commands.wanted_leds;
+            start(&mut commands, i8042::KEYBOARD_SET_LEDS, mask);
+        }
+    }
+    if commands.step == Step::SendArgument && commands.command == i8042::KEYBOARD_SET_LEDS {
+        // the latest mask, a change during the exchange is included
+        commands.argument = commands.wanted_leds;
+    }
+    let result = match commands.step {
+        Step::SendCommand => {
+            let command = commands.command;
+            send(&mut commands, command, Step::AwaitCommandAck)
+        }
+        Step::SendArgument => {
+            let argument = commands.argument;
+            send(&mut commands, argument, Step::AwaitArgumentAck)
+        }
+        _ => Ok(()),
+    };
+    if let Err(error) = result {
+        println!("keyboard: could not send command {:#x}: {:?}", commands.command, error);
+        commands.step = Step::Idle;
+    }
+}
+
+fn start(commands: &mut Commands, command: u8, argument: u8) {
+    commands.command = command;
+    commands.argument = argument;
+    commands.resends = 0;
+    commands.step = Step::SendCommand;
+}
+
+// the answer can only arrive once the lock is released, so setting the step
+// after the write is fine
+fn send(commands: &mut Commands, byte: u8, next: Step) -> Result<(), I8042Error> {
+    unsafe { i8042::write_data(byte)? };
+    commands.step = next;
+    commands.sent_at = time::uptime_ms();
+    Ok(())
+}
@@ -0,0 +1,149 @@
+// keyboard layouts map physical keys to characters
+
+use super::{KeyCode, Modifiers};
+
+pub trait Layout: Sync {
+    /// Returns the character the key produces with the given modifiers.
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char>;
+}
+
+/// US QWERTY (104 keys).
+pub struct Us104;
+
+/// Swedish (105 keys). Dead keys (´ ` ¨ ^ ~) produce nothing.
+pub struct Sv105;
+
+pub static US104: Us104 = Us104;
+pub static SV105: Sv105 = Sv105;
+
+/// Returns the layout with the given command line name (`us` or `sv`).
+pub fn by_name(name: &str) -> Option<&'static Layout> {
+    match name {
+        "us" => Some(&US104),
+        "sv" => Some(&SV105),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "layout-sv"))]
+pub fn default() -> &'static Layout {
+    &US104
+}
+
+#[cfg(feature = "layout-sv")]
+pub fn default() -> &'static Layout {
+    &SV105
+}
+
+// caps lock only affects letters
+fn is_letter(character: char) -> bool {
+    match character {
+        'a'...'z' | 'å' | 'ä' | 'ö' => true,
+        _ => false,
+    }
+}
+
+// picks the shifted or unshifted character
+fn select(characters: (char, char), modifiers: &Modifiers) -> char {
+    let (lower, upper) = characters;
+    let shifted = if is_letter(lower) {
+        modifiers.shift() != modifiers.caps_lock
+    } else {
+        modifiers.shift()
+    };
+    if shifted { upper } else { lower }
+}
+
+impl Layout for Us104 {
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        us_characters(code).map(|characters| select(characters, modifiers))
+    }
+}
+
+impl Layout for Sv105 {
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        use super::KeyCode::*;
+
+        // AltGr is the right alt key
+        if modifiers.right_alt {
+            return match code {
+                Key2 => Some('@'),
+                Key3 => Some('£'),
+                Key4 => Some('$'),
+                Key5 => Some('€'),
+                Key7 => Some('{'),
+                Key8 => Some('['),
+                Key9 => Some(']'),
+                Key0 => Some('}'),
+                Minus => Some('\\'),
+                RightBracket => Some('~'),
+                NonUsBackslash => Some('|'),
+                E => Some('€'),
+                M => Some('µ'),
+                _ => None,
+            };
+        }
+
+        let characters = match code {
+            Key2 => ('2', '"'),
+            Key3 => ('3', '#'),
+            Key4 => ('4', '¤'),
+            Key6 => ('6', '&'),
+            Key7 => ('7', '/'),
+            Key8 => ('8', '('),
+            Key9 => ('9', ')'),
+            Key0 => ('0', '='),
+            Minus => ('+', '?'),
+            Equals => return None,        // dead key ´ `
+            LeftBracket => ('å', 'Å'),
+            RightBracket => return None,  // dead key ¨ ^
+            Semicolon => ('ö', 'Ö'),
+            Quote => ('ä', 'Ä'),
+            Backtick => ('§', '½'),
+            Backslash => ('\'', '*'),
+            NonUsBackslash => ('<', '>'),
+            Comma => (',', ';'),
+            Period => ('.', ':'),
+            Slash => ('-', '_'),
+            // the letters and everything else match the US layout
+            _ => return Us104.map(code, modifiers),
+        };
+        Some(select(characters, modifiers))
+    }
+}
+
+/// Returns the (unshifted, shifted) characters of a key on a US keyboard.
+fn us_characters(code: KeyCode) -> Option<(char, char)> {
+    use self::KeyCode::*;
+
+    Some(match code {
+        Key1 => ('1', '!'), Key2 => ('2', '@'), Key3 => ('3', '#'),
+        Key4 => ('4', '$'), Key5 => ('5', '%'), Key6 => ('6', '^'),
+        Key7 => ('7', '&'), Key8 => ('8', '*'), Key9 => ('9', '('),
+        Key0 => ('0', ')'),
+        Minus => ('-', '_'), Equals => ('=', '+'),
+        Backspace => ('\u{8}', '\u{8}'), Tab => ('\t', '\t'),
+        Q => ('q', 'Q'), W => ('w', 'W'), E => ('e', 'E'), R => ('r', 'R'),
+        T => ('t', 'T'), Y => ('y', 'Y'), U => ('u', 'U'), I => ('i', 'I'),
+        O => ('o', 'O'), P => ('p', 'P'),
+        LeftBracket => ('[', '{'), RightBracket => (']', '}'),
+        Enter | KeypadEnter => ('\n', '\n'),
+        A => ('a', 'A'), S => ('s', 'S'), D => ('d', 'D'), F => ('f', 'F'),
+        G => ('g', 'G'), H => ('h', 'H'), J => ('j', 'J'), K => ('k', 'K'),
+        L => ('l', 'L'),
+        Semicolon => (';', ':'), Quote => ('\'', '"'), Backtick => ('`', '~'),
+        Backslash => ('\\', '|'),
+        Z => ('z', 'Z'), X => ('x', 'X'), C => ('c', 'C'), V => ('v', 'V'),
+        B => ('b', 'B'), N => ('n', 'N'), M => ('m', 'M'),
+        Comma => (',', '<'), Period => ('.', '>'), Slash => ('/', '?'),
+        Space => (' ', ' '),
+        KeypadMultiply => ('*', '*'), KeypadMinus => ('-', '-'),
+        KeypadPlus => ('+', '+'), KeypadDivide => ('/', '/'),
+        Keypad7 => ('7', '7'), Keypad8 => ('8', '8'), Keypad9 => ('9', '9'),
+        Keypad4 => ('4', '4'), Keypad5 => ('5', '5'), Keypad6 => ('6', '6'),
+        Keypad1 => ('1', '1'), Keypad2 => ('2', '2'), Keypad3 => ('3', '3'),
+        Keypad0 => ('0', '0'), KeypadPeriod => ('.', '.'),
+        NonUsBackslash => ('\\', '|'),
+        _ => return None,
+    })
+}
@@ -0,0 +1,480 @@
+// PS/2 keyboard driver
+// the interrupt handler decodes the scancodes and pushes complete key events
+// into a queue. decoding there means the modifier state sees every scancode,
+// even when the queue overflows and events get dropped.
+// once `start_dispatch` ran the events go through a channel to the input
+// dispatch thread first, which handles the hotkeys outside the interrupt and
+// queues the rest for the readers. Ctrl+Alt+Del still reboots from the
+// interrupt, and `poll_char` (interrupts off) bypasses the thread.
+// a `ScancodeStream` takes the raw scancodes for an executor task instead,
+// see stream.rs
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+use spin::Once;
+use sync::{IrqMutex, WaitQueue};
+use sync::mpsc::{self, Sender};
+use memory::MemoryController;
+use task::{self, Priority};
+use x86_64::instructions::port::inb;
+use interrupts::{self, InterruptContext};
+use cmdline;
+use rand;
+use i8042;
+use power;
+use monitor;
+
+pub use self::scancode::KeyCode;
+pub use self::layout::{Layout, Us104, Sv105};
+pub use self::commands::{RepeatRate, RepeatDelay};
+pub use self::stream::{ScancodeStream, PrintKeypresses, print_keypresses};
+#[cfg(debug_assertions)]
+pub use self::stream::test_scancode_stream;
+
+mod scancode;
+mod commands;
+mod stream;
+pub mod layout;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const DATA_PORT: u16 = i8042::DATA_PORT;
+
+static EVENTS: EventQueue = EventQueue::new();
+static EVENT_WAITERS: WaitQueue = WaitQueue::new();
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+// to the dispatch thread, set by `start_dispatch`
+static DISPATCH: Once<Sender<KeyEvent>> = Once::new();
+static DECODER: IrqMutex<Decoder> = IrqMutex::new(Decoder::new());
+static LAYOUT: Once<&'static Layout> = Once::new();
+// set by the `ctrlaltdel` command line flag
+static CTRL_ALT_DEL_REBOOTS: AtomicBool = AtomicBool::new(false);
+
+/// Selects the layout and registers the keyboard interrupt. The layout is
+/// chosen by the `keyboard=us|sv` command line argument, falling back to
+/// the compile time default. With `ctrlaltdel`, Ctrl+Alt+Del reboots.
+/// Ctrl+Alt+M enters the debug monitor. Num Lock starts on unless the
+/// command line says `numlock=off`. `kbd.rate=<characters per second>` and
+/// `kbd.delay=250|500|750|1000` (milliseconds) set the key repeat, what
+/// isn't given keeps the keyboard's default. Does nothing if `i8042::init`
+/// didn't bring up port 1.
+pub fn init() {
+    if !i8042::port1_ok() {
+        println!("keyboard: no working PS/2 port, no keyboard");
+        return;
+    }
+    CTRL_ALT_DEL_REBOOTS.store(cmdline::has("ctrlaltdel"), Ordering::Relaxed);
+    LAYOUT.call_once(|| {
+        match cmdline::get("keyboard") {
+            Some(name) => layout::by_name(name).unwrap_or_else(|| {
+                println!("keyboard: unknown layout {}, using default", name);
+                layout::default()
+            }),
+            None => layout::default(),
+        }
+    });
+    let num_lock = match cmdline::get("numlock") {
+        Some("on") | None => true,
+        Some("off") => false,
+        Some(other) => {
+            println!("keyboard: numlock={} is neither on nor off, using on", other);
+            true
+        }
+    };
+    let mask = {
+        let mut decoder = DECODER.lock();
+        decoder.modifiers.num_lock = num_lock;
+        decoder.modifiers.leds()
+    };
+    interrupts::register_irq(KEYBOARD_IRQ, keyboard_interrupt)
+        .expect("could not register the keyboard interrupt");
+    commands::set_leds(mask);
+
+    let rate = cmdline::get("kbd.rate").map(|value| match value.parse() {
+        Ok(cps) => RepeatRate::from_cps(cps),
+        Err(_) => {
+            println!("keyboard: kbd.rate={} is no number, using 10", value);
+            RepeatRate::Cps10
+        }
+    });
+    let delay = cmdline::get("kbd.delay").map(|value| {
+        match value.parse().ok().and_then(RepeatDelay::from_ms) {
+            Some(delay) => delay,
+            None => {
+                println!("keyboard: kbd.delay={} is not 250, 500, 750 or 1000, using 500", value);
+                RepeatDelay::Ms500
+            }
+        }
+    });
+    if rate.is_some() || delay.is_some() {
+        // close to the defaults after a keyboard reset (10.9 and 500)
+        set_typematic(rate.unwrap_or(RepeatRate::Cps10), delay.unwrap_or(RepeatDelay::Ms500));
+    }
+}
+
+/// Sets how fast held keys repeat and after how long. Returns at once, a
+/// keyboard that rejects the command is reported and keeps its rate.
+pub fn set_typematic(rate: RepeatRate, delay: RepeatDelay) {
+    commands::set_typematic(rate, delay);
+}
+
+/// Returns the active keyboard layout.
+pub fn active_layout() -> &'static Layout {
+    LAYOUT.try().map(|layout| *layout).unwrap_or_else(layout::default)
+}
+
+fn keyboard_interrupt(_context: &mut InterruptContext) {
+    let scancode = unsafe { inb(DATA_PORT) };
+    rand::add_interrupt_timing();
+    if commands::response(scancode) {
+        return;
+    }
+    if stream::push(scancode) {
+        return;
+    }
+    push_scancode(scancode);
+}
+
+/// Spawns the input dispatch thread, at `Priority::High` so typing stays
+/// responsive. Until then the interrupt handler queues the events itself.
+pub fn start_dispatch(memory_controller: &mut MemoryController) {
+    if !i8042::port1_ok() {
+        return;
+    }
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let thread = task::spawn_with_priority(memory_controller, Priority::High, move || {
+        while let Ok(event) = receiver.recv() {
+            dispatch(event);
+        }
+    });
+    match thread {
+        Ok(_) => {
+            DISPATCH.call_once(|| sender);
+        }
+        Err(error) => println!("keyboard: no dispatch thread: {:?}", error),
+    }
+}
+
+fn push_scancode(scancode: u8) {
+    let event = decode(scancode);
+    if let Some(event) = event {
+        match DISPATCH.try() {
+            Some(sender) => {
+                if sender.try_send(event).is_err() {
+                    DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            None => dispatch(event),
+        }
+    }
+}
+
+// what has to happen in the interrupt: the modifiers, the LEDs and the
+// reboot
+fn decode(scancode: u8) -> Option<KeyEvent> {
+    let event = DECODER.lock().process(scancode);
+    if let Some(event) = event {
+        if event.code == KeyCode::Delete && event.state == KeyState::Pressed
+            && event.modifiers.ctrl() && event.modifiers.alt()
+            && CTRL_ALT_DEL_REBOOTS.load(Ordering::Relaxed) {
+            power::reboot();
+        }
+        if event.state == KeyState::Pressed && is_lock_key(event.code) {
+            commands::set_leds(event.modifiers.leds());
+        }
+    }
+    event
+}
+
+// the monitor hotkey, or the queue for the readers
+fn dispatch(event: KeyEvent) {
+    if event.code == KeyCode::M && event.state == KeyState::Pressed
+        && event.modifiers.ctrl() && event.modifiers.alt() {
+        monitor::request();
+        return;
+    }
+    if !EVENTS.push(event) {
+        // the modifiers are already updated, only the event is lost
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    EVENT_WAITERS.notify_all();
+}
+
+fn is_lock_key(code: KeyCode) -> bool {
+    match code {
+        KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock => true,
+        _ => false,
+    }
+}
+
+/// Returns the next key event, or `None` if there is none queued.
+pub fn read_event() -> Option<KeyEvent> {
+    EVENTS.pop()
+}
+
+/// Blocks until a key event arrives and returns it, the other threads run
+/// meanwhile (or the CPU halts, before there are threads).
+pub fn next_event() -> KeyEvent {
+    let mut event = None;
+    EVENT_WAITERS.wait_until(|| {
+        event = read_event();
+        event.is_some()
+    });
+    event.unwrap()
+}
+
+/// Returns the next typed character, or `None` if no queued event produces
+/// one. Events without a character (releases, modifiers) are consumed.
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_event() {
+        if event.state == KeyState::Pressed && event.character.is_some() {
+            return event.character;
+        }
+    }
+    None
+}
+
+/// Like `read_char`, but first decodes the scancodes waiting in the
+/// controller itself, for code that runs with interrupts off (the monitor
+/// after a panic). The decoder's lock is broken if it is held, the holder
+/// never runs again then.
+pub fn poll_char() -> Option<char> {
+    if !i8042::port1_ok() {
+        return None;
+    }
+    if DECODER.try_lock().is_none() {
+        unsafe { DECODER.force_unlock() };
+    }
+    while let Some(scancode) = i8042::poll_keyboard_byte() {
+        if !commands::response(scancode) {
+            // the dispatch thread doesn't run anymore
+            if let Some(event) = decode(scancode) {
+                dispatch(event);
+            }
+        }
+    }
+    read_char()
+}
+
+/// Returns the number of events dropped because the queue or the channel to
+/// the dispatch thread was full.
+pub fn dropped_events() -> u64 {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    // the modifiers after this event was applied
+    pub modifiers: Modifiers,
+    // the character this key produces in the active layout
+    pub character: Option<char>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Modifiers {
+        Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    // the lock state as the mask of the set LEDs command
+    fn leds(&self) -> u8 {
+        let mut mask = 0;
+        if self.caps_lock {
+            mask |= commands::CAPS_LOCK;
+        }
+        if self.num_lock {
+            mask |= commands::NUM_LOCK;
+        }
+        if self.scroll_lock {
+            mask |= commands::SCROLL_LOCK;
+        }
+        mask
+    }
+
+    // keeps track of the modifier keys
+    fn update(&mut self, code: KeyCode, state: KeyState) {
+        let pressed = state == KeyState::Pressed;
+        match code {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftCtrl => self.left_ctrl = pressed,
+            KeyCode::RightCtrl => self.right_ctrl = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+    }
+}
+
+/// Converts the key to a character using the active layout. With ctrl held,
+/// letters turn into control characters (ctrl+a is 0x01). Without Num Lock
+/// the keypad digits and period are cursor keys and produce nothing.
+pub fn to_char(code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    if !modifiers.num_lock && is_keypad_number(code) {
+        return None;
+    }
+    let character = active_layout().map(code, modifiers);
+    match character {
+        Some(c @ 'a'...'z') | Some(c @ 'A'...'Z') if modifiers.ctrl() => {
+            Some(((c as u8) & 0x1f) as char)
+        }
+        character => character,
+    }
+}
+
+fn is_keypad_number(code: KeyCode) -> bool {
+    use self::KeyCode::*;
+    match code {
+        Keypad0 | Keypad1 | Keypad2 | Keypad3 | Keypad4 | Keypad5 | Keypad6 | Keypad7
+        | Keypad8 | Keypad9 | KeypadPeriod => true,
+        _ => false,
+    }
+}
+
+// turns scancode set 1 bytes into key events
+pub struct Decoder {
+    extended: bool,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    pub const fn new() -> Decoder {
+        Decoder {
+            extended: false,
+            modifiers: Modifiers::new(),
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Processes one scancode byte and returns the completed event, if any.
+    pub fn process(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if scancode == 0xe0 {
+            // the next byte is from the extended set
+            self.extended = true;
+            return None;
+        }
+
+        let extended = self.extended;
+        self.extended = false;
+
+        // the high bit marks a release (break code)
+        let state = if scancode & 0x80 != 0 {
+            KeyState::Released
+        } else {
+            KeyState::Pressed
+        };
+        let code = if extended {
+            scancode::extended_key(scancode & 0x7f)
+        } else {
+            scancode::key(scancode & 0x7f)
+        };
+
+        code.map(|code| {
+            self.modifiers.update(code, state);
+            KeyEvent {
+                code: code,
+                state: state,
+                modifiers: self.modifiers,
+                character: to_char(code, &self.modifiers),
+            }
+        })
+    }
+}
+
+// fixed size ring with a single producer and a single consumer. `dispatch`
+// pushes, and it runs in one of three places: the interrupt handler until
+// `start_dispatch` sets `DISPATCH`, the dispatch thread after that (the
+// handler only sends to its channel then), and `poll_char` after a panic,
+// when interrupts stay off and the dispatch thread never runs again. the
+// handler and the thread both run on the BSP, an interrupt that still
+// pushes finishes before the thread gets its first event. a full ring
+// rejects new events, so overflow never overwrites entries the consumer
+// may be reading
+const QUEUE_SIZE: usize = 64;
+
+struct EventQueue {
+    buffer: UnsafeCell<[Option<KeyEvent>; QUEUE_SIZE]>,
+    head: AtomicUsize,  // next slot to read
+    tail: AtomicUsize,  // next slot to write
+}
+
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    const fn new() -> EventQueue {
+        EventQueue {
+            buffer: UnsafeCell::new([None; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // returns false if the queue is full
+    fn push(&self, event: KeyEvent) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[tail] = Some(event) };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<KeyEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe { (*self.buffer.get())[head].take() };
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        event
+    }
+}
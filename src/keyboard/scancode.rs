@@ -0,0 +1,87 @@
+// scancode set 1 tables
+// key codes name the physical key (by its US label), the layout decides
+// which character it produces
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+    Minus, Equals, Backspace, Tab,
+    Q, W, E, R, T, Y, U, I, O, P,
+    LeftBracket, RightBracket, Enter, LeftCtrl,
+    A, S, D, F, G, H, J, K, L,
+    Semicolon, Quote, Backtick, LeftShift, Backslash,
+    Z, X, C, V, B, N, M,
+    Comma, Period, Slash, RightShift,
+    KeypadMultiply, LeftAlt, Space, CapsLock,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    NumLock, ScrollLock,
+    Keypad7, Keypad8, Keypad9, KeypadMinus,
+    Keypad4, Keypad5, Keypad6, KeypadPlus,
+    Keypad1, Keypad2, Keypad3, Keypad0, KeypadPeriod,
+    // the extra key left of Z on ISO keyboards
+    NonUsBackslash,
+    // extended (0xE0 prefixed) keys
+    KeypadEnter, RightCtrl, KeypadDivide, RightAlt,
+    Home, ArrowUp, PageUp, ArrowLeft, ArrowRight, End, ArrowDown, PageDown,
+    Insert, Delete, LeftGui, RightGui, Menu,
+}
+
+/// Returns the key for a scancode without the release bit.
+pub fn key(scancode: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    Some(match scancode {
+        0x01 => Escape,
+        0x02 => Key1, 0x03 => Key2, 0x04 => Key3, 0x05 => Key4, 0x06 => Key5,
+        0x07 => Key6, 0x08 => Key7, 0x09 => Key8, 0x0a => Key9, 0x0b => Key0,
+        0x0c => Minus, 0x0d => Equals, 0x0e => Backspace, 0x0f => Tab,
+        0x10 => Q, 0x11 => W, 0x12 => E, 0x13 => R, 0x14 => T,
+        0x15 => Y, 0x16 => U, 0x17 => I, 0x18 => O, 0x19 => P,
+        0x1a => LeftBracket, 0x1b => RightBracket, 0x1c => Enter, 0x1d => LeftCtrl,
+        0x1e => A, 0x1f => S, 0x20 => D, 0x21 => F, 0x22 => G,
+        0x23 => H, 0x24 => J, 0x25 => K, 0x26 => L,
+        0x27 => Semicolon, 0x28 => Quote, 0x29 => Backtick,
+        0x2a => LeftShift, 0x2b => Backslash,
+        0x2c => Z, 0x2d => X, 0x2e => C, 0x2f => V, 0x30 => B, 0x31 => N, 0x32 => M,
+        0x33 => Comma, 0x34 => Period, 0x35 => Slash, 0x36 => RightShift,
+        0x37 => KeypadMultiply, 0x38 => LeftAlt, 0x39 => Space, 0x3a => CapsLock,
+        0x3b => F1, 0x3c => F2, 0x3d => F3, 0x3e => F4, 0x3f => F5,
+        0x40 => F6, 0x41 => F7, 0x42 => F8, 0x43 => F9, 0x44 => F10,
+        0x45 => NumLock, 0x46 => ScrollLock,
+        0x47 => Keypad7, 0x48 => Keypad8, 0x49 => Keypad9, 0x4a => KeypadMinus,
+        0x4b => Keypad4, 0x4c => Keypad5, 0x4d => Keypad6, 0x4e => KeypadPlus,
+        0x4f => Keypad1, 0x50 => Keypad2, 0x51 => Keypad3,
+        0x52 => Keypad0, 0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11, 0x58 => F12,
+        _ => return None,
+    })
+}
+
+/// Returns the key for a scancode that followed an 0xE0 prefix.
+pub fn extended_key(scancode: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    Some(match scancode {
+        0x1c => KeypadEnter,
+        0x1d => RightCtrl,
+        0x35 => KeypadDivide,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4b => ArrowLeft,
+        0x4d => ArrowRight,
+        0x4f => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5b => LeftGui,
+        0x5c => RightGui,
+        0x5d => Menu,
+        // 0x2a/0x36 are fake shifts sent around print screen
+        _ => return None,
+    })
+}
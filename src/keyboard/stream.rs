@@ -0,0 +1,196 @@
+// the keyboard as an executor `Stream` of raw scancodes
+// while a `ScancodeStream` exists it owns the keyboard: the interrupt
+// handler puts every scancode into a ring for it and wakes the task that
+// waits, nothing is decoded or queued as a key event, Ctrl+Alt+Del and the
+// monitor hotkey don't work. dropping the stream gives the keyboard back.
+// the ring has one producer (the interrupt) and one consumer, a full one
+// drops the new scancodes
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use sync::IrqMutex;
+use task::executor::{Future, Poll, Stream, Waker};
+use super::{Decoder, KeyState};
+
+const RING_SIZE: usize = 128;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SCANCODES: ScancodeRing = ScancodeRing::new();
+static WAKER: IrqMutex<Option<Waker>> = IrqMutex::new(None);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /// Takes the keyboard over. None if another stream has it.
+    pub fn new() -> Option<ScancodeStream> {
+        if ACTIVE.compare_and_swap(false, true, Ordering::SeqCst) {
+            return None;
+        }
+        // what came while nobody listened is stale
+        while SCANCODES.pop().is_some() {}
+        Some(ScancodeStream { _private: () })
+    }
+
+    /// The scancodes lost because the ring was full.
+    pub fn dropped() -> usize {
+        DROPPED.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(&mut self, waker: &Waker) -> Poll<Option<u8>> {
+        if let Some(scancode) = SCANCODES.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        *WAKER.lock() = Some(*waker);
+        // a scancode that came before the waker was set woke nobody
+        match SCANCODES.pop() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        *WAKER.lock() = None;
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// For the keyboard interrupt: hands `scancode` to the stream and returns
+/// true, false if there is none.
+pub fn push(scancode: u8) -> bool {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        return false;
+    }
+    if !SCANCODES.push(scancode) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(waker) = *WAKER.lock() {
+        waker.wake();
+    }
+    true
+}
+
+/// A task that decodes the scancodes of a stream with a `Decoder` of its
+/// own and prints the typed characters. Never finishes.
+pub struct PrintKeypresses {
+    scancodes: ScancodeStream,
+    decoder: Decoder,
+}
+
+/// Panics if another stream has the keyboard.
+pub fn print_keypresses() -> PrintKeypresses {
+    PrintKeypresses {
+        scancodes: ScancodeStream::new().expect("keyboard: the scancode stream is taken"),
+        decoder: Decoder::new(),
+    }
+}
+
+impl Future for PrintKeypresses {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        loop {
+            let scancode = match self.scancodes.poll_next(waker) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = self.decoder.process(scancode) {
+                if let (KeyState::Pressed, Some(character)) = (event.state, event.character) {
+                    print!("{}", character);
+                }
+            }
+        }
+    }
+}
+
+struct ScancodeRing {
+    buffer: UnsafeCell<[u8; RING_SIZE]>,
+    head: AtomicUsize,  // next slot to read
+    tail: AtomicUsize,  // next slot to write
+}
+
+unsafe impl Sync for ScancodeRing {}
+
+impl ScancodeRing {
+    const fn new() -> ScancodeRing {
+        ScancodeRing {
+            buffer: UnsafeCell::new([0; RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // returns false if the ring is full
+    fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % RING_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[tail] = scancode };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = unsafe { (*self.buffer.get())[head] };
+        self.head.store((head + 1) % RING_SIZE, Ordering::Release);
+        Some(scancode)
+    }
+}
+
+/// Feeds the stream a press and release of A by hand, a task has to get
+/// both in order.
+#[cfg(debug_assertions)]
+pub fn test_scancode_stream() {
+    use task::executor;
+
+    static SEEN: IrqMutex<[u8; 2]> = IrqMutex::new([0; 2]);
+
+    struct TakeTwo {
+        scancodes: ScancodeStream,
+        count: usize,
+    }
+
+    impl Future for TakeTwo {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<()> {
+            while self.count < 2 {
+                match self.scancodes.poll_next(waker) {
+                    Poll::Ready(Some(scancode)) => {
+                        SEEN.lock()[self.count] = scancode;
+                        self.count += 1;
+                    }
+                    Poll::Ready(None) => break,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            Poll::Ready(())
+        }
+    }
+
+    let scancodes = ScancodeStream::new().expect("scancode stream taken");
+    assert!(ScancodeStream::new().is_none(), "a second stream got the keyboard");
+    executor::spawn(TakeTwo { scancodes: scancodes, count: 0 }).expect("could not spawn a task");
+    // A pressed and released, as the interrupt would see them
+    assert!(push(0x1e));
+    assert!(push(0x9e));
+    executor::run();
+    assert_eq!(*SEEN.lock(), [0x1e, 0x9e]);
+    assert!(!push(0x1e), "the dropped stream still has the keyboard");
+    println!("scancode stream test passed");
+}
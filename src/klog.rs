@@ -0,0 +1,81 @@
+// the kernel log ring
+// a copy of everything `print!` writes and of the fatal output, in a fixed
+// ring of `SIZE` bytes that overwrites the oldest text when it is full. it
+// is only in memory, so it can be read after the fact (by the GDB stub, or
+// after a warm reboot that keeps the memory), also when no screen or serial
+// port was watching
+
+use console::ConsoleSink;
+use sync::IrqMutex;
+
+const SIZE: usize = 16 * 1024;
+
+struct Ring {
+    bytes: [u8; SIZE],
+    // where the next byte goes
+    head: usize,
+    // bytes written in total
+    written: u64,
+}
+
+static RING: IrqMutex<Ring> = IrqMutex::new(Ring { bytes: [0; SIZE], head: 0, written: 0 });
+
+impl Ring {
+    fn write_str(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            self.bytes[self.head] = byte;
+            self.head = (self.head + 1) % SIZE;
+        }
+        self.written += s.len() as u64;
+    }
+}
+
+pub fn write_str(s: &str) {
+    RING.lock().write_str(s);
+}
+
+/// Copies the newest `buffer.len()` bytes of the log (or all of it) into
+/// `buffer`, oldest first. Returns the number of bytes copied.
+pub fn read(buffer: &mut [u8]) -> usize {
+    let ring = RING.lock();
+    let stored = if ring.written < SIZE as u64 { ring.written as usize } else { SIZE };
+    let length = if buffer.len() < stored { buffer.len() } else { stored };
+    let start = (ring.head + SIZE - length) % SIZE;
+    for (i, byte) in buffer[..length].iter_mut().enumerate() {
+        *byte = ring.bytes[(start + i) % SIZE];
+    }
+    length
+}
+
+/// The ring as a `console::ConsoleSink`, always enabled.
+pub struct KlogSink;
+
+impl ConsoleSink for KlogSink {
+    fn name(&self) -> &'static str {
+        "klog"
+    }
+
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn write_str(&self, s: &str) {
+        write_str(s);
+    }
+
+    fn force_write_str(&self, s: &str) {
+        if RING.try_lock().is_none() {
+            unsafe { RING.force_unlock() };
+        }
+        write_str(s);
+    }
+}
+
+#[cfg(debug_assertions)]
+pub fn test_klog() {
+    println!("klog: test marker");
+    let mut buffer = [0u8; 64];
+    let length = read(&mut buffer);
+    assert!(buffer[..length].ends_with(b"klog: test marker\n"));
+    println!("klog: test passed");
+}
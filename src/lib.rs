@@ -5,6 +5,10 @@
 #![feature(alloc)]
 #![feature(allocator_api)]
 #![feature(global_allocator)]
+#![feature(abi_x86_interrupt)]
+#![feature(asm)]
+#![feature(integer_atomics, const_atomic_u64_new)]
+#![feature(naked_functions, core_intrinsics)]
 #![no_std]
 
 extern crate rlibc;
@@ -23,99 +27,312 @@ extern crate linked_list_allocator;
 
 #[macro_use]
 mod vga_buffer;
+#[macro_use]
+mod percpu;
+mod vga;
+mod video;
+mod fbcon;
+mod bochs_vbe;
+#[macro_use]
+mod serial;
 mod memory;
-
-use memory::FrameAllocator;
+mod gdt;
+#[macro_use]
+mod interrupts;
+mod pic;
+mod pit;
+mod time;
+mod i8042;
+mod keyboard;
+mod input;
+mod monitor;
+mod cmdline;
+mod mouse;
+mod sync;
+mod cpu;
+mod apic;
+mod smp;
+mod tlb;
+mod ioapic;
+mod acpi;
+mod cmos;
+mod rtc;
+mod hpet;
+mod work;
+mod task;
+mod async_demo;
+mod watchdog;
+mod emergency;
+mod panic;
+mod backtrace;
+mod console;
+mod klog;
+mod debugcon;
+mod debug;
+mod qemu;
+mod pci;
+mod block;
+mod ata;
+mod ahci;
+mod virtio;
+mod virtio_blk;
+mod net;
+mod e1000;
+mod virtio_net;
+mod speaker;
+mod power;
+mod rand;
 
 #[no_mangle]
-pub extern "C" fn rust_main(multiboot_information_address: usize) {
+pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
     // ATTENTION: we have a very small stack and no guard page (but now it is 16kB)
-    
-    vga_buffer::clear_screen();
+
+    // the exception handlers count into per-CPU statics
+    percpu::init();
+    // first, so headless runs see everything from here on
+    serial::init();
+    serial_println!("flamingOS booting");
+
+    // the console mode comes from the command line, so parse it before
+    // anything goes to the screen
+    cmdline::init(multiboot_information_address);
+    console::init();
+
+    console::clear_screen();
     println!("Hello World{}", "!");
     //println!("{}", { println!("inner"); "outer" });
     //println!("No one puts thread in deadlock{}", "!");
 
     let boot_info = unsafe{ multiboot2::load(multiboot_information_address) };
-    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    debugcon::init();
+    debug::gdbstub::init();
 
    /* println!("memory areas:");
-    for area in memory_map_tag.memory_areas() {
+    for area in boot_info.memory_map_tag().unwrap().memory_areas() {
         println!("    start: 0x{:x}, lenght: 0x{:x}", area.base_addr, area.length);
     }*/
 
-    let elf_sections_tag = boot_info.elf_sections_tag()
-        .expect("Elf-sections tag required");
-
-    /*println!("kernel sections:");
-    for section in elf_sections_tag.sections() {
-        println!("    addr: 0x{:x}, size: 0x{:x}, flags: 0x{:x}",
-                 section.addr, section.size, section.flags);
-    }*/
-
-    let kernel_start = elf_sections_tag.sections().map(|s| s.addr)
-        .min().unwrap();
-    let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size)
-        .max().unwrap();
-
-    let multiboot_start = multiboot_information_address;
-    let multiboot_end = multiboot_start + (boot_info.total_size as usize);
-
-    // Try with:
-    /* let multiboot_start = boot_info.start_address();
-    let multiboot_end = boot_info.end_address(); */
-    
-    /*println!("kernel_start: 0x{:x}, kernel_end: 0x{:x}", kernel_start, kernel_end);
-    println!("multiboot_start: 0x{:x}, multiboot_end: 0x{:x}", multiboot_start, multiboot_end);*/
-
-    let mut frame_allocator = memory::AreaFrameAllocator::new(
-        kernel_start as usize, kernel_end as usize, multiboot_start,
-        multiboot_end, memory_map_tag.memory_areas());
-/*
-    memory::test_paging(&mut frame_allocator);
-
-    let addr = 42 * 512 * 512 * 4096; // 42th P3 entry
-    let page = Page::containing_address(addr);
-    let frame = allocator.allocate_frame().expect("no more frames");
-    println!("None = {:?}, map to {:?}",
-             page_table.translate(addr),
-             frame);
-    page_table.map_to(page, frame, EntryFlags::empty(), allocator);
-    println!("Some = {:?}", page_table.translate(addr));
-    println!("next free frame: {:?}", allocator.allocate_frame());
-  */  
-    /*println!("{:?}", frame_allocator.allocate_frame());
-
-    
-   for i in 0.. {
-        if let None = frame_allocator.allocate_frame() {
-            println!("allocated {} frames", i);
-            break;
-        }
-    }*/
-
-    
     // Remap the Kernel
     enable_nxe_bit();
     enable_write_protect_bit();
-    memory::remap_the_kernel(&mut frame_allocator, boot_info);
-    frame_allocator.allocate_frame(); // try to allocate a frame
-    println!("It did not crash, Madde!");
 
-    // set up guard page and map the heap pages
-    /*memory::init(boot_info);
+    // remap the kernel, set up guard page and map the heap pages
+    let mut memory_controller = memory::init(boot_info);
+    backtrace::init(boot_info);
 
-    unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_START + HEAP_SIZE);
+    // the GDT/TSS has to be loaded before the IDT, since the double fault
+    // entry refers to an IST stack of the TSS
+    gdt::init(&mut memory_controller);
+    interrupts::init();
+    task::init(&mut memory_controller);
+    if let Err(error) = acpi::init(&mut memory_controller) {
+        println!("acpi: {:?}", error);
+    }
+    if !cmos::checksum_valid() {
+        println!("cmos: checksum mismatch, the NVRAM contents may be garbage");
+    }
+    hpet::init(&mut memory_controller);
+    // the local APIC is used when available. the legacy IRQs only move to
+    // the I/O APIC if there is one, otherwise they stay on the PICs
+    if apic::init(&mut memory_controller) && ioapic::init(&mut memory_controller) {
+        interrupts::switch_to_ioapic();
+        println!("irq: legacy IRQs routed through the I/O APIC");
+    }
+    time::init();
+    smp::init(&mut memory_controller);
+    println!("boot time: {} (Unix time {})", time::wall_date_time(), time::wall_now());
+    let devices_start = time::Instant::now();
+    rand::init();
+    pci::init();
+    video::init(multiboot_information_address, &mut memory_controller);
+    if let Some(argument) = cmdline::get("vbe") {
+        match bochs_vbe::parse_mode(argument) {
+            Some((width, height, bits_per_pixel)) => {
+                if let Err(error) = bochs_vbe::set_mode(width, height, bits_per_pixel,
+                                                        &mut memory_controller) {
+                    println!("bochs-vbe: can't set {}: {:?}", argument, error);
+                }
+            }
+            None => println!("bochs-vbe: bad mode {}, expected like 1024x768x32", argument),
+        }
+    }
+    // from here on println! draws on the framebuffer, if there is one
+    fbcon::init();
+    ata::init(&mut memory_controller);
+    ahci::init(&mut memory_controller);
+    virtio_blk::init(&mut memory_controller);
+    if let Some(disk) = block::devices().find(|disk| disk.sector_size() == ata::SECTOR_SIZE) {
+        let mut mbr = [0; ata::SECTOR_SIZE];
+        match disk.read(0, &mut mbr) {
+            Ok(()) => println!("block: {} MBR signature {:02x} {:02x}", disk.name(),
+                               mbr[510], mbr[511]),
+            Err(error) => println!("block: could not read sector 0 of {}: {:?}",
+                                   disk.name(), error),
+        }
     }
+    if net::init(&mut memory_controller) {
+        net::device().unwrap().set_receive_callback(net::arp_demo_receive);
+        net::send_arp_request(net::DEMO_GATEWAY);
+    }
+    if let Err(error) = i8042::init() {
+        println!("i8042: initialization failed: {:?}", error);
+    }
+    keyboard::init();
+    keyboard::start_dispatch(&mut memory_controller);
+    serial::enable_receive();
+    if let Err(error) = mouse::init() {
+        println!("mouse: initialization failed: {:?}", error);
+    }
+    println!("boot: device setup took {} us", devices_start.elapsed() / 1000);
+
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+    //interrupts::test_exceptions();
+    //interrupts::test_divide_recovery();
+    //interrupts::test_unhandled_vector();
+    //interrupts::test_nmi_stack(&mut memory_controller);
+    //debug::test_watchpoint();
+    //memory::test_stack_growth(&mut memory_controller);
+    //memory::test_stack_overflow(&mut memory_controller);
 
+    println!("It did not crash, Madde!");
+
+    /*
     for i in 0..10000 {
         format!("Some String");
     }
 
         println!("It did not crash!");*/
-    
-    loop{}
+
+    // the only place interrupts get enabled: everything the handlers touch
+    // is initialized above, and nothing before this point may sti
+    interrupts::enable();
+    watchdog::init();
+    time::check_delay();
+    if cmdline::has("mode13demo") {
+        vga::mode13::demo(&mut memory_controller);
+    }
+    if cmdline::has("async_demo") {
+        async_demo::start(&mut memory_controller);
+    }
+    //sync::test_irq_mutex();
+    //work::test_deferred_work();
+
+    if qemu::test_mode() {
+        run_tests(&mut memory_controller);
+        qemu::exit(qemu::ExitCode::Success);
+    }
+
+    // echo characters typed on the keyboard or COM1 and print the uptime
+    // once per second as a smoke test for the timer and input interrupts,
+    // `quiet` skips it
+    if !cmdline::has("quiet") {
+        demo_loop();
+    }
+
+    work::idle_loop()
+}
+
+// the tests that return, for `make test`. the ones that end in a panic
+// (stack overflow, unhandled vector) have to be run by hand, except the
+// `panic_tests` of the Makefile, which `make test` boots again for
+#[cfg(debug_assertions)]
+fn run_tests(memory_controller: &mut memory::MemoryController) {
+    // these end in a panic, so each gets a run of its own
+    if cmdline::has("test_heap_exhaustion") {
+        memory::heap_allocator::test_heap_exhaustion();
+    }
+    if cmdline::has("test_double_panic") {
+        panic::test_double_panic();
+    }
+    #[cfg(feature = "kasan_lite")]
+    {
+        if cmdline::has("test_redzone_overflow") {
+            memory::redzone::test_redzone_overflow();
+        }
+    }
+    interrupts::test_exceptions();
+    interrupts::test_divide_recovery();
+    if apic::is_enabled() {
+        interrupts::test_nmi_stack(memory_controller);
+    }
+    debug::test_watchpoint();
+    memory::test_stack_growth(memory_controller);
+    memory::test_stack_canary(memory_controller);
+    #[cfg(feature = "kasan_lite")]
+    memory::redzone::test_redzones();
+    work::test_deferred_work();
+    ata::test_write_read();
+    rand::test_monobit();
+    time::test_uptime();
+    time::test_timers();
+    time::test_switch_sources();
+    rtc::test_unix_time();
+    klog::test_klog();
+    interrupts::test_dump_regs();
+    interrupts::test_oops();
+    percpu::test_percpu();
+    tlb::test_tlb_shootdown();
+    sync::test_init_cell();
+    sync::test_debug_mutex();
+    task::test_threads(memory_controller);
+    task::test_preemption(memory_controller);
+    task::test_spawn(memory_controller);
+    task::test_yield_now();
+    task::test_sleep(memory_controller);
+    task::test_join(memory_controller);
+    task::test_stack_reuse(memory_controller);
+    task::test_idle();
+    task::test_priorities(memory_controller);
+    task::executor::test_executor();
+    keyboard::test_scancode_stream();
+    time::test_timer_futures();
+    sync::test_wait_queue(memory_controller);
+    sync::test_blocking_locks(memory_controller);
+    sync::test_semaphore(memory_controller);
+    sync::test_channel(memory_controller);
+    // reprograms the PIT, so it goes last
+    sync::test_irq_mutex();
+    serial_println!("all tests passed");
+}
+
+#[cfg(not(debug_assertions))]
+fn run_tests(_memory_controller: &mut memory::MemoryController) {
+    serial_println!("the tests are only built in debug mode");
+    qemu::exit(qemu::ExitCode::Failed);
+}
+
+fn demo_loop() -> ! {
+    let mut last_second = 0;
+    let mut mouse_cursor = mouse::TextCursor::new();
+    loop {
+        while let Some(character) = input::read_char() {
+            print!("{}", character);
+        }
+        while let Some(event) = mouse::poll() {
+            if fbcon::is_enabled() {
+                fbcon::move_pointer(event.dx as i32, -(event.dy as i32));
+            } else {
+                mouse_cursor.update(&event);
+            }
+        }
+
+        let second = time::uptime_ms() / 1000;
+        if second != last_second {
+            last_second = second;
+            println!("uptime: {} s, cpu {}% busy", second, task::cpu_usage());
+        }
+
+        work::run_pending();
+        watchdog::pet();
+
+        // the timer wakes us up at least every tick, so a key that arrives
+        // right before the hlt is only handled a tick late. other threads
+        // run first, if there are any
+        if !task::yield_now() {
+            cpu::halt();
+        }
+    }
 }
 
 fn enable_write_protect_bit() {
@@ -147,15 +364,52 @@ fn test() {
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
-    println!("\n\nPANIC in {} at line {}:", file, line);
-    println!("    {}", fmt);
-    loop{}
+    use core::fmt::Write;
+
+    // nothing may interrupt the teardown, a timer tick would run into the
+    // code that just broke
+    unsafe { asm!("cli" :::: "volatile") };
+    match panic::enter() {
+        1 => {}
+        2 => {
+            panic::report_nested(file, line);
+            if qemu::test_mode() {
+                qemu::exit(if panic::is_nested_expected() {
+                    qemu::ExitCode::Success
+                } else {
+                    qemu::ExitCode::Failed
+                });
+            }
+            cpu::halt_forever();
+        }
+        _ => cpu::halt_forever(),
+    }
+
+    // every sink with its lock broken or bypassed, the panic may have hit in
+    // the middle of a println!
+    let _ = write!(emergency::Writer, "\n\nPANIC in {} at line {}:\n    {}\n",
+                   file, line, fmt);
+    if qemu::test_mode() && panic::is_expected(fmt) {
+        let _ = write!(emergency::Writer, "the panic was expected\n");
+        qemu::exit(qemu::ExitCode::Success);
+    }
+    panic::run_hook();
+    backtrace::print(&mut emergency::Writer);
+    if qemu::test_mode() {
+        qemu::exit(qemu::ExitCode::Failed);
+    }
+    if monitor::on_panic() {
+        monitor::enter(monitor::Entry::Panic);
+    }
+    debug::gdbstub::panic_session();
+    speaker::panic_beeps();
+    cpu::halt_forever()
 }
 
-use linked_list_allocator::LockedHeap;
+use memory::heap_allocator::KernelHeap;
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap::empty();
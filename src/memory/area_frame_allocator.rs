@@ -1,5 +1,8 @@
 use memory::{Frame, FrameAllocator};
 use multiboot2::{MemoryAreaIter, MemoryArea};
+use interrupts::interrupts_enabled;
+
+const LOW_MEMORY_END: usize = 0x10_0000;
 
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
@@ -9,10 +12,17 @@ pub struct AreaFrameAllocator {
     kernel_end: Frame,
     multiboot_start: Frame,
     multiboot_end: Frame,
+    // handed out so far, including the ones lost by `allocate_contiguous`
+    allocated: usize,
 }
 
+// the allocator itself has no locking. after boot it is only reached through
+// the IrqMutex in memory, which disables interrupts, so an allocation with
+// interrupts on means someone bypasses the lock
 impl FrameAllocator for AreaFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
+        debug_assert!(!interrupts_enabled(),
+                      "AreaFrameAllocator used directly with interrupts enabled");
         if let Some(area) = self.current_area {
             // "Clone" the frame to return it if it's free. Frame doesn't
             // implement Clone, but we can construct an identical frame.
@@ -40,6 +50,7 @@ impl FrameAllocator for AreaFrameAllocator {
             } else {
                 // frame is unused, increment `next_free_frame` and return it
                 self.next_free_frame.number += 1;
+                self.allocated += 1;
                 return Some(frame);
             }
             // `frame` was not valid, try it again with the updated `next_free_frame`
@@ -50,24 +61,63 @@ impl FrameAllocator for AreaFrameAllocator {
     }
 
     fn deallocate_frame(&mut self, frame: Frame) {
+        debug_assert!(!interrupts_enabled(),
+                      "AreaFrameAllocator used directly with interrupts enabled");
         unimplemented!()
     }
 }
 
 
 impl AreaFrameAllocator {
+    /// Allocates `count` physically consecutive frames and returns the first
+    /// one. Frames skipped while looking for such a run are lost, like the
+    /// deallocated ones.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        let mut start = match self.allocate_frame() {
+            Some(frame) => frame.number,
+            None => return None,
+        };
+        let mut length = 1;
+        while length < count {
+            match self.allocate_frame() {
+                Some(ref frame) if frame.number == start + length => length += 1,
+                Some(frame) => {
+                    // crossed a hole (the kernel or the end of an area)
+                    start = frame.number;
+                    length = 1;
+                }
+                None => return None,
+            }
+        }
+        Some(Frame { number: start })
+    }
+
+    /// Returns the number of frames handed out.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Returns the address of the frame tried next, None if there are no
+    /// free frames left.
+    pub fn next_free(&self) -> Option<usize> {
+        self.current_area.map(|_| self.next_free_frame.start_address())
+    }
+
     pub fn new(kernel_start: usize, kernel_end: usize,
                multiboot_start: usize, multiboot_end: usize,
                memory_areas: MemoryAreaIter) -> AreaFrameAllocator
     {
         let mut allocator = AreaFrameAllocator {
-            next_free_frame: Frame::containing_address(0),
+            // the first MiB stays as it is, for the BIOS data and the AP
+            // trampoline
+            next_free_frame: Frame::containing_address(LOW_MEMORY_END),
             current_area: None,
             areas: memory_areas,
             kernel_start: Frame::containing_address(kernel_start),
             kernel_end: Frame::containing_address(kernel_end),
             multiboot_start: Frame::containing_address(multiboot_start),
             multiboot_end: Frame::containing_address(multiboot_end),
+            allocated: 0,
         };
         allocator.choose_next_area();
         allocator
@@ -1,7 +1,171 @@
 // memory allocator
 
 use alloc::heap::{Alloc, AllocErr, Layout};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use linked_list_allocator::LockedHeap;
+use panic;
+#[cfg(feature = "kasan_lite")]
+use memory::redzone;
+
+// set once `oom` printed its diagnostic, for `test_heap_exhaustion`
+static OOM_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// The global allocator: a `LockedHeap` that counts what it hands out and
+/// stops handing out memory once the kernel panics, the heap (or its lock)
+/// may be what broke.
+pub struct KernelHeap {
+    heap: LockedHeap,
+    used: AtomicUsize,
+    allocations: AtomicUsize,
+}
+
+impl KernelHeap {
+    pub const fn empty() -> KernelHeap {
+        KernelHeap {
+            heap: LockedHeap::empty(),
+            used: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the counters, and the size and the largest free block if the
+    /// heap's lock is free.
+    pub fn stats(&self) -> HeapStats {
+        let size = self.heap.try_lock().map(|heap| heap.size());
+        HeapStats {
+            used: self.used.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            size: size,
+            largest_free_block: size.map(|_| self.largest_free_block()),
+        }
+    }
+
+    #[cfg(not(feature = "kasan_lite"))]
+    unsafe fn alloc_block(&self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        (&self.heap).alloc(layout)
+    }
+
+    #[cfg(not(feature = "kasan_lite"))]
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        (&self.heap).dealloc(ptr, layout)
+    }
+
+    // the block with a redzone on either side
+    #[cfg(feature = "kasan_lite")]
+    unsafe fn alloc_block(&self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let padded = match redzone::padded(&layout) {
+            Some(padded) => padded,
+            None => return Err(AllocErr::Exhausted { request: layout }),
+        };
+        match (&self.heap).alloc(padded) {
+            Ok(block) => Ok(redzone::arm(block, &layout)),
+            Err(_) => Err(AllocErr::Exhausted { request: layout }),
+        }
+    }
+
+    #[cfg(feature = "kasan_lite")]
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        let block = redzone::disarm(ptr, &layout);
+        let padded = redzone::padded(&layout).unwrap();
+        (&self.heap).dealloc(block, padded)
+    }
+
+    // the largest block the heap could hand out now, found by trying.
+    // `LockedHeap` doesn't show its free list
+    fn largest_free_block(&self) -> usize {
+        let (mut low, mut high) = (0, self.heap.lock().size());
+        while low < high {
+            let size = high - (high - low) / 2;
+            let layout = match Layout::from_size_align(size, 8) {
+                Some(layout) => layout,
+                None => break,
+            };
+            match unsafe { (&self.heap).alloc(layout.clone()) } {
+                Ok(block) => {
+                    unsafe { (&self.heap).dealloc(block, layout) };
+                    low = size;
+                }
+                Err(_) => high = size - 1,
+            }
+        }
+        low
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// In bytes.
+    pub used: usize,
+    pub allocations: usize,
+    pub size: Option<usize>,
+    pub largest_free_block: Option<usize>,
+}
+
+impl Deref for KernelHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.heap
+    }
+}
+
+unsafe impl<'a> Alloc for &'a KernelHeap {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        if panic::is_panicking() {
+            return Err(AllocErr::Unsupported { details: "the kernel panicked" });
+        }
+        let size = layout.size();
+        let block = self.alloc_block(layout)?;
+        self.used.fetch_add(size, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(block)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        // leaked while panicking, the lock may be held by the panicked code
+        if !panic::is_panicking() {
+            self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+            self.allocations.fetch_sub(1, Ordering::Relaxed);
+            self.dealloc_block(ptr, layout)
+        }
+    }
+
+    // this toolchain has no `#[alloc_error_handler]`, a failed allocation
+    // ends up here. the heap can't grow, so it is the end
+    fn oom(&mut self, error: AllocErr) -> ! {
+        match error {
+            AllocErr::Exhausted { ref request } => {
+                println!("heap: allocation of {} bytes (align {}) failed",
+                         request.size(), request.align());
+            }
+            AllocErr::Unsupported { details } => println!("heap: allocation failed: {}", details),
+        }
+        let size = self.heap.lock().size();
+        println!("heap: {} of {} bytes used in {} allocations, largest free block {} bytes",
+                 self.used.load(Ordering::Relaxed), size,
+                 self.allocations.load(Ordering::Relaxed), self.largest_free_block());
+        OOM_REPORTED.store(true, Ordering::SeqCst);
+        panic!("kernel heap exhausted");
+    }
+}
+
+// asks for ten times the heap, the oom diagnostic has to show up before
+// the panic, which ends the test run successfully. never returns, so
+// `run_tests` only calls it with `test_heap_exhaustion` on the command line
+#[cfg(debug_assertions)]
+pub fn test_heap_exhaustion() -> ! {
+    use alloc::vec::Vec;
+    use HEAP_SIZE;
+
+    fn reported() -> bool {
+        OOM_REPORTED.load(Ordering::SeqCst)
+    }
+
+    panic::expect("kernel heap exhausted", reported);
+    let vector: Vec<u8> = Vec::with_capacity(10 * HEAP_SIZE);
+    panic!("heap: {} bytes allocated from a {} byte heap", vector.capacity(), HEAP_SIZE);
+}
 
 #[derive(Debug)]
 
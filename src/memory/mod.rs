@@ -3,19 +3,46 @@ pub use self::paging::test_paging;
 
 pub use self::area_frame_allocator::AreaFrameAllocator;
 pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
+pub use self::stack_allocator::{Stack, StackFault, handle_stack_fault, check_canaries,
+                                clobbered_canary, free_stack};
+#[cfg(debug_assertions)]
+pub use self::stack_allocator::{test_stack_growth, test_stack_overflow, test_stack_canary};
+pub use self::paging::{PhysicalAddress, VirtualAddress, EntryFlags};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 use multiboot2::BootInformation;
+use sync::{IrqMutex, IrqMutexGuard, InitCell};
+use backtrace;
+use tlb;
 
 mod area_frame_allocator;
 mod paging;
+mod stack_allocator;
 pub mod heap_allocator;
+#[cfg(feature = "kasan_lite")]
+pub mod redzone;
 
 // size of a physical page / frame
 pub const PAGE_SIZE: usize = 4096;
+// the pages after the heap that `alloc_stack` hands out (one more, the
+// range is inclusive)
+const STACK_AREA_PAGES: usize = 100;
+
+// the frame allocator is global, so exception handlers and drivers can get
+// frames without a MemoryController. empty until `init` has remapped the kernel
+static FRAME_ALLOCATOR: InitCell<IrqMutex<AreaFrameAllocator>> = InitCell::new();
+
+// PAT entry 4 (PAT set, PCD and PWT clear) is reprogrammed from write back
+// to write combining, `map_write_combining` uses it
+const IA32_PAT: u32 = 0x277;
+const PAT_ENTRY_4_SHIFT: u64 = 32;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
 
 //map a page to a frame
-pub fn init(boot_info: &BootInformation) {
-    assert_has_not_been_called!("memory::init must be called only once");
+pub fn init(boot_info: &BootInformation) -> MemoryController {
+    // the second remap would already go wrong, before the cell notices
+    assert!(FRAME_ALLOCATOR.get().is_none(), "frame allocator: initialized twice");
 
     let memory_map_tag = boot_info.memory_map_tag().expect(
         "Memory map tag required");
@@ -24,8 +51,10 @@ pub fn init(boot_info: &BootInformation) {
 
     let kernel_start = elf_sections_tag.sections()
         .filter(|s| s.is_allocated()).map(|s| s.addr).min().unwrap();
+    // the symbol tables aren't allocated, but the backtraces read them
     let kernel_end = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated()).map(|s| s.addr + s.size).max()
+        .filter(|s| s.is_allocated() || backtrace::is_symbol_section(s))
+        .map(|s| s.addr + s.size).max()
         .unwrap();
 
     println!("kernel start: {:#x}, kernel end: {:#x}",
@@ -42,6 +71,9 @@ pub fn init(boot_info: &BootInformation) {
 
     let mut active_table = paging::remap_the_kernel(&mut frame_allocator,
                                                     boot_info);
+    FRAME_ALLOCATOR.init_or_panic("frame allocator", IrqMutex::new(frame_allocator));
+    enable_write_combining();
+    let mut frame_allocator = GlobalFrameAllocator;
 
     use self::paging::Page;
     use {HEAP_START, HEAP_SIZE};
@@ -52,6 +84,298 @@ pub fn init(boot_info: &BootInformation) {
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
         active_table.map(page, paging::WRITABLE, &mut frame_allocator);
     }
+    unsafe { ::HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+
+    // reserve the pages right after the heap for kernel stacks
+    let stack_allocator = {
+        let stack_alloc_start = heap_end_page + 1;
+        let stack_alloc_end = stack_alloc_start + STACK_AREA_PAGES;
+        let stack_alloc_range = Page::range_inclusive(stack_alloc_start,
+                                                      stack_alloc_end);
+        stack_allocator::StackAllocator::new(stack_alloc_range)
+    };
+
+    MemoryController {
+        active_table: active_table,
+        frame_allocator: frame_allocator,
+        stack_allocator: stack_allocator,
+    }
+}
+
+// owns the memory management state after init, so later subsystems
+// (GDT/TSS, drivers) can allocate without touching the globals
+pub struct MemoryController {
+    active_table: paging::ActivePageTable,
+    frame_allocator: GlobalFrameAllocator,
+    stack_allocator: stack_allocator::StackAllocator,
+}
+
+impl MemoryController {
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        let &mut MemoryController { ref mut active_table,
+                                    ref mut frame_allocator,
+                                    ref mut stack_allocator } = self;
+        stack_allocator.alloc_stack(active_table, frame_allocator,
+                                    size_in_pages)
+    }
+
+    /// Allocates a stack that starts with `size_in_pages` mapped pages and
+    /// grows on page faults up to `max_size_in_pages`.
+    pub fn alloc_growable_stack(&mut self, size_in_pages: usize,
+                                max_size_in_pages: usize) -> Option<Stack> {
+        let &mut MemoryController { ref mut active_table,
+                                    ref mut frame_allocator,
+                                    ref mut stack_allocator } = self;
+        stack_allocator.alloc_growable_stack(active_table, frame_allocator,
+                                             size_in_pages, max_size_in_pages)
+    }
+
+    /// Identity maps the physical range `address..address+size` for device
+    /// registers (writable, uncached, not executable) and returns the virtual
+    /// address to access it at. Pages that are already mapped are left alone.
+    pub fn map_mmio(&mut self, address: PhysicalAddress, size: usize)
+                    -> VirtualAddress
+    {
+        use self::paging::{Page, WRITABLE, NO_CACHE, WRITE_THROUGH, NO_EXECUTE};
+
+        assert!(size > 0, "empty MMIO range");
+        let flags = WRITABLE | NO_CACHE | WRITE_THROUGH | NO_EXECUTE;
+        let start_frame = Frame::containing_address(address);
+        let end_frame = Frame::containing_address(address + size - 1);
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let page = Page::containing_address(frame.start_address());
+            if self.active_table.translate_page(page).is_none() {
+                self.active_table.identity_map(frame, flags,
+                                               &mut self.frame_allocator);
+            }
+        }
+        address
+    }
+
+    /// Identity maps the physical range `address..address+size` below 1 MiB
+    /// writable and executable, for the real mode code and data that start
+    /// the application processors. The frame allocator never hands these
+    /// frames out. Pages that are already mapped are left alone.
+    pub fn map_low_memory(&mut self, address: PhysicalAddress, size: usize)
+                          -> VirtualAddress
+    {
+        use self::paging::{Page, WRITABLE};
+
+        assert!(size > 0 && address + size <= 0x10_0000, "not low memory");
+        let start_frame = Frame::containing_address(address);
+        let end_frame = Frame::containing_address(address + size - 1);
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let page = Page::containing_address(frame.start_address());
+            if self.active_table.translate_page(page).is_none() {
+                self.active_table.identity_map(frame, WRITABLE,
+                                               &mut self.frame_allocator);
+            }
+        }
+        address
+    }
+
+    /// Removes a mapping of `map_low_memory` again.
+    pub fn unmap_low_memory(&mut self, address: PhysicalAddress, size: usize) {
+        use self::paging::Page;
+
+        let start_page = Page::containing_address(address);
+        let end_page = Page::containing_address(address + size - 1);
+        let mut batch = tlb::Batch::new();
+        for page in Page::range_inclusive(start_page, end_page) {
+            if self.active_table.translate_page(page).is_some() {
+                self.active_table.unmap_batched(page, &mut self.frame_allocator, &mut batch);
+            }
+        }
+        batch.finish();
+    }
+
+    /// Identity maps the physical range `address..address+size` write
+    /// combining, for framebuffers. Without a PAT it is mapped uncached like
+    /// `map_mmio`. Pages that are already mapped are left alone.
+    pub fn map_write_combining(&mut self, address: PhysicalAddress, size: usize)
+                               -> VirtualAddress
+    {
+        use self::paging::{Page, WRITABLE, PAT, NO_EXECUTE};
+
+        if !WRITE_COMBINING.load(Ordering::Relaxed) {
+            return self.map_mmio(address, size);
+        }
+        assert!(size > 0, "empty framebuffer range");
+        let flags = WRITABLE | PAT | NO_EXECUTE;
+        let start_frame = Frame::containing_address(address);
+        let end_frame = Frame::containing_address(address + size - 1);
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let page = Page::containing_address(frame.start_address());
+            if self.active_table.translate_page(page).is_none() {
+                self.active_table.identity_map(frame, flags,
+                                               &mut self.frame_allocator);
+            }
+        }
+        address
+    }
+
+    /// Allocates `size_in_pages` physically contiguous, zeroed frames for
+    /// device DMA and identity maps them (writable, not executable), so the
+    /// address the device uses is the one the kernel uses too.
+    pub fn alloc_dma(&mut self, size_in_pages: usize) -> Option<DmaMemory> {
+        use self::paging::{Page, WRITABLE, NO_EXECUTE};
+
+        assert!(size_in_pages > 0, "empty DMA allocation");
+        let start_frame = match frame_allocator().lock().allocate_contiguous(size_in_pages) {
+            Some(frame) => frame,
+            None => return None,
+        };
+        let address = start_frame.start_address();
+        let end_frame = Frame::containing_address(address + size_in_pages * PAGE_SIZE - 1);
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let page = Page::containing_address(frame.start_address());
+            if self.active_table.translate_page(page).is_none() {
+                self.active_table.identity_map(frame, WRITABLE | NO_EXECUTE,
+                                               &mut self.frame_allocator);
+            }
+        }
+        unsafe { ptr::write_bytes(address as *mut u8, 0, size_in_pages * PAGE_SIZE) };
+        Some(DmaMemory {
+            address: address,
+            size: size_in_pages * PAGE_SIZE,
+        })
+    }
+}
+
+/// Physically contiguous memory from `MemoryController::alloc_dma`. It is
+/// identity mapped, the physical address is also the virtual one. Never
+/// freed, drivers keep it for their lifetime.
+#[derive(Debug)]
+pub struct DmaMemory {
+    address: usize,
+    size: usize,
+}
+
+impl DmaMemory {
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the byte at `offset`.
+    pub fn pointer(&self, offset: usize) -> *mut u8 {
+        assert!(offset < self.size, "offset {:#x} outside of the DMA memory", offset);
+        (self.address + offset) as *mut u8
+    }
+}
+
+// points PAT entry 4 at write combining, if the CPU has a PAT. no mapping
+// uses entry 4 before this, so nothing needs flushing
+fn enable_write_combining() {
+    use x86_64::registers::msr::{rdmsr, wrmsr};
+
+    if ::cpu::cpuid(1).edx & (1 << 16) == 0 {
+        return;
+    }
+    unsafe {
+        let pat = rdmsr(IA32_PAT);
+        let pat = pat & !(0xff << PAT_ENTRY_4_SHIFT) | PAT_WRITE_COMBINING << PAT_ENTRY_4_SHIFT;
+        wrmsr(IA32_PAT, pat);
+    }
+    WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+/// Returns the statistics of the kernel heap.
+pub fn heap_stats() -> heap_allocator::HeapStats {
+    ::HEAP_ALLOCATOR.stats()
+}
+
+/// Returns the number of frames handed out and the address of the next free
+/// one, None if the frame allocator is locked or not set up yet.
+pub fn frame_stats() -> Option<(usize, Option<PhysicalAddress>)> {
+    FRAME_ALLOCATOR.get()
+        .and_then(|allocator| allocator.try_lock())
+        .map(|allocator| (allocator.allocated(), allocator.next_free()))
+}
+
+/// Returns whether `address` is on a kernel stack: the boot stack or one of
+/// the stacks from `alloc_stack`. Doesn't say whether it is mapped.
+pub fn is_kernel_stack(address: usize) -> bool {
+    extern "C" {
+        // from boot.asm
+        static stack_bottom: u8;
+        static stack_top: u8;
+    }
+    use {HEAP_START, HEAP_SIZE};
+
+    let (boot_bottom, boot_top) = unsafe {
+        (&stack_bottom as *const u8 as usize, &stack_top as *const u8 as usize)
+    };
+    // the stack area starts at the page after the heap
+    let area_start = (HEAP_START + HEAP_SIZE + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let area_end = area_start + (STACK_AREA_PAGES + 1) * PAGE_SIZE;
+    (address >= boot_bottom && address < boot_top)
+        || (address >= area_start && address < area_end)
+}
+
+/// Translates `address` through the active page table and returns the
+/// physical address together with the flags of the mapping entry.
+/// Only reads the tables, so it can be used from exception handlers.
+pub fn translate_with_flags(address: VirtualAddress)
+                            -> Option<(PhysicalAddress, EntryFlags)>
+{
+    let mapper = unsafe { paging::Mapper::new() };
+    mapper.translate_with_flags(address)
+}
+
+/// Copies the bytes at `address` into `buffer` without risking a page fault:
+/// every page is checked with `translate_with_flags` before it is read, and
+/// the bytes are read fault safe in case the check was wrong (a stale TLB
+/// entry, MMIO that faults). Returns the number of bytes copied, which is
+/// short if an unmapped page or a faulting byte was reached.
+pub fn read_checked(address: VirtualAddress, buffer: &mut [u8]) -> usize {
+    let mut copied = 0;
+    while copied < buffer.len() {
+        let current = match address.checked_add(copied) {
+            Some(current) => current,
+            None => break,
+        };
+        if translate_with_flags(current).is_none() {
+            break;
+        }
+        // copy up to the end of the current page
+        let in_page = PAGE_SIZE - current % PAGE_SIZE;
+        let count = ::core::cmp::min(in_page, buffer.len() - copied);
+        for i in 0..count {
+            match ::interrupts::read_byte_fault_safe(current + i) {
+                Some(byte) => buffer[copied + i] = byte,
+                None => return copied + i,
+            }
+        }
+        copied += count;
+    }
+    copied
+}
+
+/// Like `read_checked`, but writes `data` to `address`, stopping at the
+/// first page that isn't mapped writable. Returns the number of bytes written.
+pub fn write_checked(address: VirtualAddress, data: &[u8]) -> usize {
+    let mut written = 0;
+    while written < data.len() {
+        let current = match address.checked_add(written) {
+            Some(current) => current,
+            None => break,
+        };
+        match translate_with_flags(current) {
+            Some((_, flags)) if flags.contains(paging::WRITABLE) => {}
+            _ => break,
+        }
+        let in_page = PAGE_SIZE - current % PAGE_SIZE;
+        let count = ::core::cmp::min(in_page, data.len() - written);
+        for i in 0..count {
+            unsafe { ::core::ptr::write_volatile((current + i) as *mut u8, data[written + i]) };
+        }
+        written += count;
+    }
+    written
 }
 
 // store the frame number
@@ -114,3 +438,27 @@ pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
 }
+
+fn frame_allocator() -> &'static IrqMutex<AreaFrameAllocator> {
+    FRAME_ALLOCATOR.get_or_panic("frame allocator")
+}
+
+// None if it is locked or not set up yet, for the page fault handler, which
+// mustn't wait for the code it interrupted
+fn try_lock_frame_allocator() -> Option<IrqMutexGuard<'static, AreaFrameAllocator>> {
+    FRAME_ALLOCATOR.get().and_then(|allocator| allocator.try_lock())
+}
+
+/// Handle to the global frame allocator. Every call takes the IrqMutex, so it
+/// can be used with interrupts enabled and from interrupt handlers.
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        frame_allocator().lock().allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        frame_allocator().lock().deallocate_frame(frame)
+    }
+}
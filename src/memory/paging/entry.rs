@@ -65,6 +65,9 @@ bitflags! {
         const ACCESSED =        1 << 5;
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
+        // the same bit in a level 1 entry, it selects the upper half of
+        // the page attribute table
+        const PAT =             1 << 7;
         const GLOBAL =          1 << 8;
         const NO_EXECUTE =      1 << 63;
     }
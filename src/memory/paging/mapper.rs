@@ -6,6 +6,7 @@ use super::entry::*;
 use super::table::{self, Table, Level4, Level1};
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
 use core::ptr::Unique;
+use tlb;
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -37,6 +38,50 @@ impl Mapper {
         self.translate_page(Page::containing_address(virtual_address)).map(|frame| frame.number * PAGE_SIZE + offset)
     }
 
+    // walks the table levels like translate_page, but also returns the flags of
+    // the entry that maps the address (the P3/P2 entry for huge pages)
+    /// Returns `None` if the address is not mapped or not canonical.
+    pub fn translate_with_flags(&self, virtual_address: VirtualAddress)
+                                -> Option<(PhysicalAddress, EntryFlags)>
+    {
+        if virtual_address >= 0x0000_8000_0000_0000 &&
+           virtual_address < 0xffff_8000_0000_0000 {
+            return None;
+        }
+        let page = Page::containing_address(virtual_address);
+
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            // 1GiB page
+            let start = p3_entry.pointed_frame().unwrap().start_address();
+            return Some((start + (virtual_address & 0x3fff_ffff), p3_entry.flags()));
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            // 2MiB page
+            let start = p2_entry.pointed_frame().unwrap().start_address();
+            return Some((start + (virtual_address & 0x1f_ffff), p2_entry.flags()));
+        }
+
+        let p1 = match p2.next_table(page.p2_index()) {
+            Some(p1) => p1,
+            None => return None,
+        };
+        let p1_entry = &p1[page.p1_index()];
+        p1_entry.pointed_frame().map(|frame| {
+            (frame.start_address() + virtual_address % PAGE_SIZE, p1_entry.flags())
+        })
+    }
+
     // takes a page and returns the corresponding frame
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
 
@@ -123,13 +168,20 @@ impl Mapper {
 
     // to unmap a page we set the corresponding P1 entry to unused
     /// Unmaps the given page and adds all freed frames to the given
-    /// `FrameAllocator`.
+    /// `FrameAllocator`. Flushes the page on every CPU.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
         where A: FrameAllocator
     {
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
+        let mut batch = tlb::Batch::new();
+        self.unmap_batched(page, allocator, &mut batch);
+        batch.finish();
+    }
 
+    /// Like `unmap`, but only adds the page to `batch`, the caller flushes
+    /// all of them at once.
+    pub fn unmap_batched<A>(&mut self, page: Page, allocator: &mut A, batch: &mut tlb::Batch)
+        where A: FrameAllocator
+    {
         assert!(self.translate(page.start_address()).is_some());
 
         let p1 = self.p4_mut()
@@ -141,7 +193,7 @@ impl Mapper {
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
 
-        tlb::flush(VirtualAddress(page.start_address()));
+        batch.add(page.start_address());
         // TODO free p(1,2,3) table if empty
         //allocator.deallocate_frame(frame);
     }
@@ -8,9 +8,10 @@ use self::table::{Table, Level4};
 use memory::PAGE_SIZE;
 use memory::Frame;
 use self::temporary_page::TemporaryPage;
-use core::ops::{Deref, DerefMut};
+use core::ops::{Add, Deref, DerefMut};
 use multiboot2::BootInformation;
 use memory::paging::table::P4;
+use backtrace;
 
 mod entry;
 mod table;
@@ -40,7 +41,7 @@ impl Page {
         Page { number: address / PAGE_SIZE }
     }
 
-    fn start_address(&self) -> usize {
+    pub fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
@@ -65,6 +66,15 @@ impl Page {
     }
 }
 
+impl Add<usize> for Page {
+    type Output = Page;
+
+    fn add(self, rhs: usize) -> Page {
+        Page { number: self.number + rhs }
+    }
+}
+
+#[derive(Clone)]
 pub struct PageIter {
     start: Page,
     end: Page,
@@ -240,6 +250,22 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation)
             }
         }
 
+        // the symbol tables for the backtraces, read only. they may share
+        // a frame with the sections above
+        for section in elf_sections_tag.sections() {
+            if section.is_allocated() || !backtrace::is_symbol_section(section) {
+                continue;
+            }
+            let start_frame = Frame::containing_address(section.start_address());
+            let end_frame = Frame::containing_address(section.end_address() - 1);
+            for frame in Frame::range_inclusive(start_frame, end_frame) {
+                let page = Page::containing_address(frame.start_address());
+                if mapper.translate_page(page).is_none() {
+                    mapper.identity_map(frame, PRESENT | NO_EXECUTE, allocator);
+                }
+            }
+        }
+
         // identity map the VGA text buffer
         let vga_buffer_frame = Frame::containing_address(0xb8000);
         mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
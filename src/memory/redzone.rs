@@ -0,0 +1,189 @@
+// heap redzones, a lightweight KASAN (the `kasan_lite` feature)
+// the allocator pads every block with `REDZONE` bytes of `PATTERN` on either
+// side and remembers the live blocks with the return addresses of the code
+// that allocated them. `dealloc` checks both redzones of the block it frees,
+// `check_all` those of every live block. a write a few bytes past the end
+// (or before the start) is caught, one that jumps the redzone isn't.
+// blocks beyond the table size are still padded and checked on `dealloc`,
+// only `check_all` doesn't see them. the table is small against a full
+// heap, `check_all` says how many live blocks it missed
+
+use alloc::heap::Layout;
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sync::IrqMutex;
+use backtrace;
+
+pub const REDZONE: usize = 16;
+const PATTERN: u8 = 0xfd;
+pub const MAX_TRACKED: usize = 128;
+// return addresses kept per block, the first ones are in the allocator
+const SITE_DEPTH: usize = 6;
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    // what the caller got
+    address: usize,
+    size: usize,
+    sites: [usize; SITE_DEPTH],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Before,
+    After,
+}
+
+static LIVE: IrqMutex<[Option<Allocation>; MAX_TRACKED]> = IrqMutex::new([None; MAX_TRACKED]);
+// live blocks that didn't fit into `LIVE`
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+// overwritten redzones reported so far, for `test_redzone_overflow`
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+// the front redzone keeps the block aligned
+fn front(layout: &Layout) -> usize {
+    cmp::max(REDZONE, layout.align())
+}
+
+/// The layout of the padded block for an allocation of `layout`.
+pub fn padded(layout: &Layout) -> Option<Layout> {
+    Layout::from_size_align(front(layout) + layout.size() + REDZONE, layout.align())
+}
+
+/// Fills the redzones of the padded `block` and records it. Returns the
+/// address for the caller.
+pub unsafe fn arm(block: *mut u8, layout: &Layout) -> *mut u8 {
+    let address = block.offset(front(layout) as isize);
+    ptr::write_bytes(address.offset(-(REDZONE as isize)), PATTERN, REDZONE);
+    ptr::write_bytes(address.offset(layout.size() as isize), PATTERN, REDZONE);
+
+    let mut allocation = Allocation {
+        address: address as usize,
+        size: layout.size(),
+        sites: [0; SITE_DEPTH],
+    };
+    backtrace::callers(&mut allocation.sites);
+    let mut live = LIVE.lock();
+    match live.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(allocation),
+        None => {
+            UNTRACKED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    address
+}
+
+/// Checks the redzones of the block at `address` and forgets it, panics if
+/// one is overwritten. Returns the address of the padded block.
+pub unsafe fn disarm(address: *mut u8, layout: &Layout) -> *mut u8 {
+    let tracked = {
+        let mut live = LIVE.lock();
+        let slot = live.iter_mut()
+            .find(|slot| slot.map_or(false, |allocation| allocation.address == address as usize));
+        match slot {
+            Some(slot) => slot.take(),
+            None => None,
+        }
+    };
+    if tracked.is_none() {
+        UNTRACKED.fetch_sub(1, Ordering::Relaxed);
+    }
+    let allocation = tracked.unwrap_or(Allocation {
+        address: address as usize,
+        size: layout.size(),
+        sites: [0; SITE_DEPTH],
+    });
+    if let Some(side) = damaged(&allocation) {
+        report(&allocation, side);
+        panic!("heap: redzone overwritten, freeing the {} byte block at {:#x}",
+               allocation.size, allocation.address);
+    }
+    address.offset(-(front(layout) as isize))
+}
+
+/// Checks the redzones of every live block it knows, reports the
+/// overwritten ones and returns how many there were.
+pub fn check_all() -> usize {
+    let live = LIVE.lock();
+    let mut count = 0;
+    for allocation in live.iter().filter_map(|slot| slot.as_ref()) {
+        if let Some(side) = damaged(allocation) {
+            report(allocation, side);
+            count += 1;
+        }
+    }
+    let untracked = UNTRACKED.load(Ordering::Relaxed);
+    if untracked > 0 {
+        println!("heap: {} live blocks didn't fit into the table of {}, only their free \
+                  checks them", untracked, MAX_TRACKED);
+    }
+    count
+}
+
+fn damaged(allocation: &Allocation) -> Option<Side> {
+    let intact = |start: usize| {
+        (start..start + REDZONE).all(|byte| unsafe { ptr::read_volatile(byte as *const u8) } == PATTERN)
+    };
+    if !intact(allocation.address - REDZONE) {
+        Some(Side::Before)
+    } else if !intact(allocation.address + allocation.size) {
+        Some(Side::After)
+    } else {
+        None
+    }
+}
+
+fn report(allocation: &Allocation, side: Side) {
+    use console::Writer;
+
+    REPORTED.fetch_add(1, Ordering::SeqCst);
+    println!("heap: the redzone {} the {} byte block at {:#x} is overwritten",
+             if side == Side::Before { "before" } else { "after" },
+             allocation.size, allocation.address);
+    if allocation.sites[0] != 0 {
+        println!("heap: allocated by");
+        for &site in allocation.sites.iter().take_while(|&&site| site != 0) {
+            print!("    ");
+            backtrace::print_address(&mut Writer, site);
+        }
+    }
+}
+
+// writes one byte past a `Box<[u8; 32]>`, which `check_all` has to find,
+// and repairs it before the drop would panic
+#[cfg(debug_assertions)]
+pub fn test_redzones() {
+    use alloc::boxed::Box;
+
+    assert_eq!(check_all(), 0, "a redzone is overwritten already");
+    let block = Box::into_raw(Box::new([0u8; 32])) as *mut u8;
+    unsafe { ptr::write_volatile(block.offset(32), 0) };
+    assert_eq!(check_all(), 1);
+    unsafe {
+        ptr::write_volatile(block.offset(32), PATTERN);
+        drop(Box::from_raw(block as *mut [u8; 32]));
+    }
+    assert_eq!(check_all(), 0);
+    println!("heap redzone test passed");
+}
+
+// writes one byte past a `Box<[u8; 32]>` and drops it, the free has to
+// report the block and panic. never returns, so `run_tests` only calls it
+// with `test_redzone_overflow` on the command line
+#[cfg(debug_assertions)]
+pub fn test_redzone_overflow() -> ! {
+    use alloc::boxed::Box;
+    use panic;
+
+    fn reported() -> bool {
+        REPORTED.load(Ordering::SeqCst) > 0
+    }
+
+    panic::expect("heap: redzone overwritten, freeing the 32 byte block", reported);
+    let block = Box::into_raw(Box::new([0u8; 32])) as *mut u8;
+    unsafe {
+        ptr::write_volatile(block.offset(32), 0);
+        drop(Box::from_raw(block as *mut [u8; 32]));
+    }
+    panic!("heap: the overwritten redzone went unnoticed");
+}
@@ -0,0 +1,372 @@
+// allocates kernel stacks from a reserved range of pages
+// every stack gets an unmapped guard page below it so an overflow page faults
+// instead of silently overwriting whatever lies below. growable stacks
+// reserve the pages for their maximum size up front but map only the
+// initial ones, the page fault handler maps more when the stack runs into
+// the unmapped part (see `handle_stack_fault`).
+// an overflow by a few hundred bytes into the page below a guard page goes
+// unnoticed, so the lowest usable page of every stack starts with a canary.
+// `check_canaries` compares them on every timer tick and every switch
+// between threads (`task::schedule`). growable stacks get theirs when they
+// grow into the lowest page.
+// the frame allocator can't take frames back, so `free_stack` keeps the
+// pages of a stack mapped and puts it on a free list. the next allocation
+// with the same maximum size takes it from there instead of using up more
+// of the range. freed stacks stay registered, a fault on their guard page
+// is still an overflow
+
+use core::ptr;
+use memory::paging::{self, Page, PageIter, ActivePageTable, Mapper};
+use memory::{PAGE_SIZE, FrameAllocator};
+use sync::IrqMutex;
+
+// stacks the page fault handler knows about
+const MAX_REGISTERED_STACKS: usize = 32;
+const MAX_FREE_STACKS: usize = 32;
+
+const CANARY: u64 = 0x57ac_c0de_57ac_c0de;
+// 64 bytes
+const CANARY_WORDS: usize = 8;
+
+static REGISTERED_STACKS: IrqMutex<[Option<Stack>; MAX_REGISTERED_STACKS]> =
+    IrqMutex::new([None; MAX_REGISTERED_STACKS]);
+static FREE_STACKS: IrqMutex<[Option<Stack>; MAX_FREE_STACKS]> =
+    IrqMutex::new([None; MAX_FREE_STACKS]);
+
+pub struct StackAllocator {
+    range: PageIter,
+}
+
+impl StackAllocator {
+    pub fn new(page_range: PageIter) -> StackAllocator {
+        StackAllocator { range: page_range }
+    }
+
+    /// Allocates a stack of `size_in_pages` pages plus a guard page and maps
+    /// it writable. Returns `None` if the stack range or the physical memory
+    /// is exhausted.
+    pub fn alloc_stack<FA: FrameAllocator>(&mut self,
+                                           active_table: &mut ActivePageTable,
+                                           frame_allocator: &mut FA,
+                                           size_in_pages: usize)
+                                           -> Option<Stack>
+    {
+        self.alloc_growable_stack(active_table, frame_allocator, size_in_pages,
+                                  size_in_pages)
+    }
+
+    /// Like `alloc_stack`, but reserves `max_size_in_pages` pages (plus the
+    /// guard page) and maps only the top `size_in_pages` of them. The stack
+    /// grows on demand up to the maximum.
+    pub fn alloc_growable_stack<FA: FrameAllocator>(&mut self,
+                                                    active_table: &mut ActivePageTable,
+                                                    frame_allocator: &mut FA,
+                                                    size_in_pages: usize,
+                                                    max_size_in_pages: usize)
+                                                    -> Option<Stack>
+    {
+        if size_in_pages == 0 || max_size_in_pages < size_in_pages {
+            return None; // a zero sized stack makes no sense
+        }
+        if let Some(stack) = reuse(size_in_pages, max_size_in_pages) {
+            return Some(stack);
+        }
+
+        // clone the range, since we only want to change it on success
+        let mut range = self.range.clone();
+
+        // try to allocate the stack pages and a guard page
+        let guard_page = range.next();
+        let stack_start = range.next();
+        let stack_end = if max_size_in_pages == 1 {
+            stack_start
+        } else {
+            // choose the (max_size_in_pages-2)th element, since index
+            // starts at 0 and we already allocated the start page
+            range.nth(max_size_in_pages - 2)
+        };
+
+        match (guard_page, stack_start, stack_end) {
+            (Some(_), Some(start), Some(end)) => {
+                // success! write back updated range
+                self.range = range;
+
+                // map the top pages to physical frames, the rest stays
+                // reserved for growing
+                let mapped_start = Page::containing_address(
+                    end.start_address() - (size_in_pages - 1) * PAGE_SIZE);
+                for page in Page::range_inclusive(mapped_start, end) {
+                    active_table.map(page, paging::WRITABLE, frame_allocator);
+                }
+
+                // create a new stack
+                let top_of_stack = end.start_address() + PAGE_SIZE;
+                let stack = Stack::new(top_of_stack, mapped_start.start_address(),
+                                       start.start_address());
+                if stack.bottom == stack.limit {
+                    write_canary(stack.limit);
+                }
+                register(stack);
+                Some(stack)
+            }
+            _ => None, // not enough pages
+        }
+    }
+}
+
+// a freed stack with this maximum size that has at least `size_in_pages`
+// mapped
+fn reuse(size_in_pages: usize, max_size_in_pages: usize) -> Option<Stack> {
+    let mut free = FREE_STACKS.lock();
+    free.iter_mut()
+        .find(|slot| slot.map_or(false, |stack| {
+            stack.max_size_in_pages() == max_size_in_pages && stack.size_in_pages() >= size_in_pages
+        }))
+        .and_then(|slot| slot.take())
+        .map(|stack| {
+            if stack.bottom == stack.limit {
+                write_canary(stack.limit);
+            }
+            stack
+        })
+}
+
+/// Gives a stack back to the allocator. Nothing may run on it anymore.
+/// Interrupt safe, `task` frees the stacks of reaped threads from the
+/// scheduler.
+pub fn free_stack(stack: Stack) {
+    // a growable stack may have grown since it was allocated
+    let stack = REGISTERED_STACKS.lock().iter().filter_map(|slot| *slot)
+        .find(|registered| registered.top == stack.top)
+        .unwrap_or(stack);
+    let mut free = FREE_STACKS.lock();
+    // with the free list full the stack's pages stay reserved for good
+    if let Some(slot) = free.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(stack);
+    }
+}
+
+// a full table only means the stack can't grow and overflows are reported
+// as plain page faults
+fn register(stack: Stack) {
+    let mut stacks = REGISTERED_STACKS.lock();
+    if let Some(slot) = stacks.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(stack);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackFault {
+    // more pages were mapped, the access can be retried
+    Grown,
+    // the stack is at its maximum size, or the fault hit the guard page
+    Overflow { top: usize, max_size_in_pages: usize },
+    // the stack table or the frame allocator was locked, by the faulting
+    // code itself maybe, so the stack couldn't grow
+    Locked,
+}
+
+/// Checks whether `address` lies in the unmapped part of a registered stack
+/// and maps the pages from it up to the current bottom if the stack may grow
+/// that far. Returns `None` if the address belongs to no stack. Called by the
+/// page fault handler, which runs on its own stack for this. It doesn't wait
+/// for locks, the fault may come from code that holds them.
+pub fn handle_stack_fault(address: usize) -> Option<StackFault> {
+    let mut stacks = match REGISTERED_STACKS.try_lock() {
+        Some(stacks) => stacks,
+        None => return Some(StackFault::Locked),
+    };
+    let stack = match stacks.iter_mut().filter_map(|slot| slot.as_mut())
+        .find(|stack| address >= stack.limit - PAGE_SIZE && address < stack.bottom)
+    {
+        Some(stack) => stack,
+        None => return None,
+    };
+
+    if address < stack.limit {
+        // the guard page below the maximum size
+        return Some(StackFault::Overflow {
+            top: stack.top,
+            max_size_in_pages: stack.max_size_in_pages(),
+        });
+    }
+
+    // map everything between the fault and the old bottom, the access
+    // may have skipped pages (e.g. a large stack frame)
+    let new_bottom = Page::containing_address(address);
+    let last_unmapped = Page::containing_address(stack.bottom - 1);
+    let mut frame_allocator = match super::try_lock_frame_allocator() {
+        Some(frame_allocator) => frame_allocator,
+        None => return Some(StackFault::Locked),
+    };
+    let mut mapper = unsafe { Mapper::new() };
+    for page in Page::range_inclusive(new_bottom, last_unmapped) {
+        mapper.map(page, paging::WRITABLE, &mut *frame_allocator);
+    }
+    stack.bottom = new_bottom.start_address();
+    if stack.bottom == stack.limit {
+        write_canary(stack.limit);
+    }
+    Some(StackFault::Grown)
+}
+
+fn write_canary(address: usize) {
+    for i in 0..CANARY_WORDS {
+        unsafe { ptr::write_volatile((address as *mut u64).offset(i as isize), CANARY) };
+    }
+}
+
+fn canary_intact(address: usize) -> bool {
+    (0..CANARY_WORDS).all(|i| {
+        unsafe { ptr::read_volatile((address as *const u64).offset(i as isize)) == CANARY }
+    })
+}
+
+/// Returns a stack whose canary is overwritten, if there is one. None also
+/// if the table is locked, so it can be used from exception handlers.
+pub fn clobbered_canary() -> Option<Stack> {
+    let stacks = match REGISTERED_STACKS.try_lock() {
+        Some(stacks) => stacks,
+        None => return None,
+    };
+    let clobbered = stacks.iter().filter_map(|slot| *slot)
+        .find(|stack| stack.bottom == stack.limit && !canary_intact(stack.limit));
+    clobbered
+}
+
+/// Panics if the canary of a stack is overwritten. Called by the timer
+/// interrupt.
+pub fn check_canaries() {
+    if let Some(stack) = clobbered_canary() {
+        panic!("stack: the canary of the stack {:#x}..{:#x} is overwritten, it overflowed",
+               stack.limit, stack.top);
+    }
+}
+
+// the stack grows downwards, so `top` is the initial stack pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack {
+    top: usize,
+    bottom: usize,
+    // the lowest address the stack may grow to
+    limit: usize,
+}
+
+impl Stack {
+    fn new(top: usize, bottom: usize, limit: usize) -> Stack {
+        assert!(top > bottom && bottom >= limit);
+        Stack {
+            top: top,
+            bottom: bottom,
+            limit: limit,
+        }
+    }
+
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// The bottom when the stack was allocated. A growable stack may have
+    /// grown below it since.
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    pub fn size_in_pages(&self) -> usize {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
+
+    pub fn max_size_in_pages(&self) -> usize {
+        (self.top - self.limit) / PAGE_SIZE
+    }
+
+    /// The lowest address the stack may grow to, its guard page is the page
+    /// below.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Whether `address` is on the stack or its guard page.
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.limit - PAGE_SIZE && address < self.top
+    }
+}
+
+#[cfg(debug_assertions)]
+#[inline(never)]
+fn recurse(depth: usize) -> u64 {
+    use core::ptr;
+
+    // 512 bytes of stack per level that the optimizer can't drop
+    let mut buffer = [0u8; 512];
+    unsafe { ptr::write_volatile(&mut buffer[0], depth as u8) };
+    if depth == 0 {
+        return 0;
+    }
+    let below = recurse(depth - 1);
+    below + unsafe { ptr::read_volatile(&buffer[0]) } as u64
+}
+
+#[cfg(debug_assertions)]
+extern "C" fn recurse_three_pages() {
+    // 24 levels of 512 bytes (plus the frames) need about 3 pages
+    recurse(24);
+}
+
+#[cfg(debug_assertions)]
+extern "C" fn recurse_forever() {
+    recurse(usize::max_value());
+}
+
+/// Runs a recursion that needs about 3 pages on a growable stack that
+/// starts with 1 mapped page and may grow to 8.
+#[cfg(debug_assertions)]
+pub fn test_stack_growth(memory_controller: &mut ::memory::MemoryController) {
+    use cpu;
+
+    let stack = memory_controller.alloc_growable_stack(1, 8)
+        .expect("could not allocate test stack");
+    unsafe { cpu::call_on_stack(stack.top(), recurse_three_pages) };
+
+    let grown = REGISTERED_STACKS.lock().iter().filter_map(|slot| *slot)
+        .find(|registered| registered.top == stack.top())
+        .expect("test stack not registered");
+    assert!(grown.size_in_pages() >= 3, "stack did not grow");
+    println!("stack growth test passed (grew to {} pages)", grown.size_in_pages());
+}
+
+/// Overwrites the canary of a fresh stack, which `clobbered_canary` has to
+/// find, and puts it back.
+#[cfg(debug_assertions)]
+pub fn test_stack_canary(memory_controller: &mut ::memory::MemoryController) {
+    use x86_64::instructions::interrupts as instructions;
+    use interrupts;
+
+    let stack = memory_controller.alloc_stack(1).expect("could not allocate test stack");
+    assert!(clobbered_canary().is_none(), "a canary is overwritten already");
+    // the timer tick would panic on the overwritten canary
+    let enabled = interrupts::interrupts_enabled();
+    unsafe { instructions::disable() };
+    let word = (stack.bottom() + 8 * (CANARY_WORDS - 1)) as *mut u64;
+    unsafe { ptr::write_volatile(word, 0) };
+    let found = clobbered_canary().map(|clobbered| clobbered.top());
+    write_canary(stack.bottom());
+    if enabled {
+        unsafe { instructions::enable() };
+    }
+    assert_eq!(found, Some(stack.top()));
+    assert!(clobbered_canary().is_none());
+    println!("stack canary test passed");
+}
+
+/// Recurses without end on a growable stack. Must end in the stack overflow
+/// panic once the stack reaches its maximum of 8 pages.
+#[cfg(debug_assertions)]
+pub fn test_stack_overflow(memory_controller: &mut ::memory::MemoryController) {
+    use cpu;
+
+    let stack = memory_controller.alloc_growable_stack(1, 8)
+        .expect("could not allocate test stack");
+    unsafe { cpu::call_on_stack(stack.top(), recurse_forever) };
+    unreachable!("unbounded recursion returned");
+}
@@ -0,0 +1,231 @@
+// interactive debug monitor
+// a small command loop for looking around instead of staring at a frozen
+// screen. the panic handler enters it after its report if `monitor_on_panic`
+// is on the command line, Ctrl+Alt+M enters it from the keyboard (the
+// interrupt handler only schedules it, it runs as deferred work and holds up
+// the other work until `continue`). after a panic interrupts are off, so
+// the keyboard and COM1 are polled instead. the report already broke the
+// console locks, `println!` is safe again; the heap and the frame allocator
+// are only looked at if their locks are free
+
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::fmt::Write;
+use console;
+use input::{self, LineEditor};
+use keyboard;
+use serial;
+use interrupts;
+use memory;
+use backtrace;
+use power;
+use cmdline;
+use work;
+
+const LINE_LENGTH: usize = 80;
+// `md` shows at most this many bytes
+const MAX_DUMP: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Panic,
+    Hotkey,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the panic handler should enter the monitor.
+pub fn on_panic() -> bool {
+    cmdline::has("monitor_on_panic")
+}
+
+/// For the keyboard interrupt: enters the monitor from the work queue,
+/// unless it is running already.
+pub fn request() {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        work::schedule(enter_from_hotkey);
+    }
+}
+
+fn enter_from_hotkey() {
+    enter(Entry::Hotkey);
+}
+
+/// Runs the command loop. Returns on `continue`, which only works when
+/// entered from the hotkey.
+pub fn enter(entry: Entry) {
+    if ACTIVE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    println!("monitor: entered from the {}, `help` lists the commands",
+             if entry == Entry::Panic { "panic handler" } else { "keyboard" });
+    let mut buffer = [0u8; LINE_LENGTH];
+    loop {
+        print!("monitor> ");
+        let length = read_line(&mut buffer, entry);
+        let line = match str::from_utf8(&buffer[..length]) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        match (command, words.next(), words.next()) {
+            ("help", None, None) => help(),
+            ("regs", None, None) => regs(),
+            ("bt", None, None) => backtrace::print(&mut console::Writer),
+            ("md", Some(address), length) => {
+                match (parse_address(address), length.map_or(Some(16), parse_number)) {
+                    (Some(address), Some(length)) => memory_dump(address, length),
+                    _ => println!("usage: md <hex address> [length]"),
+                }
+            }
+            ("pt", Some(address), None) => match parse_address(address) {
+                Some(address) => translate(address),
+                None => println!("usage: pt <hex address>"),
+            },
+            ("irqstat", None, None) => interrupts::print_stats(),
+            ("heap", None, None) => heap(),
+            ("frames", None, None) => frames(),
+            ("reboot", None, None) => power::reboot(),
+            ("continue", None, None) if entry == Entry::Hotkey => break,
+            ("continue", None, None) => println!("the kernel panicked, there is nothing to continue"),
+            _ => println!("unknown command or wrong arguments, try `help`"),
+        }
+    }
+    ACTIVE.store(false, Ordering::SeqCst);
+}
+
+fn read_line(buffer: &mut [u8], entry: Entry) -> usize {
+    let mut editor = LineEditor::new(buffer);
+    loop {
+        let character = match entry {
+            Entry::Hotkey => input::next_char(),
+            Entry::Panic => poll_char(),
+        };
+        if editor.feed(character, &mut |s: &str| print!("{}", s)) {
+            return editor.len();
+        }
+    }
+}
+
+// with interrupts off nothing fills the input queues, read the hardware
+fn poll_char() -> char {
+    loop {
+        if let Some(character) = keyboard::poll_char() {
+            return character;
+        }
+        if let Some(byte) = serial::poll_byte() {
+            return byte as char;
+        }
+        unsafe { asm!("pause" :::: "volatile") };
+    }
+}
+
+fn help() {
+    println!("regs              registers (of the exception, if there is one)");
+    println!("bt                backtrace");
+    println!("md <addr> [len]   memory dump, unmapped pages are skipped");
+    println!("pt <addr>         page table translation");
+    println!("irqstat           interrupt counts");
+    println!("heap              kernel heap usage (and redzones with kasan_lite)");
+    println!("frames            frame allocator state");
+    println!("reboot            reboot the machine");
+    println!("continue          leave the monitor (not after a panic)");
+}
+
+fn regs() {
+    match interrupts::captured_registers() {
+        Some(registers) => {
+            println!("registers of the exception:");
+            interrupts::print_registers(&mut console::Writer, &registers);
+        }
+        None => dump_regs!(),
+    }
+    interrupts::print_control_registers(&mut console::Writer);
+}
+
+fn memory_dump(address: usize, length: usize) {
+    const ROW: usize = 16;
+    let length = if length > MAX_DUMP { MAX_DUMP } else { length };
+    let mut offset = 0;
+    while offset < length {
+        let row_address = address.wrapping_add(offset);
+        let mut bytes = [0u8; ROW];
+        let count = if length - offset < ROW { length - offset } else { ROW };
+        let read = memory::read_checked(row_address, &mut bytes[..count]);
+        if read == 0 {
+            println!("{:016x}: <unmapped>", row_address);
+            offset += ROW;
+            continue;
+        }
+        let mut out = console::Writer;
+        let _ = write!(out, "{:016x}:", row_address);
+        for i in 0..ROW {
+            if i < read {
+                let _ = write!(out, " {:02x}", bytes[i]);
+            } else {
+                let _ = write!(out, "   ");
+            }
+        }
+        let _ = write!(out, "  ");
+        for &byte in bytes[..read].iter() {
+            let shown = if byte >= 0x20 && byte < 0x7f { byte as char } else { '.' };
+            let _ = write!(out, "{}", shown);
+        }
+        let _ = writeln!(out, "");
+        offset += ROW;
+    }
+}
+
+fn translate(address: usize) {
+    match memory::translate_with_flags(address) {
+        Some((physical, flags)) => println!("{:#x} -> {:#x} {:?}", address, physical, flags),
+        None => println!("{:#x} is not mapped", address),
+    }
+}
+
+fn heap() {
+    let stats = memory::heap_stats();
+    println!("heap: {} bytes used in {} allocations", stats.used, stats.allocations);
+    match (stats.size, stats.largest_free_block) {
+        (Some(size), Some(largest)) => {
+            println!("heap: {} bytes in total, largest free block {} bytes", size, largest)
+        }
+        _ => println!("heap: locked, no size"),
+    }
+    #[cfg(feature = "kasan_lite")]
+    {
+        // check_all says how many live blocks the table missed
+        let damaged = memory::redzone::check_all();
+        println!("heap: {} blocks with overwritten redzones among those tracked (at most {})",
+                 damaged, memory::redzone::MAX_TRACKED);
+    }
+}
+
+fn frames() {
+    match memory::frame_stats() {
+        Some((allocated, Some(next))) => {
+            println!("frames: {} allocated, next free at {:#x}", allocated, next)
+        }
+        Some((allocated, None)) => println!("frames: {} allocated, none left", allocated),
+        None => println!("frames: the allocator is locked or not set up"),
+    }
+}
+
+// hexadecimal, with or without 0x
+fn parse_address(text: &str) -> Option<usize> {
+    let digits = if text.starts_with("0x") { &text[2..] } else { text };
+    usize::from_str_radix(digits, 16).ok()
+}
+
+// decimal, or hexadecimal with 0x
+fn parse_number(text: &str) -> Option<usize> {
+    if text.starts_with("0x") {
+        usize::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
@@ -0,0 +1,218 @@
+// PS/2 mouse on the auxiliary port of the 8042 controller
+// the interrupt handler collects the 3 byte packets and queues them as events
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sync::DebugMutex;
+use x86_64::instructions::port::inb;
+use interrupts::{self, InterruptContext};
+use i8042::{self, I8042Error};
+
+pub const MOUSE_IRQ: u8 = 12;
+
+// bits of the first packet byte
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MouseEvent {
+    pub dx: i16,
+    // positive is up, like the hardware reports it
+    pub dy: i16,
+    pub buttons: Buttons,
+}
+
+#[derive(Debug)]
+pub enum MouseError {
+    /// `i8042::init` found no working port 2.
+    NoPort,
+    Controller(I8042Error),
+    NoAck(u8),
+}
+
+impl From<I8042Error> for MouseError {
+    fn from(error: I8042Error) -> MouseError {
+        MouseError::Controller(error)
+    }
+}
+
+static EVENTS: EventQueue = EventQueue::new();
+// only locked by the interrupt handler
+static PACKET: DebugMutex<Packet> = DebugMutex::new(Packet { bytes: [0; 3], index: 0 });
+
+/// Enables data reporting and registers the IRQ 12 handler. The port itself
+/// is set up by `i8042::init`, which must have run.
+pub fn init() -> Result<(), MouseError> {
+    if !i8042::port2_ok() {
+        return Err(MouseError::NoPort);
+    }
+    unsafe {
+        mouse_command(0xf6)?;  // set defaults
+        mouse_command(0xf3)?;  // set sample rate ...
+        mouse_command(100)?;   // ... to 100 samples/s
+        mouse_command(0xf4)?;  // enable data reporting
+    }
+
+    interrupts::register_irq(MOUSE_IRQ, mouse_interrupt)
+        .expect("could not register the mouse interrupt");
+    Ok(())
+}
+
+/// Returns the next mouse event, or `None` if there is none queued.
+pub fn poll() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+fn mouse_interrupt(_context: &mut InterruptContext) {
+    let byte = unsafe { inb(i8042::DATA_PORT) };
+    let event = PACKET.lock().add(byte);
+    if let Some(event) = event {
+        // if the queue is full the movement is simply lost
+        EVENTS.push(event);
+    }
+}
+
+struct Packet {
+    bytes: [u8; 3],
+    index: usize,
+}
+
+impl Packet {
+    fn add(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.index == 0 && byte & ALWAYS_ONE == 0 {
+            // out of sync, this can't be the first byte of a packet.
+            // drop it and wait for one that can be
+            return None;
+        }
+        self.bytes[self.index] = byte;
+        self.index += 1;
+        if self.index < 3 {
+            return None;
+        }
+        self.index = 0;
+
+        let flags = self.bytes[0];
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None; // the deltas are garbage
+        }
+
+        // the deltas are 9 bit two's complement with the sign in the flags
+        let mut dx = self.bytes[1] as i16;
+        if flags & X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.bytes[2] as i16;
+        if flags & Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+
+        Some(MouseEvent {
+            dx: dx,
+            dy: dy,
+            buttons: Buttons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        })
+    }
+}
+
+// sends a byte to the mouse (instead of the keyboard) and waits for the ack
+unsafe fn mouse_command(value: u8) -> Result<(), MouseError> {
+    i8042::write_port2(value)?;
+    match i8042::read_data("mouse ack", i8042::RESPONSE_TIMEOUT)? {
+        i8042::ACK => Ok(()),
+        other => Err(MouseError::NoAck(other)),
+    }
+}
+
+// single producer (the interrupt handler), single consumer ring
+const QUEUE_SIZE: usize = 32;
+
+struct EventQueue {
+    buffer: UnsafeCell<[Option<MouseEvent>; QUEUE_SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    const fn new() -> EventQueue {
+        EventQueue {
+            buffer: UnsafeCell::new([None; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, event: MouseEvent) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[tail] = Some(event) };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<MouseEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe { (*self.buffer.get())[head].take() };
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        event
+    }
+}
+
+/// Mouse cursor demo for the text screen: moves a `#` around, restoring the
+/// character underneath when it moves on.
+pub struct TextCursor {
+    // in 1/8 character cells, so slow movements still add up
+    x: i32,
+    y: i32,
+    saved: Option<(usize, usize, u8)>,
+}
+
+impl TextCursor {
+    pub const fn new() -> TextCursor {
+        TextCursor { x: 40 * 8, y: 12 * 8, saved: None }
+    }
+
+    pub fn update(&mut self, event: &MouseEvent) {
+        use vga_buffer::{self, BUFFER_WIDTH, BUFFER_HEIGHT};
+
+        let max_x = (BUFFER_WIDTH as i32) * 8 - 1;
+        let max_y = (BUFFER_HEIGHT as i32) * 8 - 1;
+        self.x = clamp(self.x + event.dx as i32, 0, max_x);
+        self.y = clamp(self.y - event.dy as i32, 0, max_y);
+
+        if let Some((row, col, character)) = self.saved.take() {
+            vga_buffer::swap_char(row, col, character);
+        }
+        let (row, col) = ((self.y / 8) as usize, (self.x / 8) as usize);
+        let character = vga_buffer::swap_char(row, col, b'#');
+        self.saved = Some((row, col, character));
+    }
+}
+
+fn clamp(value: i32, min: i32, max: i32) -> i32 {
+    if value < min { min } else if value > max { max } else { value }
+}
@@ -0,0 +1,199 @@
+// ethernet basics shared by the network drivers
+// every driver implements `NetworkDevice`, and `init` picks the first
+// card one of them found, so the code above doesn't care which it is.
+// there is no network stack yet. `arp_demo_receive` answers ARP requests
+// for a fixed address (QEMU's `-netdev user` gives the guest 10.0.2.15),
+// which is enough to check that both directions of a driver work
+
+use core::fmt;
+use spin::Once;
+use memory::MemoryController;
+use e1000;
+use virtio_net;
+
+/// Called by a driver for every received frame, in interrupt context. The
+/// frame starts with the ethernet header and has no frame check sequence.
+pub type ReceiveCallback = fn(&[u8]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No network card was initialized.
+    NoDevice,
+    FrameTooLong,
+    /// The card didn't take or send the frame in time.
+    Timeout,
+}
+
+/// A network card driver.
+pub trait NetworkDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    /// Sends `frame`, which starts with the ethernet header and doesn't
+    /// include the frame check sequence.
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Sets the function received frames are passed to.
+    fn set_receive_callback(&self, callback: ReceiveCallback);
+}
+
+static DEVICE: Once<&'static NetworkDevice> = Once::new();
+
+/// Initializes the drivers until one finds its card. Returns false if
+/// there is no supported card.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("net::init must be called only once");
+
+    let device: &'static NetworkDevice = if e1000::init(memory_controller) {
+        &e1000::E1000
+    } else if virtio_net::init(memory_controller) {
+        &virtio_net::VirtioNet
+    } else {
+        return false;
+    };
+    println!("net: using {}, MAC {}", device.name(), device.mac_address());
+    DEVICE.call_once(|| device);
+    true
+}
+
+/// Returns the card chosen by `init`.
+pub fn device() -> Option<&'static NetworkDevice> {
+    DEVICE.try().cloned()
+}
+
+/// Sends `frame` through the card chosen by `init`.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    match device() {
+        Some(device) => device.send(frame),
+        None => Err(NetError::NoDevice),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4], b[5])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = self.0;
+        write!(f, "{}.{}.{}.{}", b[0], b[1], b[2], b[3])
+    }
+}
+
+/// The address the demo answers for.
+pub const DEMO_ADDRESS: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+/// The gateway of QEMU's user networking.
+pub const DEMO_GATEWAY: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+// ARP for IPv4 over ethernet
+const ARP_SIZE: usize = 28;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    (bytes[offset] as u16) << 8 | bytes[offset + 1] as u16
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset] = (value >> 8) as u8;
+    bytes[offset + 1] = value as u8;
+}
+
+fn mac_at(bytes: &[u8], offset: usize) -> MacAddress {
+    let mut address = [0; 6];
+    address.copy_from_slice(&bytes[offset..offset + 6]);
+    MacAddress(address)
+}
+
+fn ipv4_at(bytes: &[u8], offset: usize) -> Ipv4Address {
+    let mut address = [0; 4];
+    address.copy_from_slice(&bytes[offset..offset + 4]);
+    Ipv4Address(address)
+}
+
+// builds an ARP packet in an ethernet frame
+fn arp_frame(operation: u16, source: MacAddress, source_ip: Ipv4Address,
+             destination: MacAddress, target: MacAddress, target_ip: Ipv4Address)
+             -> [u8; ETHERNET_HEADER_SIZE + ARP_SIZE] {
+    let mut frame = [0; ETHERNET_HEADER_SIZE + ARP_SIZE];
+    frame[0..6].copy_from_slice(&destination.0);
+    frame[6..12].copy_from_slice(&source.0);
+    write_u16(&mut frame, 12, ETHERTYPE_ARP);
+
+    let arp = &mut frame[ETHERNET_HEADER_SIZE..];
+    write_u16(arp, 0, ARP_HARDWARE_ETHERNET);
+    write_u16(arp, 2, ETHERTYPE_IPV4);
+    arp[4] = 6;
+    arp[5] = 4;
+    write_u16(arp, 6, operation);
+    arp[8..14].copy_from_slice(&source.0);
+    arp[14..18].copy_from_slice(&source_ip.0);
+    arp[18..24].copy_from_slice(&target.0);
+    arp[24..28].copy_from_slice(&target_ip.0);
+    frame
+}
+
+/// Broadcasts an ARP request for `target`, the reply shows up through
+/// `arp_demo_receive`.
+pub fn send_arp_request(target: Ipv4Address) {
+    let our_mac = match device() {
+        Some(device) => device.mac_address(),
+        None => return,
+    };
+    let frame = arp_frame(ARP_REQUEST, our_mac, DEMO_ADDRESS, MacAddress::BROADCAST,
+                          MacAddress([0; 6]), target);
+    if let Err(error) = send(&frame) {
+        println!("net: sending the ARP request failed: {:?}", error);
+    }
+}
+
+/// Receive callback that answers ARP requests for `DEMO_ADDRESS` and prints
+/// the replies it gets.
+pub fn arp_demo_receive(frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE + ARP_SIZE || read_u16(frame, 12) != ETHERTYPE_ARP {
+        return;
+    }
+    let arp = &frame[ETHERNET_HEADER_SIZE..];
+    if read_u16(arp, 0) != ARP_HARDWARE_ETHERNET || read_u16(arp, 2) != ETHERTYPE_IPV4 {
+        return;
+    }
+    let sender = mac_at(arp, 8);
+    let sender_ip = ipv4_at(arp, 14);
+    let target_ip = ipv4_at(arp, 24);
+
+    match read_u16(arp, 6) {
+        ARP_REQUEST if target_ip == DEMO_ADDRESS => {
+            let our_mac = match device() {
+                Some(device) => device.mac_address(),
+                None => return,
+            };
+            let reply = arp_frame(ARP_REPLY, our_mac, DEMO_ADDRESS, sender, sender, sender_ip);
+            match send(&reply) {
+                Ok(()) => println!("net: told {} ({}) that we are {}", sender_ip, sender,
+                                   DEMO_ADDRESS),
+                Err(error) => println!("net: sending the ARP reply failed: {:?}", error),
+            }
+        }
+        ARP_REPLY => println!("net: {} is at {}", sender_ip, sender),
+        _ => {}
+    }
+}
@@ -0,0 +1,130 @@
+// the panic state
+// `panic_fmt` counts its entries in `PANIC_DEPTH` first thing, with
+// interrupts already off. a second entry means the reporting itself
+// panicked (the backtrace walker, a broken formatter), so it only writes a
+// fixed line with the location to the serial port and port 0xe9, a third
+// one just halts. code that would do something clever during the teardown
+// checks `is_panicking` instead: the heap refuses to allocate.
+// the counter is global for now, there is only one CPU
+
+use core::cmp;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use sync::IrqMutex;
+use serial;
+use debugcon;
+
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// runs after the panic message, like a last words printer
+static HOOK: IrqMutex<Option<fn()>> = IrqMutex::new(None);
+// the message of a panic a test ends in on purpose, and what else has to
+// hold then
+static EXPECTED: IrqMutex<Option<(&'static str, fn() -> bool)>> = IrqMutex::new(None);
+// a panic while panicking is what the test is after, no lock for the
+// nested path
+static NESTED_EXPECTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the kernel panicked.
+pub fn is_panicking() -> bool {
+    PANIC_DEPTH.load(Ordering::SeqCst) > 0
+}
+
+/// Counts an entry into `panic_fmt`, returns the depth: 1 for a panic, 2
+/// for a panic while reporting one, and so on.
+pub fn enter() -> usize {
+    PANIC_DEPTH.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Sets the function `panic_fmt` calls after printing the message.
+pub fn set_hook(hook: fn()) {
+    *HOOK.lock() = Some(hook);
+}
+
+/// Calls the hook, if there is one and its lock is free.
+pub fn run_hook() {
+    let hook = match HOOK.try_lock() {
+        Some(hook) => *hook,
+        None => return,
+    };
+    if let Some(hook) = hook {
+        hook();
+    }
+}
+
+/// Makes a panic whose message starts with `message` the successful end of
+/// a test run, if `check` returns true then. For tests that can only end in
+/// a panic.
+pub fn expect(message: &'static str, check: fn() -> bool) {
+    *EXPECTED.lock() = Some((message, check));
+}
+
+/// Whether the panic with `message` is the one `expect` announced and its
+/// check holds. False if the lock is taken.
+pub fn is_expected(message: fmt::Arguments) -> bool {
+    let expected = match EXPECTED.try_lock() {
+        Some(expected) => *expected,
+        None => return false,
+    };
+    let (text, check) = match expected {
+        Some(expected) => expected,
+        None => return false,
+    };
+    // compared piece by piece, the heap may be what broke
+    let mut matcher = Matcher { rest: text, matches: true };
+    let _ = write!(matcher, "{}", message);
+    matcher.matches && matcher.rest.is_empty() && check()
+}
+
+/// Makes a panic while panicking the successful end of a test run, once
+/// `report_nested` wrote its line.
+pub fn expect_nested() {
+    NESTED_EXPECTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_nested_expected() -> bool {
+    NESTED_EXPECTED.load(Ordering::SeqCst)
+}
+
+// whether the text written starts with `rest`
+struct Matcher {
+    rest: &'static str,
+    matches: bool,
+}
+
+impl Write for Matcher {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let rest = self.rest;
+        if !self.matches || rest.is_empty() {
+            return Ok(());
+        }
+        let length = cmp::min(rest.len(), s.len());
+        if rest.as_bytes()[..length] == s.as_bytes()[..length] {
+            self.rest = &rest[length..];
+        } else {
+            self.matches = false;
+        }
+        Ok(())
+    }
+}
+
+/// The report of a nested panic: no message (formatting it may be what
+/// panicked), no locks, only the raw ports.
+pub fn report_nested(file: &str, line: u32) {
+    let _ = write!(serial::RawWriter, "\n\nPANIC while panicking in {} at line {}\n", file, line);
+    let _ = write!(debugcon::Writer, "\n\nPANIC while panicking in {} at line {}\n", file, line);
+}
+
+// panics from the hook, serial has to show the panic and the nested one
+// (`make test` greps for it). never returns, so `run_tests` only calls it
+// with `test_double_panic` on the command line
+#[cfg(debug_assertions)]
+pub fn test_double_panic() -> ! {
+    expect_nested();
+
+    fn panicking_hook() {
+        panic!("panic in the panic hook");
+    }
+    set_hook(panicking_hook);
+    panic!("test_double_panic");
+}
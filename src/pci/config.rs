@@ -0,0 +1,68 @@
+// configuration mechanism #1
+// the address of a dword in the configuration space of a function is
+// written to 0xcf8, then the dword is accessed at 0xcfc. bytes and words
+// are accessed at 0xcfc plus their offset within the dword, so a write
+// touches only them (read-modify-write of the whole dword would clear the
+// write-one-to-clear status bits next to the command register). the lock
+// keeps the address and the data access together
+
+use x86_64::instructions::port::{inb, inw, inl, outb, outw, outl};
+use sync::IrqMutex;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const ENABLE: u32 = 1 << 31;
+
+static LOCK: IrqMutex<()> = IrqMutex::new(());
+
+/// The location of a function in the configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Location {
+    // selects the dword containing `offset`, returns the data port for it
+    unsafe fn select(&self, offset: u8) -> u16 {
+        let address = ENABLE | (self.bus as u32) << 16 | (self.device as u32) << 11
+            | (self.function as u32) << 8 | (offset as u32 & 0xfc);
+        outl(CONFIG_ADDRESS, address);
+        CONFIG_DATA + (offset as u16 & 3)
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        assert!(offset % 4 == 0, "unaligned PCI configuration access");
+        let _guard = LOCK.lock();
+        unsafe { inl(self.select(offset)) }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        assert!(offset % 2 == 0, "unaligned PCI configuration access");
+        let _guard = LOCK.lock();
+        unsafe { inw(self.select(offset)) }
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        let _guard = LOCK.lock();
+        unsafe { inb(self.select(offset)) }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        assert!(offset % 4 == 0, "unaligned PCI configuration access");
+        let _guard = LOCK.lock();
+        unsafe { outl(self.select(offset), value) };
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        assert!(offset % 2 == 0, "unaligned PCI configuration access");
+        let _guard = LOCK.lock();
+        unsafe { outw(self.select(offset), value) };
+    }
+
+    pub fn write_u8(&self, offset: u8, value: u8) {
+        let _guard = LOCK.lock();
+        unsafe { outb(self.select(offset), value) };
+    }
+}
@@ -0,0 +1,445 @@
+// PCI bus enumeration
+// `init` walks the buses through the configuration ports, starting at bus 0
+// and following PCI-to-PCI bridges to their secondary buses, and records
+// every function it finds in a fixed table (there is no heap yet). drivers
+// look their devices up with `find` and then use the typed configuration
+// space accessors of `PciDevice`
+
+use core::fmt;
+use sync::IrqMutex;
+
+pub use self::config::Location;
+
+mod config;
+
+const MAX_DEVICES: usize = 64;
+const MAX_BARS: usize = 6;
+
+// configuration space offsets of the common header
+pub const VENDOR_ID: u8 = 0x00;
+pub const DEVICE_ID: u8 = 0x02;
+pub const COMMAND: u8 = 0x04;
+pub const STATUS: u8 = 0x06;
+pub const CLASS_REVISION: u8 = 0x08;
+pub const HEADER_TYPE: u8 = 0x0e;
+pub const BAR0: u8 = 0x10;
+// type 1 (bridge) header
+pub const SECONDARY_BUS: u8 = 0x19;
+// type 0 and type 1 header
+pub const CAPABILITIES_POINTER: u8 = 0x34;
+pub const INTERRUPT_LINE: u8 = 0x3c;
+
+const NO_VENDOR: u16 = 0xffff;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_BRIDGE: u8 = 1;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+// capability IDs
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+static DEVICES: IrqMutex<[Option<PciDevice>; MAX_DEVICES]> = IrqMutex::new([None; MAX_DEVICES]);
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory32 { base: u32, size: u32, prefetchable: bool },
+    // takes up this BAR and the next one
+    Memory64 { base: u64, size: u64, prefetchable: bool },
+    Io { base: u16, size: u16 },
+}
+
+impl Bar {
+    pub fn base(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { base, .. } => base as u64,
+            Bar::Memory64 { base, .. } => base,
+            Bar::Io { base, .. } => base as u64,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } => size as u64,
+            Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+/// One function of a PCI device, as found by `init`.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    // None for unused BARs and the upper half of 64 bit ones
+    pub bars: [Option<Bar>; MAX_BARS],
+}
+
+impl PciDevice {
+    pub fn location(&self) -> Location {
+        Location { bus: self.bus, device: self.device, function: self.function }
+    }
+
+    pub fn read_config_u8(&self, offset: u8) -> u8 {
+        self.location().read_u8(offset)
+    }
+
+    /// `offset` must be 2 byte aligned.
+    pub fn read_config_u16(&self, offset: u8) -> u16 {
+        self.location().read_u16(offset)
+    }
+
+    /// `offset` must be 4 byte aligned.
+    pub fn read_config_u32(&self, offset: u8) -> u32 {
+        self.location().read_u32(offset)
+    }
+
+    pub fn write_config_u8(&self, offset: u8, value: u8) {
+        self.location().write_u8(offset, value)
+    }
+
+    pub fn write_config_u16(&self, offset: u8, value: u16) {
+        self.location().write_u16(offset, value)
+    }
+
+    pub fn write_config_u32(&self, offset: u8, value: u32) {
+        self.location().write_u32(offset, value)
+    }
+
+    /// Sets bits of the command register.
+    pub fn set_command_bits(&self, bits: u16) {
+        let command = self.read_config_u16(COMMAND);
+        self.write_config_u16(COMMAND, command | bits);
+    }
+
+    /// Lets the device do DMA.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command_bits(COMMAND_BUS_MASTER);
+    }
+
+    /// Makes the device answer to accesses to its memory BARs.
+    pub fn enable_memory_space(&self) {
+        self.set_command_bits(COMMAND_MEMORY_SPACE);
+    }
+
+    /// Makes the device answer to accesses to its I/O BARs.
+    pub fn enable_io_space(&self) {
+        self.set_command_bits(COMMAND_IO_SPACE);
+    }
+
+    /// Returns BAR `index` as decoded by `init`. None if it is unused or
+    /// the upper half of a 64 bit BAR.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).and_then(|bar| *bar)
+    }
+
+    /// Iterates over the capability list. Empty if the device has none.
+    pub fn capabilities(&self) -> Capabilities {
+        let has_list = self.read_config_u16(STATUS) & STATUS_CAPABILITIES != 0
+            && self.header_type & HEADER_TYPE_MASK <= HEADER_TYPE_BRIDGE;
+        let first = if has_list { self.read_config_u8(CAPABILITIES_POINTER) } else { 0 };
+        Capabilities { location: self.location(), next: first, remaining: MAX_CAPABILITIES }
+    }
+
+    /// Returns the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+}
+
+// 48 capabilities of 4 bytes fill the device specific part of the
+// configuration space, a longer list must be a loop
+const MAX_CAPABILITIES: usize = 48;
+
+/// An entry of the capability list. The capability specific registers
+/// start at `offset + 2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+pub struct Capabilities {
+    location: Location,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // the low two bits of the pointers are reserved
+        let offset = self.next & !0b11;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.location.read_u16(offset);
+        self.next = (header >> 8) as u8;
+        Some(Capability { id: header as u8, offset: offset })
+    }
+}
+
+impl fmt::Display for PciDevice {
+    // one line like lspci prints it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{} {:04x}:{:04x} {:02x}{:02x} {}",
+               self.bus, self.device, self.function, self.vendor_id, self.device_id,
+               self.class, self.subclass, class_name(self.class, self.subclass))?;
+        if self.interrupt_line != 0 && self.interrupt_line != 0xff {
+            write!(f, " irq {}", self.interrupt_line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Scans all buses and records the functions found. Prints them like lspci.
+pub fn init() {
+    assert_has_not_been_called!("pci::init must be called only once");
+
+    let mut scanner = Scanner { visited: [false; 256], count: 0 };
+    let (_, _, header_type) = identify(0, 0, 0);
+    if header_type & HEADER_MULTI_FUNCTION == 0 {
+        scanner.scan_bus(0);
+    } else {
+        // several host controllers, function n is responsible for bus n
+        for function in 0..8 {
+            if identify(0, 0, function).0 != NO_VENDOR {
+                scanner.scan_bus(function);
+            }
+        }
+    }
+
+    println!("pci: {} functions", scanner.count);
+    for device in devices() {
+        println!("    {}", device);
+        for (index, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = *bar {
+                println!("        BAR{}: {:?}", index, bar);
+            }
+        }
+        for capability in device.capabilities() {
+            println!("        capability {:#04x} at {:#04x}", capability.id, capability.offset);
+        }
+    }
+}
+
+struct Scanner {
+    // a misconfigured bridge could point back to a bus we scanned already
+    visited: [bool; 256],
+    count: usize,
+}
+
+impl Scanner {
+    fn scan_bus(&mut self, bus: u8) {
+        if self.visited[bus as usize] {
+            return;
+        }
+        self.visited[bus as usize] = true;
+
+        for device in 0..32 {
+            let (vendor_id, _, header_type) = identify(bus, device, 0);
+            if vendor_id == NO_VENDOR {
+                continue;
+            }
+            let functions = if header_type & HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+            for function in 0..functions {
+                if identify(bus, device, function).0 != NO_VENDOR {
+                    self.scan_function(bus, device, function);
+                }
+            }
+        }
+    }
+
+    fn scan_function(&mut self, bus: u8, device: u8, function: u8) {
+        let found = probe(bus, device, function);
+        if self.count < MAX_DEVICES {
+            DEVICES.lock()[self.count] = Some(found);
+            self.count += 1;
+        } else {
+            println!("pci: table full, ignoring {}", found);
+        }
+
+        let bridge = found.class == CLASS_BRIDGE && found.subclass == SUBCLASS_PCI_BRIDGE;
+        if bridge && found.header_type & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE {
+            let secondary = found.read_config_u8(SECONDARY_BUS);
+            self.scan_bus(secondary);
+        }
+    }
+}
+
+// vendor, device id and header type
+fn identify(bus: u8, device: u8, function: u8) -> (u16, u16, u8) {
+    let location = Location { bus: bus, device: device, function: function };
+    (location.read_u16(VENDOR_ID), location.read_u16(DEVICE_ID),
+     location.read_u8(HEADER_TYPE))
+}
+
+fn probe(bus: u8, device: u8, function: u8) -> PciDevice {
+    let (vendor_id, device_id, header_type) = identify(bus, device, function);
+    let location = Location { bus: bus, device: device, function: function };
+    let class = location.read_u32(CLASS_REVISION);
+    let mut found = PciDevice {
+        bus: bus,
+        device: device,
+        function: function,
+        vendor_id: vendor_id,
+        device_id: device_id,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type: header_type,
+        interrupt_line: 0,
+        bars: [None; MAX_BARS],
+    };
+    // bridges have only two BARs, CardBus bridges none
+    let bar_count = match header_type & HEADER_TYPE_MASK {
+        0 => 6,
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+    if bar_count != 0 {
+        found.interrupt_line = found.read_config_u8(INTERRUPT_LINE);
+    }
+    probe_bars(&mut found, bar_count);
+    found
+}
+
+// sizes the BARs by writing all ones and reading back which address bits
+// stick. decoding is off meanwhile, so the device doesn't answer at the
+// bogus addresses
+fn probe_bars(found: &mut PciDevice, bar_count: usize) {
+    let command = found.read_config_u16(COMMAND);
+    found.write_config_u16(COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < bar_count {
+        let offset = BAR0 + 4 * index as u8;
+        let original = found.read_config_u32(offset);
+        let size_mask = size_bar(found, offset, original);
+
+        if original & BAR_IO != 0 {
+            // the upper 16 bits may read back as 0, only the low ones count
+            let mask = size_mask & 0xfffc;
+            if mask != 0 {
+                found.bars[index] = Some(Bar::Io {
+                    base: (original & !0b11) as u16,
+                    size: (!mask as u16).wrapping_add(1),
+                });
+            }
+        } else if original & BAR_TYPE_MASK == BAR_TYPE_64 && index + 1 < bar_count {
+            let original_high = found.read_config_u32(offset + 4);
+            let size_mask_high = size_bar(found, offset + 4, original_high);
+            let mask = (size_mask_high as u64) << 32 | (size_mask & !0xf) as u64;
+            if mask != 0 {
+                found.bars[index] = Some(Bar::Memory64 {
+                    base: (original_high as u64) << 32 | (original & !0xf) as u64,
+                    size: !mask + 1,
+                    prefetchable: original & BAR_PREFETCHABLE != 0,
+                });
+            }
+            index += 1; // the upper half
+        } else if size_mask & !0xf != 0 {
+            found.bars[index] = Some(Bar::Memory32 {
+                base: original & !0xf,
+                size: !(size_mask & !0xf) + 1,
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+            });
+        }
+        index += 1;
+    }
+
+    found.write_config_u16(COMMAND, command);
+}
+
+// returns the read back all ones value and restores the original
+fn size_bar(found: &PciDevice, offset: u8, original: u32) -> u32 {
+    found.write_config_u32(offset, 0xffff_ffff);
+    let size_mask = found.read_config_u32(offset);
+    found.write_config_u32(offset, original);
+    size_mask
+}
+
+/// Iterator over the functions found by `init`.
+pub struct Devices {
+    index: usize,
+}
+
+impl Iterator for Devices {
+    type Item = PciDevice;
+
+    fn next(&mut self) -> Option<PciDevice> {
+        let devices = DEVICES.lock();
+        while self.index < MAX_DEVICES {
+            let device = devices[self.index];
+            self.index += 1;
+            if device.is_some() {
+                return device;
+            }
+        }
+        None
+    }
+}
+
+/// Returns all functions found by `init`.
+pub fn devices() -> Devices {
+    Devices { index: 0 }
+}
+
+/// Returns the first function with the given class and subclass.
+pub fn find(class: u8, subclass: u8) -> Option<PciDevice> {
+    devices().find(|device| device.class == class && device.subclass == subclass)
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
@@ -0,0 +1,213 @@
+// per-CPU data
+// `percpu!` puts a static into the .percpu section of the kernel image. that
+// section is only a template, every CPU gets a copy of it (its block) and
+// `PerCpu::get` finds the static in the block of the running CPU: IA32_GS_BASE
+// points at the block, whose first word (the `Cpu` header, in .percpu.header
+// before everything else) holds its own address, so `mov gs:[0]` gives the
+// base without a `rdmsr`. the BSP's block is a static area that `init` fills
+// first thing in `rust_main`, the APs' come from the heap in `new_for_ap`
+// before they start, so the BSP keeps its block across `smp::init`.
+// KERNEL_GS_BASE points at the block too, a `swapgs` finds it either way.
+// nothing may load a selector into gs afterwards, that clears the base
+
+use alloc::vec::Vec;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::msr::wrmsr;
+use acpi::madt::MAX_LOCAL_APICS;
+use cpu;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+pub const MAX_CPUS: usize = MAX_LOCAL_APICS;
+// the largest .percpu section the BSP's area holds
+const BSP_AREA_WORDS: usize = 1024;
+
+/// Declares per-CPU statics, `NAME.get()` is the running CPU's.
+macro_rules! percpu {
+    ($($(#[$attr:meta])* static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )+
+    };
+}
+
+/// A static of the .percpu section, see `percpu!`.
+pub struct PerCpu<T> {
+    template: T,
+}
+
+/// The start of every block.
+pub struct Cpu {
+    // the address of the block itself, for `base`
+    base: usize,
+    index: usize,
+    apic_id: u8,
+}
+
+#[link_section = ".percpu.header"]
+static HEADER: PerCpu<Cpu> = PerCpu::new(Cpu { base: 0, index: 0, apic_id: 0 });
+
+// the blocks by CPU index, 0 where there is none
+static BLOCKS: [AtomicUsize; MAX_CPUS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+// one past the highest index with a block
+static CPUS: AtomicUsize = AtomicUsize::new(0);
+
+static mut BSP_AREA: [u64; BSP_AREA_WORDS] = [0; BSP_AREA_WORDS];
+
+// the linker script puts them around the .percpu section
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(template: T) -> PerCpu<T> {
+        PerCpu { template: template }
+    }
+
+    /// The running CPU's copy.
+    pub fn get(&'static self) -> &T {
+        unsafe { &*((base() + self.offset()) as *const T) }
+    }
+
+    /// The copy of the CPU with the given index, None if it has no block.
+    pub fn of(&'static self, cpu: usize) -> Option<&T> {
+        match BLOCKS.get(cpu).map(|block| block.load(Ordering::SeqCst)) {
+            Some(0) | None => None,
+            Some(block) => Some(unsafe { &*((block + self.offset()) as *const T) }),
+        }
+    }
+
+    fn offset(&'static self) -> usize {
+        &self.template as *const T as usize - section_start()
+    }
+}
+
+impl Cpu {
+    /// 0 for the BSP, the APs count up in the order they started.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+}
+
+/// The running CPU.
+pub fn current() -> &'static Cpu {
+    HEADER.get()
+}
+
+/// The CPU with the given index, None if it has no block.
+pub fn cpu(index: usize) -> Option<&'static Cpu> {
+    HEADER.of(index)
+}
+
+/// One past the highest CPU index with a block, for going through all
+/// copies with `PerCpu::of`.
+pub fn cpus() -> usize {
+    CPUS.load(Ordering::SeqCst)
+}
+
+/// Sets up the BSP's block. The first call in `rust_main`, no per-CPU static
+/// may be touched before.
+pub fn init() {
+    assert_has_not_been_called!("percpu::init must be called only once");
+    assert!(section_size() <= BSP_AREA_WORDS * 8, "percpu: the BSP area is too small");
+    let block = unsafe { &mut BSP_AREA as *mut _ as usize };
+    let apic_id = (cpu::cpuid(1).ebx >> 24) as u8;
+    unsafe {
+        fill(block, 0, apic_id);
+        load(block);
+    }
+}
+
+/// Allocates the block of the AP with the given index, before it starts.
+/// Returns its address for `init_ap`. Blocks are never freed.
+pub fn new_for_ap(index: usize, apic_id: u8) -> usize {
+    assert!(index > 0 && index < MAX_CPUS, "percpu: bad CPU index {}", index);
+    let mut area: Vec<u64> = vec![0; (section_size() + 7) / 8];
+    let block = area.as_mut_ptr() as usize;
+    mem::forget(area);
+    unsafe { fill(block, index, apic_id) };
+    block
+}
+
+/// Points the GS bases of the running AP at its block, before it touches
+/// any per-CPU static.
+pub unsafe fn init_ap(block: usize) {
+    load(block);
+}
+
+// copies the template and sets up the header
+unsafe fn fill(block: usize, index: usize, apic_id: u8) {
+    assert_eq!(HEADER.offset(), 0, "percpu: the header isn't first in .percpu");
+    ptr::copy_nonoverlapping(section_start() as *const u8, block as *mut u8, section_size());
+    ptr::write(block as *mut Cpu, Cpu { base: block, index: index, apic_id: apic_id });
+    BLOCKS[index].store(block, Ordering::SeqCst);
+    if CPUS.load(Ordering::SeqCst) <= index {
+        CPUS.store(index + 1, Ordering::SeqCst);
+    }
+}
+
+unsafe fn load(block: usize) {
+    wrmsr(IA32_GS_BASE, block as u64);
+    wrmsr(IA32_KERNEL_GS_BASE, block as u64);
+}
+
+// the header's first word
+fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov $0, gs:[0]" : "=r"(base) ::: "intel", "volatile") };
+    base
+}
+
+fn section_start() -> usize {
+    unsafe { &__percpu_start as *const u8 as usize }
+}
+
+fn section_size() -> usize {
+    unsafe { &__percpu_end as *const u8 as usize - section_start() }
+}
+
+/// The BSP's copy of a per-CPU static has to be the one `of(0)` names and
+/// not the template, the APs' copies start from the template.
+#[cfg(debug_assertions)]
+pub fn test_percpu() {
+    percpu! {
+        static TEST_VALUE: AtomicUsize = AtomicUsize::new(7);
+    }
+
+    assert_eq!(current().index(), 0, "percpu: the BSP isn't CPU 0");
+    TEST_VALUE.get().store(42, Ordering::SeqCst);
+    assert_eq!(TEST_VALUE.of(0).map(|value| value.load(Ordering::SeqCst)), Some(42));
+    assert_eq!(TEST_VALUE.template.load(Ordering::SeqCst), 7, "percpu: the template changed");
+    for cpu in 1..cpus() {
+        if let Some(value) = TEST_VALUE.of(cpu) {
+            assert_eq!(value.load(Ordering::SeqCst), 7, "percpu: CPU {} shares the BSP's copy", cpu);
+        }
+    }
+    println!("percpu test passed");
+}
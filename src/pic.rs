@@ -0,0 +1,161 @@
+// legacy 8259 programmable interrupt controllers
+// the master handles IRQ 0-7, the slave IRQ 8-15 and is cascaded through
+// IRQ 2 of the master
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::{inb, outb};
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+// by default the PICs use vectors 0-15, which are the CPU exceptions
+pub const PIC1_OFFSET: u8 = 32;
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+const CASCADE_IRQ: u8 = 2;
+
+const ICW1_ICW4: u8 = 0x01;   // ICW4 will be sent
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;   // 8086/88 mode
+const END_OF_INTERRUPT: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+// IRQ 7 and 15 are the lowest priority lines of each PIC, the PIC signals
+// them when the real interrupt went away before it could be acknowledged
+pub const MASTER_SPURIOUS_IRQ: u8 = 7;
+pub const SLAVE_SPURIOUS_IRQ: u8 = 15;
+
+static SPURIOUS_MASTER: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_SLAVE: AtomicU64 = AtomicU64::new(0);
+
+// the PICs are slow, give them time between the init words by writing to an
+// unused port
+fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
+
+/// Remaps the PICs to vectors 32-47 and masks every IRQ except the cascade.
+pub fn init() {
+    unsafe {
+        // ICW1: start the initialization sequence
+        outb(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+
+        // ICW2: vector offsets
+        outb(PIC1_DATA, PIC1_OFFSET);
+        io_wait();
+        outb(PIC2_DATA, PIC2_OFFSET);
+        io_wait();
+
+        // ICW3: tell the master that the slave sits on IRQ 2 (as a bit mask),
+        // and the slave its cascade identity (as a number)
+        outb(PIC1_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(PIC2_DATA, CASCADE_IRQ);
+        io_wait();
+
+        // ICW4: 8086 mode
+        outb(PIC1_DATA, ICW4_8086);
+        io_wait();
+        outb(PIC2_DATA, ICW4_8086);
+        io_wait();
+
+        // mask everything until a driver clears its IRQ
+        outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+/// Masks every IRQ of both PICs, including the cascade. Used when the I/O
+/// APIC takes over. The PICs stay remapped, so a spurious IRQ 7/15 that is
+/// already in flight still lands on a vector outside the exceptions.
+pub fn disable() {
+    unsafe {
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+/// Returns the IDT vector the given IRQ is delivered on.
+pub fn vector(irq: u8) -> u8 {
+    PIC1_OFFSET + irq
+}
+
+// returns the data port of the PIC handling the irq and the bit within it
+fn mask_port_and_bit(irq: u8) -> (u16, u8) {
+    assert!(irq < 16, "invalid IRQ {}", irq);
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+/// Masks (disables) the given IRQ line.
+pub fn set_mask(irq: u8) {
+    let (port, bit) = mask_port_and_bit(irq);
+    unsafe {
+        let mask = inb(port) | (1 << bit);
+        outb(port, mask);
+    }
+}
+
+/// Unmasks (enables) the given IRQ line.
+pub fn clear_mask(irq: u8) {
+    let (port, bit) = mask_port_and_bit(irq);
+    unsafe {
+        let mask = inb(port) & !(1 << bit);
+        outb(port, mask);
+    }
+}
+
+/// Acknowledges the given IRQ. Interrupts from the slave have to be
+/// acknowledged at both PICs, since they passed through the master too.
+pub fn notify_end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_COMMAND, END_OF_INTERRUPT);
+        }
+        outb(PIC1_COMMAND, END_OF_INTERRUPT);
+    }
+}
+
+// reads the in-service registers of both PICs (slave in the high byte)
+fn in_service_register() -> u16 {
+    unsafe {
+        outb(PIC1_COMMAND, OCW3_READ_ISR);
+        outb(PIC2_COMMAND, OCW3_READ_ISR);
+        ((inb(PIC2_COMMAND) as u16) << 8) | inb(PIC1_COMMAND) as u16
+    }
+}
+
+/// Checks whether an IRQ 7 or 15 is spurious. A real one has its bit set
+/// in the in-service register and is acknowledged like any other IRQ. A
+/// spurious one is counted and must not get an EOI from the PIC that raised
+/// it, but a spurious IRQ 15 still passed through the master, which needs
+/// its EOI for the cascade line. The caller must not send an EOI for a
+/// spurious IRQ.
+pub fn is_spurious(irq: u8) -> bool {
+    assert!(irq == MASTER_SPURIOUS_IRQ || irq == SLAVE_SPURIOUS_IRQ);
+
+    if in_service_register() & (1 << irq) != 0 {
+        return false;
+    }
+
+    if irq == SLAVE_SPURIOUS_IRQ {
+        SPURIOUS_SLAVE.fetch_add(1, Ordering::Relaxed);
+        unsafe { outb(PIC1_COMMAND, END_OF_INTERRUPT) };
+    } else {
+        SPURIOUS_MASTER.fetch_add(1, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Returns the number of spurious interrupts seen from the (master, slave).
+pub fn spurious_counts() -> (u64, u64) {
+    (SPURIOUS_MASTER.load(Ordering::Relaxed), SPURIOUS_SLAVE.load(Ordering::Relaxed))
+}
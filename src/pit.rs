@@ -0,0 +1,152 @@
+// programmable interval timer (8253/8254)
+// channel 0 is wired to IRQ 0 and drives the system tick. channel 2 is
+// normally the PC speaker, but its output can be read back through port
+// 0x61, which makes it usable for busy waiting without interrupts
+
+use x86_64::instructions::port::{inb, outb};
+use interrupts::{self, IrqHandler};
+use time::TickSource;
+
+// the PIT input clock
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+pub const CHANNEL0_IRQ: u8 = 0;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+const SPEAKER_PORT: u16 = 0x61;
+const CHANNEL2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL2_OUTPUT: u8 = 1 << 5;
+
+// channel 0, access lobyte/hibyte, mode 3 (square wave), binary
+const CHANNEL0_SQUARE_WAVE: u8 = 0b00_11_011_0;
+// channel 2, access lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONE_SHOT: u8 = 0b10_11_000_0;
+// channel 2, access lobyte/hibyte, mode 3 (square wave), binary
+const CHANNEL2_SQUARE_WAVE: u8 = 0b10_11_011_0;
+
+/// Programs channel 0 to fire with the given divisor of the base frequency.
+/// Returns the resulting interrupt frequency in Hz.
+pub fn init_channel0(divisor: u16) -> u32 {
+    // a divisor of 0 means 65536
+    let divisor_value = if divisor == 0 { 65536 } else { divisor as u32 };
+    unsafe {
+        outb(COMMAND, CHANNEL0_SQUARE_WAVE);
+        outb(CHANNEL0_DATA, divisor as u8);
+        outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+    }
+    BASE_FREQUENCY / divisor_value
+}
+
+/// Returns the divisor that comes closest to the given frequency.
+pub fn divisor_for(frequency_hz: u32) -> u16 {
+    let divisor = (BASE_FREQUENCY + frequency_hz / 2) / frequency_hz;
+    if divisor > 0xffff { 0 } else { divisor as u16 }
+}
+
+/// Busy waits for `count` cycles of the base frequency (at most 65535, about
+/// 55 ms) using channel 2. Works with interrupts disabled.
+pub fn wait_cycles(count: u16) {
+    unsafe {
+        // gate on, speaker off
+        let control = inb(SPEAKER_PORT) & !SPEAKER_ENABLE;
+        outb(SPEAKER_PORT, control | CHANNEL2_GATE);
+
+        // in mode 0 the output goes low when the count is written and high
+        // again when it reaches 0
+        outb(COMMAND, CHANNEL2_ONE_SHOT);
+        outb(CHANNEL2_DATA, count as u8);
+        outb(CHANNEL2_DATA, (count >> 8) as u8);
+        while inb(SPEAKER_PORT) & CHANNEL2_OUTPUT == 0 {}
+
+        outb(SPEAKER_PORT, control & !CHANNEL2_GATE);
+    }
+}
+
+/// Busy waits for the given number of milliseconds using channel 2.
+pub fn busy_wait_ms(ms: u32) {
+    const CYCLES_PER_MS: u32 = BASE_FREQUENCY / 1000;
+    const MAX_CHUNK_MS: u32 = 50;
+
+    let mut remaining = ms;
+    while remaining > 0 {
+        let chunk = ::core::cmp::min(remaining, MAX_CHUNK_MS);
+        wait_cycles((chunk * CYCLES_PER_MS) as u16);
+        remaining -= chunk;
+    }
+}
+
+/// Counts how often port 0x61 can be read during `count` cycles of the base
+/// frequency (at most 65535), to calibrate `wait_port_reads`.
+pub fn measure_port_reads(count: u16) -> u64 {
+    let mut reads = 0;
+    unsafe {
+        let control = inb(SPEAKER_PORT) & !SPEAKER_ENABLE;
+        outb(SPEAKER_PORT, control | CHANNEL2_GATE);
+        outb(COMMAND, CHANNEL2_ONE_SHOT);
+        outb(CHANNEL2_DATA, count as u8);
+        outb(CHANNEL2_DATA, (count >> 8) as u8);
+        while inb(SPEAKER_PORT) & CHANNEL2_OUTPUT == 0 {
+            reads += 1;
+        }
+        outb(SPEAKER_PORT, control & !CHANNEL2_GATE);
+    }
+    reads
+}
+
+/// Busy waits by reading port 0x61 `count` times. Port reads take about the
+/// same time on every CPU speed, and this leaves channel 2 alone.
+pub fn wait_port_reads(count: u64) {
+    for _ in 0..count {
+        unsafe { inb(SPEAKER_PORT) };
+    }
+}
+
+/// Starts a square wave of `frequency_hz` on channel 2 and connects it to
+/// the speaker. Returns the previous value of port 0x61 for `stop_tone`.
+pub fn start_tone(frequency_hz: u32) -> u8 {
+    let divisor = divisor_for(frequency_hz);
+    unsafe {
+        let control = inb(SPEAKER_PORT);
+        outb(COMMAND, CHANNEL2_SQUARE_WAVE);
+        outb(CHANNEL2_DATA, divisor as u8);
+        outb(CHANNEL2_DATA, (divisor >> 8) as u8);
+        outb(SPEAKER_PORT, control | CHANNEL2_GATE | SPEAKER_ENABLE);
+        control
+    }
+}
+
+/// Disconnects the speaker again and restores port 0x61.
+pub fn stop_tone(previous_control: u8) {
+    unsafe { outb(SPEAKER_PORT, previous_control & !SPEAKER_ENABLE) };
+}
+
+/// Channel 0 as a `time::TickSource`. Always there, but the worst periodic
+/// timer apart from the RTC.
+pub struct PitTick;
+
+pub static PIT_TICK: PitTick = PitTick;
+
+impl TickSource for PitTick {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn start(&self, hz: u32, handler: IrqHandler) -> Result<u32, ()> {
+        let hz = init_channel0(divisor_for(hz));
+        interrupts::register_irq(CHANNEL0_IRQ, handler).map_err(|_| ())?;
+        Ok(hz)
+    }
+
+    // masking the IRQ is enough, the channel just keeps counting
+    fn stop(&self, handler: IrqHandler) {
+        let _ = interrupts::unregister_irq(CHANNEL0_IRQ, handler);
+    }
+}
@@ -0,0 +1,144 @@
+// rebooting and powering off
+// there is no single way to reboot that works everywhere, so `reboot` goes
+// from the friendliest to the most brutal: the 8042 reset line, the ACPI
+// reset register, and a triple fault, which always resets the CPU.
+// `shutdown` enters ACPI sleep state S5 through the PM1 control registers,
+// with the sleep types from the \_S5 package of the DSDT
+
+use x86_64::instructions::port::{inw, outb, outw, outl};
+use acpi::{self, aml};
+use acpi::fadt::{self, AddressSpace};
+use pci::Location;
+use i8042;
+use pit;
+
+// how long each method gets before the next one is tried
+const RESET_WAIT_MS: u32 = 50;
+
+// PM1 control register
+const SCI_ENABLED: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_TYPE_MASK: u16 = 0b111 << SLEEP_TYPE_SHIFT;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+// what QEMU's DSDT says for S5, and its PM1a control ports on the PIIX4
+// (pc) and ICH9 (q35) machines, for when the tables are no help
+const QEMU_S5_SLEEP_TYPE: u8 = 0;
+const QEMU_PM1A_CONTROL_PORTS: [u16; 2] = [0xb004, 0x604];
+
+// polls of the control register after asking the firmware for ACPI mode
+const ACPI_ENABLE_TIMEOUT: usize = 1_000_000;
+
+/// Reboots the machine. Can be called from any context, interrupts are
+/// disabled first.
+pub fn reboot() -> ! {
+    use x86_64::instructions::interrupts;
+
+    unsafe { interrupts::disable() };
+    println!("power: rebooting");
+
+    i8042::pulse_reset();
+    pit::busy_wait_ms(RESET_WAIT_MS);
+
+    if let Some((register, value)) = fadt::reset_register() {
+        write_reset_register(register, value);
+        pit::busy_wait_ms(RESET_WAIT_MS);
+    }
+
+    triple_fault()
+}
+
+fn write_reset_register(register: fadt::GenericAddress, value: u8) {
+    match register.space {
+        AddressSpace::Io => unsafe {
+            match register.bit_width {
+                16 => outw(register.address as u16, value as u16),
+                32 => outl(register.address as u16, value as u32),
+                _ => outb(register.address as u16, value),
+            }
+        },
+        // bus 0, the device and function are in the upper words
+        AddressSpace::PciConfig => {
+            let location = Location {
+                bus: 0,
+                device: (register.address >> 32) as u8,
+                function: (register.address >> 16) as u8,
+            };
+            location.write_u8(register.address as u8, value);
+        }
+        // a memory register would have to be mapped first, which needs the
+        // memory controller. the triple fault does the job as well
+        _ => println!("power: can't use the reset register in {:?}", register.space),
+    }
+}
+
+// with an empty IDT the breakpoint can't be delivered, neither can the
+// resulting double fault, and the CPU shuts down, which resets it
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{DescriptorTablePointer, lidt};
+
+    let empty = DescriptorTablePointer { base: 0, limit: 0 };
+    unsafe {
+        lidt(&empty);
+        asm!("int3" :::: "volatile");
+    }
+    // not reached
+    ::cpu::halt_forever()
+}
+
+/// Powers the machine off through ACPI. If that doesn't work, halts.
+pub fn shutdown() -> ! {
+    use x86_64::instructions::interrupts;
+
+    unsafe { interrupts::disable() };
+    println!("power: shutting down");
+
+    let sleep_types = acpi::dsdt().and_then(|dsdt| aml::find_s5(dsdt.data()));
+    match (fadt::pm1_control_ports(), sleep_types) {
+        (Some((pm1a, pm1b)), Some((type_a, type_b))) => {
+            enable_acpi_mode(pm1a);
+            enter_s5(pm1a, type_a);
+            if let Some(pm1b) = pm1b {
+                enter_s5(pm1b, type_b);
+            }
+        }
+        (ports, _) => {
+            println!("power: no \\_S5 in the ACPI tables, using the QEMU values");
+            match ports {
+                Some((pm1a, _)) => {
+                    enable_acpi_mode(pm1a);
+                    enter_s5(pm1a, QEMU_S5_SLEEP_TYPE);
+                }
+                None => for &port in QEMU_PM1A_CONTROL_PORTS.iter() {
+                    enter_s5(port, QEMU_S5_SLEEP_TYPE);
+                },
+            }
+        }
+    }
+    pit::busy_wait_ms(RESET_WAIT_MS);
+    println!("power: still running, halting instead");
+    ::cpu::halt_forever()
+}
+
+// the sleep registers are ignored until the firmware hands over to ACPI
+fn enable_acpi_mode(pm1a: u16) {
+    if unsafe { inw(pm1a) } & SCI_ENABLED != 0 {
+        return;
+    }
+    if let Some((port, value)) = fadt::acpi_enable_command() {
+        unsafe { outb(port, value) };
+        for _ in 0..ACPI_ENABLE_TIMEOUT {
+            if unsafe { inw(pm1a) } & SCI_ENABLED != 0 {
+                return;
+            }
+        }
+        println!("power: the firmware didn't switch to ACPI mode");
+    }
+}
+
+fn enter_s5(port: u16, sleep_type: u8) {
+    unsafe {
+        let control = inw(port) & !SLEEP_TYPE_MASK;
+        outw(port, control | (sleep_type as u16) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+    }
+}
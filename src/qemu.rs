@@ -0,0 +1,43 @@
+// QEMU's isa-debug-exit device, for scripted test runs
+// with `-device isa-debug-exit,iobase=0xf4,iosize=0x04` a write to the port
+// ends QEMU with the exit status (value << 1) | 1. without the device (or on
+// real hardware) nothing listens on the port and the write is harmless
+
+use x86_64::instructions::port::{outb, outw, outl};
+use cmdline;
+use power;
+
+// must match the iobase and iosize arguments of the device
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+pub const DEBUG_EXIT_PORT_SIZE: usize = 4;
+
+/// The values written to the port. 0 is avoided, QEMU would exit with 1,
+/// which also means it failed to start. Success ends in exit status 33,
+/// failure in 35.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Returns whether this is a scripted test run: built with the `test-mode`
+/// feature or booted with `test` on the command line.
+pub fn test_mode() -> bool {
+    cfg!(feature = "test-mode") || cmdline::has("test")
+}
+
+/// Ends QEMU with the given exit code. Without a debug exit device it
+/// powers off through ACPI, which loses the code, and halts if that fails
+/// too.
+pub fn exit(code: ExitCode) -> ! {
+    let value = code as u32;
+    unsafe {
+        match DEBUG_EXIT_PORT_SIZE {
+            1 => outb(DEBUG_EXIT_PORT, value as u8),
+            2 => outw(DEBUG_EXIT_PORT, value as u16),
+            _ => outl(DEBUG_EXIT_PORT, value),
+        }
+    }
+    power::shutdown()
+}
@@ -0,0 +1,218 @@
+// kernel random numbers
+// RDRAND when the CPU has it. otherwise the ChaCha20 keystream, keyed from
+// the TSC and the RTC at boot. keyboard interrupts fold their TSC value
+// into a jitter pool that goes into the key at every new block. that makes
+// the output hard to guess for addresses and canaries, but it is no vetted
+// cryptographic generator
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use sync::IrqMutex;
+use cpu;
+use rtc;
+
+// the number of tries the Intel DRNG guide recommends before giving up
+const RDRAND_RETRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Rdrand,
+    ChaCha,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Rdrand => write!(f, "RDRAND"),
+            Source::ChaCha => write!(f, "ChaCha20 seeded from TSC, RTC and keyboard jitter"),
+        }
+    }
+}
+
+static SOURCE: Once<Source> = Once::new();
+static CHACHA: IrqMutex<ChaCha> = IrqMutex::new(ChaCha::new());
+static JITTER: AtomicU64 = AtomicU64::new(0);
+
+/// Chooses the source and prints it. Random numbers requested earlier
+/// choose it too, just silently.
+pub fn init() {
+    println!("rand: using {}", source());
+}
+
+/// Returns the source behind `u64`.
+pub fn source() -> Source {
+    *SOURCE.call_once(|| {
+        // the fallback is needed even with RDRAND, it may run dry
+        CHACHA.lock().seed();
+        if has_rdrand() && rdrand().is_some() {
+            Source::Rdrand
+        } else {
+            Source::ChaCha
+        }
+    })
+}
+
+/// Returns a random number.
+pub fn u64() -> u64 {
+    if source() == Source::Rdrand {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    CHACHA.lock().next_u64()
+}
+
+/// Fills `buffer` with random bytes.
+pub fn fill_bytes(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let value = u64();
+        for (index, byte) in chunk.iter_mut().enumerate() {
+            *byte = (value >> (index * 8)) as u8;
+        }
+    }
+}
+
+/// Mixes the time of an interrupt into the ChaCha key. Called from the
+/// keyboard interrupt, where the timing depends on a human.
+pub fn add_interrupt_timing() {
+    // a lost update only loses a bit of entropy, no need for a CAS loop
+    let pool = JITTER.load(Ordering::Relaxed);
+    JITTER.store(pool.rotate_left(7) ^ cpu::rdtsc(), Ordering::Relaxed);
+}
+
+fn has_rdrand() -> bool {
+    cpu::cpuid(1).ecx & (1 << 30) != 0
+}
+
+// RDRAND clears the carry flag when the generator had nothing ready
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let success: u8;
+        unsafe { asm!("rdrand $0; setc $1" : "=r"(value), "=r"(success) :: "cc" : "volatile") };
+        if success != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+struct ChaCha {
+    key: [u32; 8],
+    counter: u64,
+    block: [u32; 16],
+    // words of `block` already handed out
+    used: usize,
+    // the key word pair `mix` goes into next
+    mix_position: usize,
+}
+
+impl ChaCha {
+    const fn new() -> ChaCha {
+        ChaCha {
+            key: [0; 8],
+            counter: 0,
+            block: [0; 16],
+            used: 16,
+            mix_position: 0,
+        }
+    }
+
+    fn seed(&mut self) {
+        let time = rtc::now();
+        let time = (time.year as u64) << 40 | (time.month as u64) << 32 | (time.day as u64) << 24
+            | (time.hour as u64) << 16 | (time.minute as u64) << 8 | time.second as u64;
+        self.mix(time);
+        // the gaps between the reads vary a little with caches and SMIs
+        for _ in 0..4 {
+            self.mix(cpu::rdtsc());
+            rtc::now();
+        }
+        self.used = 16;
+    }
+
+    fn mix(&mut self, value: u64) {
+        // a different pair every time, so equal values don't cancel out
+        self.key[self.mix_position] ^= value as u32;
+        self.key[self.mix_position + 1] ^= (value >> 32) as u32;
+        self.mix_position = (self.mix_position + 2) % self.key.len();
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        if self.used + 2 > self.block.len() {
+            let jitter = JITTER.swap(0, Ordering::Relaxed);
+            if jitter != 0 {
+                self.mix(jitter);
+            }
+            self.refill();
+        }
+        let value = (self.block[self.used + 1] as u64) << 32 | self.block[self.used] as u64;
+        self.used += 2;
+        value
+    }
+
+    fn refill(&mut self) {
+        let mut input = [0; 16];
+        input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        // words 14 and 15 are the nonce, which stays zero
+
+        let mut state = input;
+        for _ in 0..10 {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+        for (word, input) in state.iter_mut().zip(input.iter()) {
+            *word = word.wrapping_add(*input);
+        }
+        self.block = state;
+        self.counter = self.counter.wrapping_add(1);
+        self.used = 0;
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// counts the one bits in 4 KiB from `next`. for random bits the count is
+// 16384 with a standard deviation of about 90, so being more than 500 off
+// is next to impossible
+#[cfg(debug_assertions)]
+fn check_monobit<F: FnMut() -> u64>(name: &str, mut next: F) {
+    const WORDS: usize = 4096 / 8;
+    let ones: u32 = (0..WORDS).map(|_| next().count_ones()).sum();
+    let expected = (WORDS * 64 / 2) as u32;
+    let deviation = if ones > expected { ones - expected } else { expected - ones };
+    assert!(deviation < 500, "rand: {} produced {} one bits out of {}", name, ones, WORDS * 64);
+    println!("rand: {} monobit count {} of {}", name, ones, WORDS * 64);
+}
+
+/// Runs the monobit test on `u64` and, if that is RDRAND, on the ChaCha
+/// fallback as well.
+#[cfg(debug_assertions)]
+pub fn test_monobit() {
+    check_monobit("u64", u64);
+    if source() == Source::Rdrand {
+        check_monobit("ChaCha20", || CHACHA.lock().next_u64());
+    }
+}
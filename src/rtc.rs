@@ -0,0 +1,265 @@
+// CMOS real-time clock
+// the registers are reached through the `cmos` layer. the firmware chooses
+// whether the values are BCD or binary and whether hours are 12 or 24 hour
+// based, register B tells which
+
+use core::fmt;
+use spin::Once;
+use acpi;
+use cmos::{self, Cmos};
+use interrupts::{self, InterruptContext, IrqHandler};
+use time::TickSource;
+
+pub const RTC_IRQ: u8 = 8;
+pub const DEFAULT_PERIODIC_HZ: u32 = 1024;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_FLAG: u8 = 1 << 6;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+static PERIODIC_HANDLER: Once<IrqHandler> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day,
+               self.hour, self.minute, self.second)
+    }
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+impl DateTime {
+    /// Returns the seconds since 1970-01-01 00:00:00 UTC (the RTC is
+    /// assumed to run on UTC). 64 bits, so 2038 is no problem.
+    pub fn to_unix(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days * SECONDS_PER_DAY + self.hour as i64 * 3600 + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// The inverse of `to_unix`, for the years 0 to 65535.
+    pub fn from_unix(timestamp: i64) -> DateTime {
+        let days = floor_div(timestamp, SECONDS_PER_DAY);
+        let seconds = timestamp - days * SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+fn floor_div(value: i64, divisor: i64) -> i64 {
+    if value >= 0 { value / divisor } else { (value - divisor + 1) / divisor }
+}
+
+// days since 1970-01-01 in the proleptic Gregorian calendar. counts in eras
+// of 400 years (146097 days) that start on March 1st, so the leap day is
+// the last day of the year (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = floor_div(year, 400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// the inverse of `days_from_civil`, returns year, month and day
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = floor_div(days, 146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// the registers as read, before any conversion
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn update_in_progress(cmos: &Cmos) -> bool {
+    cmos.read(cmos::STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw(cmos: &Cmos, century_register: Option<u8>) -> RawTime {
+    while update_in_progress(cmos) {}
+    RawTime {
+        second: cmos.read(cmos::SECONDS),
+        minute: cmos.read(cmos::MINUTES),
+        hour: cmos.read(cmos::HOURS),
+        day: cmos.read(cmos::DAY),
+        month: cmos.read(cmos::MONTH),
+        year: cmos.read(cmos::YEAR),
+        century: century_register.map(|r| cmos.read(r)).unwrap_or(0),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the current date and time. The RTC may update between two register
+/// reads, so it reads until two consecutive reads agree.
+pub fn now() -> DateTime {
+    let century_register = acpi::fadt::century_register();
+
+    let cmos = cmos::lock();
+    let mut raw = read_raw(&cmos, century_register);
+    loop {
+        let again = read_raw(&cmos, century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = cmos.read(cmos::STATUS_B);
+
+    // the PM flag is not part of the BCD value
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = raw.hour & !HOUR_PM;
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 { value } else { from_bcd(value) }
+    };
+    hour = convert(hour);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 am is 0:xx, 12 pm is 12:xx
+        hour = if pm { hour % 12 + 12 } else { hour % 12 };
+    }
+
+    let century = match century_register {
+        Some(_) => convert(raw.century) as u16,
+        None => 20,
+    };
+
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour: hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/// Returns the rate setting for register A that gives `hz` periodic
+/// interrupts. The RTC divides 32768 Hz by powers of two, so only powers of
+/// two from 2 to 8192 Hz work.
+pub fn rate_for(hz: u32) -> Option<u8> {
+    (3..16).find(|&rate| 32768 >> (rate - 1) == hz)
+}
+
+/// Starts periodic interrupts on IRQ 8 at `hz` (see `rate_for`) and calls
+/// `handler` for each of them, after acknowledging the interrupt. The
+/// handler can't change after the first start.
+pub fn start_periodic(hz: u32, handler: IrqHandler) -> Result<(), ()> {
+    let rate = rate_for(hz).ok_or(())?;
+    PERIODIC_HANDLER.call_once(|| handler);
+    interrupts::register_irq(RTC_IRQ, rtc_interrupt).map_err(|_| ())?;
+
+    let mut cmos = cmos::lock();
+    let status_a = cmos.read(cmos::STATUS_A);
+    cmos.write(cmos::STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+    let status_b = cmos.read(cmos::STATUS_B);
+    cmos.write(cmos::STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+    // a pending interrupt would keep IRQ 8 from ever firing again
+    cmos.read(cmos::STATUS_C);
+    Ok(())
+}
+
+/// Switches the periodic interrupts off again.
+pub fn stop_periodic() {
+    let _ = interrupts::unregister_irq(RTC_IRQ, rtc_interrupt);
+    let mut cmos = cmos::lock();
+    let status_b = cmos.read(cmos::STATUS_B);
+    cmos.write(cmos::STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+}
+
+/// The periodic interrupt as a `time::TickSource`, only used when asked
+/// for. It always runs at `DEFAULT_PERIODIC_HZ`, which isn't a whole number
+/// of milliseconds, so it tests that the uptime doesn't assume one.
+pub struct RtcTick;
+
+pub static RTC_TICK: RtcTick = RtcTick;
+
+impl TickSource for RtcTick {
+    fn name(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn rating(&self) -> u32 {
+        50
+    }
+
+    fn start(&self, _hz: u32, handler: IrqHandler) -> Result<u32, ()> {
+        start_periodic(DEFAULT_PERIODIC_HZ, handler)?;
+        Ok(DEFAULT_PERIODIC_HZ)
+    }
+
+    fn stop(&self, _handler: IrqHandler) {
+        stop_periodic();
+    }
+}
+
+fn rtc_interrupt(context: &mut InterruptContext) {
+    // the RTC raises no further interrupts until register C is read
+    let status_c = cmos::read(cmos::STATUS_C);
+    if status_c & STATUS_C_PERIODIC_FLAG != 0 {
+        if let Some(handler) = PERIODIC_HANDLER.try() {
+            handler(context);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+pub fn test_unix_time() {
+    let date = |year, month, day, hour, minute, second| DateTime {
+        year: year, month: month, day: day, hour: hour, minute: minute, second: second,
+    };
+    let known = [
+        (date(1970, 1, 1, 0, 0, 0), 0),
+        (date(2000, 2, 29, 12, 0, 0), 951_825_600),
+        (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+        // one second after the 32 bit time_t overflows
+        (date(2038, 1, 19, 3, 14, 8), 2_147_483_648),
+        (date(2100, 3, 1, 0, 0, 0), 4_107_542_400),
+    ];
+    for &(date, timestamp) in known.iter() {
+        assert_eq!(date.to_unix(), timestamp, "{} to Unix time", date);
+        assert_eq!(DateTime::from_unix(timestamp), date, "{} from Unix time", timestamp);
+    }
+    println!("unix time test passed");
+}
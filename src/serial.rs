@@ -0,0 +1,267 @@
+// 16550 UART serial ports
+// COM1 carries the kernel output for headless runs (`-serial stdio`), the
+// `serial_print!` macros write to it through an IrqMutex. everything here
+// is static, so it works before the heap and from the panic handler.
+// received bytes are moved into a ring by the IRQ 4 handler
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+use x86_64::instructions::port::{inb, outb};
+use sync::{IrqMutex, WaitQueue};
+use interrupts::{self, InterruptContext};
+use input::LineEditor;
+
+pub const COM1_IRQ: u8 = 4;
+
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+
+// register offsets from the base port. with DLAB set in the line control
+// register the first two are the divisor latch
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_CONTROL_8N1: u8 = 0b11;
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+// enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE_CLEAR_14: u8 = 0xc7;
+// DTR, RTS and OUT2 (which gates the IRQ line)
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+const RECEIVED_DATA_INTERRUPT: u8 = 1 << 0;
+
+// the UART clock is 115200 Hz divided by the divisor
+const BAUD_DIVISOR: u16 = 1;
+
+static COM1_PORT: IrqMutex<SerialPort> = IrqMutex::new(SerialPort::new(COM1));
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static RECEIVED: ByteQueue = ByteQueue::new();
+static RECEIVE_WAITERS: WaitQueue = WaitQueue::new();
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base: base }
+    }
+
+    /// Sets the port to 115200 baud, 8N1 with FIFOs. Returns false if the
+    /// UART fails the loopback test, i.e. there is none.
+    pub fn init(&mut self) -> bool {
+        unsafe {
+            outb(self.base + INTERRUPT_ENABLE, 0);
+            outb(self.base + LINE_CONTROL, LINE_CONTROL_DLAB);
+            outb(self.base + DATA, BAUD_DIVISOR as u8);
+            outb(self.base + INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+            outb(self.base + LINE_CONTROL, LINE_CONTROL_8N1);
+            outb(self.base + FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+
+            // a byte sent in loopback mode has to come back
+            outb(self.base + MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+            outb(self.base + DATA, 0xae);
+            let present = inb(self.base + DATA) == 0xae;
+            outb(self.base + MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+            present
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            // bounded wait, so a missing UART can't hang us
+            for _ in 0..100_000 {
+                if inb(self.base + LINE_STATUS) & TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+
+    /// Returns the next byte from the receive FIFO, if there is one.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            if inb(self.base + LINE_STATUS) & DATA_READY != 0 {
+                Some(inb(self.base + DATA))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Raises the port's IRQ whenever a byte arrives.
+    pub fn enable_receive_interrupt(&mut self) {
+        unsafe { outb(self.base + INTERRUPT_ENABLE, RECEIVED_DATA_INTERRUPT) };
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // terminals want a carriage return before the line feed
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte)
+        }
+        Ok(())
+    }
+}
+
+// writes straight to the port without any lock, for handlers that can
+// interrupt code holding the other locks (NMI, machine check, panic).
+// concurrent writers may interleave their characters
+pub struct RawWriter;
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        SerialPort::new(COM1).write_str(s)
+    }
+}
+
+/// Initializes COM1. Called first thing in `rust_main`.
+pub fn init() {
+    assert_has_not_been_called!("serial::init must be called only once");
+    let present = COM1_PORT.lock().init();
+    PRESENT.store(present, Ordering::SeqCst);
+}
+
+/// Returns whether `init` found a UART at COM1.
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::SeqCst)
+}
+
+/// Registers the IRQ 4 handler and enables the receive interrupt of COM1.
+/// Needs `interrupts::init`.
+pub fn enable_receive() {
+    if !is_present() {
+        return;
+    }
+    interrupts::register_irq(COM1_IRQ, com1_interrupt)
+        .expect("could not register the COM1 interrupt");
+    COM1_PORT.lock().enable_receive_interrupt();
+}
+
+fn com1_interrupt(_context: &mut InterruptContext) {
+    // reading the data register acknowledges the interrupt. the lock isn't
+    // needed (and might be held by the interrupted print), the receive side
+    // is only touched here
+    let mut port = SerialPort::new(COM1);
+    while let Some(byte) = port.try_read_byte() {
+        if !RECEIVED.push(byte) {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    RECEIVE_WAITERS.notify_all();
+}
+
+/// Returns the next received byte, or `None` if none is queued.
+pub fn read_byte() -> Option<u8> {
+    RECEIVED.pop()
+}
+
+/// Reads a byte straight from the UART, for code that runs with interrupts
+/// off and can't wait for the receive interrupt.
+pub fn poll_byte() -> Option<u8> {
+    if !is_present() {
+        return None;
+    }
+    SerialPort::new(COM1).try_read_byte()
+}
+
+/// Blocks until a byte arrives and returns it, like `keyboard::next_event`.
+pub fn next_byte() -> u8 {
+    let mut byte = None;
+    RECEIVE_WAITERS.wait_until(|| {
+        byte = read_byte();
+        byte.is_some()
+    });
+    byte.unwrap()
+}
+
+/// Reads a line from COM1 into `buffer`, echoing it back and handling
+/// backspace. Returns the length of the line without the line end. Input
+/// beyond the end of the buffer is dropped.
+pub fn read_line(buffer: &mut [u8]) -> usize {
+    let mut editor = LineEditor::new(buffer);
+    loop {
+        let byte = next_byte();
+        if editor.feed(byte as char, &mut |s: &str| serial_print!("{}", s)) {
+            return editor.len();
+        }
+    }
+}
+
+/// Returns the number of received bytes dropped because the ring was full.
+pub fn dropped_bytes() -> u64 {
+    DROPPED_BYTES.load(Ordering::Relaxed)
+}
+
+// single producer (the interrupt handler), single consumer ring like the
+// keyboard event queue
+const QUEUE_SIZE: usize = 256;
+
+struct ByteQueue {
+    buffer: UnsafeCell<[u8; QUEUE_SIZE]>,
+    head: AtomicUsize,  // next slot to read
+    tail: AtomicUsize,  // next slot to write
+}
+
+unsafe impl Sync for ByteQueue {}
+
+impl ByteQueue {
+    const fn new() -> ByteQueue {
+        ByteQueue {
+            buffer: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // returns false if the queue is full
+    fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[tail] = byte };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buffer.get())[head] };
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        Some(byte)
+    }
+}
+
+macro_rules! serial_println {
+    ($fmt:expr) => (serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (serial_print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+macro_rules! serial_print {
+    ($($arg:tt)*) => ({
+        $crate::serial::print(format_args!($($arg)*));
+    });
+}
+
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    COM1_PORT.lock().write_fmt(args).unwrap();
+}
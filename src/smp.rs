@@ -0,0 +1,193 @@
+// starting the application processors (APs)
+// the MADT lists a local APIC per CPU. `init` copies the real mode code of
+// ap_trampoline.asm to `TRAMPOLINE`, below 1 MiB where a startup IPI can
+// point, and starts the APs one by one the MP specification way: the warm
+// reset vector and CMOS shutdown code for the older CPUs that reset on
+// INIT, then INIT, 10 ms, startup IPI, 200 us, startup IPI. every AP gets a
+// stack, a GDT with its own TSS and a per-CPU block beforehand, that the
+// trampoline data passes on. it goes to long mode on the kernel's page
+// tables, loads its GDT, the shared IDT and enables its local APIC, reports
+// in by counting `ONLINE` up and parks in a `hlt` loop. nothing runs on the
+// APs yet, they only answer TLB shootdowns (see `tlb`), the BSP still does
+// all the work
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::control_regs;
+use memory::{MemoryController, PAGE_SIZE};
+use gdt::{self, Gdt};
+use acpi;
+use percpu;
+use task;
+use apic;
+use cmos;
+use cmdline;
+use interrupts;
+use time;
+use cpu;
+
+/// Must match `TRAMPOLINE` in ap_trampoline.asm.
+const TRAMPOLINE: usize = 0x8000;
+// segment:offset in the BIOS data area, where the BIOS jumps after an INIT
+// with the shutdown code set
+const WARM_RESET_VECTOR: usize = 0x467;
+const AP_STACK_PAGES: usize = 4;
+// how long an AP gets to report in after the second startup IPI
+const STARTUP_TIMEOUT_US: u32 = 100_000;
+const STARTUP_POLL_US: u32 = 100;
+
+// the BSP counts, the APs add themselves
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Must match `ap_trampoline_data` in ap_trampoline.asm.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    gdt: u64,
+    percpu: u64,
+}
+
+// in ap_trampoline.asm
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_data: u8;
+}
+
+/// Starts the enabled APs of the MADT. Needs the local APIC, without it or
+/// with `nosmp` on the command line only the BSP runs. An AP that doesn't
+/// report in ends the startup, a late one would take the next one's stack.
+pub fn init(memory_controller: &mut MemoryController) {
+    assert_has_not_been_called!("smp::init must be called only once");
+
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return,
+    };
+    let usable = madt.usable_cpus();
+    if usable <= 1 {
+        return;
+    }
+    if !apic::is_enabled() {
+        println!("smp: {} CPUs detected, but no local APIC, only the BSP runs", usable);
+        return;
+    }
+    if cmdline::has("nosmp") {
+        println!("smp: {} CPUs detected, disabled on the command line", usable);
+        return;
+    }
+    // the trampoline loads it in real mode, 32 bits at most
+    let cr3 = control_regs::cr3().0 as usize;
+    if cr3 >> 32 != 0 {
+        println!("smp: the page tables are above 4 GiB, only the BSP runs");
+        return;
+    }
+
+    let (start, size, data_offset) = unsafe {
+        let start = &ap_trampoline_start as *const u8 as usize;
+        (start,
+         &ap_trampoline_end as *const u8 as usize - start,
+         &ap_trampoline_data as *const u8 as usize - start)
+    };
+    assert!(size <= PAGE_SIZE, "smp: the trampoline is larger than a page");
+    memory_controller.map_low_memory(TRAMPOLINE, size);
+    unsafe { ptr::copy_nonoverlapping(start as *const u8, TRAMPOLINE as *mut u8, size) };
+    let data = (TRAMPOLINE + data_offset) as *mut TrampolineData;
+
+    // page 0 is mapped only as long as the vector is needed, so null
+    // pointers keep faulting
+    memory_controller.map_low_memory(0, PAGE_SIZE);
+    unsafe {
+        ptr::write_volatile(WARM_RESET_VECTOR as *mut u16, 0);
+        ptr::write_volatile((WARM_RESET_VECTOR + 2) as *mut u16, (TRAMPOLINE >> 4) as u16);
+    }
+    cmos::write(cmos::SHUTDOWN_STATUS, cmos::SHUTDOWN_JUMP_WITHOUT_EOI);
+
+    let bsp = apic::id();
+    let aps = madt.local_apics().iter().filter(|entry| entry.enabled && entry.apic_id != bsp);
+    for local_apic in aps {
+        if !start_ap(memory_controller, local_apic.apic_id, data, cr3) {
+            println!("smp: the CPU with APIC ID {} didn't start, giving up",
+                     local_apic.apic_id);
+            break;
+        }
+    }
+
+    cmos::write(cmos::SHUTDOWN_STATUS, 0);
+    unsafe { ptr::write_volatile(WARM_RESET_VECTOR as *mut u32, 0) };
+    memory_controller.unmap_low_memory(0, PAGE_SIZE);
+    // the trampoline stays mapped, a late AP may still run through it
+    println!("smp: {} of {} CPUs online", cpu_count(), usable);
+}
+
+/// The CPUs that run, the BSP included.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+// returns whether the AP reported in
+fn start_ap(memory_controller: &mut MemoryController, apic_id: u8, data: *mut TrampolineData,
+            cr3: usize) -> bool {
+    let stack = match memory_controller.alloc_stack(AP_STACK_PAGES) {
+        Some(stack) => stack,
+        None => {
+            println!("smp: no stack for another CPU");
+            return false;
+        }
+    };
+    let ap_gdt = gdt::new_for_ap(memory_controller);
+    let online = cpu_count();
+    let block = percpu::new_for_ap(online, apic_id);
+    unsafe {
+        ptr::write_volatile(data, TrampolineData {
+            cr3: cr3 as u64,
+            stack_top: stack.top() as u64,
+            entry: ap_entry as usize as u64,
+            gdt: ap_gdt as *const Gdt as u64,
+            percpu: block as u64,
+        });
+    }
+
+    apic::send_init(apic_id);
+    time::delay_us(10_000);
+    // a CPU that already started ignores the second one
+    apic::send_startup(apic_id, (TRAMPOLINE / PAGE_SIZE) as u8);
+    time::delay_us(200);
+    apic::send_startup(apic_id, (TRAMPOLINE / PAGE_SIZE) as u8);
+
+    let mut waited = 0;
+    while cpu_count() == online {
+        if waited >= STARTUP_TIMEOUT_US {
+            return false;
+        }
+        time::delay_us(STARTUP_POLL_US);
+        waited += STARTUP_POLL_US;
+    }
+    true
+}
+
+// the trampoline calls this on the AP's stack. the data is only rewritten
+// after the AP counted itself online
+extern "C" fn ap_entry(data: *const TrampolineData) -> ! {
+    let data = unsafe { ptr::read_volatile(data) };
+    unsafe {
+        percpu::init_ap(data.percpu as usize);
+        gdt::init_ap(&*(data.gdt as *const Gdt));
+    }
+    interrupts::init_ap();
+    apic::init_ap();
+    task::init_ap();
+    println!("smp: hello from CPU {} (APIC ID {})", percpu::current().index(), apic::id());
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    park()
+}
+
+// the local timer is masked and the I/O APIC sends nothing here, only
+// IPIs end the `hlt`
+fn park() -> ! {
+    loop {
+        cpu::enable_interrupts_and_halt();
+    }
+}
@@ -0,0 +1,60 @@
+// PC speaker
+// PIT channel 2 generates the tone, bits 0 and 1 of port 0x61 gate it and
+// connect it to the speaker. the duration is measured with the timer tick
+// when interrupts are enabled, otherwise by busy waiting on port reads
+// calibrated against the PIT, since channel 2 is busy with the tone.
+// `audiblepanic` on the command line makes the panic handler beep
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts as instructions;
+use interrupts;
+use cmdline;
+use time;
+use pit;
+
+// port 0x61 reads per millisecond, 0 until the first busy wait
+static READS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Plays `frequency_hz` for `duration_ms`. Can be called before the
+/// interrupts are enabled and from the panic handler.
+pub fn beep(frequency_hz: u32, duration_ms: u32) {
+    assert!(frequency_hz > 0, "speaker: frequency 0");
+    // calibrating needs channel 2, so do that before the tone starts
+    let ticking = interrupts::interrupts_enabled() && time::tick_hz() != 0;
+    let reads_per_ms = if ticking { 0 } else { reads_per_ms() };
+
+    let control = pit::start_tone(frequency_hz);
+    if ticking {
+        // not the busy wait fallback, that needs channel 2 as well
+        time::sleep_ms(duration_ms as u64);
+    } else {
+        pit::wait_port_reads(reads_per_ms * duration_ms as u64);
+    }
+    pit::stop_tone(control);
+}
+
+fn reads_per_ms() -> u64 {
+    const CALIBRATION_MS: u32 = 10;
+
+    let reads = READS_PER_MS.load(Ordering::Relaxed);
+    if reads != 0 {
+        return reads;
+    }
+    let cycles = (pit::BASE_FREQUENCY / 1000 * CALIBRATION_MS) as u16;
+    let reads = ::core::cmp::max(pit::measure_port_reads(cycles) / CALIBRATION_MS as u64, 1);
+    READS_PER_MS.store(reads, Ordering::Relaxed);
+    reads
+}
+
+/// Beeps three times if `audiblepanic` is on the command line. Called by
+/// the panic handler, leaves interrupts disabled.
+pub fn panic_beeps() {
+    if !cmdline::has("audiblepanic") {
+        return;
+    }
+    unsafe { instructions::disable() };
+    for _ in 0..3 {
+        beep(880, 150);
+        pit::wait_port_reads(reads_per_ms() * 100);
+    }
+}
@@ -0,0 +1,176 @@
+// spinlocks with deadlock and misuse detection (the `lock_debug` feature)
+// the kernel uses `DebugMutex` wherever it would use a `spin::Mutex`,
+// `IrqMutex` included. without the feature it is a `spin::Mutex`, so it
+// costs nothing. with it the lock remembers where it was taken, on which
+// CPU, by which thread and from which context (thread or interrupt
+// handler). taking it again where it is held (all three the same) can
+// never succeed and panics at once. another thread or CPU just waits, but
+// more than `MAX_SPINS` rounds panic with the holder's address, instead of
+// hanging silently
+
+#[cfg(not(feature = "lock_debug"))]
+pub use spin::Mutex as DebugMutex;
+#[cfg(not(feature = "lock_debug"))]
+pub use spin::MutexGuard as DebugMutexGuard;
+
+#[cfg(feature = "lock_debug")]
+pub use self::checked::{DebugMutex, DebugMutexGuard};
+
+#[cfg(feature = "lock_debug")]
+mod checked {
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use spin::{Mutex, MutexGuard};
+    use backtrace::{self, Address};
+    use interrupts;
+    use percpu;
+    use task;
+
+    const MAX_SPINS: u64 = 100_000_000;
+
+    const THREAD: usize = 1;
+    const INTERRUPT: usize = 2;
+
+    pub struct DebugMutex<T> {
+        inner: Mutex<T>,
+        // the return address of the `lock` call that holds it, 0 if free
+        holder: AtomicUsize,
+        // `THREAD` or `INTERRUPT`
+        context: AtomicUsize,
+        // the CPU index and thread id of the holder
+        cpu: AtomicUsize,
+        thread: AtomicUsize,
+    }
+
+    pub struct DebugMutexGuard<'a, T: 'a> {
+        guard: MutexGuard<'a, T>,
+        lock: &'a DebugMutex<T>,
+    }
+
+    fn context() -> usize {
+        if interrupts::in_interrupt_context() { INTERRUPT } else { THREAD }
+    }
+
+    // the running thread's id, usize::MAX on an AP, which runs none
+    fn thread() -> usize {
+        task::try_current().map_or(usize::max_value(), |id| id.0)
+    }
+
+    // the return address into the caller of `lock` or `try_lock`
+    #[inline(always)]
+    fn caller() -> usize {
+        let mut addresses = [0; 2];
+        backtrace::callers(&mut addresses);
+        addresses[1]
+    }
+
+    impl<T> DebugMutex<T> {
+        pub const fn new(value: T) -> DebugMutex<T> {
+            DebugMutex {
+                inner: Mutex::new(value),
+                holder: AtomicUsize::new(0),
+                context: AtomicUsize::new(0),
+                cpu: AtomicUsize::new(0),
+                thread: AtomicUsize::new(0),
+            }
+        }
+
+        #[inline(never)]
+        pub fn lock(&self) -> DebugMutexGuard<T> {
+            let context = context();
+            let mut spins = 0;
+            loop {
+                if let Some(guard) = self.inner.try_lock() {
+                    return self.acquired(guard, context, caller());
+                }
+                if spins == 0 && self.held_here(context) {
+                    panic!("recursive lock: already held by {} in the same thread and context",
+                           Address(self.holder.load(Ordering::SeqCst)));
+                }
+                spins += 1;
+                if spins == MAX_SPINS {
+                    panic!("possible deadlock: lock held by {}",
+                           Address(self.holder.load(Ordering::SeqCst)));
+                }
+                unsafe { asm!("pause" :::: "volatile") };
+            }
+        }
+
+        #[inline(never)]
+        pub fn try_lock(&self) -> Option<DebugMutexGuard<T>> {
+            let context = context();
+            match self.inner.try_lock() {
+                Some(guard) => Some(self.acquired(guard, context, caller())),
+                None => None,
+            }
+        }
+
+        pub unsafe fn force_unlock(&self) {
+            self.holder.store(0, Ordering::SeqCst);
+            self.context.store(0, Ordering::SeqCst);
+            self.inner.force_unlock();
+        }
+
+        // whether the holder is this thread on this CPU, in `context`
+        fn held_here(&self, context: usize) -> bool {
+            self.context.load(Ordering::SeqCst) == context &&
+                self.cpu.load(Ordering::SeqCst) == percpu::current().index() &&
+                self.thread.load(Ordering::SeqCst) == thread()
+        }
+
+        fn acquired<'a>(&'a self, guard: MutexGuard<'a, T>, context: usize, holder: usize)
+                        -> DebugMutexGuard<'a, T> {
+            self.holder.store(holder, Ordering::SeqCst);
+            self.cpu.store(percpu::current().index(), Ordering::SeqCst);
+            self.thread.store(thread(), Ordering::SeqCst);
+            self.context.store(context, Ordering::SeqCst);
+            DebugMutexGuard { guard: guard, lock: self }
+        }
+    }
+
+    impl<'a, T> Deref for DebugMutexGuard<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &*self.guard
+        }
+    }
+
+    impl<'a, T> DerefMut for DebugMutexGuard<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut *self.guard
+        }
+    }
+
+    // the fields are cleared before the inner guard releases the lock
+    impl<'a, T> Drop for DebugMutexGuard<'a, T> {
+        fn drop(&mut self) {
+            self.lock.holder.store(0, Ordering::SeqCst);
+            self.lock.context.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Without `lock_debug` a `DebugMutex` has to be a plain spinlock, with it
+/// the lock has to remember its holder.
+#[cfg(debug_assertions)]
+pub fn test_debug_mutex() {
+    use core::mem;
+    use spin::Mutex;
+
+    static LOCK: DebugMutex<u64> = DebugMutex::new(0);
+
+    if cfg!(feature = "lock_debug") {
+        assert!(mem::size_of::<DebugMutex<u64>>() > mem::size_of::<Mutex<u64>>());
+    } else {
+        assert_eq!(mem::size_of::<DebugMutex<u64>>(), mem::size_of::<Mutex<u64>>());
+    }
+    {
+        let mut value = LOCK.lock();
+        *value += 1;
+        assert!(LOCK.try_lock().is_none());
+    }
+    assert_eq!(*LOCK.lock(), 1);
+    println!("debug mutex test passed ({} bytes for a lock around a u64)",
+             mem::size_of::<DebugMutex<u64>>());
+}
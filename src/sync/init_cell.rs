@@ -0,0 +1,86 @@
+// one time initialization
+// an `InitCell` starts out empty, `init` stores its value once and fails on
+// every later call, `get` sees the value only when it is complete. unlike
+// `assert_has_not_been_called!` a second `init` is an error the caller can
+// handle, and the `_or_panic` variants say which subsystem it was and
+// whether it was used too early or set up twice. lock free, a `get` that
+// races with the `init` on another CPU returns None
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const EMPTY: usize = 0;
+// an `init` is writing the value
+const INITIALIZING: usize = 1;
+const READY: usize = 2;
+
+pub struct InitCell<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+// the value is written once, before READY, and only read after
+unsafe impl<T: Send + Sync> Sync for InitCell<T> {}
+
+/// `InitCell::init` was called on a cell that has its value already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "initialized twice")
+    }
+}
+
+impl<T> InitCell<T> {
+    pub const fn new() -> InitCell<T> {
+        InitCell { state: AtomicUsize::new(EMPTY), value: UnsafeCell::new(None) }
+    }
+
+    /// Stores `value`, unless the cell has (or is getting) one already.
+    pub fn init(&self, value: T) -> Result<(), AlreadyInitialized> {
+        if self.state.compare_and_swap(EMPTY, INITIALIZING, Ordering::Acquire) != EMPTY {
+            return Err(AlreadyInitialized);
+        }
+        unsafe { *self.value.get() = Some(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// Like `init`, but panics naming `subsystem` if it fails.
+    pub fn init_or_panic(&self, subsystem: &str, value: T) {
+        if let Err(error) = self.init(value) {
+            panic!("{}: {}", subsystem, error);
+        }
+    }
+
+    /// The value, None before `init` finished.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != READY {
+            return None;
+        }
+        unsafe { (*self.value.get()).as_ref() }
+    }
+
+    /// Like `get`, but panics naming `subsystem` if there is no value yet.
+    pub fn get_or_panic(&self, subsystem: &str) -> &T {
+        match self.get() {
+            Some(value) => value,
+            None => panic!("{}: not yet initialized", subsystem),
+        }
+    }
+}
+
+/// Only the first `init` may store its value, and `get` has to see it.
+#[cfg(debug_assertions)]
+pub fn test_init_cell() {
+    static CELL: InitCell<usize> = InitCell::new();
+
+    assert_eq!(CELL.get(), None);
+    assert_eq!(CELL.init(1), Ok(()));
+    assert_eq!(CELL.init(2), Err(AlreadyInitialized));
+    assert_eq!(CELL.get(), Some(&1));
+    assert_eq!(*CELL.get_or_panic("init cell test"), 1);
+    println!("init cell test passed");
+}
@@ -0,0 +1,206 @@
+// spinlocks that disable interrupts while they are held
+// a plain spin::Mutex deadlocks as soon as an interrupt handler tries to take
+// a lock the interrupted code holds. these save the interrupt flag, cli, take
+// the lock and restore the flag when the guard is dropped. the lock inside
+// is a `DebugMutex`, with `lock_debug` it reports the caller of `lock`
+
+use core::ops::{Deref, DerefMut};
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use super::{DebugMutex, DebugMutexGuard};
+use x86_64::instructions::interrupts;
+
+// disables interrupts and returns whether they were enabled before
+fn save_and_disable_interrupts() -> bool {
+    let enabled = ::interrupts::interrupts_enabled();
+    unsafe { interrupts::disable() };
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { interrupts::enable() };
+    }
+}
+
+pub struct IrqMutex<T> {
+    inner: DebugMutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    // always Some until dropped, the lock has to be released before the
+    // interrupts are enabled again
+    guard: Option<DebugMutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: DebugMutex::new(value) }
+    }
+
+    // inlined, so the lock sees our caller as its holder
+    #[cfg_attr(feature = "lock_debug", inline(always))]
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        IrqMutexGuard {
+            guard: Some(self.inner.lock()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+
+    #[cfg_attr(feature = "lock_debug", inline(always))]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: Some(guard),
+                interrupts_enabled: interrupts_enabled,
+            }),
+            None => {
+                restore_interrupts(interrupts_enabled);
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard. Only for the panic path, where the
+    /// holder will never run again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+pub struct IrqRwLock<T> {
+    inner: RwLock<T>,
+}
+
+pub struct IrqRwLockReadGuard<'a, T: 'a> {
+    guard: Option<RwLockReadGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+pub struct IrqRwLockWriteGuard<'a, T: 'a> {
+    guard: Option<RwLockWriteGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqRwLock<T> {
+    pub const fn new(value: T) -> IrqRwLock<T> {
+        IrqRwLock { inner: RwLock::new(value) }
+    }
+
+    pub fn read(&self) -> IrqRwLockReadGuard<T> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        IrqRwLockReadGuard {
+            guard: Some(self.inner.read()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+
+    pub fn write(&self) -> IrqRwLockWriteGuard<T> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        IrqRwLockWriteGuard {
+            guard: Some(self.inner.write()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqRwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+impl<'a, T> Deref for IrqRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqRwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqRwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+// (locks from the test loop, locks from the timer)
+#[cfg(debug_assertions)]
+static CONTENDED: IrqMutex<(u64, u64)> = IrqMutex::new((0, 0));
+
+#[cfg(debug_assertions)]
+fn contend_from_timer(_context: &mut ::interrupts::InterruptContext) {
+    CONTENDED.lock().1 += 1;
+}
+
+/// Lets the PIT interrupt and this function take the same IrqMutex over and
+/// over. With a plain spinlock this hangs the first time the timer
+/// interrupts us while we hold the lock. Interrupts must be enabled.
+#[cfg(debug_assertions)]
+pub fn test_irq_mutex() {
+    use {pit, time};
+
+    const ITERATIONS: u64 = 100_000;
+    const TIMER_LOCKS: u64 = 10;
+
+    assert!(::interrupts::interrupts_enabled(), "test_irq_mutex needs interrupts");
+    *CONTENDED.lock() = (0, 0);
+    // with the APIC timer as tick source channel 0 isn't running yet
+    pit::init_channel0(pit::divisor_for(time::DEFAULT_HZ));
+    ::interrupts::register_irq(time::TIMER_IRQ, contend_from_timer)
+        .expect("could not register test timer handler");
+
+    // keep going until the timer got in a few times as well
+    loop {
+        let mut counts = CONTENDED.lock();
+        counts.0 += 1;
+        if counts.0 >= ITERATIONS && counts.1 >= TIMER_LOCKS {
+            break;
+        }
+    }
+
+    ::interrupts::unregister_irq(time::TIMER_IRQ, contend_from_timer)
+        .expect("could not unregister test timer handler");
+    let (from_loop, from_timer) = *CONTENDED.lock();
+    println!("irq mutex test passed ({} locks from the loop, {} from the timer)",
+             from_loop, from_timer);
+}
@@ -0,0 +1,32 @@
+// synchronization primitives
+
+pub use self::irq_mutex::{IrqMutex, IrqMutexGuard, IrqRwLock, IrqRwLockReadGuard,
+                          IrqRwLockWriteGuard};
+pub use self::debug_mutex::{DebugMutex, DebugMutexGuard};
+pub use self::wait_queue::WaitQueue;
+pub use self::mutex::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::init_cell::{InitCell, AlreadyInitialized};
+
+mod irq_mutex;
+mod debug_mutex;
+mod wait_queue;
+mod mutex;
+mod semaphore;
+mod init_cell;
+pub mod mpsc;
+
+#[cfg(debug_assertions)]
+pub use self::irq_mutex::test_irq_mutex;
+#[cfg(debug_assertions)]
+pub use self::debug_mutex::test_debug_mutex;
+#[cfg(debug_assertions)]
+pub use self::wait_queue::test_wait_queue;
+#[cfg(debug_assertions)]
+pub use self::mutex::test_blocking_locks;
+#[cfg(debug_assertions)]
+pub use self::semaphore::test_semaphore;
+#[cfg(debug_assertions)]
+pub use self::mpsc::test_channel;
+#[cfg(debug_assertions)]
+pub use self::init_cell::test_init_cell;
@@ -0,0 +1,243 @@
+// channels from any number of senders to one receiver
+// the values wait in a `VecDeque` on the heap. `channel` has no bound,
+// `sync_channel` holds at most `bound` values and `send` blocks while it is
+// full. a bounded channel allocates its whole buffer up front, so
+// `try_send` on one never allocates and works in interrupt handlers.
+// once every `Sender` is dropped `recv` returns what is still queued and
+// then `RecvError::Disconnected`, once the `Receiver` is dropped sending
+// fails and gives the value back
+
+use alloc::arc::Arc;
+use alloc::vec_deque::VecDeque;
+use sync::{IrqMutex, WaitQueue};
+use interrupts;
+
+/// The value could not be sent, the receiver is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: IrqMutex<State<T>>,
+    // None for an unbounded channel
+    bound: Option<usize>,
+    // the receiver, for a value or the last sender gone
+    receive_waiters: WaitQueue,
+    // senders of a bounded channel, for room or the receiver gone
+    send_waiters: WaitQueue,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver: bool,
+}
+
+/// A channel without a bound, `send` never blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    create(VecDeque::new(), None)
+}
+
+/// A channel that holds at most `bound` values (at least 1).
+pub fn sync_channel<T>(bound: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(bound > 0, "sync_channel without room");
+    create(VecDeque::with_capacity(bound), Some(bound))
+}
+
+fn create<T>(queue: VecDeque<T>, bound: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: IrqMutex::new(State { queue: queue, senders: 1, receiver: true }),
+        bound: bound,
+        receive_waiters: WaitQueue::new(),
+        send_waiters: WaitQueue::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared: shared })
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, on a bounded channel after blocking until there is
+    /// room.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let value = match self.try_send(value) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(value)) => return Err(SendError(value)),
+            Err(TrySendError::Full(value)) => value,
+        };
+        assert!(!interrupts::in_interrupt_context(), "blocking send in an interrupt handler");
+        let mut value = Some(value);
+        self.shared.send_waiters.wait_until(|| {
+            let mut state = self.shared.state.lock();
+            if !state.receiver {
+                return true;
+            }
+            if state.queue.len() < self.shared.bound.unwrap() {
+                state.queue.push_back(value.take().unwrap());
+                return true;
+            }
+            false
+        });
+        match value {
+            Some(value) => Err(SendError(value)),
+            None => {
+                self.shared.receive_waiters.notify_one();
+                Ok(())
+            }
+        }
+    }
+
+    /// Queues `value` if there is room. Interrupt safe on a bounded channel.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        {
+            let mut state = self.shared.state.lock();
+            if !state.receiver {
+                return Err(TrySendError::Disconnected(value));
+            }
+            if self.shared.bound.map_or(false, |bound| state.queue.len() >= bound) {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+        }
+        self.shared.receive_waiters.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.receive_waiters.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until there is a value. Fails once the queue is empty and
+    /// every sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        assert!(!interrupts::in_interrupt_context(), "recv in an interrupt handler");
+        let mut result = Err(RecvError::Disconnected);
+        self.shared.receive_waiters.wait_until(|| {
+            match self.try_recv() {
+                Ok(value) => {
+                    result = Ok(value);
+                    true
+                }
+                Err(TryRecvError::Disconnected) => true,
+                Err(TryRecvError::Empty) => false,
+            }
+        });
+        result
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let value = {
+            let mut state = self.shared.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => value,
+                None if state.senders == 0 => return Err(TryRecvError::Disconnected),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        if self.shared.bound.is_some() {
+            self.shared.send_waiters.notify_one();
+        }
+        Ok(value)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // the values still queued are dropped with the last sender
+        self.shared.state.lock().receiver = false;
+        self.shared.send_waiters.notify_all();
+    }
+}
+
+/// Three threads send 100 values each through an unbounded channel, then
+/// one sends through a channel of two. Dropping all senders has to end
+/// `recv`, dropping the receiver has to fail `send`.
+#[cfg(debug_assertions)]
+pub fn test_channel(memory_controller: &mut ::memory::MemoryController) {
+    use task;
+
+    const VALUES: usize = 100;
+
+    let (sender, receiver) = channel();
+    let mut handles = [None, None, None];
+    for (index, handle) in handles.iter_mut().enumerate() {
+        let sender = sender.clone();
+        *handle = Some(task::spawn(memory_controller, move || {
+            for value in 0..VALUES {
+                sender.send(index * VALUES + value).expect("the receiver is gone");
+            }
+        }).expect("could not spawn a thread"));
+    }
+    drop(sender);
+    let mut sum = 0;
+    let mut count = 0;
+    while let Ok(value) = receiver.recv() {
+        sum += value;
+        count += 1;
+    }
+    for handle in handles.iter_mut() {
+        handle.take().unwrap().join();
+    }
+    assert_eq!(count, 3 * VALUES);
+    assert_eq!(sum, (3 * VALUES) * (3 * VALUES - 1) / 2);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+    let (sender, receiver) = sync_channel(2);
+    sender.try_send(1).unwrap();
+    sender.try_send(2).unwrap();
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    // blocks until the main thread makes room
+    let blocked = task::spawn(memory_controller, move || sender.send(3).is_ok())
+        .expect("could not spawn a thread");
+    task::sleep_ms(10);
+    assert_eq!(receiver.recv(), Ok(1));
+    assert!(blocked.join(), "the blocked send failed");
+    assert_eq!(receiver.recv(), Ok(2));
+    assert_eq!(receiver.recv(), Ok(3));
+    assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(7), Err(SendError(7)));
+    println!("channel test passed");
+}
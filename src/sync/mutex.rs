@@ -0,0 +1,272 @@
+// blocking locks for threads
+// `Mutex` and `RwLock` block a thread that has to wait on a `WaitQueue`
+// instead of spinning, for critical sections that may take long (a disk
+// transfer). never from interrupt handlers, that is what `IrqMutex` is for.
+// the waiters are woken in the order they came, a thread that comes along
+// at the right moment can still take the lock first. the `RwLock` prefers
+// writers: no new reader gets in while a writer waits. in debug builds the
+// `Mutex` remembers its owner and panics when the owner locks it again
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use super::WaitQueue;
+use interrupts;
+#[cfg(debug_assertions)]
+use task;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    // the id of the owning thread plus one, 0 while unlocked
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: 'a> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[cfg(debug_assertions)]
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            owner: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks until the lock is free.
+    pub fn lock(&self) -> MutexGuard<T> {
+        assert!(!interrupts::in_interrupt_context(),
+                "sync::Mutex in an interrupt handler, use an IrqMutex");
+        if !self.acquire() {
+            self.check_recursion();
+            self.waiters.wait_until(|| self.acquire());
+        }
+        self.acquired()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.acquire() { Some(self.acquired()) } else { None }
+    }
+
+    /// The thread holding the lock, debug builds only.
+    #[cfg(debug_assertions)]
+    pub fn owner(&self) -> Option<task::ThreadId> {
+        match self.owner.load(Ordering::SeqCst) {
+            0 => None,
+            owner => Some(task::ThreadId(owner - 1)),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        !self.locked.compare_and_swap(false, true, Ordering::Acquire)
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_recursion(&self) {
+        if self.owner() == Some(task::current()) {
+            panic!("sync::Mutex locked again by its owner, thread {}", task::current().0);
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_recursion(&self) {}
+
+    fn acquired(&self) -> MutexGuard<T> {
+        #[cfg(debug_assertions)]
+        self.owner.store(task::current().0 + 1, Ordering::SeqCst);
+        MutexGuard { lock: self }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.owner.store(0, Ordering::SeqCst);
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.notify_one();
+    }
+}
+
+// set in `state` while a writer holds the lock, the rest counts the readers
+const WRITER: usize = 1 << 63;
+
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks while a writer holds or waits for the lock.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        assert!(!interrupts::in_interrupt_context(),
+                "sync::RwLock in an interrupt handler, use an IrqRwLock");
+        if !self.acquire_read() {
+            self.waiters.wait_until(|| self.acquire_read());
+        }
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.acquire_read() { Some(RwLockReadGuard { lock: self }) } else { None }
+    }
+
+    /// Blocks until no reader or writer holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        assert!(!interrupts::in_interrupt_context(),
+                "sync::RwLock in an interrupt handler, use an IrqRwLock");
+        if !self.acquire_write() {
+            self.waiting_writers.fetch_add(1, Ordering::SeqCst);
+            self.waiters.wait_until(|| self.acquire_write());
+            self.waiting_writers.fetch_sub(1, Ordering::SeqCst);
+        }
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.acquire_write() { Some(RwLockWriteGuard { lock: self }) } else { None }
+    }
+
+    fn acquire_read(&self) -> bool {
+        loop {
+            let state = self.state.load(Ordering::SeqCst);
+            if state & WRITER != 0 || self.waiting_writers.load(Ordering::SeqCst) != 0 {
+                return false;
+            }
+            if self.state.compare_and_swap(state, state + 1, Ordering::Acquire) == state {
+                return true;
+            }
+        }
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state.compare_and_swap(0, WRITER, Ordering::Acquire) == 0
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+// readers and writers wait in the same queue, everybody is woken and
+// checks again
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.notify_all();
+    }
+}
+
+/// Two threads add to a counter under a `Mutex` and sleep while they hold
+/// it, so the other has to block. A writer waiting for an `RwLock` held by
+/// a reader has to keep new readers out.
+#[cfg(debug_assertions)]
+pub fn test_blocking_locks(memory_controller: &mut ::memory::MemoryController) {
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    static TABLE: RwLock<usize> = RwLock::new(0);
+
+    fn add() {
+        for _ in 0..5 {
+            let mut counter = COUNTER.lock();
+            let before = *counter;
+            task::sleep_ms(2);
+            *counter = before + 1;
+        }
+    }
+
+    let first = task::spawn(memory_controller, add).expect("could not spawn a thread");
+    let second = task::spawn(memory_controller, add).expect("could not spawn a thread");
+    first.join();
+    second.join();
+    assert_eq!(*COUNTER.lock(), 10, "an update under the mutex got lost");
+    assert!(COUNTER.owner().is_none());
+
+    let reader = TABLE.read();
+    let writer = task::spawn(memory_controller, || *TABLE.write() += 1)
+        .expect("could not spawn a thread");
+    task::sleep_ms(10);
+    assert!(TABLE.try_read().is_none(), "a reader got past a waiting writer");
+    drop(reader);
+    writer.join();
+    assert_eq!(*TABLE.read(), 1);
+    println!("blocking lock test passed");
+}
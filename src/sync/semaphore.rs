@@ -0,0 +1,200 @@
+// counting semaphore
+// for pools of something (DMA buffers, slots in a bounded queue). `release`
+// doesn't just count up while threads wait, it hands the permit to the one
+// that waits longest, so a thread that comes along later can't take it
+// first. the woken thread finds its id among the handed permits. threads
+// that wait without a slot in the `WaitQueue` (before `task::init`, or all
+// taken) only get permits nobody waits for. `release` works in interrupt
+// handlers, acquiring doesn't
+
+use sync::IrqMutex;
+use task::{self, ThreadId};
+use time;
+use interrupts;
+use super::WaitQueue;
+use super::wait_queue::MAX_WAITERS;
+
+pub struct Semaphore {
+    permits: IrqMutex<Permits>,
+    waiters: WaitQueue,
+}
+
+struct Permits {
+    // free for anyone
+    available: usize,
+    // given to woken threads that haven't taken them yet
+    handed: [Option<ThreadId>; MAX_WAITERS],
+}
+
+impl Semaphore {
+    pub const fn new(initial: usize) -> Semaphore {
+        Semaphore {
+            permits: IrqMutex::new(Permits { available: initial, handed: [None; MAX_WAITERS] }),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until it gets a permit.
+    pub fn acquire(&self) {
+        assert!(!interrupts::in_interrupt_context(), "Semaphore::acquire in an interrupt handler");
+        self.waiters.wait_until(|| self.take());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.take()
+    }
+
+    /// Like `acquire`, but gives up after `ms` milliseconds. Returns whether
+    /// it got a permit.
+    pub fn acquire_timeout(&self, ms: u64) -> bool {
+        assert!(!interrupts::in_interrupt_context(), "Semaphore::acquire in an interrupt handler");
+        let deadline = time::uptime_us() + ms * 1000;
+        loop {
+            if self.take() {
+                return true;
+            }
+            let now = time::uptime_us();
+            if now >= deadline {
+                return false;
+            }
+            let remaining_ms = (deadline - now + 999) / 1000;
+            let timer = match time::after_in_interrupt(remaining_ms, wake, task::current().0) {
+                Some(timer) => timer,
+                // nothing would wake a blocked thread at the deadline
+                None => return self.poll_until(deadline),
+            };
+            // blocks once, woken by the timer or by a `release`
+            let mut acquired = false;
+            let mut woken = false;
+            self.waiters.wait_until(|| {
+                acquired = self.take();
+                let done = acquired || woken;
+                woken = true;
+                done
+            });
+            timer.cancel();
+            if acquired {
+                return true;
+            }
+        }
+    }
+
+    /// Hands a permit to the thread that waits longest, or puts it back if
+    /// nobody waits. Interrupt safe.
+    pub fn release(&self) {
+        let mut permits = self.permits.lock();
+        // the woken thread can't run before the lock is released
+        match self.waiters.notify_one() {
+            Some(id) => match permits.handed.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(id),
+                None => permits.available += 1,
+            },
+            None => permits.available += 1,
+        }
+    }
+
+    /// The permits nobody waits for.
+    pub fn available(&self) -> usize {
+        self.permits.lock().available
+    }
+
+    // takes a permit handed to this thread, or a free one
+    fn take(&self) -> bool {
+        let mut permits = self.permits.lock();
+        let id = task::current();
+        if let Some(slot) = permits.handed.iter_mut().find(|slot| **slot == Some(id)) {
+            *slot = None;
+            return true;
+        }
+        if permits.available > 0 {
+            permits.available -= 1;
+            return true;
+        }
+        false
+    }
+
+    fn poll_until(&self, deadline: u64) -> bool {
+        loop {
+            if self.take() {
+                return true;
+            }
+            if time::uptime_us() >= deadline {
+                return false;
+            }
+            task::sleep_ms(1);
+        }
+    }
+}
+
+// the timer callback of `acquire_timeout`, in the timer interrupt
+fn wake(index: usize) {
+    task::unblock(ThreadId(index));
+}
+
+/// A producer and two consumers pass 20 values through a buffer of two,
+/// one semaphore counts the free slots and one the filled ones. Then a
+/// timeout has to expire and a release from the timer interrupt has to
+/// end a wait.
+#[cfg(debug_assertions)]
+pub fn test_semaphore(memory_controller: &mut ::memory::MemoryController) {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const VALUES: usize = 20;
+    const SLOTS: usize = 2;
+
+    static FREE: Semaphore = Semaphore::new(SLOTS);
+    static FILLED: Semaphore = Semaphore::new(0);
+    static BUFFER: IrqMutex<([usize; SLOTS], usize, usize)> = IrqMutex::new(([0; SLOTS], 0, 0));
+    static SUM: AtomicUsize = AtomicUsize::new(0);
+    static SIGNAL: Semaphore = Semaphore::new(0);
+
+    fn consume() {
+        for _ in 0..VALUES / 2 {
+            FILLED.acquire();
+            let value = {
+                let mut buffer = BUFFER.lock();
+                let (ref mut slots, ref mut head, ref mut count) = *buffer;
+                assert!(*count > 0, "a consumer got past an empty buffer");
+                let value = slots[*head];
+                *head = (*head + 1) % SLOTS;
+                *count -= 1;
+                value
+            };
+            FREE.release();
+            SUM.fetch_add(value, Ordering::SeqCst);
+        }
+    }
+
+    fn release_signal(_: usize) {
+        SIGNAL.release();
+    }
+
+    let first = task::spawn(memory_controller, consume).expect("could not spawn a thread");
+    let second = task::spawn(memory_controller, consume).expect("could not spawn a thread");
+    let producer = task::spawn(memory_controller, || {
+        for value in 1..VALUES + 1 {
+            FREE.acquire();
+            {
+                let mut buffer = BUFFER.lock();
+                let (ref mut slots, head, ref mut count) = *buffer;
+                assert!(*count < SLOTS, "the producer got past a full buffer");
+                slots[(head + *count) % SLOTS] = value;
+                *count += 1;
+            }
+            FILLED.release();
+        }
+    }).expect("could not spawn a thread");
+    producer.join();
+    first.join();
+    second.join();
+    assert_eq!(SUM.load(Ordering::SeqCst), VALUES * (VALUES + 1) / 2);
+    assert_eq!(FREE.available(), SLOTS);
+    assert!(!FILLED.try_acquire());
+
+    let start = time::uptime_us();
+    assert!(!SIGNAL.acquire_timeout(10), "got a permit nobody released");
+    assert!(time::uptime_us() - start >= 10_000, "the timeout expired early");
+    time::after_in_interrupt(5, release_signal, 0).expect("timer table full");
+    assert!(SIGNAL.acquire_timeout(1000), "the release from the interrupt got lost");
+    println!("semaphore test passed");
+}
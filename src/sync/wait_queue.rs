@@ -0,0 +1,152 @@
+// blocking until a condition holds
+// `wait_until` checks the condition with interrupts off and enqueues and
+// blocks the thread before they are on again, so a notify from an interrupt
+// handler can't fall in between. the waiters are woken in the order they
+// came and check their condition again, a wakeup only means it may hold.
+// notifying never switches, it only makes the waiters ready and asks for a
+// reschedule, which an interrupt handler does on its way out. where the
+// caller can't block (before `task::init`, with preemption disabled, or all
+// slots taken) it halts until the next interrupt instead
+
+use x86_64::instructions::interrupts as cpu_interrupts;
+use sync::IrqMutex;
+use task::{self, ThreadId};
+use interrupts;
+use cpu;
+
+pub const MAX_WAITERS: usize = 16;
+
+pub struct WaitQueue {
+    // in the order they came, the first `count` are used
+    waiters: IrqMutex<Waiters>,
+}
+
+struct Waiters {
+    ids: [Option<ThreadId>; MAX_WAITERS],
+    count: usize,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqMutex::new(Waiters { ids: [None; MAX_WAITERS], count: 0 }),
+        }
+    }
+
+    /// Returns once `condition` is true, blocking the thread while it is
+    /// false. `condition` runs with interrupts off and must not block.
+    /// Interrupts must not be off for good, or nobody can notify.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        assert!(!interrupts::in_interrupt_context(), "wait_until in an interrupt handler");
+        let enabled = interrupts::interrupts_enabled();
+        loop {
+            unsafe { cpu_interrupts::disable() };
+            if condition() {
+                break;
+            }
+            let id = task::current();
+            if task::can_switch() && self.waiters.lock().push(id) {
+                task::block();
+                // woken by something else, like a timer
+                self.waiters.lock().remove(id);
+            } else {
+                cpu::enable_interrupts_and_halt();
+            }
+        }
+        if enabled {
+            unsafe { cpu_interrupts::enable() };
+        }
+    }
+
+    /// Wakes the thread that waits longest and returns it, None if nobody
+    /// waits. Interrupt safe.
+    pub fn notify_one(&self) -> Option<ThreadId> {
+        let first = self.waiters.lock().pop();
+        if let Some(id) = first {
+            task::unblock(id);
+        }
+        first
+    }
+
+    /// Wakes every waiting thread. Interrupt safe.
+    pub fn notify_all(&self) {
+        let mut waiters = self.waiters.lock();
+        while let Some(id) = waiters.pop() {
+            task::unblock(id);
+        }
+    }
+}
+
+impl Waiters {
+    // returns false if all slots are taken
+    fn push(&mut self, id: ThreadId) -> bool {
+        if self.count == MAX_WAITERS {
+            return false;
+        }
+        self.ids[self.count] = Some(id);
+        self.count += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<ThreadId> {
+        if self.count == 0 {
+            return None;
+        }
+        let first = self.ids[0];
+        self.take(0);
+        first
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        if let Some(index) = self.ids[..self.count].iter().position(|&entry| entry == Some(id)) {
+            self.take(index);
+        }
+    }
+
+    fn take(&mut self, index: usize) {
+        for i in index..self.count - 1 {
+            self.ids[i] = self.ids[i + 1];
+        }
+        self.ids[self.count - 1] = None;
+        self.count -= 1;
+    }
+}
+
+/// Three threads wait for a flag, one `notify_one` may only let one of
+/// them through, `notify_all` the rest.
+#[cfg(debug_assertions)]
+pub fn test_wait_queue(memory_controller: &mut ::memory::MemoryController) {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static TICKETS: AtomicUsize = AtomicUsize::new(0);
+    static PASSED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut handles = [None, None, None];
+    for handle in handles.iter_mut() {
+        *handle = Some(task::spawn(memory_controller, || {
+            QUEUE.wait_until(|| {
+                let tickets = TICKETS.load(Ordering::SeqCst);
+                tickets > 0
+                    && TICKETS.compare_and_swap(tickets, tickets - 1, Ordering::SeqCst) == tickets
+            });
+            PASSED.fetch_add(1, Ordering::SeqCst);
+        }).expect("could not spawn a thread"));
+    }
+    // all three are blocked after this
+    task::sleep_ms(10);
+    assert_eq!(PASSED.load(Ordering::SeqCst), 0, "a waiter passed without a notify");
+
+    TICKETS.store(1, Ordering::SeqCst);
+    QUEUE.notify_one();
+    task::sleep_ms(10);
+    assert_eq!(PASSED.load(Ordering::SeqCst), 1);
+
+    TICKETS.store(2, Ordering::SeqCst);
+    QUEUE.notify_all();
+    for handle in handles.iter_mut() {
+        handle.take().unwrap().join();
+    }
+    assert_eq!(PASSED.load(Ordering::SeqCst), 3);
+    println!("wait queue test passed");
+}
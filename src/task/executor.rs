@@ -0,0 +1,381 @@
+// cooperative tasks on one thread
+// a task is a `Future` on the heap that `run` polls until it is ready. a
+// pending future keeps the `Waker` it was polled with and calls `wake` when
+// it can go on, from a thread or an interrupt handler. this toolchain has no
+// `core::future`, so the trait is our own, and without `Pin` a future is
+// polled through `&mut`.
+// `wake` puts the task's index into the ready queue, a bounded lock free
+// ring (Vyukov's, with a sequence number per slot), unless it is queued
+// already, so the ring can't overflow and a task woken twice is polled
+// once. `run` only polls what it takes from the ring. a waker remembers the
+// generation of its slot, after the task finished it wakes nothing, even
+// when the slot holds a new task. a slot stays taken while its task is
+// polled, a task spawned from the poll gets another one. with nothing
+// ready `run` halts with interrupts on, an interrupt that wakes a task also
+// ends the `hlt`.
+// `block_on` waits for one future outside the executor, in a driver say. its
+// waker wakes nothing, the future is polled again after every interrupt
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use spin::Once;
+use sync::IrqMutex;
+use interrupts;
+use cpu;
+
+// a power of two, for the ring
+pub const MAX_TASKS: usize = 64;
+// the index of the waker `block_on` polls with
+const BLOCK_ON: usize = MAX_TASKS;
+
+pub enum Poll<T> {
+    Ready(T),
+    Pending,
+}
+
+/// A computation that may have to wait. `poll` returns `Pending` until it is
+/// done, after arranging for `waker` to be woken when it can go on.
+pub trait Future {
+    type Output;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output>;
+}
+
+/// A source of values that may have to wait for each. `poll_next` returns
+/// `Ready(None)` once there are no more.
+pub trait Stream {
+    type Item;
+
+    fn poll_next(&mut self, waker: &Waker) -> Poll<Option<Self::Item>>;
+}
+
+/// Makes a task ready again. Interrupt safe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waker {
+    index: usize,
+    generation: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyTasks,
+}
+
+pub struct Task {
+    future: Box<Future<Output = ()> + Send>,
+}
+
+enum TaskSlot {
+    Free,
+    // the task is out of the slot, `poll` has it
+    Polling,
+    Occupied(Task),
+}
+
+struct Executor {
+    tasks: IrqMutex<Vec<TaskSlot>>,
+    // by slot, incremented when a task finishes
+    generations: Vec<AtomicUsize>,
+    // by slot, whether the index is in `ready`
+    queued: Vec<AtomicBool>,
+    ready: ReadyQueue,
+    live: AtomicUsize,
+}
+
+struct ReadyQueue {
+    slots: Vec<Slot>,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+}
+
+struct Slot {
+    sequence: AtomicUsize,
+    index: AtomicUsize,
+}
+
+static EXECUTOR: Once<Executor> = Once::new();
+
+fn executor() -> &'static Executor {
+    EXECUTOR.call_once(|| {
+        let mut tasks = Vec::with_capacity(MAX_TASKS);
+        for _ in 0..MAX_TASKS {
+            tasks.push(TaskSlot::Free);
+        }
+        Executor {
+            tasks: IrqMutex::new(tasks),
+            generations: (0..MAX_TASKS).map(|_| AtomicUsize::new(0)).collect(),
+            queued: (0..MAX_TASKS).map(|_| AtomicBool::new(false)).collect(),
+            ready: ReadyQueue::new(),
+            live: AtomicUsize::new(0),
+        }
+    })
+}
+
+/// Adds a task that `run` polls for the first time soon.
+pub fn spawn<F>(future: F) -> Result<(), SpawnError>
+    where F: Future<Output = ()> + Send + 'static
+{
+    let executor = executor();
+    let task = Task { future: Box::new(future) };
+    let index = {
+        let mut tasks = executor.tasks.lock();
+        let index = match tasks.iter().position(|slot| match *slot {
+            TaskSlot::Free => true,
+            _ => false,
+        }) {
+            Some(index) => index,
+            None => return Err(SpawnError::TooManyTasks),
+        };
+        tasks[index] = TaskSlot::Occupied(task);
+        index
+    };
+    executor.live.fetch_add(1, Ordering::SeqCst);
+    executor.waker(index).wake();
+    Ok(())
+}
+
+/// Polls the woken tasks until none is left, halting while none is ready.
+/// Needs interrupts, they do the waking.
+pub fn run() {
+    assert!(interrupts::interrupts_enabled(), "executor::run with interrupts off");
+    let executor = executor();
+    while executor.live.load(Ordering::SeqCst) > 0 {
+        // with interrupts off from the check to the `hlt`, a wake in
+        // between ends the `hlt` instead of being missed
+        unsafe { cpu_interrupts::disable() };
+        let index = executor.ready.pop();
+        match index {
+            Some(index) => {
+                unsafe { cpu_interrupts::enable() };
+                executor.poll(index);
+            }
+            None => cpu::enable_interrupts_and_halt(),
+        }
+    }
+}
+
+/// Polls `future` until it is ready and returns its output, halting until
+/// the next interrupt while it is pending. The future is polled with
+/// interrupts off, so it has to be woken by an interrupt handler (or wait
+/// for the tick), a wake from another thread isn't noticed earlier.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    assert!(interrupts::interrupts_enabled(), "executor::block_on with interrupts off");
+    let waker = Waker { index: BLOCK_ON, generation: 0 };
+    loop {
+        // as in `run`, an interrupt after the poll ends the `hlt`
+        unsafe { cpu_interrupts::disable() };
+        match future.poll(&waker) {
+            Poll::Ready(output) => {
+                unsafe { cpu_interrupts::enable() };
+                return output;
+            }
+            Poll::Pending => cpu::enable_interrupts_and_halt(),
+        }
+    }
+}
+
+/// The number of tasks that didn't finish yet.
+pub fn live_tasks() -> usize {
+    executor().live.load(Ordering::SeqCst)
+}
+
+impl Executor {
+    fn waker(&self, index: usize) -> Waker {
+        Waker { index: index, generation: self.generations[index].load(Ordering::SeqCst) }
+    }
+
+    fn poll(&self, index: usize) {
+        // a wake from here on queues the task again
+        self.queued[index].store(false, Ordering::SeqCst);
+        let mut task = {
+            let mut tasks = self.tasks.lock();
+            match mem::replace(&mut tasks[index], TaskSlot::Polling) {
+                TaskSlot::Occupied(task) => task,
+                // finished while it was queued
+                other => {
+                    tasks[index] = other;
+                    return;
+                }
+            }
+        };
+        match task.future.poll(&self.waker(index)) {
+            Poll::Pending => self.tasks.lock()[index] = TaskSlot::Occupied(task),
+            Poll::Ready(()) => {
+                // wakers of the task mustn't reach the next one in the slot
+                self.generations[index].fetch_add(1, Ordering::SeqCst);
+                self.tasks.lock()[index] = TaskSlot::Free;
+                self.live.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        if self.index == BLOCK_ON {
+            return;
+        }
+        let executor = executor();
+        if executor.generations[self.index].load(Ordering::SeqCst) != self.generation {
+            return;
+        }
+        if !executor.queued[self.index].swap(true, Ordering::SeqCst) {
+            // every task is queued at most once, there is always room
+            assert!(executor.ready.push(self.index), "executor: ready queue overflow");
+        }
+    }
+
+    /// Whether both wake the same task.
+    pub fn will_wake(&self, other: &Waker) -> bool {
+        self == other
+    }
+}
+
+impl ReadyQueue {
+    fn new() -> ReadyQueue {
+        ReadyQueue {
+            slots: (0..MAX_TASKS)
+                .map(|i| Slot { sequence: AtomicUsize::new(i), index: AtomicUsize::new(0) })
+                .collect(),
+            enqueue: AtomicUsize::new(0),
+            dequeue: AtomicUsize::new(0),
+        }
+    }
+
+    // a slot whose sequence equals the position is free for it, one whose
+    // sequence is the position plus one holds its value
+    fn push(&self, index: usize) -> bool {
+        let mut position = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % MAX_TASKS];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == position {
+                let found = self.enqueue.compare_and_swap(position, position + 1,
+                                                          Ordering::Relaxed);
+                if found == position {
+                    slot.index.store(index, Ordering::Relaxed);
+                    slot.sequence.store(position + 1, Ordering::Release);
+                    return true;
+                }
+                position = found;
+            } else if (sequence as isize - position as isize) < 0 {
+                return false; // full
+            } else {
+                position = self.enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        let mut position = self.dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % MAX_TASKS];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == position + 1 {
+                let found = self.dequeue.compare_and_swap(position, position + 1,
+                                                          Ordering::Relaxed);
+                if found == position {
+                    let index = slot.index.load(Ordering::Relaxed);
+                    slot.sequence.store(position + MAX_TASKS, Ordering::Release);
+                    return Some(index);
+                }
+                position = found;
+            } else if (sequence as isize - (position + 1) as isize) < 0 {
+                return None; // empty
+            } else {
+                position = self.dequeue.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Two tasks: one is ready at once, the other waits for a timer interrupt
+/// that wakes it. It has to be polled exactly twice, before and after the
+/// wake, and its waker must not queue anything once it finished. Then a
+/// task that spawns another from its poll, both have to run to the end.
+#[cfg(debug_assertions)]
+pub fn test_executor() {
+    use time;
+
+    static WAKER: IrqMutex<Option<Waker>> = IrqMutex::new(None);
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    static SPAWNER_POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Immediate;
+
+    impl Future for Immediate {
+        type Output = ();
+
+        fn poll(&mut self, _waker: &Waker) -> Poll<()> {
+            DONE.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(())
+        }
+    }
+
+    struct WaitForTimer;
+
+    impl Future for WaitForTimer {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<()> {
+            POLLS.fetch_add(1, Ordering::SeqCst);
+            if FIRED.load(Ordering::SeqCst) {
+                DONE.fetch_add(1, Ordering::SeqCst);
+                return Poll::Ready(());
+            }
+            *WAKER.lock() = Some(*waker);
+            time::after_in_interrupt(20, fire, 0).expect("timer table full");
+            Poll::Pending
+        }
+    }
+
+    struct Spawner;
+
+    impl Future for Spawner {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<()> {
+            if SPAWNER_POLLS.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Poll::Ready(());
+            }
+            // its own slot is taken while it is polled
+            spawn(Immediate).expect("could not spawn a task");
+            waker.wake();
+            Poll::Pending
+        }
+    }
+
+    fn fire(_: usize) {
+        FIRED.store(true, Ordering::SeqCst);
+        if let Some(waker) = *WAKER.lock() {
+            waker.wake();
+        }
+    }
+
+    spawn(WaitForTimer).expect("could not spawn a task");
+    spawn(Immediate).expect("could not spawn a task");
+    run();
+    assert_eq!(DONE.load(Ordering::SeqCst), 2);
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2, "a task was polled without a wake");
+
+    // the old waker names a finished task
+    let stale = WAKER.lock().take().unwrap();
+    stale.wake();
+    assert!(executor().ready.pop().is_none(), "a stale waker queued its slot");
+    spawn(Immediate).expect("could not spawn a task");
+    run();
+    assert_eq!(DONE.load(Ordering::SeqCst), 3);
+    assert_eq!(live_tasks(), 0);
+
+    spawn(Spawner).expect("could not spawn a task");
+    run();
+    assert_eq!(DONE.load(Ordering::SeqCst), 4, "the task spawned in a poll didn't run");
+    assert_eq!(SPAWNER_POLLS.load(Ordering::SeqCst), 2, "a task was polled without a wake");
+    assert_eq!(live_tasks(), 0);
+    println!("executor test passed");
+}
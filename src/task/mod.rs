@@ -0,0 +1,1064 @@
+// preemptive kernel threads
+// every thread has a stack from the stack allocator and a `Context` with the
+// registers the System V ABI makes a callee preserve, RSP and RFLAGS.
+// `switch_context` (switch_context.asm) stores them for the running thread
+// and loads the next one's, the compiler saved the others around the call.
+// `schedule` picks the next ready thread from the run queue, which has a
+// queue per `Priority`: the highest level with a ready thread wins, round
+// robin within it, and a thread that waited `STARVATION_TICKS` moves up a
+// level until it ran once. threads can call it to yield, and after
+// `TIME_SLICE_TICKS` timer ticks of the same thread the IRQ stubs call it
+// on their way out, after the EOI: the stub saved all
+// registers of the interrupted code on its stack, so switching threads
+// from there keeps it intact, and the iretq of the stub resumes the thread
+// when it is picked again. `disable_preemption` defers that for critical
+// sections, an `IrqMutex` prevents it anyway. the code running `rust_main`
+// becomes thread 0 in `init` and keeps the boot stack.
+// `spawn` runs a closure, boxed on the heap, through `trampoline`, which
+// calls `exit` when it returns. the closure's value goes to the
+// `JoinHandle`, `join` blocks until the thread exited. the slot of an
+// exited thread is reaped by the next `schedule` on another stack (never by
+// the thread itself, it still runs on its stack until the switch), which
+// gives its stack back to the stack allocator. the stacks have guard pages,
+// `stack_owner` tells the page fault handler and backtraces whose stack an
+// address is on.
+// `sleep_ms` blocks the thread until a timer from `time::after_in_interrupt`
+// makes it ready again.
+// when the running thread blocks or exits and no other is ready, `schedule`
+// switches to the idle thread, which halts until an interrupt makes one
+// ready. it isn't in the round robin and can't block. the timer ticks that
+// land in it give `cpu_usage`. there is one for the boot CPU so far
+
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use core::{cmp, mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use memory::{self, MemoryController, Stack};
+use sync::IrqMutex;
+use interrupts::{self, InterruptContext};
+use time;
+use cpu;
+use percpu;
+
+pub mod executor;
+
+const MAX_THREADS: usize = 16;
+pub const DEFAULT_STACK_PAGES: usize = 4;
+pub const TIME_SLICE_TICKS: usize = 5;
+// new threads start with interrupts enabled (IF and the reserved bit 1)
+const INITIAL_RFLAGS: u64 = 0x202;
+const NO_THREAD: usize = usize::max_value();
+// enough for `hlt` and the interrupts on top of it
+const IDLE_STACK_PAGES: usize = 1;
+const HLT: u8 = 0xf4;
+const PRIORITIES: usize = 3;
+// ticks in the run queue before a thread moves up a level
+pub const STARVATION_TICKS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    // in `sleep_ms` or `JoinHandle::join`
+    Blocked,
+    // waits to be reaped
+    Exited,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyThreads,
+    OutOfStacks,
+}
+
+/// A ready thread only runs while no thread of a higher priority is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+// by their index in the run queue
+const LEVELS: [Priority; PRIORITIES] = [Priority::Low, Priority::Normal, Priority::High];
+
+/// The registers `switch_context` saves, in the order it expects them.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+}
+
+impl Context {
+    const fn empty() -> Context {
+        Context { rsp: 0, rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0, rflags: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Thread {
+    pub id: ThreadId,
+    pub state: State,
+    context: Context,
+    // None for thread 0, which runs on the boot stack
+    stack: Option<Stack>,
+    // `PREEMPT_COUNT` while the thread isn't running
+    preempt_count: usize,
+    // the boxed closure of `spawn` until `trampoline` takes it, 0 if none
+    closure: usize,
+    // the thread blocked in `join` on this one, `NO_THREAD` if none
+    joiner: usize,
+    priority: Priority,
+    // the level it is queued at, above `priority` while starving
+    effective: Priority,
+    // `time::ticks` when it was queued or moved up
+    ready_since: u64,
+}
+
+// the indices of the ready threads, one queue per priority. the idle thread
+// and the running one aren't in it
+struct RunQueue {
+    levels: [Level; PRIORITIES],
+}
+
+#[derive(Clone, Copy)]
+struct Level {
+    // a ring, `count` from `head` on are used
+    indices: [usize; MAX_THREADS],
+    head: usize,
+    count: usize,
+}
+
+/// Owns the value of a thread from `spawn`. Dropping it detaches the
+/// thread, the value is dropped when it exits.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<IrqMutex<Option<T>>>,
+}
+
+/// Defers preemption until it is dropped. Nests.
+pub struct PreemptGuard {
+    _private: (),
+}
+
+// a thread's id is its index
+static THREADS: IrqMutex<[Option<Thread>; MAX_THREADS]> = IrqMutex::new([None; MAX_THREADS]);
+// only taken with `THREADS`
+static RUN_QUEUE: IrqMutex<RunQueue> = IrqMutex::new(RunQueue::new());
+percpu! {
+    // the thread on this CPU, NO_THREAD on the APs, which run none yet
+    static CURRENT: AtomicUsize = AtomicUsize::new(0);
+}
+// of the running thread
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+// ticks since the running thread was switched to
+static SLICE_TICKS: AtomicUsize = AtomicUsize::new(0);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static IDLE: AtomicUsize = AtomicUsize::new(NO_THREAD);
+// ticks of the current second, and those of them in the idle thread
+static WINDOW_TICKS: AtomicUsize = AtomicUsize::new(0);
+percpu! {
+    static IDLE_TICKS: AtomicUsize = AtomicUsize::new(0);
+}
+// the percentage of the last full second
+static USAGE: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+}
+
+/// Makes the running code thread 0 and creates the idle thread. Has to be
+/// called before `spawn_raw`.
+pub fn init(memory_controller: &mut MemoryController) {
+    assert_has_not_been_called!("task::init must be called only once");
+    THREADS.lock()[0] = Some(Thread {
+        id: ThreadId(0),
+        state: State::Running,
+        context: Context::empty(),
+        stack: None,
+        preempt_count: 0,
+        closure: 0,
+        joiner: NO_THREAD,
+        priority: Priority::Normal,
+        effective: Priority::Normal,
+        ready_since: 0,
+    });
+    let idle = create(memory_controller, IDLE_STACK_PAGES, None, idle_thread, 0)
+        .expect("task: could not create the idle thread");
+    IDLE.store(idle.0, Ordering::SeqCst);
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+// switches away whenever another thread is ready, `switch_to_next` comes
+// back here when none is
+extern "C" fn idle_thread() -> ! {
+    loop {
+        unsafe { cpu_interrupts::disable() };
+        if !switch_to_next() {
+            cpu::enable_interrupts_and_halt();
+        }
+    }
+}
+
+/// For an AP that just started: it runs no thread of the scheduler.
+pub fn init_ap() {
+    CURRENT.get().store(NO_THREAD, Ordering::SeqCst);
+}
+
+/// The thread that is running. Panics on a CPU that runs none, an AP.
+pub fn current() -> ThreadId {
+    match try_current() {
+        Some(id) => id,
+        None => panic!("task: CPU {} runs no thread", percpu::current().index()),
+    }
+}
+
+/// The thread that is running, None on a CPU that runs none.
+pub fn try_current() -> Option<ThreadId> {
+    match CURRENT.get().load(Ordering::SeqCst) {
+        NO_THREAD => None,
+        index => Some(ThreadId(index)),
+    }
+}
+
+/// Creates a ready thread of `Priority::Normal` that starts at `entry` on a
+/// new stack of `DEFAULT_STACK_PAGES` the first time `schedule` picks it.
+pub fn spawn_raw(memory_controller: &mut MemoryController, entry: extern "C" fn() -> !)
+                 -> Result<ThreadId, SpawnError>
+{
+    create(memory_controller, DEFAULT_STACK_PAGES, Some(Priority::Normal), entry, 0)
+}
+
+/// Creates a ready thread of `Priority::Normal` that runs `closure` on a
+/// stack of `DEFAULT_STACK_PAGES` and exits when it returns.
+pub fn spawn<F, T>(memory_controller: &mut MemoryController, closure: F)
+                   -> Result<JoinHandle<T>, SpawnError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    spawn_with_stack(memory_controller, DEFAULT_STACK_PAGES, closure)
+}
+
+/// Like `spawn`, with a stack of `stack_pages` pages.
+pub fn spawn_with_stack<F, T>(memory_controller: &mut MemoryController, stack_pages: usize,
+                              closure: F) -> Result<JoinHandle<T>, SpawnError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    spawn_thread(memory_controller, stack_pages, Priority::Normal, closure)
+}
+
+/// Like `spawn`, at `priority`.
+pub fn spawn_with_priority<F, T>(memory_controller: &mut MemoryController, priority: Priority,
+                                 closure: F) -> Result<JoinHandle<T>, SpawnError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    spawn_thread(memory_controller, DEFAULT_STACK_PAGES, priority, closure)
+}
+
+fn spawn_thread<F, T>(memory_controller: &mut MemoryController, stack_pages: usize,
+                      priority: Priority, closure: F) -> Result<JoinHandle<T>, SpawnError>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    let result = Arc::new(IrqMutex::new(None));
+    let destination = result.clone();
+    let id = spawn_closure(memory_controller, stack_pages, priority, move || {
+        let value = closure();
+        *destination.lock() = Some(value);
+    })?;
+    Ok(JoinHandle { id: id, result: result })
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread exited and returns the value of its closure,
+    /// at once if it exited already. Not for interrupt handlers.
+    pub fn join(self) -> T {
+        assert!(!interrupts::in_interrupt_context(), "join in an interrupt handler");
+        assert!(current() != self.id, "a thread can't join itself");
+        let enabled = interrupts::interrupts_enabled();
+        // with interrupts off the thread can't exit between the check and
+        // registering as its joiner. a slot with the value set may already
+        // be reaped and reused, so it is only touched without one
+        let value = loop {
+            unsafe { cpu_interrupts::disable() };
+            if let Some(value) = self.result.lock().take() {
+                break value;
+            }
+            if let Some(ref mut thread) = THREADS.lock()[self.id.0] {
+                thread.joiner = CURRENT.get().load(Ordering::SeqCst);
+            }
+            block();
+        };
+        if enabled {
+            unsafe { cpu_interrupts::enable() };
+        }
+        value
+    }
+}
+
+fn spawn_closure<F>(memory_controller: &mut MemoryController, stack_pages: usize,
+                    priority: Priority, closure: F) -> Result<ThreadId, SpawnError>
+    where F: FnOnce() + Send + 'static
+{
+    let closure = Box::into_raw(Box::new(closure));
+    let result = create(memory_controller, stack_pages, Some(priority), trampoline::<F>,
+                        closure as usize);
+    if result.is_err() {
+        drop(unsafe { Box::from_raw(closure) });
+    }
+    result
+}
+
+// the entry of the threads from `spawn`
+extern "C" fn trampoline<F: FnOnce()>() -> ! {
+    let closure = {
+        let mut threads = THREADS.lock();
+        let thread = threads[current().0].as_mut().expect("trampoline without a thread");
+        mem::replace(&mut thread.closure, 0)
+    };
+    let closure = *unsafe { Box::from_raw(closure as *mut F) };
+    closure();
+    exit()
+}
+
+// without a priority the thread isn't queued, that is the idle thread
+fn create(memory_controller: &mut MemoryController, stack_pages: usize,
+          priority: Option<Priority>, entry: extern "C" fn() -> !, closure: usize)
+          -> Result<ThreadId, SpawnError>
+{
+    let mut threads = THREADS.lock();
+    assert!(threads[0].is_some(), "task::spawn called before task::init");
+    let index = match threads.iter().position(|slot| slot.is_none()) {
+        Some(index) => index,
+        None => return Err(SpawnError::TooManyThreads),
+    };
+    let stack = match memory_controller.alloc_stack(stack_pages) {
+        Some(stack) => stack,
+        None => return Err(SpawnError::OutOfStacks),
+    };
+
+    // `switch_context` returns into `entry` as if it had been called from
+    // address 0, with the alignment the ABI expects at a function entry.
+    // the zero return address and RBP end backtraces of the thread
+    let top = stack.top();
+    unsafe {
+        *((top - 8) as *mut u64) = 0;
+        *((top - 16) as *mut u64) = entry as u64;
+    }
+    let mut context = Context::empty();
+    context.rsp = (top - 16) as u64;
+    context.rflags = INITIAL_RFLAGS;
+    let mut thread = Thread {
+        id: ThreadId(index),
+        state: State::Ready,
+        context: context,
+        stack: Some(stack),
+        preempt_count: 0,
+        closure: closure,
+        joiner: NO_THREAD,
+        priority: priority.unwrap_or(Priority::Low),
+        effective: priority.unwrap_or(Priority::Low),
+        ready_since: 0,
+    };
+    if priority.is_some() {
+        enqueue(&mut RUN_QUEUE.lock(), &mut thread);
+    }
+    threads[index] = Some(thread);
+    Ok(ThreadId(index))
+}
+
+/// Ends the running thread. Thread 0 can't exit.
+pub fn exit() -> ! {
+    unsafe { cpu_interrupts::disable() };
+    {
+        let mut threads = THREADS.lock();
+        let current = CURRENT.get().load(Ordering::SeqCst);
+        assert!(current != 0, "thread 0 can't exit");
+        let joiner = {
+            let thread = threads[current].as_mut().unwrap();
+            thread.state = State::Exited;
+            mem::replace(&mut thread.joiner, NO_THREAD)
+        };
+        if joiner != NO_THREAD {
+            make_ready(&mut threads, joiner);
+        }
+    }
+    switch_to_next();
+    unreachable!("an exited thread was scheduled");
+}
+
+/// `yield_now` has to return false at once while preemption is disabled.
+#[cfg(debug_assertions)]
+pub fn test_yield_now() {
+    {
+        let _guard = disable_preemption();
+        let before = current();
+        assert!(!yield_now(), "yield_now switched with preemption disabled");
+        assert_eq!(current(), before);
+    }
+    yield_now();
+    assert_eq!(current(), ThreadId(0));
+    println!("yield_now test passed");
+}
+
+/// Returns whether the thread `id` exists and hasn't exited.
+pub fn is_alive(id: ThreadId) -> bool {
+    THREADS.lock().get(id.0)
+        .map_or(false, |slot| slot.map_or(false, |thread| thread.state != State::Exited))
+}
+
+pub fn disable_preemption() -> PreemptGuard {
+    PREEMPT_COUNT.fetch_add(1, Ordering::SeqCst);
+    PreemptGuard { _private: () }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        PREEMPT_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// whether the interrupt woke a `hlt`, the wait loops of thread 0 halt
+// without the idle thread. the byte before the return address is 0xf4 then,
+// the end of another instruction only rarely
+fn halted(context: &InterruptContext) -> bool {
+    let address = context.stack_frame.instruction_pointer.0;
+    unsafe { ptr::read_volatile((address - 1) as *const u8) == HLT }
+}
+
+/// The percentage of the last second the CPU was busy, not in the idle
+/// thread or halted. 0 in the first second.
+pub fn cpu_usage() -> usize {
+    USAGE.load(Ordering::Relaxed)
+}
+
+/// How many `PreemptGuard`s the running thread holds.
+pub fn preempt_count() -> usize {
+    PREEMPT_COUNT.load(Ordering::SeqCst)
+}
+
+/// For the timer interrupt: asks for a reschedule when the running thread
+/// has used up its time slice, and counts the ticks that find the CPU idle.
+pub fn tick(context: &InterruptContext) {
+    if CURRENT.get().load(Ordering::Relaxed) == IDLE.load(Ordering::Relaxed) || halted(context) {
+        IDLE_TICKS.get().fetch_add(1, Ordering::Relaxed);
+    }
+    let window = WINDOW_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if window >= cmp::max(time::tick_hz() as usize, 1) {
+        let idle = IDLE_TICKS.get().swap(0, Ordering::Relaxed);
+        WINDOW_TICKS.store(0, Ordering::Relaxed);
+        USAGE.store(100 - cmp::min(idle, window) * 100 / window, Ordering::Relaxed);
+    }
+    if SLICE_TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE_TICKS {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// For the IRQ stubs, after their EOI: switches threads if a reschedule is
+/// needed and allowed. Only the outermost interrupt does, a nested one
+/// would leave the handler it interrupted half done.
+pub fn preempt_on_interrupt_return() {
+    if NEED_RESCHED.load(Ordering::Relaxed) && preempt_count() == 0
+        && !interrupts::in_interrupt_context() {
+        schedule();
+    }
+}
+
+/// Switches to the next ready thread. Returns when this thread is picked
+/// again, or at once if no other thread is ready. Must not be called from
+/// an interrupt handler.
+pub fn schedule() {
+    assert!(!interrupts::in_interrupt_context(), "task::schedule in an interrupt handler");
+    reschedule();
+}
+
+/// For wait loops: lets the other ready threads run and returns true when
+/// this one is picked again. Returns false at once if there was no other
+/// ready thread, or if it can't switch: before `init`, with preemption
+/// disabled or in an interrupt handler.
+pub fn yield_now() -> bool {
+    if !can_switch() {
+        return false;
+    }
+    reschedule()
+}
+
+/// Returns whether the running code can give up the CPU: it is a thread
+/// (after `init`, not on an AP), preemption is enabled and it isn't an
+/// interrupt handler.
+pub fn can_switch() -> bool {
+    is_initialized() && try_current().is_some() && preempt_count() == 0 &&
+        !interrupts::in_interrupt_context()
+}
+
+/// Blocks the running thread for at least `ms` milliseconds, the others
+/// run meanwhile. Falls back to `time::sleep_ms` where it can't switch:
+/// before `init`, with preemption or interrupts disabled, or in an interrupt
+/// handler.
+pub fn sleep_ms(ms: u64) {
+    if !can_switch() || !interrupts::interrupts_enabled() {
+        return time::sleep_ms(ms);
+    }
+    let index = CURRENT.get().load(Ordering::SeqCst);
+    let deadline = time::uptime_us() + ms * 1000;
+    loop {
+        let now = time::uptime_us();
+        if now >= deadline {
+            break;
+        }
+        // interrupts stay off from arming the timer to the switch. on this
+        // CPU it can't fire in between, `wake` still copes if it does
+        unsafe { cpu_interrupts::disable() };
+        let remaining_ms = (deadline - now + 999) / 1000;
+        let timer = match time::after_in_interrupt(remaining_ms, wake, index) {
+            Some(timer) => timer,
+            None => {
+                unsafe { cpu_interrupts::enable() };
+                return time::sleep_ms(remaining_ms);
+            }
+        };
+        block();
+        unsafe { cpu_interrupts::enable() };
+        timer.cancel();
+    }
+}
+
+/// Blocks the running thread until `unblock`. Has to be called with
+/// interrupts off, after whatever will call `unblock` knows about the thread,
+/// so the wakeup can't come before the thread is blocked. Without another
+/// ready thread it idles meanwhile (with interrupts on), the interrupts do
+/// the waking. Returns with interrupts off. Only where `can_switch`.
+pub fn block() {
+    let index = CURRENT.get().load(Ordering::SeqCst);
+    assert!(index != IDLE.load(Ordering::SeqCst), "the idle thread can't block");
+    set_state(index, State::Blocked);
+    while !switch_to_next() && state(index) == Some(State::Blocked) {
+        cpu::enable_interrupts_and_halt();
+        unsafe { cpu_interrupts::disable() };
+    }
+}
+
+/// Makes a thread blocked in `block` ready and asks for a reschedule,
+/// never switches itself. Interrupt safe. A thread that didn't switch away
+/// yet just keeps running.
+pub fn unblock(id: ThreadId) {
+    let mut threads = THREADS.lock();
+    if id.0 < MAX_THREADS {
+        make_ready(&mut threads, id.0);
+    }
+}
+
+fn make_ready(threads: &mut [Option<Thread>; MAX_THREADS], index: usize) {
+    let current = CURRENT.get().load(Ordering::SeqCst);
+    // an AP runs no thread, the woken one waits for the BSP
+    let running = if current == NO_THREAD {
+        None
+    } else {
+        threads[current].map(|thread| thread.effective)
+    };
+    if let Some(ref mut thread) = threads[index] {
+        if thread.state == State::Blocked {
+            if current == index {
+                thread.state = State::Running;
+            } else {
+                enqueue(&mut RUN_QUEUE.lock(), thread);
+                // a lower priority would only wait in the queue anyway
+                if running.map_or(true, |running| thread.priority >= running) {
+                    NEED_RESCHED.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+// marks the thread ready at its own priority and queues it at the end
+fn enqueue(queue: &mut RunQueue, thread: &mut Thread) {
+    thread.state = State::Ready;
+    thread.effective = thread.priority;
+    thread.ready_since = time::ticks();
+    queue.levels[thread.priority as usize].push(thread.id.0);
+}
+
+/// Changes the priority of the thread `id`. A ready thread goes to the end
+/// of its new level. Not for the idle thread.
+pub fn set_priority(id: ThreadId, priority: Priority) {
+    assert!(id.0 != IDLE.load(Ordering::SeqCst), "the idle thread has no priority");
+    let mut threads = THREADS.lock();
+    if let Some(thread) = threads.get_mut(id.0).and_then(|slot| slot.as_mut()) {
+        thread.priority = priority;
+        if thread.state == State::Ready {
+            let mut queue = RUN_QUEUE.lock();
+            queue.levels[thread.effective as usize].remove(id.0);
+            enqueue(&mut queue, thread);
+        } else {
+            thread.effective = priority;
+        }
+    }
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// The priority the thread `id` was given, None if there is no such thread.
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    THREADS.lock().get(id.0).and_then(|slot| slot.map(|thread| thread.priority))
+}
+
+/// The thread whose stack (or its guard page) `address` is on, with the
+/// stack. None for the boot stack of thread 0, and if the thread table is
+/// locked, so it can be used from exception handlers.
+pub fn stack_owner(address: usize) -> Option<(ThreadId, Stack)> {
+    let threads = match THREADS.try_lock() {
+        Some(threads) => threads,
+        None => return None,
+    };
+    let owner = threads.iter().filter_map(|slot| *slot)
+        .filter_map(|thread| thread.stack.map(|stack| (thread.id, stack)))
+        .find(|&(_, stack)| stack.contains(address));
+    owner
+}
+
+// the timer callback of `sleep_ms`, in the timer interrupt
+fn wake(index: usize) {
+    unblock(ThreadId(index));
+}
+
+fn state(index: usize) -> Option<State> {
+    THREADS.lock()[index].map(|thread| thread.state)
+}
+
+fn set_state(index: usize, state: State) {
+    if let Some(ref mut thread) = THREADS.lock()[index] {
+        thread.state = state;
+    }
+}
+
+/// Returns whether `init` made the running code a thread.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+fn reschedule() -> bool {
+    memory::check_canaries();
+
+    // with interrupts off up to the switch, a tick in between would
+    // preempt a thread that `CURRENT` doesn't name anymore. the new thread
+    // gets its own RFLAGS from its context, this one its IF back below
+    let enabled = interrupts::interrupts_enabled();
+    unsafe { cpu_interrupts::disable() };
+    let switched = switch_to_next();
+    if enabled {
+        unsafe { cpu_interrupts::enable() };
+    }
+    switched
+}
+
+// returns whether another thread ran
+fn switch_to_next() -> bool {
+    SLICE_TICKS.store(0, Ordering::Relaxed);
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    let (old, new) = {
+        let mut threads = THREADS.lock();
+        let current = CURRENT.get().load(Ordering::SeqCst);
+        if current == NO_THREAD || threads[current].is_none() {
+            // on an AP, or before `init`
+            return false;
+        }
+        reap(&mut threads, current);
+        let idle = IDLE.load(Ordering::SeqCst);
+        let mut queue = RUN_QUEUE.lock();
+        queue.boost_starving(&mut threads, time::ticks());
+        // a thread that can go on keeps running rather than idle or let a
+        // lower priority run
+        let running = match threads[current] {
+            Some(thread) if thread.state == State::Running && current != idle => {
+                Some(thread.effective)
+            }
+            _ => None,
+        };
+        let next = match (queue.highest(), running) {
+            (Some(highest), Some(running)) if highest < running => return false,
+            (Some(highest), _) => queue.levels[highest as usize].pop().unwrap(),
+            (None, None) if idle != NO_THREAD && current != idle => idle,
+            (None, _) => return false,
+        };
+        {
+            let thread = threads[current].as_mut().unwrap();
+            if thread.state == State::Running {
+                if current == idle {
+                    thread.state = State::Ready;
+                } else {
+                    enqueue(&mut queue, thread);
+                }
+            }
+            thread.preempt_count = PREEMPT_COUNT.load(Ordering::SeqCst);
+        }
+        {
+            let thread = threads[next].as_mut().unwrap();
+            thread.state = State::Running;
+            PREEMPT_COUNT.store(thread.preempt_count, Ordering::SeqCst);
+        }
+        CURRENT.get().store(next, Ordering::SeqCst);
+        let old = &mut threads[current].as_mut().unwrap().context as *mut Context;
+        let new = &threads[next].as_ref().unwrap().context as *const Context;
+        (old, new)
+    };
+    // the lock is released before the switch, the new thread may take it.
+    // the pointers stay valid, interrupts are off and a slot is only reaped
+    // by another thread
+    unsafe { switch_context(old, new) };
+    true
+}
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue { levels: [Level { indices: [0; MAX_THREADS], head: 0, count: 0 }; PRIORITIES] }
+    }
+
+    // the highest level with a ready thread
+    fn highest(&self) -> Option<Priority> {
+        LEVELS.iter().rev().cloned().find(|&level| self.levels[level as usize].count > 0)
+    }
+
+    // moves the longest waiting thread of every level below the top one up
+    // a level, if it waited too long
+    fn boost_starving(&mut self, threads: &mut [Option<Thread>; MAX_THREADS], now: u64) {
+        for level in 0..PRIORITIES - 1 {
+            let index = match self.levels[level].front() {
+                Some(index) => index,
+                None => continue,
+            };
+            if let Some(ref mut thread) = threads[index] {
+                if now - thread.ready_since >= STARVATION_TICKS {
+                    self.levels[level].pop();
+                    thread.effective = LEVELS[level + 1];
+                    thread.ready_since = now;
+                    self.levels[level + 1].push(index);
+                }
+            }
+        }
+    }
+}
+
+impl Level {
+    fn push(&mut self, index: usize) {
+        assert!(self.count < MAX_THREADS, "task: run queue overflow");
+        self.indices[(self.head + self.count) % MAX_THREADS] = index;
+        self.count += 1;
+    }
+
+    fn front(&self) -> Option<usize> {
+        if self.count == 0 { None } else { Some(self.indices[self.head]) }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        let front = self.front();
+        if front.is_some() {
+            self.head = (self.head + 1) % MAX_THREADS;
+            self.count -= 1;
+        }
+        front
+    }
+
+    fn remove(&mut self, index: usize) {
+        for _ in 0..self.count {
+            let entry = self.pop().unwrap();
+            if entry != index {
+                self.push(entry);
+            }
+        }
+    }
+}
+
+// frees the slots of the exited threads, except the one that is still
+// exiting on its stack
+fn reap(threads: &mut [Option<Thread>; MAX_THREADS], current: usize) {
+    for (index, slot) in threads.iter_mut().enumerate() {
+        let exited = slot.map_or(false, |thread| thread.state == State::Exited);
+        if !exited || index == current {
+            continue;
+        }
+        if let Some(stack) = slot.take().and_then(|thread| thread.stack) {
+            memory::free_stack(stack);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+static TEST_ROUNDS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(debug_assertions)]
+extern "C" fn test_thread() -> ! {
+    let id = current();
+    let stack = THREADS.lock()[id.0].and_then(|thread| thread.stack)
+        .expect("test thread without a stack");
+    let mut rounds = 0;
+    loop {
+        // a local of this thread has to be on its own stack
+        let local = &rounds as *const usize as usize;
+        assert!(local >= stack.bottom() && local < stack.top(),
+                "thread {} runs on the wrong stack", id.0);
+        if rounds < 3 {
+            println!("thread {}: round {}", id.0, rounds);
+            TEST_ROUNDS.fetch_add(1, Ordering::SeqCst);
+        }
+        schedule();
+        assert_eq!(current(), id, "thread {} resumed as another thread", id.0);
+        rounds += 1;
+    }
+}
+
+/// Spawns two threads that print their id and yield, three rounds each, and
+/// yields to them until they are done.
+#[cfg(debug_assertions)]
+pub fn test_threads(memory_controller: &mut MemoryController) {
+    let first = spawn_raw(memory_controller, test_thread).expect("could not spawn a thread");
+    let second = spawn_raw(memory_controller, test_thread).expect("could not spawn a thread");
+    assert!(first != second);
+    let mut switches = 0;
+    while TEST_ROUNDS.load(Ordering::SeqCst) < 6 {
+        schedule();
+        assert_eq!(current(), ThreadId(0));
+        switches += 1;
+        assert!(switches < 100, "the test threads don't make progress");
+    }
+    println!("thread test passed ({} rounds back in thread 0)", switches);
+}
+
+#[cfg(debug_assertions)]
+static TEST_STOP: AtomicBool = AtomicBool::new(false);
+#[cfg(debug_assertions)]
+static TEST_COUNTERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+// counts without ever yielding until the test is over, then only yields
+#[cfg(debug_assertions)]
+fn count_until_stopped(counter: &AtomicUsize) -> ! {
+    while !TEST_STOP.load(Ordering::SeqCst) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    loop {
+        schedule();
+    }
+}
+
+#[cfg(debug_assertions)]
+extern "C" fn test_count_first() -> ! {
+    count_until_stopped(&TEST_COUNTERS[0])
+}
+
+#[cfg(debug_assertions)]
+extern "C" fn test_count_second() -> ! {
+    count_until_stopped(&TEST_COUNTERS[1])
+}
+
+/// Spawns two threads that count in a loop without yielding, waits a while
+/// and checks that both made progress, and about the same.
+#[cfg(debug_assertions)]
+pub fn test_preemption(memory_controller: &mut MemoryController) {
+    use time;
+
+    assert!(interrupts::interrupts_enabled(), "test_preemption needs the timer");
+    spawn_raw(memory_controller, test_count_first).expect("could not spawn a thread");
+    spawn_raw(memory_controller, test_count_second).expect("could not spawn a thread");
+    time::sleep_ms(500);
+    TEST_STOP.store(true, Ordering::SeqCst);
+    let first = TEST_COUNTERS[0].load(Ordering::SeqCst);
+    let second = TEST_COUNTERS[1].load(Ordering::SeqCst);
+    assert!(first > 0 && second > 0, "a thread never ran: {} and {}", first, second);
+    assert!(first < second * 2 && second < first * 2,
+            "the threads didn't get the same time: {} and {}", first, second);
+    println!("preemption test passed ({} and {} increments)", first, second);
+}
+
+/// Spawns a closure that captures a value with a `Drop` that sets a flag
+/// and a number it stores, and waits until the thread exited and was reaped.
+#[cfg(debug_assertions)]
+pub fn test_spawn(memory_controller: &mut MemoryController) {
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+
+    struct SetOnDrop(&'static AtomicBool);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = SetOnDrop(&DROPPED);
+    let number = 42;
+    let id = spawn_with_stack(memory_controller, 2, move || {
+        let _flag = flag;
+        RESULT.store(number, Ordering::SeqCst);
+    }).expect("could not spawn a thread").id();
+    let mut switches = 0;
+    while is_alive(id) {
+        schedule();
+        switches += 1;
+        assert!(switches < 100, "the spawned thread doesn't exit");
+    }
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+    assert!(DROPPED.load(Ordering::SeqCst), "the captured value wasn't dropped");
+    // the next switch reaps it
+    schedule();
+    assert!(THREADS.lock()[id.0].is_none(), "the exited thread wasn't reaped");
+    println!("spawn test passed");
+}
+
+/// Three threads sleep for 30, 10 and 20 ms and record when they wake up,
+/// they have to wake in deadline order and not before it.
+#[cfg(debug_assertions)]
+pub fn test_sleep(memory_controller: &mut MemoryController) {
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+    static ORDER: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0),
+                                      AtomicUsize::new(0)];
+    static WAKE_TIMES: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0),
+                                           AtomicUsize::new(0)];
+
+    let start = time::uptime_us();
+    let mut ids = [ThreadId(0); 3];
+    for (id, &ms) in ids.iter_mut().zip([30, 10, 20].iter()) {
+        *id = spawn(memory_controller, move || {
+            sleep_ms(ms);
+            let woke = time::uptime_us();
+            let position = WOKEN.fetch_add(1, Ordering::SeqCst);
+            ORDER[position].store(ms as usize, Ordering::SeqCst);
+            WAKE_TIMES[position].store(woke as usize, Ordering::SeqCst);
+        }).expect("could not spawn a thread").id();
+    }
+    // thread 0 sleeps too, so nothing may be left to run at times
+    let give_up = start + 1_000_000;
+    while ids.iter().any(|&id| is_alive(id)) {
+        assert!(time::uptime_us() < give_up, "the sleeping threads didn't wake up");
+        sleep_ms(5);
+    }
+    for (position, &ms) in [10, 20, 30].iter().enumerate() {
+        assert_eq!(ORDER[position].load(Ordering::SeqCst), ms, "woken out of order");
+        let woke = WAKE_TIMES[position].load(Ordering::SeqCst) as u64;
+        assert!(woke >= start + ms as u64 * 1000, "woken {} us early",
+                start + ms as u64 * 1000 - woke);
+    }
+    println!("sleep test passed (woken after {}, {} and {} us)",
+             WAKE_TIMES[0].load(Ordering::SeqCst) as u64 - start,
+             WAKE_TIMES[1].load(Ordering::SeqCst) as u64 - start,
+             WAKE_TIMES[2].load(Ordering::SeqCst) as u64 - start);
+}
+
+/// Joins a thread that is still running and one that exited already, and
+/// drops the handle of a third without joining it.
+#[cfg(debug_assertions)]
+pub fn test_join(memory_controller: &mut MemoryController) {
+    let running = spawn(memory_controller, || {
+        sleep_ms(20);
+        6 * 7
+    }).expect("could not spawn a thread");
+    assert_eq!(running.join(), 42);
+
+    let finished = spawn(memory_controller, || Box::new([1u8, 2, 3]))
+        .expect("could not spawn a thread");
+    let id = finished.id();
+    while is_alive(id) {
+        schedule();
+    }
+    assert_eq!(*finished.join(), [1, 2, 3]);
+
+    let detached = spawn(memory_controller, || sleep_ms(10)).expect("could not spawn a thread");
+    let id = detached.id();
+    drop(detached);
+    while is_alive(id) {
+        sleep_ms(5);
+    }
+    println!("join test passed");
+}
+
+/// Spawns and joins 10,000 threads, their stacks have to be reused instead
+/// of using up the stack area and physical memory. Each finds its own stack
+/// with `stack_owner`.
+#[cfg(debug_assertions)]
+pub fn test_stack_reuse(memory_controller: &mut MemoryController) {
+    const ROUNDS: usize = 10_000;
+    // a few stacks and the page tables for them
+    const SLACK_FRAMES: usize = 16;
+
+    let frames = || memory::frame_stats().expect("frame allocator locked").0;
+    let before = frames();
+    for round in 0..ROUNDS {
+        let thread = spawn(memory_controller, || {
+            let local = 0u8;
+            stack_owner(&local as *const u8 as usize).map(|(id, _)| id) == Some(current())
+        }).unwrap_or_else(|error| panic!("spawn {} failed: {:?}", round, error));
+        assert!(thread.join(), "a thread is not on its own stack");
+    }
+    assert!(frames() <= before + SLACK_FRAMES,
+            "{} threads used {} frames", ROUNDS, frames() - before);
+    println!("stack reuse test passed ({} threads)", ROUNDS);
+}
+
+/// Sleeps a bit over a second with nothing else to run, the CPU has to go
+/// to the idle thread and `cpu_usage` has to show it mostly idle.
+#[cfg(debug_assertions)]
+pub fn test_idle() {
+    let idle = ThreadId(IDLE.load(Ordering::SeqCst));
+    assert!(is_alive(idle), "no idle thread");
+    sleep_ms(1100);
+    let usage = cpu_usage();
+    assert!(usage < 50, "{}% busy while sleeping", usage);
+    println!("idle test passed ({}% busy)", usage);
+}
+
+/// Runs two `Normal` threads that never give up the CPU. A `High` thread
+/// that sleeps in steps of 10 ms has to run within a time slice of every
+/// wakeup, and a `Low` one has to get the CPU through the starvation boost.
+#[cfg(debug_assertions)]
+pub fn test_priorities(memory_controller: &mut MemoryController) {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static LOW_RAN: AtomicBool = AtomicBool::new(false);
+
+    fn hog() {
+        while !STOP.load(Ordering::SeqCst) {}
+    }
+
+    STOP.store(false, Ordering::SeqCst);
+    let first = spawn(memory_controller, hog).expect("could not spawn a thread");
+    let second = spawn(memory_controller, hog).expect("could not spawn a thread");
+    let slice_us = TIME_SLICE_TICKS as u64 * 1_000_000 / cmp::max(time::tick_hz(), 1) as u64;
+    let high = spawn_with_priority(memory_controller, Priority::High, || {
+        let mut worst = 0;
+        for _ in 0..10 {
+            let start = time::uptime_us();
+            sleep_ms(10);
+            worst = cmp::max(worst, time::uptime_us() - start - 10_000);
+        }
+        worst
+    }).expect("could not spawn a thread");
+    assert_eq!(priority(high.id()), Some(Priority::High));
+    let worst = high.join();
+    assert!(worst < slice_us, "the high priority thread waited {} us", worst);
+
+    let low = spawn_with_priority(memory_controller, Priority::Low, || {
+        LOW_RAN.store(true, Ordering::SeqCst);
+    }).expect("could not spawn a thread");
+    let start = time::uptime_ms();
+    while !LOW_RAN.load(Ordering::SeqCst) {
+        assert!(time::uptime_ms() - start < 2000, "the low priority thread starved");
+        sleep_ms(10);
+    }
+    low.join();
+
+    STOP.store(true, Ordering::SeqCst);
+    first.join();
+    second.join();
+    println!("priority test passed (high waited at most {} us)", worst);
+}
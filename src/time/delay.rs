@@ -0,0 +1,52 @@
+// short busy waits
+// `delay_us` counts down PIT channel 2 in one shot mode and watches its
+// output on port 0x61, which needs neither interrupts nor channel 0. once
+// `tsc::init` has measured an invariant TSC it spins on the TSC instead,
+// which is finer than the ~1 us a port access takes. everything runs
+// before the tick exists, so nothing here may use `ticks`
+
+use core::cmp;
+use cpu;
+use pit;
+use super::{ticks, tick_hz, tsc};
+
+const CALIBRATION_MS: u64 = 10;
+// the longest one shot count, 0 would mean 65536
+const MAX_CYCLES: u64 = 0xffff;
+
+/// Busy waits for at least `us` microseconds. Works with interrupts
+/// disabled and before `time::init`.
+pub fn delay_us(us: u32) {
+    let tsc_per_ms = tsc::per_ms();
+    if tsc_per_ms != 0 && tsc::is_invariant() {
+        let end = cpu::rdtsc() + (us as u64 * tsc_per_ms + 999) / 1000;
+        while cpu::rdtsc() < end {}
+        return;
+    }
+    let mut cycles = (us as u64 * pit::BASE_FREQUENCY as u64 + 999_999) / 1_000_000;
+    while cycles > 0 {
+        let chunk = cmp::min(cycles, MAX_CYCLES);
+        pit::wait_cycles(chunk as u16);
+        cycles -= chunk;
+    }
+}
+
+/// Compares a 10 ms `delay_us` with the tick and complains if they disagree
+/// by more than a tick. Needs the tick running and interrupts enabled.
+pub fn check_delay() {
+    let hz = tick_hz() as u64;
+    if hz == 0 {
+        return;
+    }
+    // start right after a tick, so only the end is off by up to one
+    let first = ticks();
+    while ticks() == first {}
+    let start = ticks();
+    delay_us((CALIBRATION_MS * 1000) as u32);
+    let elapsed = ticks() - start;
+    let expected = hz * CALIBRATION_MS / 1000;
+    if elapsed + 1 < expected || elapsed > expected + 1 {
+        println!("time: delay_us({}) took {} ticks instead of {}, delays are off",
+                 CALIBRATION_MS * 1000, elapsed, expected);
+    }
+}
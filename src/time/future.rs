@@ -0,0 +1,142 @@
+// timers as executor futures
+// a `Sleep` arms a timer with the waker it was polled with, the timer
+// interrupt wakes it at the deadline and the next poll finds the deadline
+// passed. polled with another waker it re-arms, dropped it disarms, so a
+// `timeout` that finished early leaves no timer behind. if the table is full
+// it wakes itself at once and just gets polled again
+
+use task::executor::{Future, Poll, Waker};
+use super::timer::{self, TimerHandle};
+use super::uptime_us;
+
+/// The future of `timeout` didn't finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+pub struct Sleep {
+    deadline_us: u64,
+    // the armed timer and the waker it wakes
+    timer: Option<(TimerHandle, Waker)>,
+}
+
+/// A future that is ready once at least `ms` milliseconds have passed.
+pub fn sleep_async(ms: u64) -> Sleep {
+    Sleep { deadline_us: uptime_us() + ms * 1000, timer: None }
+}
+
+impl Sleep {
+    fn disarm(&mut self) {
+        if let Some((timer, _)) = self.timer.take() {
+            timer.cancel();
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        if uptime_us() >= self.deadline_us {
+            self.disarm();
+            return Poll::Ready(());
+        }
+        let armed = self.timer.map_or(false, |(_, armed)| armed.will_wake(waker));
+        if armed {
+            return Poll::Pending;
+        }
+        self.disarm();
+        match timer::wake_at(self.deadline_us, *waker) {
+            Some(timer) => self.timer = Some((timer, *waker)),
+            None => waker.wake(),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.disarm();
+    }
+}
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Runs `future`, but gives up after `ms` milliseconds.
+pub fn timeout<F: Future>(ms: u64, future: F) -> Timeout<F> {
+    Timeout { future: future, sleep: sleep_async(ms) }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Result<F::Output, TimedOut>> {
+        // a future that finishes right at the deadline still counts
+        if let Poll::Ready(output) = self.future.poll(waker) {
+            return Poll::Ready(Ok(output));
+        }
+        match self.sleep.poll(waker) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Sleeps through `block_on` and in an executor task, then lets a future
+/// that never finishes time out and one that finishes in time through.
+#[cfg(debug_assertions)]
+pub fn test_timer_futures() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use task::executor;
+
+    static SLEPT: AtomicUsize = AtomicUsize::new(0);
+
+    struct Never;
+
+    impl Future for Never {
+        type Output = ();
+
+        fn poll(&mut self, _waker: &Waker) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    struct SleepTwice {
+        sleep: Sleep,
+    }
+
+    impl Future for SleepTwice {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<()> {
+            loop {
+                if let Poll::Pending = self.sleep.poll(waker) {
+                    return Poll::Pending;
+                }
+                if SLEPT.fetch_add(1, Ordering::SeqCst) == 1 {
+                    return Poll::Ready(());
+                }
+                self.sleep = sleep_async(10);
+            }
+        }
+    }
+
+    let start = uptime_us();
+    executor::block_on(sleep_async(20));
+    assert!(uptime_us() - start >= 20_000, "sleep_async woke early");
+
+    let start = uptime_us();
+    executor::spawn(SleepTwice { sleep: sleep_async(10) }).expect("could not spawn a task");
+    executor::run();
+    assert_eq!(SLEPT.load(Ordering::SeqCst), 2);
+    assert!(uptime_us() - start >= 20_000, "a task's sleep woke early");
+
+    let start = uptime_us();
+    assert_eq!(executor::block_on(timeout(10, Never)), Err(TimedOut));
+    assert!(uptime_us() - start >= 10_000, "the timeout expired early");
+    assert_eq!(executor::block_on(timeout(1000, sleep_async(5))), Ok(()));
+    assert!(uptime_us() - start < 500_000, "the timeout didn't let the sleep finish");
+    println!("timer futures test passed");
+}
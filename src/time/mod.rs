@@ -0,0 +1,369 @@
+// timekeeping based on the timer interrupt
+// the tick comes from the best rated registered `TickSource` (the HPET,
+// then the local APIC timer, then PIT channel 0), `now_ns` reads the best
+// `ClockSource` (an invariant TSC, then the HPET, else the tick).
+// `clocksource=` and `counter=` on the command line pick them explicitly,
+// `switch_tick_source` and `switch_counter` change them at runtime.
+// everything above `ticks` doesn't care which.
+// both times are kept as the value at the last switch plus what the new
+// source counted since, so a switch never makes them jump back. between two
+// ticks the uptime advances with the TSC. `sleep_async` and `timeout` are
+// the same timers as executor futures
+
+use core::cmp;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts as instructions;
+use sync::IrqMutex;
+use cpu;
+use pit;
+use rtc;
+use hpet;
+use watchdog;
+use memory;
+use task;
+use cmdline;
+use interrupts::{self, InterruptContext};
+
+pub use self::delay::{delay_us, check_delay};
+pub use self::timer::{after, after_with, after_in_interrupt, every, TimerHandle};
+pub use self::future::{sleep_async, timeout, Sleep, Timeout, TimedOut};
+pub use self::wall::{wall_now, wall_now_us, wall_date_time, resync};
+pub use self::source::{ClockSource, TickSource, register_clock_source, register_tick_source};
+#[cfg(debug_assertions)]
+pub use self::timer::test_timers;
+#[cfg(debug_assertions)]
+pub use self::future::test_timer_futures;
+
+mod delay;
+mod future;
+mod source;
+mod timer;
+mod wall;
+pub mod tsc;
+
+pub const TIMER_IRQ: u8 = 0;
+
+// default frequency of the timer interrupt
+pub const DEFAULT_HZ: u32 = 100;
+
+// incremented by the timer interrupt, so it must stay lock free
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicUsize = AtomicUsize::new(0);
+// the uptime and the tick count when the rate last changed
+static OFFSET_US: AtomicU64 = AtomicU64::new(0);
+static OFFSET_TICKS: AtomicU64 = AtomicU64::new(0);
+// the TSC at the last tick, for the time in between
+static LAST_TICK_TSC: AtomicU64 = AtomicU64::new(0);
+// the largest uptime handed out, nothing earlier is ever returned
+static LAST_UPTIME_US: AtomicU64 = AtomicU64::new(0);
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+static TICK: IrqMutex<Option<&'static TickSource>> = IrqMutex::new(None);
+static COUNTER: IrqMutex<Option<Counter>> = IrqMutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    UnknownSource,
+    StartFailed,
+}
+
+// the clock source behind `now_ns`
+struct Counter {
+    source: &'static ClockSource,
+    // the counter value and the time at the switch to it
+    start: u64,
+    start_ns: u64,
+}
+
+impl Counter {
+    fn now_ns(&self) -> u64 {
+        let elapsed = self.source.read().wrapping_sub(self.start);
+        let frequency = self.source.frequency();
+        // split up to not overflow
+        self.start_ns + elapsed / frequency * 1_000_000_000
+            + elapsed % frequency * 1_000_000_000 / frequency
+    }
+}
+
+/// Starts the tick at `DEFAULT_HZ` (unless the source has a fixed rate)
+/// and selects the clock source for `now_ns`. Without `clocksource=` the
+/// best tick source that starts is used, the local APIC timer is calibrated
+/// against the PIT. `delay_us` switches to the TSC here if it is invariant.
+pub fn init() {
+    tsc::init();
+    // always there, the others registered in their drivers' `init`
+    register_tick_source(&pit::PIT_TICK);
+    register_tick_source(&rtc::RTC_TICK);
+
+    let requested = cmdline::get("clocksource").and_then(|name| {
+        let source = source::find_tick_source(name);
+        if source.is_none() {
+            println!("time: tick source {} not available", name);
+        }
+        source
+    });
+    let mut started = requested.map_or(false, |source| start_tick(source).is_ok());
+    // the others from the best down
+    let mut below = u32::max_value();
+    while !started {
+        let source = source::best_tick_source(below).expect("time: no tick source starts");
+        below = source.rating();
+        if requested.map_or(true, |requested| requested.name() != source.name()) {
+            started = start_tick(source).is_ok();
+        }
+    }
+
+    let counter = match cmdline::get("counter") {
+        Some(name) => source::find_clock_source(name).or_else(|| {
+            println!("time: counter {} not available", name);
+            source::best_clock_source()
+        }),
+        None => source::best_clock_source(),
+    };
+    if let Some(counter) = counter {
+        use_counter(counter);
+    }
+    println!("time: {} tick at {} Hz, {} counter",
+             tick_source().map_or("no", |source| source.name()), tick_hz(),
+             counter.map_or("tick", |source| source.name()));
+    wall::init();
+}
+
+/// Returns the source of the tick, `None` before `init`.
+pub fn tick_source() -> Option<&'static TickSource> {
+    *TICK.lock()
+}
+
+/// Moves the tick to the named source. If it doesn't start, the old one
+/// goes on. The uptime goes on from where it was.
+pub fn switch_tick_source(name: &str) -> Result<(), TimeError> {
+    let source = source::find_tick_source(name).ok_or(TimeError::UnknownSource)?;
+    start_tick(source)
+}
+
+/// Makes `now_ns` read the named clock source. It goes on from where it
+/// was.
+pub fn switch_counter(name: &str) -> Result<(), TimeError> {
+    let source = source::find_clock_source(name).ok_or(TimeError::UnknownSource)?;
+    use_counter(source);
+    Ok(())
+}
+
+// stops the current tick and starts `source` instead, the old one again if
+// that fails. the lock keeps interrupts off, so no tick is counted at the
+// wrong rate
+fn start_tick(source: &'static TickSource) -> Result<(), TimeError> {
+    let mut tick = TICK.lock();
+    let old = tick.take();
+    if let Some(old) = old {
+        old.stop(timer_interrupt);
+    }
+    match source.start(DEFAULT_HZ, timer_interrupt) {
+        Ok(hz) => {
+            set_tick_hz(hz);
+            *tick = Some(source);
+            Ok(())
+        }
+        Err(()) => {
+            println!("time: the {} tick doesn't start", source.name());
+            if let Some(old) = old {
+                if let Ok(hz) = old.start(DEFAULT_HZ, timer_interrupt) {
+                    set_tick_hz(hz);
+                    *tick = Some(old);
+                }
+            }
+            Err(TimeError::StartFailed)
+        }
+    }
+}
+
+fn use_counter(source: &'static ClockSource) {
+    let mut counter = COUNTER.lock();
+    let now = counter.as_ref().map_or_else(|| uptime_us() * 1000, |counter| counter.now_ns());
+    *counter = Some(Counter {
+        source: source,
+        start: source.read(),
+        start_ns: now,
+    });
+}
+
+// starts counting the uptime at a new rate from where it is now. with
+// interrupts off, so no tick and no reader on this CPU sees half of it
+fn set_tick_hz(hz: u32) {
+    let enabled = interrupts::interrupts_enabled();
+    unsafe { instructions::disable() };
+    OFFSET_US.store(uptime_us(), Ordering::Relaxed);
+    OFFSET_TICKS.store(ticks(), Ordering::Relaxed);
+    LAST_TICK_TSC.store(cpu::rdtsc(), Ordering::Relaxed);
+    TICK_HZ.store(hz as usize, Ordering::Relaxed);
+    if enabled {
+        unsafe { instructions::enable() };
+    }
+}
+
+fn timer_interrupt(context: &mut InterruptContext) {
+    LAST_TICK_TSC.store(cpu::rdtsc(), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::expire();
+    watchdog::check(context);
+    memory::check_canaries();
+    task::tick(context);
+    // a 32 bit HPET counter has to be read at least once per wraparound
+    if hpet::is_enabled() {
+        hpet::counter();
+    }
+}
+
+/// Returns the number of timer interrupts since `init`. The rate can
+/// change, `uptime_us` is the time.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the frequency of the tick in Hz, or 0 before `init`.
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed) as u32
+}
+
+/// Returns the microseconds since `init`. Never goes backwards, also not
+/// when the tick source or rate changes. 0 before `init`.
+pub fn uptime_us() -> u64 {
+    let hz = tick_hz() as u64;
+    if hz == 0 {
+        return 0;
+    }
+    // converting the ticks since the last rate change at once, so rates
+    // that aren't a whole number of us per tick don't drift
+    let (ticks, last_tick_tsc) = loop {
+        let ticks = ticks();
+        let last_tick_tsc = LAST_TICK_TSC.load(Ordering::Relaxed);
+        if ticks == self::ticks() {
+            break (ticks, last_tick_tsc);
+        }
+    };
+    let ticks_us = (ticks - OFFSET_TICKS.load(Ordering::Relaxed)) * 1_000_000 / hz;
+    let mut uptime = OFFSET_US.load(Ordering::Relaxed) + ticks_us;
+    let tsc_per_ms = tsc::per_ms();
+    if tsc_per_ms != 0 {
+        // stays below the next tick, a late interrupt can't be overtaken
+        let since_tick = cpu::rdtsc().saturating_sub(last_tick_tsc) * 1000 / tsc_per_ms;
+        uptime += cmp::min(since_tick, 1_000_000 / hz - 1);
+    }
+    monotonic(&LAST_UPTIME_US, uptime)
+}
+
+// returns `value`, or the largest value returned before if that is larger.
+// another CPU or an unlucky interrupt can still produce an older value
+fn monotonic(last_returned: &AtomicU64, value: u64) -> u64 {
+    let mut last = last_returned.load(Ordering::Relaxed);
+    loop {
+        if value <= last {
+            return last;
+        }
+        let previous = last_returned.compare_and_swap(last, value, Ordering::Relaxed);
+        if previous == last {
+            return value;
+        }
+        last = previous;
+    }
+}
+
+/// Returns the milliseconds since `init`, see `uptime_us`.
+pub fn uptime_ms() -> u64 {
+    uptime_us() / 1000
+}
+
+/// Returns the nanoseconds since `init` from the selected clock source, or
+/// from the uptime if there is none. Never goes backwards, also not when
+/// the clock source changes.
+pub fn now_ns() -> u64 {
+    let ns = match *COUNTER.lock() {
+        Some(ref counter) => counter.now_ns(),
+        None => uptime_us() * 1000,
+    };
+    monotonic(&LAST_NS, ns)
+}
+
+/// A point in time for measuring how long something takes, see `now_ns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { ns: now_ns() }
+    }
+
+    /// Returns the nanoseconds since `self`.
+    pub fn elapsed(&self) -> u64 {
+        now_ns().saturating_sub(self.ns)
+    }
+}
+
+/// Waits at least `ms` milliseconds, running the other threads or halting
+/// the CPU between ticks. Before interrupts are enabled (or without a tick)
+/// it busy waits with `delay_us`. Must not be called from an interrupt
+/// handler, the tick could never arrive. `task::sleep_ms` only blocks the
+/// calling thread.
+pub fn sleep_ms(ms: u64) {
+    if ms == 0 {
+        return;
+    }
+    debug_assert!(!interrupts::in_interrupt_context(), "sleep_ms in interrupt context");
+    let hz = tick_hz() as u64;
+    if hz == 0 || !interrupts::interrupts_enabled() {
+        let mut remaining = ms;
+        while remaining > 0 {
+            let chunk = cmp::min(remaining, 1000);
+            delay_us((chunk * 1000) as u32);
+            remaining -= chunk;
+        }
+        return;
+    }
+    // the current tick is already partly over, so one more. the other
+    // threads get the time, the CPU only halts if there are none
+    let deadline = ticks() + (ms * hz + 999) / 1000 + 1;
+    while ticks() < deadline {
+        if !task::yield_now() {
+            cpu::halt();
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+pub fn test_uptime() {
+    assert!(tick_hz() != 0, "test_uptime needs the tick");
+    let before = uptime_us();
+    delay_us(1000);
+    let elapsed = uptime_us() - before;
+    // a tick worth of slack without a TSC to interpolate with
+    let slack = if tsc::per_ms() != 0 { 500 } else { 1_000_000 / tick_hz() as u64 };
+    assert!(elapsed + slack >= 1000 && elapsed <= 1000 + slack,
+            "1 ms delay took {} us of uptime", elapsed);
+    println!("uptime test passed");
+}
+
+/// Moves the tick to another source and back, and the counter to itself,
+/// checking that the uptime and `now_ns` keep going forward.
+#[cfg(debug_assertions)]
+pub fn test_switch_sources() {
+    let original = tick_source().expect("test_switch_sources needs the tick").name();
+    let other = if original == "pit" { "rtc" } else { "pit" };
+    let (before_us, before_ns) = (uptime_us(), now_ns());
+    switch_tick_source(other).expect("could not switch the tick");
+    assert_eq!(tick_source().map(|source| source.name()), Some(other));
+    let switched_us = uptime_us();
+    assert!(switched_us >= before_us, "uptime went back at the switch");
+    let first = ticks();
+    sleep_ms(50);
+    assert!(ticks() > first, "no ticks from {}", other);
+    assert!(uptime_us() >= switched_us + 50_000, "uptime didn't advance on {}", other);
+
+    switch_tick_source(original).expect("could not switch the tick back");
+    if let Some(counter) = source::best_clock_source() {
+        switch_counter(counter.name()).expect("could not switch the counter");
+    }
+    assert!(now_ns() >= before_ns + 50_000_000, "now_ns went back");
+    println!("clock source switch test passed");
+}
@@ -0,0 +1,87 @@
+// clock and tick sources
+// a `ClockSource` is a free running counter to read the time from (the TSC,
+// the HPET main counter), a `TickSource` a timer that raises the periodic
+// interrupt (PIT channel 0, the local APIC timer, HPET timer 0, the RTC).
+// drivers register theirs as they find the hardware, `time` picks the best
+// rated of each kind unless the command line names one
+
+use interrupts::IrqHandler;
+use sync::IrqMutex;
+
+const MAX_SOURCES: usize = 8;
+
+/// A counter that runs at a fixed frequency.
+pub trait ClockSource: Sync {
+    /// The name `counter=` selects it by.
+    fn name(&self) -> &'static str;
+
+    /// The counter frequency in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Returns the counter. Must not go backwards.
+    fn read(&self) -> u64;
+
+    /// Higher is better: more precise, cheaper to read, more reliable.
+    fn rating(&self) -> u32;
+}
+
+/// A timer that can raise a periodic interrupt.
+pub trait TickSource: Sync {
+    /// The name `clocksource=` selects it by.
+    fn name(&self) -> &'static str;
+
+    /// Higher is better. No two tick sources may have the same rating.
+    fn rating(&self) -> u32;
+
+    /// Starts calling `handler` about `hz` times a second. Returns the real
+    /// frequency, some timers can't do every rate.
+    fn start(&self, hz: u32, handler: IrqHandler) -> Result<u32, ()>;
+
+    /// Stops the interrupts again, `handler` is the one given to `start`.
+    fn stop(&self, handler: IrqHandler);
+}
+
+static CLOCK_SOURCES: IrqMutex<[Option<&'static ClockSource>; MAX_SOURCES]> =
+    IrqMutex::new([None; MAX_SOURCES]);
+static TICK_SOURCES: IrqMutex<[Option<&'static TickSource>; MAX_SOURCES]> =
+    IrqMutex::new([None; MAX_SOURCES]);
+
+pub fn register_clock_source(source: &'static ClockSource) {
+    let mut sources = CLOCK_SOURCES.lock();
+    match sources.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(source),
+        None => println!("time: too many clock sources, dropping {}", source.name()),
+    }
+}
+
+pub fn register_tick_source(source: &'static TickSource) {
+    let mut sources = TICK_SOURCES.lock();
+    match sources.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(source),
+        None => println!("time: too many tick sources, dropping {}", source.name()),
+    }
+}
+
+pub fn find_clock_source(name: &str) -> Option<&'static ClockSource> {
+    CLOCK_SOURCES.lock().iter().filter_map(|source| *source)
+        .find(|source| source.name() == name)
+}
+
+pub fn find_tick_source(name: &str) -> Option<&'static TickSource> {
+    TICK_SOURCES.lock().iter().filter_map(|source| *source)
+        .find(|source| source.name() == name)
+}
+
+/// Returns the best rated clock source, None if none is registered.
+pub fn best_clock_source() -> Option<&'static ClockSource> {
+    CLOCK_SOURCES.lock().iter().filter_map(|source| *source)
+        .max_by_key(|source| source.rating())
+}
+
+/// Returns the best rated tick source below `below` (to find the next one
+/// after a source failed to start), None if there is none.
+pub fn best_tick_source(below: u32) -> Option<&'static TickSource> {
+    TICK_SOURCES.lock().iter().filter_map(|source| *source)
+        .filter(|source| source.rating() < below)
+        .max_by_key(|source| source.rating())
+}
@@ -0,0 +1,237 @@
+// timer callbacks
+// the pending timers are kept sorted by deadline in a fixed table, so the
+// timer interrupt only looks at the front. it doesn't run the callbacks
+// itself, it moves every expired one onto the deferred work queue. a
+// callback that doesn't fit there stays in the table and is tried again on
+// the next tick. only the ones from `after_in_interrupt` run in the timer
+// interrupt, for the scheduler, which can't wait for the work queue, and
+// the executor wakers of `wake_at`, which are interrupt safe anyway
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use sync::IrqMutex;
+use work;
+use task::executor::Waker;
+use super::uptime_us;
+
+const MAX_TIMERS: usize = 32;
+
+#[derive(Clone, Copy)]
+enum Callback {
+    Plain(fn()),
+    WithArgument(fn(usize), usize),
+    InInterrupt(fn(usize), usize),
+    Wake(Waker),
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: u64,
+    deadline_us: u64,
+    // 0 for one shot timers
+    period_us: u64,
+    callback: Callback,
+}
+
+struct Timers {
+    // sorted by deadline, the first `count` are used
+    entries: [Option<Timer>; MAX_TIMERS],
+    count: usize,
+}
+
+static TIMERS: IrqMutex<Timers> = IrqMutex::new(Timers {
+    entries: [None; MAX_TIMERS],
+    count: 0,
+});
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies an armed timer for `cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    id: u64,
+}
+
+impl TimerHandle {
+    /// Disarms the timer. Returns false if it already fired (one shot) or
+    /// was cancelled. A callback that is already on the work queue still
+    /// runs.
+    pub fn cancel(self) -> bool {
+        TIMERS.lock().remove(self.id).is_some()
+    }
+}
+
+/// Calls `callback` from the deferred work queue once at least `ms`
+/// milliseconds have passed. Returns None if the table is full.
+pub fn after(ms: u64, callback: fn()) -> Option<TimerHandle> {
+    arm(ms, 0, Callback::Plain(callback))
+}
+
+/// Like `after`, but passes `argument` to the callback.
+pub fn after_with(ms: u64, callback: fn(usize), argument: usize) -> Option<TimerHandle> {
+    arm(ms, 0, Callback::WithArgument(callback, argument))
+}
+
+/// Like `after_with`, but calls `callback` in the timer interrupt. It has to
+/// be interrupt safe and short.
+pub fn after_in_interrupt(ms: u64, callback: fn(usize), argument: usize)
+                          -> Option<TimerHandle>
+{
+    arm(ms, 0, Callback::InInterrupt(callback, argument))
+}
+
+/// Calls `callback` every `ms` milliseconds (at least 1) until it is
+/// cancelled. Late ticks don't add up, the timer keeps its phase.
+pub fn every(ms: u64, callback: fn()) -> Option<TimerHandle> {
+    let period = if ms == 0 { 1 } else { ms };
+    arm(period, period, Callback::Plain(callback))
+}
+
+/// Wakes `waker` in the timer interrupt once the uptime reaches
+/// `deadline_us`, for the timer futures.
+pub fn wake_at(deadline_us: u64, waker: Waker) -> Option<TimerHandle> {
+    arm_at(deadline_us, 0, Callback::Wake(waker))
+}
+
+fn arm(ms: u64, period_ms: u64, callback: Callback) -> Option<TimerHandle> {
+    arm_at(uptime_us() + ms * 1000, period_ms, callback)
+}
+
+fn arm_at(deadline_us: u64, period_ms: u64, callback: Callback) -> Option<TimerHandle> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = Timer {
+        id: id,
+        deadline_us: deadline_us,
+        period_us: period_ms * 1000,
+        callback: callback,
+    };
+    if TIMERS.lock().insert(timer) {
+        Some(TimerHandle { id: id })
+    } else {
+        println!("time: timer table full");
+        None
+    }
+}
+
+/// Called by the timer interrupt.
+pub fn expire() {
+    let now = uptime_us();
+    let mut timers = TIMERS.lock();
+    while let Some(timer) = timers.entries[0] {
+        if timer.deadline_us > now {
+            break;
+        }
+        let scheduled = match timer.callback {
+            Callback::Plain(function) => work::schedule(function),
+            Callback::WithArgument(function, argument) => {
+                work::schedule_with(function, argument)
+            }
+            Callback::InInterrupt(function, argument) => {
+                function(argument);
+                true
+            }
+            Callback::Wake(waker) => {
+                waker.wake();
+                true
+            }
+        };
+        if !scheduled {
+            break; // `work` counted it, retried on the next tick
+        }
+        timers.remove(timer.id);
+        if timer.period_us != 0 {
+            let mut rearmed = timer;
+            rearmed.deadline_us += timer.period_us;
+            if rearmed.deadline_us <= now {
+                rearmed.deadline_us = now + timer.period_us;
+            }
+            // there is room, the old entry was just removed
+            timers.insert(rearmed);
+        }
+    }
+}
+
+impl Timers {
+    // keeps the entries sorted, a new timer goes after those with the same
+    // deadline. returns false if the table is full
+    fn insert(&mut self, timer: Timer) -> bool {
+        if self.count == MAX_TIMERS {
+            return false;
+        }
+        let mut index = self.count;
+        while index > 0 && self.deadline(index - 1) > timer.deadline_us {
+            self.entries[index] = self.entries[index - 1];
+            index -= 1;
+        }
+        self.entries[index] = Some(timer);
+        self.count += 1;
+        true
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Timer> {
+        let count = self.count;
+        let index = match self.entries[..count].iter()
+            .position(|entry| entry.map(|timer| timer.id) == Some(id)) {
+            Some(index) => index,
+            None => return None,
+        };
+        let timer = self.entries[index];
+        for i in index..count - 1 {
+            self.entries[i] = self.entries[i + 1];
+        }
+        self.entries[count - 1] = None;
+        self.count -= 1;
+        timer
+    }
+
+    fn deadline(&self, index: usize) -> u64 {
+        self.entries[index].map(|timer| timer.deadline_us).unwrap_or(0)
+    }
+}
+
+#[cfg(debug_assertions)]
+static TEST_ONE_SHOTS: AtomicU64 = AtomicU64::new(0);
+#[cfg(debug_assertions)]
+static TEST_PERIODIC: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(debug_assertions)]
+fn test_one_shot(argument: usize) {
+    assert!(!::interrupts::in_interrupt_context(), "timer callback in interrupt context");
+    TEST_ONE_SHOTS.fetch_add(argument as u64, Ordering::SeqCst);
+}
+
+#[cfg(debug_assertions)]
+fn test_periodic() {
+    TEST_PERIODIC.fetch_add(1, Ordering::SeqCst);
+}
+
+#[cfg(debug_assertions)]
+fn test_cancelled() {
+    panic!("a cancelled timer fired");
+}
+
+/// Arms one shot timers that expire in the same tick, a periodic one and a
+/// cancelled one, and waits for them. Needs the tick and interrupts.
+#[cfg(debug_assertions)]
+pub fn test_timers() {
+    use cpu;
+
+    assert!(::interrupts::interrupts_enabled(), "test_timers needs interrupts");
+    TEST_ONE_SHOTS.store(0, Ordering::SeqCst);
+    TEST_PERIODIC.store(0, Ordering::SeqCst);
+    for _ in 0..8 {
+        after_with(20, test_one_shot, 1).expect("timer table full");
+    }
+    let cancelled = after(10, test_cancelled).expect("timer table full");
+    assert!(cancelled.cancel());
+    assert!(!cancelled.cancel());
+    let periodic = every(10, test_periodic).expect("timer table full");
+
+    let give_up = uptime_us() + 1_000_000;
+    while TEST_ONE_SHOTS.load(Ordering::SeqCst) < 8 || TEST_PERIODIC.load(Ordering::SeqCst) < 3 {
+        assert!(uptime_us() < give_up, "timers didn't fire");
+        work::run_pending();
+        cpu::halt();
+    }
+    assert!(periodic.cancel());
+    assert_eq!(TEST_ONE_SHOTS.load(Ordering::SeqCst), 8);
+    println!("timer test passed");
+}
@@ -0,0 +1,86 @@
+// time stamp counter
+// `init` measures its rate against PIT channel 2. a single window can be
+// stretched by an SMI the kernel never sees, so it takes the median of a
+// few. only an invariant TSC (cpuid 0x80000007) counts at the same rate in
+// every power state, the others are still good enough to interpolate
+// between two ticks, but rated below the HPET as a clock source
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use cpu;
+use pit;
+use super::{register_clock_source, ClockSource};
+
+const SAMPLES: usize = 5;
+const SAMPLE_MS: u64 = 10;
+
+// cpuid leaf 0x80000007, edx
+const INVARIANT_TSC: u32 = 1 << 8;
+
+// 0 until `init` ran
+static PER_MS: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// The TSC as a `time::ClockSource`.
+pub struct Tsc;
+
+static TSC: Tsc = Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        per_ms() * 1000
+    }
+
+    fn read(&self) -> u64 {
+        cpu::rdtsc()
+    }
+
+    fn rating(&self) -> u32 {
+        if is_invariant() { 300 } else { 50 }
+    }
+}
+
+/// Measures the TSC and registers it. Called by `time::init`, while
+/// interrupts are still off, so none is counted into the measurement.
+pub fn init() {
+    let cycles = (pit::BASE_FREQUENCY as u64 * SAMPLE_MS / 1000) as u16;
+    let mut samples = [0; SAMPLES];
+    for sample in samples.iter_mut() {
+        let start = cpu::rdtsc();
+        pit::wait_cycles(cycles);
+        *sample = (cpu::rdtsc() - start) / SAMPLE_MS;
+    }
+    // insertion sort, there are only a few
+    for i in 1..SAMPLES {
+        let mut j = i;
+        while j > 0 && samples[j - 1] > samples[j] {
+            samples.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+    let per_ms = samples[SAMPLES / 2];
+    PER_MS.store(per_ms, Ordering::Relaxed);
+
+    let invariant = cpu::cpuid(0x8000_0000).eax >= 0x8000_0007
+        && cpu::cpuid(0x8000_0007).edx & INVARIANT_TSC != 0;
+    INVARIANT.store(invariant, Ordering::Relaxed);
+    if invariant {
+        println!("time: invariant TSC at {} MHz", per_ms / 1000);
+    } else {
+        println!("time: warning: the TSC at {} MHz isn't invariant", per_ms / 1000);
+    }
+    register_clock_source(&TSC);
+}
+
+/// Returns the measured TSC ticks per millisecond, 0 before `init`.
+pub fn per_ms() -> u64 {
+    PER_MS.load(Ordering::Relaxed)
+}
+
+/// Returns whether the TSC keeps its rate in every power state.
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
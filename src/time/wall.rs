@@ -0,0 +1,77 @@
+// wall clock time
+// the RTC is read once in `init`, after that the time is the RTC time plus
+// the uptime since then. the RTC only has whole seconds, so the base sits
+// in the middle of the second it showed. `resync` compares with the RTC
+// again every hour and only moves the base if the clock ran out of that
+// second
+
+use sync::IrqMutex;
+use rtc::{self, DateTime};
+use super::{every, uptime_us};
+
+const RESYNC_MS: u64 = 60 * 60 * 1000;
+const HALF_SECOND_US: i64 = 500_000;
+
+struct Base {
+    // microseconds since 1970 at `uptime_us`
+    unix_us: i64,
+    uptime_us: u64,
+}
+
+static BASE: IrqMutex<Option<Base>> = IrqMutex::new(None);
+
+/// Reads the RTC and starts the hourly resync. Called by `time::init` once
+/// the tick runs.
+pub fn init() {
+    let now = rtc::now();
+    *BASE.lock() = Some(Base {
+        unix_us: now.to_unix() * 1_000_000 + HALF_SECOND_US,
+        uptime_us: uptime_us(),
+    });
+    if every(RESYNC_MS, resync).is_none() {
+        println!("time: no timer for the RTC resync");
+    }
+}
+
+/// Returns the microseconds since 1970-01-01 00:00:00 UTC, 0 before
+/// `time::init`.
+pub fn wall_now_us() -> i64 {
+    match *BASE.lock() {
+        Some(ref base) => base.unix_us + (uptime_us() - base.uptime_us) as i64,
+        None => 0,
+    }
+}
+
+/// Returns the Unix time in seconds.
+pub fn wall_now() -> i64 {
+    let us = wall_now_us();
+    // rounds down, also before 1970
+    if us >= 0 { us / 1_000_000 } else { (us - 999_999) / 1_000_000 }
+}
+
+/// Returns the current date and time in UTC.
+pub fn wall_date_time() -> DateTime {
+    DateTime::from_unix(wall_now())
+}
+
+/// Compares the clock with the RTC and moves it back into the second the
+/// RTC shows, logging the drift.
+pub fn resync() {
+    let rtc_us = rtc::now().to_unix() * 1_000_000;
+    let drift_us = {
+        let mut base = BASE.lock();
+        let base = match *base {
+            Some(ref mut base) => base,
+            None => return,
+        };
+        let uptime = uptime_us();
+        let clock_us = base.unix_us + (uptime - base.uptime_us) as i64;
+        if clock_us >= rtc_us && clock_us < rtc_us + 2 * HALF_SECOND_US {
+            return;
+        }
+        base.unix_us = rtc_us + HALF_SECOND_US;
+        base.uptime_us = uptime;
+        clock_us - base.unix_us
+    };
+    println!("time: the clock drifted {} ms from the RTC, resynced", drift_us / 1000);
+}
@@ -0,0 +1,191 @@
+// TLB shootdowns
+// changing a mapping flushes the TLB of the CPU that changed it only, the
+// others may keep using the old translation. a `Batch` collects the pages a
+// change touched, `finish` flushes them here and makes every other online
+// CPU flush them too: the pages (or a full flush, past FULL_FLUSH_PAGES) go
+// into the static request, a bit per target CPU into `PENDING`, and each
+// target gets the TLB_SHOOTDOWN_VECTOR IPI. its handler flushes and clears
+// its bit without taking a lock, so the initiator may hold any lock while
+// it spins until `PENDING` is empty. there is one request at a time, a
+// second initiator waits for `BUSY` with interrupts off and answers the
+// running request meanwhile, it may be one of its targets.
+// with only the BSP online (before `smp::init` or without APs) a batch is
+// a plain local flush. a target that spins for a lock with interrupts off
+// can't answer, fine as long as the APs only park
+
+use core::usize;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::VirtualAddress;
+use x86_64::instructions::tlb as cpu_tlb;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use interrupts;
+use percpu;
+use apic;
+use smp;
+
+/// Past this many pages a batch flushes the whole TLB instead.
+pub const FULL_FLUSH_PAGES: usize = 32;
+// `COUNT` of a full flush
+const FULL: usize = usize::MAX;
+
+/// The pages of a mapping change, flushed everywhere by `finish`.
+#[must_use]
+pub struct Batch {
+    pages: [usize; FULL_FLUSH_PAGES],
+    count: usize,
+    // more pages than fit
+    full: bool,
+}
+
+// held by the initiator of the running request
+static BUSY: AtomicBool = AtomicBool::new(false);
+// the request, only written while `BUSY` is held and `PENDING` is empty
+static PAGES: [AtomicUsize; FULL_FLUSH_PAGES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+// the pages in `PAGES` that count, or FULL
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+// a bit per CPU index that still has to flush, there are at most as many
+// CPUs as bits
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch { pages: [0; FULL_FLUSH_PAGES], count: 0, full: false }
+    }
+
+    /// Adds the page containing `address`.
+    pub fn add(&mut self, address: usize) {
+        if self.count == FULL_FLUSH_PAGES {
+            self.full = true;
+        } else {
+            self.pages[self.count] = address;
+            self.count += 1;
+        }
+    }
+
+    /// Flushes the pages on every online CPU, returns once all did.
+    pub fn finish(self) {
+        if self.count == 0 {
+            return;
+        }
+        let count = if self.full { FULL } else { self.count };
+        flush_local(count, |i| self.pages[i]);
+
+        let me = percpu::current().index();
+        let targets = online_mask() & !(1 << me);
+        if targets == 0 {
+            return;
+        }
+
+        // an interrupt handler that changes a mapping mustn't wait for our
+        // request
+        let interrupts_enabled = interrupts::interrupts_enabled();
+        unsafe { cpu_interrupts::disable() };
+        while BUSY.compare_and_swap(false, true, Ordering::Acquire) {
+            answer();
+        }
+        if !self.full {
+            for (slot, &page) in PAGES.iter().zip(self.pages[..self.count].iter()) {
+                slot.store(page, Ordering::Relaxed);
+            }
+        }
+        COUNT.store(count, Ordering::Relaxed);
+        // publishes the request
+        PENDING.store(targets, Ordering::SeqCst);
+        for cpu in 0..percpu::cpus() {
+            if targets & (1 << cpu) != 0 {
+                let target = percpu::cpu(cpu).expect("tlb: an online CPU has no block");
+                apic::send_fixed(target.apic_id(), apic::TLB_SHOOTDOWN_VECTOR);
+            }
+        }
+        while PENDING.load(Ordering::SeqCst) != 0 {}
+        BUSY.store(false, Ordering::Release);
+        if interrupts_enabled {
+            unsafe { cpu_interrupts::enable() };
+        }
+    }
+}
+
+/// Flushes the page containing `address` on every online CPU.
+pub fn flush(address: usize) {
+    let mut batch = Batch::new();
+    batch.add(address);
+    batch.finish();
+}
+
+/// The handler of TLB_SHOOTDOWN_VECTOR, lock free.
+pub fn shootdown_interrupt() {
+    answer();
+}
+
+// flushes the running request if it names this CPU
+fn answer() {
+    let bit = 1 << percpu::current().index();
+    if PENDING.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+    flush_local(COUNT.load(Ordering::Relaxed), |i| PAGES[i].load(Ordering::Relaxed));
+    PENDING.fetch_and(!bit, Ordering::SeqCst);
+}
+
+fn flush_local<F: Fn(usize) -> usize>(count: usize, page: F) {
+    if count == FULL {
+        cpu_tlb::flush_all();
+    } else {
+        for i in 0..count {
+            cpu_tlb::flush(VirtualAddress(page(i)));
+        }
+    }
+}
+
+// the APs count up the CPU indices as they come online
+fn online_mask() -> usize {
+    let online = smp::cpu_count();
+    if online >= percpu::MAX_CPUS {
+        !0
+    } else {
+        (1 << online) - 1
+    }
+}
+
+/// Every other online CPU has to answer a batch once, both one of a few
+/// pages and one that falls back to a full flush.
+#[cfg(debug_assertions)]
+pub fn test_tlb_shootdown() {
+    use memory::PAGE_SIZE;
+    use acpi;
+    use cmdline;
+
+    let others = smp::cpu_count() as u64 - 1;
+    // or the test would pass without a single IPI
+    let listed = acpi::madt()
+        .map_or(0, |madt| madt.local_apics().iter().filter(|cpu| cpu.enabled).count());
+    if listed > 1 && apic::is_enabled() && !cmdline::has("nosmp") {
+        assert!(others > 0, "tlb: {} CPUs listed, but only the BSP is online", listed);
+    }
+    let before = interrupts::vector_count(apic::TLB_SHOOTDOWN_VECTOR);
+    // flushing a page that isn't mapped is harmless
+    let base = 0xffff_ff00_0000_0000usize;
+    flush(base);
+    assert_eq!(interrupts::vector_count(apic::TLB_SHOOTDOWN_VECTOR) - before, others,
+               "tlb: not every CPU answered a shootdown");
+
+    let mut batch = Batch::new();
+    for i in 0..FULL_FLUSH_PAGES + 1 {
+        batch.add(base + i * PAGE_SIZE);
+    }
+    assert!(batch.full);
+    batch.finish();
+    assert_eq!(interrupts::vector_count(apic::TLB_SHOOTDOWN_VECTOR) - before, 2 * others,
+               "tlb: not every CPU answered a full flush");
+    assert_eq!(PENDING.load(Ordering::SeqCst), 0);
+    println!("tlb shootdown test passed");
+}
@@ -0,0 +1,162 @@
+// VGA register programming
+// a mode is the miscellaneous output register plus the indexed register
+// sets of the sequencer, the CRT controller, the graphics controller and
+// the attribute controller. `write_registers` loads a complete set, the
+// tables come from the register dumps of the standard BIOS modes. the
+// text console itself is still `vga_buffer`
+
+use x86_64::instructions::port::{inb, outb};
+
+pub mod mode13;
+
+const MISC_WRITE: u16 = 0x3c2;
+const SEQUENCER_INDEX: u16 = 0x3c4;
+const SEQUENCER_DATA: u16 = 0x3c5;
+const DAC_READ_INDEX: u16 = 0x3c7;
+const DAC_WRITE_INDEX: u16 = 0x3c8;
+const DAC_DATA: u16 = 0x3c9;
+const GRAPHICS_INDEX: u16 = 0x3ce;
+const GRAPHICS_DATA: u16 = 0x3cf;
+const ATTRIBUTE_INDEX: u16 = 0x3c0;
+// with the color I/O addresses, which the misc register selects
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+// reading it resets the attribute controller's index/data flip-flop
+const INPUT_STATUS_1: u16 = 0x3da;
+
+// sequencer registers
+pub const SEQUENCER_MAP_MASK: u8 = 0x02;
+pub const SEQUENCER_MEMORY_MODE: u8 = 0x04;
+// graphics controller registers
+pub const GRAPHICS_READ_MAP: u8 = 0x04;
+pub const GRAPHICS_MODE: u8 = 0x05;
+pub const GRAPHICS_MISC: u8 = 0x06;
+
+// CRTC registers 0 to 7 are write protected by bit 7 of register 0x11
+const CRTC_END_HORIZONTAL_BLANKING: u8 = 0x03;
+const CRTC_VERTICAL_RETRACE_END: u8 = 0x11;
+const CRTC_PROTECT: u8 = 1 << 7;
+
+// set in the attribute index after loading, or the screen stays blank
+const ATTRIBUTE_PALETTE_SOURCE: u8 = 1 << 5;
+
+/// A complete mode.
+pub struct Registers {
+    pub misc: u8,
+    pub sequencer: [u8; 5],
+    pub crtc: [u8; 25],
+    pub graphics: [u8; 9],
+    pub attribute: [u8; 21],
+}
+
+/// 80x25 text with 9x16 characters (mode 3).
+pub const TEXT_80X25: Registers = Registers {
+    misc: 0x67,
+    sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x55, 0x81, 0xbf, 0x1f, 0x00, 0x4f, 0x0d, 0x0e, 0x00, 0x00,
+        0x00, 0x50, 0x9c, 0x0e, 0x8f, 0x28, 0x1f, 0x96, 0xb9, 0xa3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0e, 0x00, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+        0x3e, 0x3f, 0x0c, 0x00, 0x0f, 0x08, 0x00,
+    ],
+};
+
+/// 320x200 with 256 colors, one byte per pixel at 0xa0000 (mode 13h).
+pub const GRAPHICS_320X200X256: Registers = Registers {
+    misc: 0x63,
+    sequencer: [0x03, 0x01, 0x0f, 0x00, 0x0e],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+        0x0e, 0x0f, 0x41, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+/// Loads all registers of `mode`. The caller has to make sure nothing else
+/// touches the VGA meanwhile.
+pub unsafe fn write_registers(mode: &Registers) {
+    outb(MISC_WRITE, mode.misc);
+    for (index, &value) in mode.sequencer.iter().enumerate() {
+        write_sequencer(index as u8, value);
+    }
+
+    // unlock CRTC registers 0 to 7, and keep them unlocked while the table
+    // is written
+    write_crtc(CRTC_END_HORIZONTAL_BLANKING, read_crtc(CRTC_END_HORIZONTAL_BLANKING) | 0x80);
+    write_crtc(CRTC_VERTICAL_RETRACE_END, read_crtc(CRTC_VERTICAL_RETRACE_END) & !CRTC_PROTECT);
+    for (index, &value) in mode.crtc.iter().enumerate() {
+        let value = match index as u8 {
+            CRTC_END_HORIZONTAL_BLANKING => value | 0x80,
+            CRTC_VERTICAL_RETRACE_END => value & !CRTC_PROTECT,
+            _ => value,
+        };
+        write_crtc(index as u8, value);
+    }
+
+    for (index, &value) in mode.graphics.iter().enumerate() {
+        write_graphics(index as u8, value);
+    }
+
+    inb(INPUT_STATUS_1);
+    for (index, &value) in mode.attribute.iter().enumerate() {
+        outb(ATTRIBUTE_INDEX, index as u8);
+        outb(ATTRIBUTE_INDEX, value);
+    }
+    inb(INPUT_STATUS_1);
+    outb(ATTRIBUTE_INDEX, ATTRIBUTE_PALETTE_SOURCE);
+}
+
+pub unsafe fn read_sequencer(index: u8) -> u8 {
+    outb(SEQUENCER_INDEX, index);
+    inb(SEQUENCER_DATA)
+}
+
+pub unsafe fn write_sequencer(index: u8, value: u8) {
+    outb(SEQUENCER_INDEX, index);
+    outb(SEQUENCER_DATA, value);
+}
+
+pub unsafe fn read_graphics(index: u8) -> u8 {
+    outb(GRAPHICS_INDEX, index);
+    inb(GRAPHICS_DATA)
+}
+
+pub unsafe fn write_graphics(index: u8, value: u8) {
+    outb(GRAPHICS_INDEX, index);
+    outb(GRAPHICS_DATA, value);
+}
+
+unsafe fn read_crtc(index: u8) -> u8 {
+    outb(CRTC_INDEX, index);
+    inb(CRTC_DATA)
+}
+
+unsafe fn write_crtc(index: u8, value: u8) {
+    outb(CRTC_INDEX, index);
+    outb(CRTC_DATA, value);
+}
+
+/// Sets DAC entry `index` to the given 6 bit (0 to 63) intensities.
+pub fn write_dac(index: u8, red: u8, green: u8, blue: u8) {
+    unsafe {
+        outb(DAC_WRITE_INDEX, index);
+        outb(DAC_DATA, red & 0x3f);
+        outb(DAC_DATA, green & 0x3f);
+        outb(DAC_DATA, blue & 0x3f);
+    }
+}
+
+/// Returns the 6 bit intensities of DAC entry `index`.
+pub fn read_dac(index: u8) -> (u8, u8, u8) {
+    unsafe {
+        outb(DAC_READ_INDEX, index);
+        (inb(DAC_DATA), inb(DAC_DATA), inb(DAC_DATA))
+    }
+}
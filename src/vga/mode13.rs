@@ -0,0 +1,164 @@
+// mode 13h: 320x200 with 256 colors
+// one byte per pixel, linear at 0xa0000, chain-4 spreads the bytes over the
+// four planes. that overwrites the text (planes 0 and 1) and the font
+// (plane 2), so `enter` saves both and the DAC, and `restore_text_mode`
+// puts everything back. the WRITER lock is held while switching, output
+// printed in mode 13h only reaches the serial port
+
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::IrqMutex;
+use memory::MemoryController;
+use vga_buffer::{self, BUFFER_WIDTH, BUFFER_HEIGHT};
+use super::{write_registers, read_sequencer, write_sequencer, read_graphics, write_graphics,
+            read_dac, write_dac, TEXT_80X25, GRAPHICS_320X200X256, SEQUENCER_MAP_MASK,
+            SEQUENCER_MEMORY_MODE, GRAPHICS_READ_MAP, GRAPHICS_MODE, GRAPHICS_MISC};
+use time;
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+
+const FRAMEBUFFER: usize = 0xa0000;
+const WINDOW_SIZE: usize = 0x10000;
+const TEXT_BUFFER: usize = 0xb8000;
+const TEXT_SIZE: usize = BUFFER_WIDTH * BUFFER_HEIGHT * 2;
+// 256 characters of 32 bytes, the 9x16 font uses the first 16
+const FONT_SIZE: usize = 256 * 32;
+
+// plane 2 as a plain 64 KiB window at 0xa0000
+const FONT_PLANE: u8 = 2;
+const MEMORY_MODE_SEQUENTIAL: u8 = 0x06;
+const GRAPHICS_MODE_PLAIN: u8 = 0x00;
+const GRAPHICS_MISC_A0000: u8 = 0x04;
+
+struct Saved {
+    text: [u8; TEXT_SIZE],
+    font: [u8; FONT_SIZE],
+    dac: [(u8, u8, u8); 256],
+}
+
+static SAVED: IrqMutex<Saved> = IrqMutex::new(Saved {
+    text: [0; TEXT_SIZE],
+    font: [0; FONT_SIZE],
+    dac: [(0, 0, 0); 256],
+});
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Switches from text mode to mode 13h with a black screen. The palette
+/// is the one the text mode had, see `set_palette`.
+pub fn enter(memory_controller: &mut MemoryController) {
+    memory_controller.map_mmio(FRAMEBUFFER, WINDOW_SIZE);
+
+    let _writer = vga_buffer::WRITER.lock();
+    if ACTIVE.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut saved = SAVED.lock();
+    unsafe {
+        ptr::copy_nonoverlapping(TEXT_BUFFER as *const u8, saved.text.as_mut_ptr(), TEXT_SIZE);
+        with_font_plane(|| {
+            ptr::copy_nonoverlapping(FRAMEBUFFER as *const u8, saved.font.as_mut_ptr(), FONT_SIZE)
+        });
+        for (index, entry) in saved.dac.iter_mut().enumerate() {
+            *entry = read_dac(index as u8);
+        }
+
+        write_registers(&GRAPHICS_320X200X256);
+        ptr::write_bytes(FRAMEBUFFER as *mut u8, 0, WIDTH * HEIGHT);
+    }
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Returns to 80x25 text mode with the text, font and palette from before
+/// `enter`. Does nothing if mode 13h isn't active.
+pub fn restore_text_mode() {
+    let _writer = vga_buffer::WRITER.lock();
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let saved = SAVED.lock();
+    unsafe {
+        write_registers(&TEXT_80X25);
+        with_font_plane(|| {
+            write_sequencer(SEQUENCER_MAP_MASK, 1 << FONT_PLANE);
+            ptr::copy_nonoverlapping(saved.font.as_ptr(), FRAMEBUFFER as *mut u8, FONT_SIZE);
+        });
+        ptr::copy_nonoverlapping(saved.text.as_ptr(), TEXT_BUFFER as *mut u8, TEXT_SIZE);
+    }
+    for (index, &(red, green, blue)) in saved.dac.iter().enumerate() {
+        write_dac(index as u8, red, green, blue);
+    }
+}
+
+// runs `f` with plane 2 mapped at 0xa0000, for reading and writing the font
+// from text mode
+unsafe fn with_font_plane<F: FnOnce()>(f: F) {
+    let map_mask = read_sequencer(SEQUENCER_MAP_MASK);
+    let memory_mode = read_sequencer(SEQUENCER_MEMORY_MODE);
+    let read_map = read_graphics(GRAPHICS_READ_MAP);
+    let mode = read_graphics(GRAPHICS_MODE);
+    let misc = read_graphics(GRAPHICS_MISC);
+
+    write_sequencer(SEQUENCER_MEMORY_MODE, MEMORY_MODE_SEQUENTIAL);
+    write_graphics(GRAPHICS_READ_MAP, FONT_PLANE);
+    write_graphics(GRAPHICS_MODE, GRAPHICS_MODE_PLAIN);
+    write_graphics(GRAPHICS_MISC, GRAPHICS_MISC_A0000);
+    f();
+
+    write_sequencer(SEQUENCER_MAP_MASK, map_mask);
+    write_sequencer(SEQUENCER_MEMORY_MODE, memory_mode);
+    write_graphics(GRAPHICS_READ_MAP, read_map);
+    write_graphics(GRAPHICS_MODE, mode);
+    write_graphics(GRAPHICS_MISC, misc);
+}
+
+/// Sets the pixel at `x`, `y` to palette entry `color`. Pixels outside the
+/// screen, or while mode 13h isn't active, are ignored.
+pub fn set_pixel(x: usize, y: usize, color: u8) {
+    if !ACTIVE.load(Ordering::Relaxed) || x >= WIDTH || y >= HEIGHT {
+        return;
+    }
+    unsafe { ptr::write_volatile((FRAMEBUFFER + y * WIDTH + x) as *mut u8, color) };
+}
+
+/// Fills the rectangle with palette entry `color`, clipped to the screen.
+pub fn fill_rect(x: usize, y: usize, width: usize, height: usize, color: u8) {
+    if !ACTIVE.load(Ordering::Relaxed) || x >= WIDTH || y >= HEIGHT {
+        return;
+    }
+    let width = cmp::min(width, WIDTH - x);
+    for row in y..cmp::min(y + height, HEIGHT) {
+        unsafe { ptr::write_bytes((FRAMEBUFFER + row * WIDTH + x) as *mut u8, color, width) };
+    }
+}
+
+/// Sets palette entry `index` to the given color. The DAC has 6 bits per
+/// channel, so the low 2 bits are dropped.
+pub fn set_palette(index: u8, red: u8, green: u8, blue: u8) {
+    write_dac(index, red >> 2, green >> 2, blue >> 2);
+}
+
+/// Draws a gradient with a frame for a few seconds, then goes back to
+/// text mode. Needs interrupts, it waits with the timer.
+pub fn demo(memory_controller: &mut MemoryController) {
+    enter(memory_controller);
+    // entry 0 black for the frame, 1 to 255 from blue to red
+    set_palette(0, 0, 0, 0);
+    for index in 1..256 {
+        set_palette(index as u8, index as u8, 0x40, 255 - index as u8);
+    }
+    for x in 0..WIDTH {
+        fill_rect(x, 0, 1, HEIGHT, (1 + x * 255 / WIDTH) as u8);
+    }
+    fill_rect(0, 0, WIDTH, 4, 0);
+    fill_rect(0, HEIGHT - 4, WIDTH, 4, 0);
+    fill_rect(0, 0, 4, HEIGHT, 0);
+    fill_rect(WIDTH - 4, 0, 4, HEIGHT, 0);
+    for offset in 0..100 {
+        set_pixel(110 + offset, 50 + offset, 0);
+    }
+
+    time::sleep_ms(3000);
+    restore_text_mode();
+    println!("vga: mode 13h demo done, back in text mode");
+}
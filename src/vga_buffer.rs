@@ -36,8 +36,8 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 use volatile::Volatile;
 
@@ -58,6 +58,7 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
 	    b'\n' => self.new_line(),
+	    0x08 => self.backspace(),
 	    byte => {
 		if self.column_position >= BUFFER_WIDTH {
 		    self.new_line();
@@ -77,8 +78,8 @@ impl Writer {
     }
     
     pub fn write_str(&mut self, s: &str) {
-        for byte in s.bytes() {
-	      self.write_byte(byte)
+        for character in s.chars() {
+	      self.write_byte(to_cp437(character))
 	}
     }
 
@@ -98,6 +99,19 @@ impl Writer {
 	self.column_position = 0;
     }
 
+    // erase the character before the cursor
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let blank = ScreenChar {
+                ascii_character: b' ',
+                color_code: self.color_code,
+            };
+            let col = self.column_position;
+            self.buffer().chars[BUFFER_HEIGHT - 1][col].write(blank);
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
        let blank = ScreenChar {
        	    ascii_character: b' ',
@@ -113,16 +127,35 @@ use core::fmt;
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-	    self.write_byte(byte)
+        for character in s.chars() {
+	    self.write_byte(to_cp437(character))
 	}
     	Ok(())
     }
 }
 
-use spin::Mutex;
+// the VGA text mode font uses code page 437, so non-ASCII characters need to
+// be translated. characters missing from it are shown as a small square
+fn to_cp437(character: char) -> u8 {
+    match character {
+        '\u{0}'...'\u{7f}' => character as u8,
+        'Ç' => 0x80, 'ü' => 0x81, 'é' => 0x82, 'â' => 0x83, 'ä' => 0x84,
+        'à' => 0x85, 'å' => 0x86, 'ç' => 0x87, 'ê' => 0x88, 'ë' => 0x89,
+        'è' => 0x8a, 'ï' => 0x8b, 'î' => 0x8c, 'ì' => 0x8d, 'Ä' => 0x8e,
+        'Å' => 0x8f, 'É' => 0x90, 'æ' => 0x91, 'Æ' => 0x92, 'ô' => 0x93,
+        'ö' => 0x94, 'ò' => 0x95, 'û' => 0x96, 'ù' => 0x97, 'ÿ' => 0x98,
+        'Ö' => 0x99, 'Ü' => 0x9a, '¢' => 0x9b, '£' => 0x9c, '¥' => 0x9d,
+        'á' => 0xa0, 'í' => 0xa1, 'ó' => 0xa2, 'ú' => 0xa3, 'ñ' => 0xa4,
+        'Ñ' => 0xa5, '¿' => 0xa8, '½' => 0xab, '¼' => 0xac, '¡' => 0xad,
+        '«' => 0xae, '»' => 0xaf, 'ß' => 0xe1, 'µ' => 0xe6, '°' => 0xf8,
+        '±' => 0xf1, '§' => 0x15, '¶' => 0x14,
+        _ => 0xfe,
+    }
+}
+
+use sync::IrqMutex;
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+pub static WRITER: IrqMutex<Writer> = IrqMutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::LightGreen, Color::Black),
     buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
@@ -135,7 +168,7 @@ macro_rules! println {
 
 macro_rules! print {
     ($($arg:tt)*) => ({
-       $crate::vga_buffer::print(format_args!($($arg)*));
+       $crate::console::print(format_args!($($arg)*));
     });
 }
 
@@ -144,9 +177,46 @@ pub fn print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+/// Writes `s` unless the WRITER lock is held, returns whether it did.
+pub fn try_write_str(s: &str) -> bool {
+    use core::fmt::Write;
+    match WRITER.try_lock() {
+        Some(mut writer) => writer.write_str(s).is_ok(),
+        None => false,
+    }
+}
+
+/// Writes `s` even if the WRITER lock is held, by breaking the lock. Only
+/// for fatal paths, where the holder never runs again.
+pub fn force_write_str(s: &str) {
+    use core::fmt::Write;
+    let mut writer = match WRITER.try_lock() {
+        Some(writer) => writer,
+        None => {
+            unsafe { WRITER.force_unlock() };
+            WRITER.lock()
+        }
+    };
+    let _ = writer.write_str(s);
+}
+
+/// Writes `byte` to the given screen cell, keeping its color, and returns the
+/// character that was there before.
+pub fn swap_char(row: usize, col: usize, byte: u8) -> u8 {
+    let mut writer = WRITER.lock();
+    let buffer = writer.buffer();
+    let old = buffer.chars[row][col].read();
+    buffer.chars[row][col].write(ScreenChar {
+        ascii_character: byte,
+        color_code: old.color_code,
+    });
+    old.ascii_character
+}
+
 pub fn clear_screen() {
+    let mut writer = WRITER.lock();
     for _ in 0..BUFFER_HEIGHT {
-         println!("");
+         writer.new_line();
     }
 }
 
@@ -0,0 +1,123 @@
+// linear framebuffers
+// whoever sets up a pixel framebuffer (GRUB through the multiboot
+// framebuffer tag, or `bochs_vbe` at runtime) describes it with a
+// `FramebufferInfo` and registers it here, so the renderers don't have to
+// know where it came from. a newly set framebuffer replaces the old one
+
+use core::{cmp, fmt};
+use sync::IrqMutex;
+use memory::{MemoryController, PhysicalAddress, VirtualAddress};
+
+const FRAMEBUFFER_TAG: u32 = 8;
+// the types in the tag, the EGA text type is no pixel framebuffer
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+/// Position and width of a color channel within a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorField {
+    pub position: u8,
+    pub size: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub physical_address: PhysicalAddress,
+    /// Where the framebuffer is mapped (write combining).
+    pub address: VirtualAddress,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from one line to the next.
+    pub pitch: usize,
+    pub bits_per_pixel: u8,
+    pub red: ColorField,
+    pub green: ColorField,
+    pub blue: ColorField,
+}
+
+impl FramebufferInfo {
+    /// Returns the size of the visible part in bytes.
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    /// Packs an 8 bit per channel color into a pixel value.
+    pub fn pixel(&self, red: u8, green: u8, blue: u8) -> u32 {
+        fn channel(value: u8, field: ColorField) -> u32 {
+            ((value as u32) >> (8 - cmp::min(field.size, 8))) << field.position
+        }
+        channel(red, self.red) | channel(green, self.green) | channel(blue, self.blue)
+    }
+}
+
+impl fmt::Display for FramebufferInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}x{} at {:#x}, pitch {}", self.width, self.height,
+               self.bits_per_pixel, self.physical_address, self.pitch)
+    }
+}
+
+static FRAMEBUFFER: IrqMutex<Option<FramebufferInfo>> = IrqMutex::new(None);
+
+/// Registers the framebuffer from the multiboot information, if GRUB set up
+/// a direct color one. Returns whether it did.
+pub fn init(multiboot_information_address: usize,
+            memory_controller: &mut MemoryController) -> bool {
+    let mut info = match unsafe { find_framebuffer_tag(multiboot_information_address) } {
+        Some(info) => info,
+        None => return false,
+    };
+    info.address = memory_controller.map_write_combining(info.physical_address, info.size());
+    println!("video: multiboot framebuffer {}", info);
+    set_framebuffer(info);
+    true
+}
+
+unsafe fn find_framebuffer_tag(multiboot_information_address: usize) -> Option<FramebufferInfo> {
+    let total_size = *(multiboot_information_address as *const u32) as usize;
+    let end = multiboot_information_address + total_size;
+
+    // same walk as `cmdline`
+    let mut tag = multiboot_information_address + 8;
+    while tag + 8 <= end {
+        let tag_type = *(tag as *const u32);
+        let tag_size = *((tag + 4) as *const u32) as usize;
+        if tag_type == 0 || tag_size < 8 {
+            break;
+        }
+        if tag_type == FRAMEBUFFER_TAG {
+            // address, pitch, width, height, bpp, type, reserved, then the
+            // color fields of the RGB type
+            if tag_size < 38 || *((tag + 29) as *const u8) != FRAMEBUFFER_TYPE_RGB {
+                return None;
+            }
+            let field = |offset: usize| ColorField {
+                position: *((tag + offset) as *const u8),
+                size: *((tag + offset + 1) as *const u8),
+            };
+            let address = *((tag + 8) as *const u64) as usize;
+            return Some(FramebufferInfo {
+                physical_address: address,
+                address: address,
+                pitch: *((tag + 16) as *const u32) as usize,
+                width: *((tag + 20) as *const u32) as usize,
+                height: *((tag + 24) as *const u32) as usize,
+                bits_per_pixel: *((tag + 28) as *const u8),
+                red: field(32),
+                green: field(34),
+                blue: field(36),
+            });
+        }
+        tag += (tag_size + 7) & !7;
+    }
+    None
+}
+
+/// Makes `info` the framebuffer the renderers draw on. It must be mapped.
+pub fn set_framebuffer(info: FramebufferInfo) {
+    *FRAMEBUFFER.lock() = Some(info);
+}
+
+/// Returns the current framebuffer, None in text mode.
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    *FRAMEBUFFER.lock()
+}
@@ -0,0 +1,323 @@
+// virtio over the legacy PCI transport
+// the device registers are in I/O BAR 0: feature bits, the device status,
+// the queue registers and an interrupt status register, followed by the
+// device specific configuration. each virtqueue lives in physically
+// contiguous memory: the descriptor table, the ring of descriptor chains
+// the driver made available and, on the next page, the ring of chains the
+// device has used. the device drivers (virtio_blk, ...) share this module
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::{inb, inl, inw, outb, outl, outw};
+use memory::{MemoryController, DmaMemory, PhysicalAddress, PAGE_SIZE};
+use pci::{self, PciDevice, Bar};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+// legacy (transitional) device IDs
+pub const DEVICE_ID_NET: u16 = 0x1000;
+pub const DEVICE_ID_BLOCK: u16 = 0x1001;
+
+// legacy register offsets
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+// without MSI-X the device configuration follows directly
+const DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+pub const ISR_QUEUE: u8 = 1 << 0;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+// the legacy interface takes the queue address as a page number and puts
+// the used ring on the next page boundary
+const QUEUE_ALIGN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// BAR 0 isn't an I/O BAR.
+    NoIoBar,
+    /// The device doesn't have the queue.
+    NoQueue(u16),
+    OutOfMemory,
+}
+
+/// The registers of one device.
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Enables I/O decoding and bus mastering for `device` and resets it.
+    pub fn new(device: &PciDevice) -> Result<Transport, VirtioError> {
+        let base = match device.bar(0) {
+            Some(Bar::Io { base, .. }) => base,
+            _ => return Err(VirtioError::NoIoBar),
+        };
+        device.set_command_bits(pci::COMMAND_IO_SPACE | pci::COMMAND_BUS_MASTER);
+        let transport = Transport { base: base };
+        transport.set_status(0);
+        Ok(transport)
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { inb(self.base + DEVICE_STATUS) }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { outb(self.base + DEVICE_STATUS, status) };
+    }
+
+    /// Acknowledges the device and accepts the offered features that are
+    /// in `supported`. Returns the accepted ones.
+    pub fn negotiate(&self, supported: u32) -> u32 {
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = unsafe { inl(self.base + DEVICE_FEATURES) } & supported;
+        unsafe { outl(self.base + GUEST_FEATURES, features) };
+        features
+    }
+
+    /// Allocates the memory for queue `index` and tells the device about it.
+    pub fn setup_queue(&self, index: u16, memory_controller: &mut MemoryController)
+                       -> Result<Virtqueue, VirtioError> {
+        let size = unsafe {
+            outw(self.base + QUEUE_SELECT, index);
+            inw(self.base + QUEUE_SIZE)
+        };
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+        let layout = Layout::new(size);
+        let memory = match memory_controller.alloc_dma(layout.size / PAGE_SIZE) {
+            Some(memory) => memory,
+            None => return Err(VirtioError::OutOfMemory),
+        };
+        unsafe {
+            outl(self.base + QUEUE_ADDRESS, (memory.physical_address() / QUEUE_ALIGN) as u32)
+        };
+        Ok(Virtqueue::new(*self, index, size, layout, memory))
+    }
+
+    /// Tells the device that the driver is set up.
+    pub fn driver_ok(&self) {
+        let status = self.status();
+        self.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device that the driver gave up on it.
+    pub fn fail(&self) {
+        let status = self.status();
+        self.set_status(status | STATUS_FAILED);
+    }
+
+    /// Reads (and thereby acknowledges) the interrupt status.
+    pub fn read_isr(&self) -> u8 {
+        unsafe { inb(self.base + ISR_STATUS) }
+    }
+
+    fn notify(&self, queue: u16) {
+        unsafe { outw(self.base + QUEUE_NOTIFY, queue) };
+    }
+
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        unsafe { inb(self.base + DEVICE_CONFIG + offset) }
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        unsafe { inl(self.base + DEVICE_CONFIG + offset) }
+    }
+
+    /// Reads a 64 bit field as two halves. Only for fields the device
+    /// doesn't change while running.
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+// byte offsets of the parts of a queue with `size` entries
+struct Layout {
+    available: usize,
+    used: usize,
+    size: usize,
+}
+
+impl Layout {
+    fn new(size: u16) -> Layout {
+        let size = size as usize;
+        // flags, index, the ring and the used event field
+        let available = 16 * size;
+        let used = align_up(available + 6 + 2 * size, QUEUE_ALIGN);
+        Layout {
+            available: available,
+            used: used,
+            size: align_up(used + 6 + 8 * size, PAGE_SIZE),
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+/// One buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysicalAddress,
+    pub length: u32,
+    /// Whether the device writes to it (instead of reading it).
+    pub device_writes: bool,
+}
+
+pub struct Virtqueue {
+    transport: Transport,
+    index: u16,
+    size: u16,
+    layout: Layout,
+    memory: DmaMemory,
+    // the unused descriptors form a list through their `next` fields
+    free_head: u16,
+    free_count: u16,
+    // the next used ring entry we haven't looked at
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(transport: Transport, index: u16, size: u16, layout: Layout, memory: DmaMemory)
+           -> Virtqueue {
+        let queue = Virtqueue {
+            transport: transport,
+            index: index,
+            size: size,
+            layout: layout,
+            memory: memory,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        for descriptor in 0..size {
+            unsafe { (*queue.descriptor(descriptor)).next = descriptor + 1 };
+        }
+        queue
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        assert!(index < self.size);
+        unsafe { (self.memory.pointer(0) as *mut Descriptor).offset(index as isize) }
+    }
+
+    // the available ring: flags, index, then the heads of the chains
+    fn available_field(&self, index: usize) -> *mut u16 {
+        unsafe { (self.memory.pointer(self.layout.available) as *mut u16).offset(index as isize) }
+    }
+
+    fn used_index(&self) -> u16 {
+        unsafe {
+            ptr::read_volatile((self.memory.pointer(self.layout.used) as *const u16).offset(1))
+        }
+    }
+
+    // the used ring: flags, index, then (head, length) pairs
+    fn used_element(&self, slot: u16) -> (u16, u32) {
+        let elements = self.memory.pointer(self.layout.used + 4) as *const u32;
+        let slot = (slot % self.size) as isize * 2;
+        unsafe {
+            let head = ptr::read_volatile(elements.offset(slot));
+            let length = ptr::read_volatile(elements.offset(slot + 1));
+            (head as u16, length)
+        }
+    }
+
+    /// Makes a chain of `buffers` available to the device and notifies it.
+    /// Returns the head descriptor, which `pop_used` returns on completion,
+    /// or None if there aren't enough free descriptors.
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut current = head;
+        for (index, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(current);
+            unsafe {
+                let next = (*descriptor).next;
+                (*descriptor).address = buffer.address as u64;
+                (*descriptor).length = buffer.length;
+                (*descriptor).flags = if buffer.device_writes { DESCRIPTOR_WRITE } else { 0 };
+                if index + 1 < buffers.len() {
+                    (*descriptor).flags |= DESCRIPTOR_NEXT;
+                    current = next;
+                } else {
+                    self.free_head = next;
+                }
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let available_index = ptr::read_volatile(self.available_field(1));
+            let slot = 2 + (available_index % self.size) as usize;
+            ptr::write_volatile(self.available_field(slot), head);
+            // the device must see the ring entry before the new index, and
+            // the index before the notification
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.available_field(1), available_index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        self.transport.notify(self.index);
+        Some(head)
+    }
+
+    /// Returns the head and the written length of the next chain the
+    /// device has finished with, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.used_index() == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let (head, length) = self.used_element(self.last_used);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // put the chain back on the free list
+        let mut last = head;
+        let mut count = 1;
+        unsafe {
+            while (*self.descriptor(last)).flags & DESCRIPTOR_NEXT != 0 {
+                last = (*self.descriptor(last)).next;
+                count += 1;
+            }
+            (*self.descriptor(last)).next = self.free_head;
+        }
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, length))
+    }
+}
+
+/// Returns the first virtio device with the given (legacy) device ID.
+pub fn find(device_id: u16) -> Option<PciDevice> {
+    pci::devices().find(|device| device.vendor_id == VENDOR_ID && device.device_id == device_id)
+}
@@ -0,0 +1,274 @@
+// virtio block device
+// every request is a chain of three buffers: a header with the operation
+// and the first sector, the data and a status byte the device writes. the
+// header, the status and a bounce buffer for the data live in one DMA
+// allocation, so callers can pass any buffer (kernel stacks aren't
+// identity mapped). one request is in flight at a time. the device
+// interrupt only wakes up the waiting caller, which then collects the
+// completion from the used ring itself
+
+use core::ptr;
+use spin::Once;
+use sync::{IrqMutex, Mutex};
+use memory::{MemoryController, DmaMemory, PAGE_SIZE};
+use interrupts::{self, InterruptContext};
+use virtio::{self, Transport, Virtqueue, Buffer};
+use block::{self, BlockDevice, BlockError};
+use cpu;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// feature bits
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+
+// device configuration
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_READ: u32 = 0;
+const REQUEST_WRITE: u32 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_IO_ERROR: u8 = 1;
+
+// layout of the request memory: header, status byte, then the bounce buffer
+const HEADER_OFFSET: usize = 0;
+const HEADER_SIZE: u32 = 16;
+const STATUS_OFFSET: usize = 16;
+const BOUNCE_OFFSET: usize = PAGE_SIZE;
+const BOUNCE_PAGES: usize = 16;
+const SECTORS_PER_REQUEST: usize = BOUNCE_PAGES * PAGE_SIZE / SECTOR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioBlkError {
+    /// `init` found no device.
+    NoDevice,
+    /// The device reported an I/O error.
+    IoError,
+    /// The device doesn't support the request.
+    Unsupported,
+    /// The device is read only.
+    WriteProtected,
+    /// The sectors lie beyond the end of the device.
+    LbaOutOfRange,
+}
+
+struct BlockDevice {
+    queue: Virtqueue,
+    request: DmaMemory,
+    capacity: u64,
+    read_only: bool,
+}
+
+static DEVICE: IrqMutex<Option<BlockDevice>> = IrqMutex::new(None);
+// requests sleep while holding this, so it doesn't disable interrupts
+static REQUEST: Mutex<()> = Mutex::new(());
+// for the interrupt handler, which must not take the DEVICE lock
+static TRANSPORT: Once<Transport> = Once::new();
+
+/// Sets up the first virtio block device. Returns false if there is none,
+/// or it couldn't be initialized.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("virtio_blk::init must be called only once");
+
+    let pci_device = match virtio::find(virtio::DEVICE_ID_BLOCK) {
+        Some(device) => device,
+        None => return false,
+    };
+    let transport = match Transport::new(&pci_device) {
+        Ok(transport) => transport,
+        Err(error) => {
+            println!("virtio-blk: {}: {:?}", pci_device, error);
+            return false;
+        }
+    };
+    let features = transport.negotiate(FEATURE_READ_ONLY);
+    let queue = match transport.setup_queue(0, memory_controller) {
+        Ok(queue) => queue,
+        Err(error) => {
+            println!("virtio-blk: could not set up the queue: {:?}", error);
+            transport.fail();
+            return false;
+        }
+    };
+    let request = match memory_controller.alloc_dma(1 + BOUNCE_PAGES) {
+        Some(memory) => memory,
+        None => {
+            println!("virtio-blk: out of memory");
+            transport.fail();
+            return false;
+        }
+    };
+
+    let capacity = transport.config_u64(CONFIG_CAPACITY);
+    let read_only = features & FEATURE_READ_ONLY != 0;
+    TRANSPORT.call_once(|| transport);
+    if let Err(error) = interrupts::register_irq(pci_device.interrupt_line, virtio_blk_interrupt) {
+        println!("virtio-blk: can't use IRQ {}: {:?}, polling", pci_device.interrupt_line, error);
+    }
+    transport.driver_ok();
+
+    println!("virtio-blk: {} sectors ({} MiB){}, queue size {}, IRQ {}", capacity,
+             capacity * SECTOR_SIZE as u64 >> 20, if read_only { ", read only" } else { "" },
+             queue.size(), pci_device.interrupt_line);
+    *DEVICE.lock() = Some(BlockDevice {
+        queue: queue,
+        request: request,
+        capacity: capacity,
+        read_only: read_only,
+    });
+    block::register(&VirtioBlk);
+    true
+}
+
+fn virtio_blk_interrupt(_context: &mut InterruptContext) {
+    // reading the ISR status deasserts the (level triggered) line. the line
+    // may be shared, so there might be nothing to acknowledge
+    if let Some(transport) = TRANSPORT.try() {
+        transport.read_isr();
+    }
+}
+
+/// Returns the number of sectors of the device, None without one.
+pub fn capacity() -> Option<u64> {
+    DEVICE.lock().as_ref().map(|device| device.capacity)
+}
+
+/// The device as a `block::BlockDevice`.
+pub struct VirtioBlk;
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        capacity().unwrap_or(0)
+    }
+
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_length(buffer.len(), SECTOR_SIZE)?;
+        read(lba, buffer).map_err(BlockError::from)
+    }
+
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_length(buffer.len(), SECTOR_SIZE)?;
+        write(lba, buffer).map_err(BlockError::from)
+    }
+}
+
+/// Reads `buffer.len() / SECTOR_SIZE` sectors starting at `lba`. The
+/// length must be a multiple of `SECTOR_SIZE`. Must not be called from
+/// interrupt context.
+pub fn read(lba: u64, buffer: &mut [u8]) -> Result<(), VirtioBlkError> {
+    assert!(buffer.len() % SECTOR_SIZE == 0, "virtio-blk: partial sector");
+    let _request = REQUEST.lock();
+    for (index, chunk) in buffer.chunks_mut(SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+        let sector = lba + (index * SECTORS_PER_REQUEST) as u64;
+        transfer(REQUEST_READ, sector, chunk.len())?;
+        let device = DEVICE.lock();
+        let device = device.as_ref().expect("virtio-blk device vanished");
+        unsafe {
+            ptr::copy_nonoverlapping(device.request.pointer(BOUNCE_OFFSET), chunk.as_mut_ptr(),
+                                     chunk.len());
+        }
+    }
+    Ok(())
+}
+
+/// Writes `buffer` to the sectors starting at `lba`. The length must be a
+/// multiple of `SECTOR_SIZE`. Must not be called from interrupt context.
+pub fn write(lba: u64, buffer: &[u8]) -> Result<(), VirtioBlkError> {
+    assert!(buffer.len() % SECTOR_SIZE == 0, "virtio-blk: partial sector");
+    let _request = REQUEST.lock();
+    for (index, chunk) in buffer.chunks(SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+        let sector = lba + (index * SECTORS_PER_REQUEST) as u64;
+        {
+            let device = DEVICE.lock();
+            let device = match device.as_ref() {
+                Some(device) => device,
+                None => return Err(VirtioBlkError::NoDevice),
+            };
+            if device.read_only {
+                return Err(VirtioBlkError::WriteProtected);
+            }
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), device.request.pointer(BOUNCE_OFFSET),
+                                         chunk.len());
+            }
+        }
+        transfer(REQUEST_WRITE, sector, chunk.len())?;
+    }
+    Ok(())
+}
+
+// submits one request with the bounce buffer and waits for it. the caller
+// holds the REQUEST lock
+fn transfer(operation: u32, sector: u64, length: usize) -> Result<(), VirtioBlkError> {
+    let head = {
+        let mut device = DEVICE.lock();
+        let device = match device.as_mut() {
+            Some(device) => device,
+            None => return Err(VirtioBlkError::NoDevice),
+        };
+        if sector + (length / SECTOR_SIZE) as u64 > device.capacity {
+            return Err(VirtioBlkError::LbaOutOfRange);
+        }
+
+        let base = device.request.physical_address();
+        unsafe {
+            let header = device.request.pointer(HEADER_OFFSET);
+            ptr::write_volatile(header as *mut u32, operation);
+            ptr::write_volatile(header.offset(4) as *mut u32, 0);
+            ptr::write_volatile(header.offset(8) as *mut u64, sector);
+            ptr::write_volatile(device.request.pointer(STATUS_OFFSET), 0xff);
+        }
+        let buffers = [
+            Buffer { address: base + HEADER_OFFSET, length: HEADER_SIZE, device_writes: false },
+            Buffer {
+                address: base + BOUNCE_OFFSET,
+                length: length as u32,
+                device_writes: operation == REQUEST_READ,
+            },
+            Buffer { address: base + STATUS_OFFSET, length: 1, device_writes: true },
+        ];
+        device.queue.submit(&buffers).expect("virtio-blk: queue full with one request in flight")
+    };
+
+    wait_for(head);
+
+    let device = DEVICE.lock();
+    let device = device.as_ref().expect("virtio-blk device vanished");
+    match unsafe { ptr::read_volatile(device.request.pointer(STATUS_OFFSET)) } {
+        STATUS_OK => Ok(()),
+        STATUS_IO_ERROR => Err(VirtioBlkError::IoError),
+        _ => Err(VirtioBlkError::Unsupported),
+    }
+}
+
+fn wait_for(head: u16) {
+    use x86_64::instructions::interrupts;
+
+    // same pattern as `keyboard::next_event`, the device interrupt ends the
+    // hlt. before interrupts are enabled this just polls
+    let interrupts_enabled = ::interrupts::interrupts_enabled();
+    loop {
+        unsafe { interrupts::disable() };
+        let completed = DEVICE.lock().as_mut()
+            .expect("virtio-blk device vanished")
+            .queue.pop_used();
+        if let Some((used_head, _)) = completed {
+            assert_eq!(used_head, head, "virtio-blk: completion of an unknown request");
+            if interrupts_enabled {
+                unsafe { interrupts::enable() };
+            }
+            return;
+        }
+        if interrupts_enabled {
+            cpu::enable_interrupts_and_halt();
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// virtio network card
+// queue 0 receives, queue 1 transmits. every packet is preceded by a
+// virtio-net header (checksum and segmentation offload, which we don't
+// negotiate, so it stays zero), in its own descriptor as the legacy
+// interface wants. the receive buffers are posted once and go back into
+// the available ring right after the callback has seen the frame
+
+use core::{mem, ptr, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use sync::IrqMutex;
+use memory::{MemoryController, DmaMemory, PAGE_SIZE};
+use interrupts::{self, InterruptContext};
+use net::{MacAddress, NetError, NetworkDevice, ReceiveCallback};
+use virtio::{self, Transport, Virtqueue, Buffer};
+
+// feature bits
+const FEATURE_MAC: u32 = 1 << 5;
+
+// device configuration
+const CONFIG_MAC: u16 = 0;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// without the mergeable receive buffers feature
+const HEADER_SIZE: usize = 10;
+// a header and an ethernet frame without the frame check sequence
+const BUFFER_SIZE: usize = 2048;
+const MAX_FRAME_SIZE: usize = 1514;
+const RX_BUFFERS: usize = 16;
+
+// in used ring polls
+const TIMEOUT: usize = 1_000_000;
+
+// the buffers of one queue, every buffer starts with the header
+struct Ring {
+    queue: Virtqueue,
+    buffers: DmaMemory,
+    // head descriptor of each chain -> index of its buffer
+    buffer_of_head: [u8; 256],
+}
+
+impl Ring {
+    fn new(queue: Virtqueue, buffers: DmaMemory) -> Ring {
+        Ring {
+            queue: queue,
+            buffers: buffers,
+            buffer_of_head: [0; 256],
+        }
+    }
+
+    // submits buffer `index` as a chain of the header and the frame
+    fn submit(&mut self, index: usize, frame_length: usize, device_writes: bool) -> bool {
+        let address = self.buffers.physical_address() + index * BUFFER_SIZE;
+        let chain = [
+            Buffer {
+                address: address,
+                length: HEADER_SIZE as u32,
+                device_writes: device_writes,
+            },
+            Buffer {
+                address: address + HEADER_SIZE,
+                length: frame_length as u32,
+                device_writes: device_writes,
+            },
+        ];
+        match self.queue.submit(&chain) {
+            Some(head) => {
+                self.buffer_of_head[head as usize] = index as u8;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn frame(&self, index: usize) -> *mut u8 {
+        self.buffers.pointer(index * BUFFER_SIZE + HEADER_SIZE)
+    }
+}
+
+static TRANSPORT: Once<Transport> = Once::new();
+static MAC_ADDRESS: Once<MacAddress> = Once::new();
+// separate locks, so the receive callback can send a reply
+static RX: IrqMutex<Option<Ring>> = IrqMutex::new(None);
+static TX: IrqMutex<Option<Ring>> = IrqMutex::new(None);
+// stored as usize like the IRQ handlers, 0 means none
+static RECEIVE_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets up the first virtio network card. Returns false if there is none,
+/// or it couldn't be initialized.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    assert_has_not_been_called!("virtio_net::init must be called only once");
+
+    let pci_device = match virtio::find(virtio::DEVICE_ID_NET) {
+        Some(device) => device,
+        None => return false,
+    };
+    let transport = match Transport::new(&pci_device) {
+        Ok(transport) => transport,
+        Err(error) => {
+            println!("virtio-net: {}: {:?}", pci_device, error);
+            return false;
+        }
+    };
+    if transport.negotiate(FEATURE_MAC) & FEATURE_MAC == 0 {
+        // we would have to make up an address
+        println!("virtio-net: the device has no MAC address");
+        transport.fail();
+        return false;
+    }
+    let rx_queue = transport.setup_queue(RECEIVE_QUEUE, memory_controller);
+    let tx_queue = transport.setup_queue(TRANSMIT_QUEUE, memory_controller);
+    let (rx_queue, tx_queue) = match (rx_queue, tx_queue) {
+        (Ok(rx), Ok(tx)) => (rx, tx),
+        (Err(error), _) | (_, Err(error)) => {
+            println!("virtio-net: could not set up the queues: {:?}", error);
+            transport.fail();
+            return false;
+        }
+    };
+    // one transmit buffer, `send` waits for every frame
+    let rx_buffers = memory_controller.alloc_dma(RX_BUFFERS * BUFFER_SIZE / PAGE_SIZE);
+    let tx_buffer = memory_controller.alloc_dma(1);
+    let (rx_buffers, tx_buffer) = match (rx_buffers, tx_buffer) {
+        (Some(rx), Some(tx)) => (rx, tx),
+        _ => {
+            println!("virtio-net: out of memory");
+            transport.fail();
+            return false;
+        }
+    };
+
+    let mut mac_address = [0; 6];
+    for (index, byte) in mac_address.iter_mut().enumerate() {
+        *byte = transport.config_u8(CONFIG_MAC + index as u16);
+    }
+    let mac_address = MacAddress(mac_address);
+
+    TRANSPORT.call_once(|| transport);
+    MAC_ADDRESS.call_once(|| mac_address);
+    if let Err(error) = interrupts::register_irq(pci_device.interrupt_line, virtio_net_interrupt) {
+        println!("virtio-net: can't use IRQ {}: {:?}", pci_device.interrupt_line, error);
+        transport.fail();
+        return false;
+    }
+    transport.driver_ok();
+
+    let mut rx = Ring::new(rx_queue, rx_buffers);
+    for index in 0..RX_BUFFERS {
+        assert!(rx.submit(index, BUFFER_SIZE - HEADER_SIZE, true),
+                "virtio-net: receive queue too small");
+    }
+    *RX.lock() = Some(rx);
+    *TX.lock() = Some(Ring::new(tx_queue, tx_buffer));
+
+    println!("virtio-net: MAC {}, IRQ {}", mac_address, pci_device.interrupt_line);
+    true
+}
+
+/// Returns the MAC address of the card, None without one.
+pub fn mac_address() -> Option<MacAddress> {
+    MAC_ADDRESS.try().cloned()
+}
+
+/// Sets the function received frames are passed to. It runs in interrupt
+/// context.
+pub fn set_receive_callback(callback: ReceiveCallback) {
+    RECEIVE_CALLBACK.store(callback as usize, Ordering::SeqCst);
+}
+
+fn virtio_net_interrupt(_context: &mut InterruptContext) {
+    // reading the ISR status deasserts the (level triggered) line
+    let isr = match TRANSPORT.try() {
+        Some(transport) => transport.read_isr(),
+        None => return,
+    };
+    if isr & virtio::ISR_QUEUE != 0 {
+        receive();
+    }
+}
+
+fn receive() {
+    let mut rx = RX.lock();
+    let rx = match rx.as_mut() {
+        Some(rx) => rx,
+        None => return,
+    };
+    let callback = RECEIVE_CALLBACK.load(Ordering::Acquire);
+    while let Some((head, length)) = rx.queue.pop_used() {
+        let index = rx.buffer_of_head[head as usize] as usize;
+        // the length includes the header
+        let frame_length = (length as usize).saturating_sub(HEADER_SIZE);
+        if callback != 0 && frame_length > 0 {
+            let callback: ReceiveCallback = unsafe { mem::transmute(callback) };
+            callback(unsafe { slice::from_raw_parts(rx.frame(index), frame_length) });
+        }
+        // the descriptors were freed by `pop_used`, so this can't fail
+        rx.submit(index, BUFFER_SIZE - HEADER_SIZE, true);
+    }
+}
+
+/// Sends `frame`, which starts with the ethernet header and doesn't
+/// include the frame check sequence. Waits until the card has sent it.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(NetError::FrameTooLong);
+    }
+    let mut tx = TX.lock();
+    let tx = match tx.as_mut() {
+        Some(tx) => tx,
+        None => return Err(NetError::NoDevice),
+    };
+    unsafe {
+        ptr::write_bytes(tx.buffers.pointer(0), 0, HEADER_SIZE);
+        ptr::copy_nonoverlapping(frame.as_ptr(), tx.frame(0), frame.len());
+    }
+    if !tx.submit(0, frame.len(), false) {
+        return Err(NetError::Timeout);
+    }
+    // the used ring entry also means the device is done with the buffer
+    for _ in 0..TIMEOUT {
+        if tx.queue.pop_used().is_some() {
+            return Ok(());
+        }
+    }
+    Err(NetError::Timeout)
+}
+
+/// The card as a `net::NetworkDevice`.
+pub struct VirtioNet;
+
+impl NetworkDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        mac_address().expect("virtio-net not initialized")
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        send(frame)
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        set_receive_callback(callback)
+    }
+}
@@ -0,0 +1,71 @@
+// software watchdog
+// code that runs for long pets the watchdog regularly, the timer interrupt
+// checks that it was petted within the timeout. it can't catch hangs with
+// interrupts disabled, since then the timer doesn't run either
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use interrupts::{self, InterruptContext};
+use cmdline;
+use time;
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+// in ticks, 0 means disarmed
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static LAST_PET: AtomicU64 = AtomicU64::new(0);
+static PANIC_ON_EXPIRY: AtomicBool = AtomicBool::new(false);
+
+/// Arms the watchdog with `DEFAULT_TIMEOUT_MS` according to the command line:
+/// `watchdog=off` leaves it disarmed, `watchdog=panic` panics when it
+/// expires, anything else (the default) only logs. The tick must be running.
+pub fn init() {
+    match cmdline::get("watchdog") {
+        Some("off") => return,
+        Some("panic") => PANIC_ON_EXPIRY.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+    arm(DEFAULT_TIMEOUT_MS);
+}
+
+/// Starts watching: `pet` has to be called at least every `timeout_ms`.
+pub fn arm(timeout_ms: u64) {
+    let ticks = timeout_ms * time::tick_hz() as u64 / 1000;
+    pet();
+    TIMEOUT.store(::core::cmp::max(ticks, 1), Ordering::SeqCst);
+}
+
+/// Stops watching, for operations that legitimately take longer.
+pub fn disarm() {
+    TIMEOUT.store(0, Ordering::SeqCst);
+}
+
+pub fn is_armed() -> bool {
+    TIMEOUT.load(Ordering::Relaxed) != 0
+}
+
+/// Tells the watchdog that we are still making progress.
+pub fn pet() {
+    LAST_PET.store(time::ticks(), Ordering::Relaxed);
+}
+
+/// Called by the timer interrupt for every tick.
+pub fn check(context: &InterruptContext) {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return;
+    }
+    let now = time::ticks();
+    let elapsed = now.saturating_sub(LAST_PET.load(Ordering::Relaxed));
+    if elapsed <= timeout {
+        return;
+    }
+
+    println!("\nWATCHDOG: not petted for {} ticks, interrupted at {:#x}",
+             elapsed, context.stack_frame.instruction_pointer.0);
+    interrupts::print_stats();
+    if PANIC_ON_EXPIRY.load(Ordering::Relaxed) {
+        panic!("watchdog expired");
+    }
+    // log again only after another full timeout
+    LAST_PET.store(now, Ordering::Relaxed);
+}
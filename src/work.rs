@@ -0,0 +1,206 @@
+// deferred work
+// interrupt handlers push work items here and return quickly, the items run
+// later from the idle loop with interrupts enabled. the queue is a bounded
+// lock free multi producer, single consumer ring: producers claim a slot by
+// advancing `TAIL`, and every slot has a sequence number saying whether it
+// is free or filled for the current lap
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use interrupts;
+use cpu;
+use watchdog;
+use task;
+
+const QUEUE_SIZE: usize = 64; // must be a power of two
+const MASK: usize = QUEUE_SIZE - 1;
+
+struct Slot {
+    // stored relative to the slot index, so every slot can start at 0: the
+    // slot at index i contains the logical sequence number `sequence + i`
+    sequence: AtomicUsize,
+    function: AtomicUsize,
+    argument: AtomicUsize,
+}
+
+macro_rules! slot {
+    () => {
+        Slot {
+            sequence: AtomicUsize::new(0),
+            function: AtomicUsize::new(0),
+            argument: AtomicUsize::new(0),
+        }
+    };
+}
+
+static SLOTS: [Slot; QUEUE_SIZE] = [
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+    slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(), slot!(),
+];
+
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn sequence(position: usize) -> usize {
+    let index = position & MASK;
+    SLOTS[index].sequence.load(Ordering::Acquire).wrapping_add(index)
+}
+
+fn set_sequence(position: usize, sequence: usize) {
+    let index = position & MASK;
+    SLOTS[index].sequence.store(sequence.wrapping_sub(index), Ordering::Release);
+}
+
+/// Queues `function` to run soon with interrupts enabled. Can be called
+/// from interrupt handlers. Returns false (and counts a dropped item) if
+/// the queue is full.
+pub fn schedule(function: fn()) -> bool {
+    schedule_with(call_plain, function as usize)
+}
+
+/// Like `schedule`, but passes `argument` to the function.
+pub fn schedule_with(function: fn(usize), argument: usize) -> bool {
+    let mut position = TAIL.load(Ordering::Relaxed);
+    loop {
+        let difference = sequence(position).wrapping_sub(position) as isize;
+        if difference == 0 {
+            // the slot is free for this lap, try to claim it
+            let previous = TAIL.compare_and_swap(position, position + 1, Ordering::Relaxed);
+            if previous == position {
+                break;
+            }
+            position = previous;
+        } else if difference < 0 {
+            // the consumer hasn't emptied the slot of the previous lap
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return false;
+        } else {
+            position = TAIL.load(Ordering::Relaxed);
+        }
+    }
+
+    let slot = &SLOTS[position & MASK];
+    slot.function.store(function as usize, Ordering::Relaxed);
+    slot.argument.store(argument, Ordering::Relaxed);
+    set_sequence(position, position.wrapping_add(1));
+    true
+}
+
+fn call_plain(function: usize) {
+    let function: fn() = unsafe { mem::transmute(function) };
+    function();
+}
+
+// only one consumer: the CPU running the idle loop
+fn pop() -> Option<(fn(usize), usize)> {
+    let position = HEAD.load(Ordering::Relaxed);
+    if sequence(position) != position.wrapping_add(1) {
+        return None; // empty, or the producer isn't done with the slot yet
+    }
+    let slot = &SLOTS[position & MASK];
+    let function: fn(usize) = unsafe {
+        mem::transmute(slot.function.load(Ordering::Relaxed))
+    };
+    let argument = slot.argument.load(Ordering::Relaxed);
+    set_sequence(position, position.wrapping_add(QUEUE_SIZE));
+    HEAD.store(position.wrapping_add(1), Ordering::Relaxed);
+    Some((function, argument))
+}
+
+/// Returns whether work is waiting.
+pub fn has_pending() -> bool {
+    let position = HEAD.load(Ordering::Relaxed);
+    sequence(position) == position.wrapping_add(1)
+}
+
+/// Runs all queued work, including work queued by the items themselves.
+/// Must not be called from interrupt context.
+pub fn run_pending() {
+    assert!(!interrupts::in_interrupt_context(), "work run in interrupt context");
+    // one consumer at a time
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    while let Some((function, argument)) = pop() {
+        function(argument);
+    }
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Returns how many work items were dropped because the queue was full.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Runs the queued work and halts whenever there is none, forever. Being
+/// idle counts as progress for the watchdog.
+pub fn idle_loop() -> ! {
+    loop {
+        watchdog::pet();
+        run_pending();
+        if task::yield_now() {
+            continue;
+        }
+        // only halt if nothing was queued since the check, see
+        // `cpu::enable_interrupts_and_halt`
+        unsafe { cpu_interrupts::disable() };
+        if has_pending() {
+            unsafe { cpu_interrupts::enable() };
+        } else {
+            cpu::enable_interrupts_and_halt();
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+static TEST_SCHEDULED: AtomicBool = AtomicBool::new(false);
+#[cfg(debug_assertions)]
+static TEST_RAN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(debug_assertions)]
+fn test_timer_handler(_context: &mut interrupts::InterruptContext) {
+    if !TEST_SCHEDULED.swap(true, Ordering::SeqCst) {
+        schedule(test_work);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn test_work() {
+    let in_interrupt = interrupts::in_interrupt_context();
+    println!("deferred work ran (in interrupt context: {})", in_interrupt);
+    assert!(!in_interrupt);
+    TEST_RAN.store(true, Ordering::SeqCst);
+}
+
+/// Lets the PIT interrupt schedule work that prints, and checks that it runs
+/// outside of interrupt context. Interrupts must be enabled.
+#[cfg(debug_assertions)]
+pub fn test_deferred_work() {
+    use {pit, time};
+
+    assert!(::interrupts::interrupts_enabled(), "test_deferred_work needs interrupts");
+    TEST_SCHEDULED.store(false, Ordering::SeqCst);
+    TEST_RAN.store(false, Ordering::SeqCst);
+    // with another tick source channel 0 isn't running yet
+    pit::init_channel0(pit::divisor_for(time::DEFAULT_HZ));
+    interrupts::register_irq(time::TIMER_IRQ, test_timer_handler)
+        .expect("could not register test timer handler");
+
+    while !TEST_RAN.load(Ordering::SeqCst) {
+        run_pending();
+        cpu::halt();
+    }
+
+    interrupts::unregister_irq(time::TIMER_IRQ, test_timer_handler)
+        .expect("could not unregister test timer handler");
+    println!("deferred work test passed");
+}